@@ -1,8 +1,9 @@
+use crate::effects::{EffectCommand, EffectConfig};
 use crate::gui_channel::WAVEFORM_DOWNSAMPLE;
-use crate::music::{SavedMetricStructure};
+use crate::music::{Groove, GrooveGrid, MetronomePattern, SavedMetricStructure, Subdivision};
 use derive_more::{Add, Div, Mul, Sub};
-use serde::{Deserialize, Serialize};
-use std::ops::{Index, IndexMut};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::ops::Index;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -11,33 +12,366 @@ use std::sync::Arc;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::effects::EffectParam;
 
     #[test]
     fn test_from_str() {
+        let mut names = Names::default();
+
         assert_eq!(
             Command::Start,
-            Command::from_str("Start", &[][..]).unwrap()(CommandData { data: 0 })
+            Command::from_str("Start", &[][..], &mut names).unwrap()(CommandData { data: 0 })
         );
 
         assert_eq!(
             Command::SetTime(FrameTime(100)),
-            Command::from_str("SetTime", &["100"][..]).unwrap()(CommandData { data: 0 })
+            Command::from_str("SetTime", &["100"][..], &mut names).unwrap()(CommandData { data: 0 })
         );
 
         assert_eq!(
             Command::Looper(LooperCommand::Record, LooperTarget::All),
-            Command::from_str("Record", &["All"][..]).unwrap()(CommandData { data: 0 })
+            Command::from_str("Record", &["All"][..], &mut names).unwrap()(CommandData { data: 0 })
         );
 
         assert_eq!(
             Command::Looper(LooperCommand::Overdub, LooperTarget::Selected),
-            Command::from_str("Overdub", &["Selected"][..]).unwrap()(CommandData { data: 0 })
+            Command::from_str("Overdub", &["Selected"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
         );
 
         assert_eq!(
             Command::Looper(LooperCommand::Mute, LooperTarget::Index(13)),
-            Command::from_str("Mute", &["13"][..]).unwrap()(CommandData { data: 0 })
+            Command::from_str("Mute", &["13"][..], &mut names).unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::Looper(
+                LooperCommand::Effect(EffectCommand::SetParam(1, EffectParam::Mix, 1.0)),
+                LooperTarget::Selected
+            ),
+            Command::from_str(
+                "SetEffectParam",
+                &["Selected", "1", "Mix", "$data"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 127 })
+        );
+
+        assert_eq!(
+            Command::AddPlugin(
+                InsertTarget::Master,
+                Arc::new(SavedPlugin {
+                    path: PathBuf::from("/usr/lib/clap/reverb.clap"),
+                    id: Some("com.example.reverb".to_string()),
+                    state: None,
+                })
+            ),
+            Command::from_str(
+                "AddPlugin",
+                &["Master", "/usr/lib/clap/reverb.clap", "com.example.reverb"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::SetPluginParam(
+                InsertTarget::Looper(LooperTarget::Index(2)),
+                0,
+                7,
+                PluginParamValue::Normalized(0.0)
+            ),
+            Command::from_str("SetPluginParam", &["2", "0", "7", "$data"][..], &mut names).unwrap()(
+                CommandData { data: 0 }
+            )
+        );
+
+        assert!(Command::from_str("RemovePlugin", &["Master"][..], &mut names).is_err());
+
+        assert_eq!(
+            Command::Looper(LooperCommand::SetSend(1, 0.5), LooperTarget::All),
+            Command::from_str(
+                "SetSend",
+                &["All", "Aux1", "0.5"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::AuxEffect(0, EffectCommand::Remove(2)),
+            Command::from_str("RemoveEffect", &["Aux0", "2"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert_eq!(
+            Command::AddPlugin(
+                InsertTarget::Aux(1),
+                Arc::new(SavedPlugin {
+                    path: PathBuf::from("delay.clap"),
+                    id: None,
+                    state: None,
+                })
+            ),
+            Command::from_str(
+                "AddPlugin",
+                &["Aux1", "delay.clap"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+
+        assert!(Command::from_str("SetAuxReturnLevel", &["Aux9", "1"][..], &mut names).is_err());
+
+        assert_eq!(
+            Command::SetAccentPattern(0b10101),
+            Command::from_str("SetAccentPattern", &["2+2+3"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert!(
+            Command::from_str("SetMetronomeSubdivision", &["Quintuplets"][..], &mut names).is_err()
+        );
+
+        assert_eq!(
+            Command::SetSwing(66, GrooveGrid::Sixteenths),
+            Command::from_str(
+                "SetSwing",
+                &["66", "Sixteenths"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+        assert!(Command::from_str("SetSwing", &["90"][..], &mut names).is_err());
+
+        assert_eq!(
+            Command::SetGrooveTemplate([0, 10, -5, 0]),
+            Command::from_str(
+                "SetGrooveTemplate",
+                &["0,10,-5"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+        assert!(Command::from_str("SetGrooveTemplate", &["0,0,0,0,0"][..], &mut names).is_err());
+        assert!(Command::from_str("SetGrooveTemplate", &["80"][..], &mut names).is_err());
+
+        assert_eq!(
+            Command::SetQuantizationMode(QuantizationMode::Phrase(4)),
+            Command::from_str("SetQuantizationMode", &["Phrase", "4"][..], &mut names).unwrap()(
+                CommandData { data: 0 }
+            )
         );
+        assert_eq!(
+            Command::SetQuantizationMode(QuantizationMode::LoopEnd(2)),
+            Command::from_str("SetQuantizationMode", &["LoopEnd", "2"][..], &mut names).unwrap()(
+                CommandData { data: 0 }
+            )
+        );
+        assert!(Command::from_str("SetQuantizationMode", &["Phrase"][..], &mut names).is_err());
+        assert!(Command::from_str("SetQuantizationMode", &["Bar"][..], &mut names).is_err());
+
+        for mode in &[
+            QuantizationMode::Free,
+            QuantizationMode::Measure,
+            QuantizationMode::Phrase(8),
+            QuantizationMode::LoopEnd(0),
+            QuantizationMode::LongestLoop,
+            QuantizationMode::MasterLoop(1),
+        ] {
+            assert_eq!(Ok(*mode), QuantizationMode::from_str(&mode.to_string()));
+        }
+        assert!(QuantizationMode::from_str("Phrase:0").is_err());
+        assert!(QuantizationMode::from_str("MasterLoop").is_err());
+        assert!(QuantizationMode::from_str("Measure:2").is_err());
+
+        assert_eq!(
+            Command::CancelTriggers(Some(LooperTarget::Selected)),
+            Command::from_str(
+                "CancelTriggers",
+                &["Selected"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+        assert_eq!(
+            Command::CancelTriggers(None),
+            Command::from_str("CancelTriggers", &[][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert_eq!(
+            Command::RecallScene(Arc::new("Chorus".to_string())),
+            Command::from_str("RecallScene", &["Chorus"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert_eq!(
+            Command::RecallSceneByIndex(2),
+            Command::from_str("RecallSceneByIndex", &["2"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert!(Command::from_str("SaveScene", &[][..], &mut names).is_err());
+
+        let verse = names.part("Verse").unwrap();
+        assert_eq!(Part(4), verse);
+        assert_eq!(
+            Command::GoToPart(verse),
+            Command::from_str("GoToPart", &["Verse"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert_eq!(
+            Command::Looper(LooperCommand::AddToPart(verse), LooperTarget::Selected),
+            Command::from_str(
+                "AddToPart",
+                &["Selected", "Verse"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+        assert_eq!(
+            Command::AddPart(Arc::new("Outro".to_string())),
+            Command::from_str("AddPart", &["Outro"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert_eq!(Some(5), names.parts.get("Outro"));
+        assert!(Command::from_str("GoToPart", &[""][..], &mut names).is_err());
+
+        let drums = names.group("drums").unwrap();
+        assert_eq!(Group(0), drums);
+        assert_eq!(
+            Command::Looper(LooperCommand::Mute, LooperTarget::Group(drums)),
+            Command::from_str("Mute", &["Group:drums"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert_eq!(
+            Command::Looper(LooperCommand::AddToGroup(drums), LooperTarget::Index(0)),
+            Command::from_str("AddToGroup", &["0", "drums"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+        assert!(Command::from_str("Solo", &["Group:"][..], &mut names).is_err());
+
+        assert_eq!(
+            Command::SetMetronomeSound(Arc::new("Woodblock".to_string())),
+            Command::from_str(
+                "SetMetronomeSound",
+                &["Woodblock"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+
+        assert_eq!(
+            Command::SetMasterGain(-6.0),
+            Command::from_str("SetMasterGain", &["-6"][..], &mut names).unwrap()(CommandData {
+                data: 0
+            })
+        );
+
+        assert_eq!(
+            Command::SetMasterGain(MAX_MASTER_GAIN_DB),
+            Command::from_str("SetMasterGain", &["$data"][..], &mut names).unwrap()(CommandData {
+                data: 127
+            })
+        );
+
+        assert_eq!(
+            Command::SetOutputProtection(OutputProtection::Limiter),
+            Command::from_str(
+                "SetOutputProtection",
+                &["Limiter"][..],
+                &mut names
+            )
+            .unwrap()(CommandData { data: 0 })
+        );
+    }
+
+    #[test]
+    fn test_parts() {
+        let mut names = Names::default();
+        assert_eq!(Ok(Part::C), names.part("C"));
+
+        let bridge = names.part("Bridge").unwrap();
+        assert_eq!("Bridge", names.part_name(bridge));
+        assert_eq!(Ok(bridge), names.part("Bridge"));
+        assert_ne!(bridge, names.part("Outro").unwrap());
+        assert!(names.part("Middle 8").is_err());
+
+        // ids are indices into the table, which is limited so that sets of parts fit in a bitset
+        for i in names.parts.names.len()..MAX_NAMES {
+            names.part(&format!("P{}", i)).unwrap();
+        }
+        assert!(names.part("Extra").is_err());
+        assert_eq!(Ok(bridge), names.part("Bridge"));
+
+        let mut parts = PartSet::with(Part::C);
+        assert!(!parts.is_empty());
+        assert!(!parts[Part::A]);
+
+        parts.insert(bridge);
+        parts.insert(bridge);
+        parts.insert(Part(MAX_NAMES as u8 - 1));
+        assert_eq!(3, parts.len());
+        assert!(parts[bridge]);
+        assert_eq!(
+            vec![Part::C, bridge, Part(MAX_NAMES as u8 - 1)],
+            parts.iter().collect::<Vec<_>>()
+        );
+
+        parts.remove(Part::C);
+        parts.remove(bridge);
+        parts.remove(Part(MAX_NAMES as u8 - 1));
+        assert!(parts.is_empty());
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut names = Names::default();
+        let drums = names.group("drums").unwrap();
+        let pads = names.group("pads").unwrap();
+        assert_eq!(Ok(drums), names.group("drums"));
+        assert!(names.group("lead synth").is_err());
+
+        let mut groups = GroupSet::default();
+        assert!(groups.is_empty());
+        groups.insert(pads);
+        groups.insert(pads);
+        assert!(groups.contains(pads));
+        assert!(!groups.contains(drums));
+        assert_eq!(vec![pads], groups.iter().collect::<Vec<_>>());
+
+        let saved = [Arc::new("drums".to_string()), Arc::new("bass".to_string())];
+        let groups = names.group_set(&saved);
+        assert_eq!(vec![drums, Group(2)], groups.iter().collect::<Vec<_>>());
+        assert_eq!(Some(2), names.groups.get("bass"));
+    }
+
+    #[test]
+    fn test_saved_parts() {
+        let looper = |parts: &str| {
+            serde_json::from_str::<SavedLooper>(&format!(
+                r#"{{"id": 0, "mode": "Playing", "samples": [], {}}}"#,
+                parts
+            ))
+            .unwrap()
+            .parts
+        };
+        let names = |ns: &[&str]| ns.iter().map(|n| Arc::new(n.to_string())).collect::<Vec<_>>();
+
+        assert_eq!(names(&["Verse", "B"]), looper(r#""parts": ["Verse", "B"]"#));
+        assert_eq!(
+            names(&["A", "C"]),
+            looper(r#""parts": {"a": true, "b": false, "c": true, "d": false}"#)
+        );
+        assert_eq!(names(&["A"]), looper(r#""level": 1.0"#));
     }
 }
 
@@ -98,6 +432,77 @@ pub enum LooperTarget {
     Index(u8),
     All,
     Selected,
+    Group(Group),
+}
+
+/// The number of aux buses that loopers can send to
+pub const AUX_BUS_COUNT: usize = 4;
+
+/// Where a plugin insert is placed: on the output of one or more loopers, on an aux bus or on the
+/// master output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum InsertTarget {
+    Looper(LooperTarget),
+    Aux(u8),
+    Master,
+}
+
+// aux buses are referred to as Aux0, Aux1, etc.
+fn aux_bus(command: &str, bus: &str) -> Result<u8, String> {
+    bus.strip_prefix("Aux")
+        .and_then(|b| u8::from_str(b).ok())
+        .filter(|b| (*b as usize) < AUX_BUS_COUNT)
+        .ok_or(format!(
+            "{} expects an aux bus (Aux0 to Aux{})",
+            command,
+            AUX_BUS_COUNT - 1
+        ))
+}
+
+fn looper_target(command: &str, target: &str, names: &mut Names) -> Result<LooperTarget, String> {
+    Ok(match target {
+        "All" => LooperTarget::All,
+        "Selected" => LooperTarget::Selected,
+        g if g.starts_with("Group:") => LooperTarget::Group(
+            names
+                .group(&g["Group:".len()..])
+                .map_err(|e| format!("{}: {}", command, e))?,
+        ),
+        i => LooperTarget::Index(u8::from_str(i).map_err(|_| {
+            format!(
+                "{} expects a target (All, Selected, Group:name, or a looper index)",
+                command
+            )
+        })?),
+    })
+}
+
+fn insert_target(
+    command: &str,
+    target: Option<&&str>,
+    names: &mut Names,
+) -> Result<InsertTarget, String> {
+    match target {
+        Some(&"Master") => Ok(InsertTarget::Master),
+        Some(t) if t.starts_with("Aux") => aux_bus(command, t).map(InsertTarget::Aux),
+        Some(t) => looper_target(command, t, names)
+            .map(InsertTarget::Looper)
+            .map_err(|_| {
+                format!(
+                    "{} expects a target (Master, an aux bus, All, Selected, or a looper index)",
+                    command
+                )
+            }),
+        None => Err(format!("{} expects a target", command)),
+    }
+}
+
+/// A value for a plugin parameter, either in the plugin's own units or as a proportion of the
+/// parameter's range (as when it is set from a midi controller)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PluginParamValue {
+    Plain(f64),
+    Normalized(f32),
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
@@ -121,37 +526,37 @@ pub enum LooperCommand {
     // Composite commands
     RecordOverdubPlay,
 
-    // not currently usable from midi
     AddToPart(Part),
     RemoveFromPart(Part),
 
+    AddToGroup(Group),
+    RemoveFromGroup(Group),
+
     // delete
     Delete,
 
     Undo,
     Redo,
+
+    // Changes to the looper's insert effects
+    Effect(EffectCommand),
+
+    // aux bus, level in [0, 1]
+    SetSend(u8, f32),
 }
 
 impl LooperCommand {
     pub fn from_str(
         command: &str,
         args: &[&str],
+        names: &mut Names,
     ) -> Result<Box<dyn Fn(CommandData) -> Command + Send>, String> {
         use Command::Looper;
         use LooperCommand::*;
 
         let target_type = args.get(0).ok_or(format!("{} expects a target", command))?;
 
-        let target = match *target_type {
-            "All" => LooperTarget::All,
-            "Selected" => LooperTarget::Selected,
-            i => LooperTarget::Index(u8::from_str(i).map_err(|_| {
-                format!(
-                    "{} expects a target (All, Selected, or a looper index)",
-                    command
-                )
-            })?),
-        };
+        let target = looper_target(command, target_type, names)?;
 
         Ok(match command {
             "Record" => Box::new(move |_| Looper(Record, target)),
@@ -216,9 +621,65 @@ impl LooperCommand {
             "1x" => Box::new(move |_| Looper(SetSpeed(LooperSpeed::One), target)),
             "2x" => Box::new(move |_| Looper(SetSpeed(LooperSpeed::Double), target)),
 
+            "AddToPart" | "RemoveFromPart" => {
+                let part = args
+                    .get(1)
+                    .ok_or(format!("{} expects a target and a part name", command))
+                    .and_then(|s| names.part(s))?;
+
+                if command == "AddToPart" {
+                    Box::new(move |_| Looper(AddToPart(part), target))
+                } else {
+                    Box::new(move |_| Looper(RemoveFromPart(part), target))
+                }
+            }
+
+            "AddToGroup" | "RemoveFromGroup" => {
+                let group = args
+                    .get(1)
+                    .ok_or(format!("{} expects a target and a group name", command))
+                    .and_then(|s| names.group(s))?;
+
+                if command == "AddToGroup" {
+                    Box::new(move |_| Looper(AddToGroup(group), target))
+                } else {
+                    Box::new(move |_| Looper(RemoveFromGroup(group), target))
+                }
+            }
+
             "Undo" => Box::new(move |_| Looper(Undo, target)),
             "Redo" => Box::new(move |_| Looper(Redo, target)),
 
+            "AddEffect" | "RemoveEffect" | "ClearEffects" | "SetEffectParam" => {
+                let effect = EffectCommand::from_str(command, &args[1..])?;
+                Box::new(move |d| Looper(Effect(effect(d)), target))
+            }
+
+            "SetSend" => {
+                if args.len() != 3 {
+                    return Err(
+                        "SetSend expects a target, an aux bus and a level between 0 and 1"
+                            .to_string(),
+                    );
+                }
+
+                let bus = aux_bus(command, args[1])?;
+                let arg = if args[2] == "$data" {
+                    None
+                } else {
+                    let f = f32::from_str(args[2])
+                        .map_err(|_| format!("Invalid value for SetSend: '{}'", args[2]))?;
+                    if !(0.0..=1.0).contains(&f) {
+                        return Err("Value for SetSend must be between 0 and 1".to_string());
+                    }
+                    Some(f)
+                };
+
+                Box::new(move |d| {
+                    Looper(SetSend(bus, arg.unwrap_or(d.data as f32 / 127.0)), target)
+                })
+            }
+
             _ => return Err(format!("{} is not a valid command", command)),
         })
     }
@@ -248,22 +709,69 @@ pub enum Command {
     PreviousPart,
     NextPart,
     GoToPart(Part),
+    // adds a part after the existing ones; parts are also added the first time they're used
+    AddPart(Arc<String>),
+    // removes a part, moving loopers that were only in that part to the first part
+    RemovePart(Part),
+
+    // scenes are snapshots of the loopers' mix (see Scene), identified by name
+    SaveScene(Arc<String>),
+    RecallScene(Arc<String>),
+    RecallSceneByIndex(u8),
+    DeleteScene(Arc<String>),
 
     SetQuantizationMode(QuantizationMode),
+    // cancels the pending triggers of the targeted loopers, or all pending triggers if None
+    CancelTriggers(Option<LooperTarget>),
 
     SaveSession(Arc<PathBuf>),
     LoadSession(Arc<PathBuf>),
 
+    // a song structure file (see song::Song) for the engine to play through
+    LoadSong(Arc<PathBuf>),
+    ClearSong,
+
     SetMetronomeLevel(u8),
+    // the name of a sound pack in the metronome config directory
+    SetMetronomeSound(Arc<String>),
+    SetMetronomeSubdivision(Subdivision),
+    // a bitset of the accented beats of the measure (see MetronomePattern)
+    SetAccentPattern(u32),
+    // the number of measures counted in when the transport starts from Stopped
+    SetCountIn(u8),
 
     SetTempoBPM(f32),
+    // sets the tempo from the intervals between taps, starting at the next measure
+    TapTempo,
     SetTimeSignature(u8, u8),
+    // the swing percentage (see Groove) and the grid it applies to
+    SetSwing(u8, GrooveGrid),
+    // the offset of each step of the beat, in percent of a step
+    SetGrooveTemplate([i8; Groove::MAX_STEPS]),
+
+    // in dB, [MIN_MASTER_GAIN_DB, MAX_MASTER_GAIN_DB]
+    SetMasterGain(f32),
+    SetOutputProtection(OutputProtection),
+    ResetLoudness,
+
+    // CLAP plugin inserts; plugins are identified by their index in the target's chain
+    AddPlugin(InsertTarget, Arc<SavedPlugin>),
+    RemovePlugin(InsertTarget, u8),
+    // index, parameter id, value
+    SetPluginParam(InsertTarget, u8, u32, PluginParamValue),
+
+    // Aux buses: level in [0, 1]
+    SetAuxReturnLevel(u8, f32),
+    AuxEffect(u8, EffectCommand),
 }
 
 impl Command {
+    /// Parses a command with its arguments. Part and group names are looked up in (or added to)
+    /// `names`, which must be the table that the engine will use.
     pub fn from_str(
         command: &str,
         args: &[&str],
+        names: &mut Names,
     ) -> Result<Box<dyn Fn(CommandData) -> Command + Send>, String> {
         Ok(match command {
             "Start" => Box::new(|_| Command::Start),
@@ -309,102 +817,533 @@ impl Command {
 
             "PreviousPart" => Box::new(|_| Command::PreviousPart),
             "NextPart" => Box::new(|_| Command::NextPart),
-            "GoToPart" => {
+            "GoToPart" | "AddPart" | "RemovePart" => {
+                let name = args
+                    .get(0)
+                    .ok_or(format!("{} expects a part name", command))?;
+                let arg = names.part(name)?;
+
+                match command {
+                    "GoToPart" => Box::new(move |_| Command::GoToPart(arg)),
+                    "AddPart" => {
+                        let name = names.parts.name(arg.0).unwrap().clone();
+                        Box::new(move |_| Command::AddPart(name.clone()))
+                    }
+                    _ => Box::new(move |_| Command::RemovePart(arg)),
+                }
+            }
+
+            "SaveScene" | "RecallScene" | "DeleteScene" => {
+                let name = Arc::new(
+                    args.get(0)
+                        .ok_or(format!("{} expects the name of a scene", command))?
+                        .to_string(),
+                );
+                match command {
+                    "SaveScene" => Box::new(move |_| Command::SaveScene(name.clone())),
+                    "RecallScene" => Box::new(move |_| Command::RecallScene(name.clone())),
+                    _ => Box::new(move |_| Command::DeleteScene(name.clone())),
+                }
+            }
+            "RecallSceneByIndex" => {
+                let arg = args.get(0).and_then(|s| u8::from_str(s).ok()).ok_or(
+                    "RecallSceneByIndex expects a single numeric argument, the scene index"
+                        .to_string(),
+                )?;
+                Box::new(move |_| Command::RecallSceneByIndex(arg))
+            }
+
+            "CancelTriggers" => {
+                let target = args
+                    .get(0)
+                    .map(|t| looper_target("CancelTriggers", t, names))
+                    .transpose()?;
+                Box::new(move |_| Command::CancelTriggers(target))
+            }
+
+            "SetQuantizationMode" => {
+                // modes with a parameter are written like `Phrase 4`
+                let arg = QuantizationMode::from_str(&args.join(":")).map_err(|e| {
+                    format!("SetQuantizationMode expects a sync mode: {}", e)
+                })?;
+                Box::new(move |_| Command::SetQuantizationMode(arg))
+            }
+
+            "SetMetronomeLevel" => {
+                let arg = args.get(0).and_then(|s| u8::from_str(s).ok()).ok_or(
+                    "SetMetronomeLevel expects a single numeric argument, the level between 0-100"
+                        .to_string(),
+                )?;
+                Box::new(move |_| Command::SetMetronomeLevel(arg))
+            }
+
+            "SetMetronomeSound" => {
+                let arg = Arc::new(
+                    args.get(0)
+                        .ok_or("SetMetronomeSound expects the name of a sound pack".to_string())?
+                        .to_string(),
+                );
+                Box::new(move |_| Command::SetMetronomeSound(arg.clone()))
+            }
+
+            "SetMetronomeSubdivision" => {
                 let arg = args
                     .get(0)
-                    .and_then(|s| match s.as_ref() {
-                        "A" => Some(Part::A),
-                        "B" => Some(Part::B),
-                        "C" => Some(Part::C),
-                        "D" => Some(Part::D),
+                    .and_then(|s| match *s {
+                        "None" => Some(Subdivision::None),
+                        "Eighths" => Some(Subdivision::Eighths),
+                        "Triplets" => Some(Subdivision::Triplets),
+                        "Sixteenths" => Some(Subdivision::Sixteenths),
                         _ => None,
                     })
-                    .ok_or("GoToPart expects a part name (one of A, B, C, or D)".to_string())?;
+                    .ok_or(
+                        "SetMetronomeSubdivision expects one of None, Eighths, Triplets, or \
+                        Sixteenths"
+                            .to_string(),
+                    )?;
+                Box::new(move |_| Command::SetMetronomeSubdivision(arg))
+            }
+
+            "SetAccentPattern" => {
+                let arg = args.get(0).ok_or(
+                    "SetAccentPattern expects a grouping of beats, like 2+2+3".to_string(),
+                )?;
+                let accents = MetronomePattern::accents_from_groups(arg)?;
+                Box::new(move |_| Command::SetAccentPattern(accents))
+            }
 
-                Box::new(move |_| Command::GoToPart(arg))
+            "SetCountIn" => {
+                let arg = args.get(0).and_then(|s| u8::from_str(s).ok()).ok_or(
+                    "SetCountIn expects a single numeric argument, the number of measures"
+                        .to_string(),
+                )?;
+                Box::new(move |_| Command::SetCountIn(arg))
             }
 
-            "SetQuantizationMode" => {
+            "TapTempo" => Box::new(|_| Command::TapTempo),
+
+            "SetSwing" => {
+                let swing = args
+                    .get(0)
+                    .and_then(|s| u8::from_str(s).ok())
+                    .filter(|s| (Groove::MIN_SWING..=Groove::MAX_SWING).contains(s))
+                    .ok_or(format!(
+                        "SetSwing expects a swing percentage between {} and {}",
+                        Groove::MIN_SWING,
+                        Groove::MAX_SWING
+                    ))?;
+
+                let grid = match args.get(1) {
+                    None | Some(&"Eighths") => GrooveGrid::Eighths,
+                    Some(&"Sixteenths") => GrooveGrid::Sixteenths,
+                    Some(s) => {
+                        return Err(format!(
+                            "Invalid grid '{}' for SetSwing (expected Eighths or Sixteenths)",
+                            s
+                        ))
+                    }
+                };
+                Box::new(move |_| Command::SetSwing(swing, grid))
+            }
+
+            "SetGrooveTemplate" => {
+                let arg = args.get(0).ok_or(
+                    "SetGrooveTemplate expects a comma-separated offset for each step, like 0,10"
+                        .to_string(),
+                )?;
+
+                let mut template = [0i8; Groove::MAX_STEPS];
+                for (i, v) in arg.split(',').enumerate() {
+                    if i >= Groove::MAX_STEPS {
+                        return Err(format!(
+                            "SetGrooveTemplate accepts at most {} steps",
+                            Groove::MAX_STEPS
+                        ));
+                    }
+                    template[i] = i8::from_str(v.trim())
+                        .ok()
+                        .filter(|v| v.abs() <= Groove::MAX_TEMPLATE_OFFSET)
+                        .ok_or(format!(
+                            "Invalid offset '{}' for SetGrooveTemplate (expected -{} to {})",
+                            v.trim(),
+                            Groove::MAX_TEMPLATE_OFFSET,
+                            Groove::MAX_TEMPLATE_OFFSET
+                        ))?;
+                }
+                Box::new(move |_| Command::SetGrooveTemplate(template))
+            }
+
+            "LoadSong" => {
+                let arg = Arc::new(PathBuf::from(
+                    args.get(0)
+                        .ok_or("LoadSong expects the path to a song file".to_string())?,
+                ));
+                Box::new(move |_| Command::LoadSong(arg.clone()))
+            }
+            "ClearSong" => Box::new(|_| Command::ClearSong),
+
+            "SetMasterGain" => {
+                let v = args.get(0).ok_or(format!(
+                    "SetMasterGain expects a gain in dB between {} and {}",
+                    MIN_MASTER_GAIN_DB, MAX_MASTER_GAIN_DB
+                ))?;
+
+                let arg = if *v == "$data" {
+                    None
+                } else {
+                    let f = f32::from_str(v)
+                        .map_err(|_| format!("Invalid value for SetMasterGain: '{}'", v))?;
+                    if !(MIN_MASTER_GAIN_DB..=MAX_MASTER_GAIN_DB).contains(&f) {
+                        return Err(format!(
+                            "Value for SetMasterGain must be between {} and {}",
+                            MIN_MASTER_GAIN_DB, MAX_MASTER_GAIN_DB
+                        ));
+                    }
+                    Some(f)
+                };
+
+                Box::new(move |d| {
+                    Command::SetMasterGain(arg.unwrap_or(
+                        MIN_MASTER_GAIN_DB
+                            + d.data as f32 / 127.0 * (MAX_MASTER_GAIN_DB - MIN_MASTER_GAIN_DB),
+                    ))
+                })
+            }
+
+            "SetOutputProtection" => {
                 let arg = args
                     .get(0)
                     .and_then(|s| match s.as_ref() {
-                        "Free" => Some(QuantizationMode::Free),
-                        "Beat" => Some(QuantizationMode::Beat),
-                        "Measure" => Some(QuantizationMode::Measure),
+                        "Off" => Some(OutputProtection::Off),
+                        "Limiter" => Some(OutputProtection::Limiter),
+                        "SoftClip" => Some(OutputProtection::SoftClip),
                         _ => None,
                     })
                     .ok_or(
-                        "SetQuantizationMode expects a sync mode (one of Free, Beat, or Measure)"
+                        "SetOutputProtection expects a mode (one of Off, Limiter, or SoftClip)"
                             .to_string(),
                     )?;
-                Box::new(move |_| Command::SetQuantizationMode(arg))
+                Box::new(move |_| Command::SetOutputProtection(arg))
             }
 
-            "SetMetronomeLevel" => {
-                let arg = args.get(0).and_then(|s| u8::from_str(s).ok()).ok_or(
-                    "SetMetronomeLevel expects a single numeric argument, the level between 0-100"
+            "ResetLoudness" => Box::new(|_| Command::ResetLoudness),
+
+            "SetAuxReturnLevel" => {
+                if args.len() != 2 {
+                    return Err(
+                        "SetAuxReturnLevel expects an aux bus and a level between 0 and 1"
+                            .to_string(),
+                    );
+                }
+
+                let bus = aux_bus(command, args[0])?;
+                let arg = if args[1] == "$data" {
+                    None
+                } else {
+                    let f = f32::from_str(args[1]).map_err(|_| {
+                        format!("Invalid value for SetAuxReturnLevel: '{}'", args[1])
+                    })?;
+                    if !(0.0..=1.0).contains(&f) {
+                        return Err(
+                            "Value for SetAuxReturnLevel must be between 0 and 1".to_string()
+                        );
+                    }
+                    Some(f)
+                };
+
+                Box::new(move |d| {
+                    Command::SetAuxReturnLevel(bus, arg.unwrap_or(d.data as f32 / 127.0))
+                })
+            }
+
+            // effect commands may target an aux bus rather than a looper
+            "AddEffect" | "RemoveEffect" | "ClearEffects" | "SetEffectParam"
+                if matches!(args.get(0), Some(t) if t.starts_with("Aux")) =>
+            {
+                let bus = aux_bus(command, args[0])?;
+                let effect = EffectCommand::from_str(command, &args[1..])?;
+                Box::new(move |d| Command::AuxEffect(bus, effect(d)))
+            }
+
+            "AddPlugin" => {
+                let target = insert_target(command, args.get(0), names)?;
+                let path = args.get(1).ok_or(
+                    "AddPlugin expects a target, the path to a CLAP plugin and optionally \
+                    the id of the plugin"
                         .to_string(),
                 )?;
-                Box::new(move |_| Command::SetMetronomeLevel(arg))
+
+                let plugin = Arc::new(SavedPlugin {
+                    path: PathBuf::from(path),
+                    id: args.get(2).map(|s| s.to_string()),
+                    state: None,
+                });
+
+                Box::new(move |_| Command::AddPlugin(target, plugin.clone()))
+            }
+
+            "RemovePlugin" => {
+                let target = insert_target(command, args.get(0), names)?;
+                let idx = args.get(1).and_then(|s| u8::from_str(s).ok()).ok_or(
+                    "RemovePlugin expects a target and the index of the plugin".to_string(),
+                )?;
+
+                Box::new(move |_| Command::RemovePlugin(target, idx))
+            }
+
+            "SetPluginParam" => {
+                if args.len() != 4 {
+                    return Err("SetPluginParam expects a target, the index of the plugin, \
+                        a parameter id and a value"
+                        .to_string());
+                }
+
+                let target = insert_target(command, args.get(0), names)?;
+                let idx = u8::from_str(args[1])
+                    .map_err(|_| format!("Invalid plugin index: '{}'", args[1]))?;
+                let param = u32::from_str(args[2])
+                    .map_err(|_| format!("Invalid parameter id: '{}'", args[2]))?;
+
+                let arg = if args[3] == "$data" {
+                    None
+                } else {
+                    Some(f64::from_str(args[3]).map_err(|_| {
+                        format!("Invalid value for SetPluginParam: '{}'", args[3])
+                    })?)
+                };
+
+                Box::new(move |d| {
+                    Command::SetPluginParam(
+                        target,
+                        idx,
+                        param,
+                        arg.map(PluginParamValue::Plain).unwrap_or(
+                            PluginParamValue::Normalized(d.data.min(127) as f32 / 127.0),
+                        ),
+                    )
+                })
             }
 
             _ => {
-                return LooperCommand::from_str(command, args);
+                return LooperCommand::from_str(command, args, names);
             }
         })
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize, Hash)]
-pub enum Part {
-    A,
-    B,
-    C,
-    D,
-}
+/// The most names that a `NameTable` can hold, so that sets of parts fit in a bitset
+pub const MAX_NAMES: usize = 64;
+
+/// A part of the performance, which loopers can belong to. Parts have names, which can be any
+/// text without spaces, but are referred to by their index in the engine's `Names` so that they
+/// can be copied around freely. The engine starts with parts named A, B, C and D.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Part(pub u8);
+
+pub static DEFAULT_PARTS: [Part; 4] = [Part::A, Part::B, Part::C, Part::D];
 
 impl Part {
-    pub fn name(&self) -> &'static str {
-        match self {
-            Part::A => "A",
-            Part::B => "B",
-            Part::C => "C",
-            Part::D => "D",
+    pub const A: Part = Part(0);
+    pub const B: Part = Part(1);
+    pub const C: Part = Part(2);
+    pub const D: Part = Part(3);
+}
+
+/// A list of names, for parts or for groups, where each name's id is its index. Names are never
+/// removed, so an id keeps referring to the same name for as long as the table exists.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameTable {
+    kind: &'static str,
+    names: Vec<Arc<String>>,
+}
+
+impl NameTable {
+    fn new(kind: &'static str, names: &[&str]) -> NameTable {
+        let mut table = NameTable {
+            kind,
+            names: Vec::with_capacity(MAX_NAMES),
+        };
+        table
+            .names
+            .extend(names.iter().map(|n| Arc::new(n.to_string())));
+        table
+    }
+
+    pub fn get(&self, name: &str) -> Option<u8> {
+        self.names.iter().position(|n| **n == name).map(|id| id as u8)
+    }
+
+    pub fn name(&self, id: u8) -> Option<&Arc<String>> {
+        self.names.get(id as usize)
+    }
+
+    /// Returns the id of `name`, adding it to the table if it isn't there yet. The name is shared
+    /// rather than copied, so this doesn't allocate.
+    pub fn intern(&mut self, name: &Arc<String>) -> Result<u8, String> {
+        if let Some(id) = self.get(name) {
+            return Ok(id);
+        }
+
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("'{}' is not a valid {} name", name, self.kind));
+        }
+
+        if self.names.len() >= MAX_NAMES {
+            return Err(format!("There can't be more than {} {} names", MAX_NAMES, self.kind));
+        }
+
+        self.names.push(name.clone());
+        Ok((self.names.len() - 1) as u8)
+    }
+
+    /// Like `intern`, for names that are being parsed from text
+    pub fn parse(&mut self, name: &str) -> Result<u8, String> {
+        match self.get(name) {
+            Some(id) => Ok(id),
+            None => self.intern(&Arc::new(name.to_string())),
+        }
+    }
+}
+
+/// The names of the parts and groups that commands and loopers refer to by id. The config
+/// assigns ids to the names used in the midi mappings, and the engine then takes the tables over,
+/// adding the names that come from sessions and the gui.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Names {
+    pub parts: NameTable,
+    pub groups: NameTable,
+}
+
+impl Names {
+    pub fn part(&mut self, name: &str) -> Result<Part, String> {
+        self.parts.parse(name).map(Part)
+    }
+
+    /// Returns the name of a part, or `?` for an id that isn't in the table
+    pub fn part_name(&self, part: Part) -> &str {
+        self.parts.name(part.0).map(|n| n.as_str()).unwrap_or("?")
+    }
+
+    /// Looks up (adding if necessary) the parts with the given names; invalid names are skipped,
+    /// and a looper with no valid parts is placed in all of them
+    pub fn part_set(&mut self, names: &[Arc<String>]) -> PartSet {
+        let mut set = PartSet(0);
+        for name in names {
+            match self.parts.intern(name) {
+                Ok(id) => set.insert(Part(id)),
+                Err(e) => warn!("Skipping part: {}", e),
+            }
+        }
+
+        if set.is_empty() {
+            PartSet::new()
+        } else {
+            set
+        }
+    }
+
+    pub fn group(&mut self, name: &str) -> Result<Group, String> {
+        self.groups.parse(name).map(Group)
+    }
+
+    /// Looks up (adding if necessary) the groups with the given names, skipping invalid names
+    pub fn group_set(&mut self, names: &[Arc<String>]) -> GroupSet {
+        let mut set = GroupSet::default();
+        for name in names {
+            match self.groups.intern(name) {
+                Ok(id) => set.insert(Group(id)),
+                Err(e) => warn!("Skipping group: {}", e),
+            }
         }
+        set
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
-pub struct PartSet {
-    a: bool,
-    b: bool,
-    c: bool,
-    d: bool,
+impl Default for Names {
+    fn default() -> Self {
+        Names {
+            parts: NameTable::new("part", &["A", "B", "C", "D"]),
+            groups: NameTable::new("group", &[]),
+        }
+    }
 }
 
+/// A user-defined group of loopers (like "drums" or "pads") that looper commands can target
+/// together. Like parts, groups are named, and are identified by their index in `Names::groups`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Group(pub u8);
+
+/// The parts that a looper belongs to, as a bitset over part ids
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PartSet(u64);
+
 impl PartSet {
     pub fn new() -> PartSet {
-        PartSet {
-            a: true,
-            b: false,
-            c: false,
-            d: false,
-        }
+        PartSet::with(Part::A)
     }
 
     pub fn with(part: Part) -> PartSet {
-        let mut parts = PartSet {
-            a: false,
-            b: false,
-            c: false,
-            d: false,
-        };
-        parts[part] = true;
+        let mut parts = PartSet(0);
+        parts.insert(part);
         parts
     }
 
+    pub fn contains(&self, part: Part) -> bool {
+        (part.0 as usize) < MAX_NAMES && self.0 & (1 << part.0) != 0
+    }
+
+    pub fn insert(&mut self, part: Part) {
+        if (part.0 as usize) < MAX_NAMES {
+            self.0 |= 1 << part.0;
+        }
+    }
+
+    pub fn remove(&mut self, part: Part) {
+        if (part.0 as usize) < MAX_NAMES {
+            self.0 &= !(1 << part.0);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Part> + '_ {
+        (0..MAX_NAMES as u8).map(Part).filter(move |p| self.contains(*p))
+    }
+}
+
+/// The groups that a looper belongs to, as a bitset over group ids
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct GroupSet(u64);
+
+impl GroupSet {
+    pub fn contains(&self, group: Group) -> bool {
+        (group.0 as usize) < MAX_NAMES && self.0 & (1 << group.0) != 0
+    }
+
+    pub fn insert(&mut self, group: Group) {
+        if (group.0 as usize) < MAX_NAMES {
+            self.0 |= 1 << group.0;
+        }
+    }
+
+    pub fn remove(&mut self, group: Group) {
+        if (group.0 as usize) < MAX_NAMES {
+            self.0 &= !(1 << group.0);
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
-        !(self.a || self.b || self.c || self.c)
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Group> + '_ {
+        (0..MAX_NAMES as u8).map(Group).filter(move |g| self.contains(*g))
     }
 }
 
@@ -418,28 +1357,14 @@ impl Index<Part> for PartSet {
     type Output = bool;
 
     fn index(&self, index: Part) -> &Self::Output {
-        match index {
-            Part::A => &self.a,
-            Part::B => &self.b,
-            Part::C => &self.c,
-            Part::D => &self.d,
-        }
-    }
-}
-
-impl IndexMut<Part> for PartSet {
-    fn index_mut(&mut self, index: Part) -> &mut Self::Output {
-        match index {
-            Part::A => &mut self.a,
-            Part::B => &mut self.b,
-            Part::C => &mut self.c,
-            Part::D => &mut self.d,
+        if self.contains(index) {
+            &true
+        } else {
+            &false
         }
     }
 }
 
-pub static PARTS: [Part; 4] = [Part::A, Part::B, Part::C, Part::D];
-
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum LooperMode {
     Recording,
@@ -463,14 +1388,117 @@ fn looper_speed_default() -> LooperSpeed {
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum QuantizationMode {
     Free,
+    // the next step of the groove grid, e.g. the next (possibly swung) eighth
+    Subdivision,
     Beat,
     Measure,
+    // the start of the next phrase of the given number of measures
+    Phrase(u8),
+    // the end of the loop of the looper with the given index
+    LoopEnd(u8),
+    // the end of the longest loop that is playing in the current part
+    LongestLoop,
+    // the start of the loop of the master looper with the given index; loops recorded in this
+    // mode are ended so that their length is a multiple or fraction of the master loop's
+    MasterLoop(u8),
+}
+
+impl FromStr for QuantizationMode {
+    type Err = String;
+
+    /// Parses a mode in the format written by `to_string`, e.g. `Measure` or `Phrase:4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let arg = parts.next().map(u8::from_str);
+
+        Ok(match (name, arg) {
+            ("Free", None) => QuantizationMode::Free,
+            ("Subdivision", None) => QuantizationMode::Subdivision,
+            ("Beat", None) => QuantizationMode::Beat,
+            ("Measure", None) => QuantizationMode::Measure,
+            ("Phrase", Some(Ok(measures))) if measures > 0 => QuantizationMode::Phrase(measures),
+            ("LoopEnd", Some(Ok(idx))) => QuantizationMode::LoopEnd(idx),
+            ("LongestLoop", None) => QuantizationMode::LongestLoop,
+            ("MasterLoop", Some(Ok(idx))) => QuantizationMode::MasterLoop(idx),
+            ("Phrase", _) => return Err("Phrase expects a number of measures".to_string()),
+            ("LoopEnd", _) => return Err("LoopEnd expects the index of a looper".to_string()),
+            ("MasterLoop", _) => {
+                return Err("MasterLoop expects the index of a looper".to_string())
+            }
+            _ => {
+                return Err(format!(
+                    "'{}' is not one of Free, Subdivision, Beat, Measure, Phrase, LoopEnd, \
+                    LongestLoop, or MasterLoop",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for QuantizationMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuantizationMode::Free => write!(f, "Free"),
+            QuantizationMode::Subdivision => write!(f, "Subdivision"),
+            QuantizationMode::Beat => write!(f, "Beat"),
+            QuantizationMode::Measure => write!(f, "Measure"),
+            QuantizationMode::Phrase(measures) => write!(f, "Phrase:{}", measures),
+            QuantizationMode::LoopEnd(idx) => write!(f, "LoopEnd:{}", idx),
+            QuantizationMode::LongestLoop => write!(f, "LongestLoop"),
+            QuantizationMode::MasterLoop(idx) => write!(f, "MasterLoop:{}", idx),
+        }
+    }
+}
+
+fn parts_default() -> Vec<Arc<String>> {
+    Names::default().parts.names
+}
+
+fn looper_parts_default() -> Vec<Arc<String>> {
+    vec![Arc::new("A".to_string())]
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedParts {
+    Names(Vec<Arc<String>>),
+    // sessions saved when there were only four parts
+    Fixed { a: bool, b: bool, c: bool, d: bool },
+}
+
+fn deserialize_parts<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Arc<String>>, D::Error> {
+    Ok(match SavedParts::deserialize(d)? {
+        SavedParts::Names(names) => names,
+        SavedParts::Fixed { a, b, c, d } => parts_default()
+            .into_iter()
+            .zip([a, b, c, d])
+            .filter(|(_, included)| *included)
+            .map(|(name, _)| name)
+            .collect(),
+    })
 }
 
 fn sync_mode_default() -> QuantizationMode {
     QuantizationMode::Measure
 }
 
+pub const MIN_MASTER_GAIN_DB: f32 = -60.0;
+pub const MAX_MASTER_GAIN_DB: f32 = 12.0;
+
+/// How the master bus keeps the output from going over full scale
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash, Default)]
+pub enum OutputProtection {
+    /// The output is passed through unchanged (and will clip if it exceeds 0 dBFS)
+    #[default]
+    Off,
+    /// A look-ahead brickwall limiter holds the output below the configured ceiling
+    Limiter,
+    /// Peaks are saturated smoothly towards the configured ceiling
+    SoftClip,
+}
+
 fn level_default() -> f32 {
     1.0
 }
@@ -485,11 +1513,66 @@ pub struct SavedLooper {
     pub pan: f32,
     #[serde(default = "level_default")]
     pub level: f32,
+    // the names of the parts the looper is in
+    #[serde(default = "looper_parts_default", deserialize_with = "deserialize_parts")]
+    pub parts: Vec<Arc<String>>,
+    // the names of the groups the looper is in
     #[serde(default)]
-    pub parts: PartSet,
+    pub groups: Vec<Arc<String>>,
     pub samples: Vec<PathBuf>,
     #[serde(default)]
     pub offset_samples: i64,
+    #[serde(default)]
+    pub effects: Vec<EffectConfig>,
+    #[serde(default)]
+    pub plugins: Vec<SavedPlugin>,
+    // send levels for each aux bus
+    #[serde(default)]
+    pub sends: Vec<f32>,
+}
+
+/// A named snapshot of the mix state of the loopers, which can be recalled to bring them all back
+/// to that state at once
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Scene {
+    pub name: Arc<String>,
+    pub loopers: Vec<SceneLooper>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SceneLooper {
+    pub id: u32,
+    // one of Playing, Muted or Soloed
+    pub mode: LooperMode,
+    #[serde(default = "level_default")]
+    pub level: f32,
+    #[serde(default)]
+    pub pan: f32,
+    #[serde(default = "looper_speed_default")]
+    pub speed: LooperSpeed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedAuxBus {
+    #[serde(default = "level_default")]
+    pub return_level: f32,
+    #[serde(default)]
+    pub effects: Vec<EffectConfig>,
+    #[serde(default)]
+    pub plugins: Vec<SavedPlugin>,
+}
+
+/// A CLAP plugin insert
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedPlugin {
+    // the path to the .clap bundle
+    pub path: PathBuf,
+    // the id of the plugin within the bundle; if not set, the first plugin is used
+    #[serde(default)]
+    pub id: Option<String>,
+    // the file holding the plugin's state, relative to the session directory
+    #[serde(default)]
+    pub state: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -497,10 +1580,31 @@ pub struct SavedSession {
     pub save_time: i64,
     #[serde(default)]
     pub metronome_volume: u8,
+    // the metronome sound pack; if not set, the built-in sounds are used
+    #[serde(default)]
+    pub metronome_sound: Option<String>,
+    #[serde(default)]
+    pub metronome_pattern: MetronomePattern,
     pub metric_structure: SavedMetricStructure,
     #[serde(default = "sync_mode_default")]
     pub sync_mode: QuantizationMode,
     #[serde(default)]
     pub sample_rate: usize,
+    #[serde(default)]
+    pub master_gain: f32,
+    #[serde(default)]
+    pub output_protection: OutputProtection,
+    #[serde(default)]
+    pub master_plugins: Vec<SavedPlugin>,
+    #[serde(default)]
+    pub aux_buses: Vec<SavedAuxBus>,
+    // the song structure file in the session directory, if the session has a song
+    #[serde(default)]
+    pub song: Option<String>,
+    // the names of the parts, in order
+    #[serde(default = "parts_default")]
+    pub parts: Vec<Arc<String>>,
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
     pub loopers: Vec<SavedLooper>,
 }
@@ -1,6 +1,8 @@
-use crate::api::{Command, CommandData};
+use crate::api::{Command, CommandData, LooperCommand, Names, QuantizationMode};
 use crate::midi::MidiEvent;
+use crate::music::MetricStructure;
 use csv::StringRecord;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io;
 use std::str::FromStr;
@@ -8,8 +10,9 @@ use std::str::FromStr;
 #[cfg(test)]
 mod tests {
     use crate::api::LooperCommand::{RecordOverdubPlay, SetPan};
-    use crate::api::{Command, CommandData, LooperTarget};
-    use crate::config::{DataValue, MidiMapping, FILE_HEADER};
+    use crate::api::{Command, CommandData, LooperTarget, Names, Part, QuantizationMode};
+    use crate::config::{DataValue, LatePolicy, LateTriggerPolicies, MidiMapping, FILE_HEADER};
+    use crate::midi::MidiEvent;
     use std::fs::File;
     use std::io::Write;
     use tempfile::NamedTempFile;
@@ -29,12 +32,16 @@ mod tests {
             writeln!(file, "*\t23\t*\tSetMetronomeLevel\t50").unwrap();
             writeln!(file, "1\t24\t6\tStart").unwrap();
             writeln!(file, "1\t24\t0-127\tSetPan\tSelected\t$data").unwrap();
+            writeln!(file, "*\t25\t127\tRecordOverdubPlay@Phrase:4\t0").unwrap();
+            writeln!(file, "*\t26\t127\tGoToPart\tVerse").unwrap();
             file.flush().unwrap();
         }
 
+        let mut names = Names::default();
         let mapping = MidiMapping::from_file(
             &file.path().to_string_lossy(),
             &File::open(&file.path()).unwrap(),
+            &mut names,
         )
         .unwrap();
 
@@ -69,6 +76,61 @@ mod tests {
             Command::Looper(SetPan(1.0), LooperTarget::Selected),
             (mapping[3].command)(CommandData { data: 127 })
         );
+        assert_eq!(None, mapping[3].quantization);
+
+        assert_eq!(Some(QuantizationMode::Phrase(4)), mapping[4].quantization);
+        assert_eq!(
+            Some(Command::Looper(RecordOverdubPlay, LooperTarget::Index(0))),
+            mapping[4].command_for_event(&MidiEvent::ControllerChange {
+                channel: 1,
+                controller: 25,
+                data: 127,
+            })
+        );
+
+        // part names are given ids as the mappings are read
+        assert_eq!(Some(4), names.parts.get("Verse"));
+        assert_eq!(
+            Command::GoToPart(Part(4)),
+            (mapping[5].command)(CommandData { data: 127 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_quantization_override() {
+        let mut file = NamedTempFile::new().unwrap();
+        {
+            let file = file.as_file_mut();
+            writeln!(file, "{}", FILE_HEADER).unwrap();
+            writeln!(file, "*\t22\t127\tMute@Sometimes\tSelected").unwrap();
+            file.flush().unwrap();
+        }
+
+        assert!(MidiMapping::from_file(
+            &file.path().to_string_lossy(),
+            &File::open(&file.path()).unwrap(),
+            &mut Names::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_late_trigger_policies() {
+        let policies = LateTriggerPolicies {
+            record: LatePolicy::Fire,
+            looper: LatePolicy::Defer,
+            engine: LatePolicy::Drop,
+        };
+
+        assert_eq!(
+            LatePolicy::Fire,
+            policies.policy(&Command::Looper(RecordOverdubPlay, LooperTarget::Id(0)))
+        );
+        assert_eq!(
+            LatePolicy::Defer,
+            policies.policy(&Command::Looper(SetPan(0.0), LooperTarget::All))
+        );
+        assert_eq!(LatePolicy::Drop, policies.policy(&Command::NextPart));
     }
 }
 
@@ -76,12 +138,188 @@ pub static FILE_HEADER: &str = "Channel\tController\tData\tCommand\tArg1\tArg2\t
 
 pub struct Config {
     pub midi_mappings: Vec<MidiMapping>,
+    pub settings: Settings,
+    // the part names used by the midi mappings, which the engine takes over
+    pub names: Names,
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             midi_mappings: vec![],
+            settings: Settings::default(),
+            names: Names::default(),
+        }
+    }
+}
+
+fn parameter_ramp_ms_default() -> f32 {
+    20.0
+}
+
+fn limiter_ceiling_db_default() -> f32 {
+    -1.0
+}
+
+fn limiter_release_ms_default() -> f32 {
+    100.0
+}
+
+fn count_in_measures_default() -> u8 {
+    1
+}
+
+/// General engine settings, read from `settings.toml` in the config directory. Every field has a
+/// default so that the file only needs to contain the values the user wants to change.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Time (in milliseconds) over which level, pan and metronome volume changes are ramped
+    #[serde(default = "parameter_ramp_ms_default")]
+    pub parameter_ramp_ms: f32,
+
+    /// Maximum output level (in dBFS) of the master limiter and soft clipper
+    #[serde(default = "limiter_ceiling_db_default")]
+    pub limiter_ceiling_db: f32,
+
+    /// Time (in milliseconds) the master limiter takes to recover after reducing the gain
+    #[serde(default = "limiter_release_ms_default")]
+    pub limiter_release_ms: f32,
+
+    /// Number of measures the metronome counts in when the transport starts from Stopped
+    #[serde(default = "count_in_measures_default")]
+    pub count_in_measures: u8,
+
+    /// Whether the count-in clicks are sent to the host's count-in output (when it has one)
+    /// instead of the metronome output
+    #[serde(default)]
+    pub separate_count_in_output: bool,
+
+    /// What happens to queued commands whose time has already passed when they are processed
+    #[serde(default)]
+    pub late_triggers: LateTriggerPolicies,
+
+    /// Whether new loops are rounded to a whole number of beats or measures when recording ends
+    #[serde(default)]
+    pub loop_length_rounding: LoopLengthRounding,
+
+    /// The audio format that loops are saved in with the session
+    #[serde(default)]
+    pub session_audio_format: SessionAudioFormat,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            parameter_ramp_ms: parameter_ramp_ms_default(),
+            limiter_ceiling_db: limiter_ceiling_db_default(),
+            limiter_release_ms: limiter_release_ms_default(),
+            count_in_measures: count_in_measures_default(),
+            separate_count_in_output: false,
+            late_triggers: LateTriggerPolicies::default(),
+            loop_length_rounding: LoopLengthRounding::default(),
+            session_audio_format: SessionAudioFormat::default(),
+        }
+    }
+}
+
+/// The unit that new loops are rounded to when recording ends, so that loops recorded over a
+/// long session don't drift out of phase with each other
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LoopLengthRounding {
+    /// Keep the length that was recorded
+    #[default]
+    Off,
+    /// Round to the nearest whole number of beats
+    Beat,
+    /// Round to the nearest whole number of measures
+    Measure,
+}
+
+impl LoopLengthRounding {
+    /// The length (in frames) that loops are rounded to a multiple of, if any
+    pub fn unit(&self, metric_structure: &MetricStructure) -> Option<u64> {
+        let spb = metric_structure.tempo.samples_per_beat();
+        match self {
+            LoopLengthRounding::Off => None,
+            LoopLengthRounding::Beat => Some(spb),
+            LoopLengthRounding::Measure => {
+                Some(spb * metric_structure.time_signature.upper as u64)
+            }
+        }
+    }
+}
+
+/// The format of the audio files that loops are saved to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SessionAudioFormat {
+    /// 32-bit float WAV, which keeps loops exactly as they were recorded
+    #[default]
+    WavFloat,
+    /// 16-bit PCM WAV
+    Wav16,
+    /// 24-bit PCM WAV
+    Wav24,
+    /// 16-bit FLAC, which is losslessly compressed
+    Flac16,
+    /// 24-bit FLAC
+    Flac24,
+}
+
+impl SessionAudioFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SessionAudioFormat::WavFloat
+            | SessionAudioFormat::Wav16
+            | SessionAudioFormat::Wav24 => "wav",
+            SessionAudioFormat::Flac16 | SessionAudioFormat::Flac24 => "flac",
+        }
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        match self {
+            SessionAudioFormat::Wav16 | SessionAudioFormat::Flac16 => 16,
+            SessionAudioFormat::Wav24 | SessionAudioFormat::Flac24 => 24,
+            SessionAudioFormat::WavFloat => 32,
+        }
+    }
+}
+
+/// What the engine does with a queued command that it couldn't run on time (for example, because
+/// of an xrun)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LatePolicy {
+    /// Discard the command
+    Drop,
+    /// Run the command as soon as possible
+    #[default]
+    Fire,
+    /// Wait for the next boundary of the command's quantization
+    Defer,
+}
+
+/// The policies for late commands, by the kind of command
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LateTriggerPolicies {
+    /// Looper commands that start recording or overdubbing
+    #[serde(default)]
+    pub record: LatePolicy,
+
+    /// Other looper commands
+    #[serde(default)]
+    pub looper: LatePolicy,
+
+    /// Engine commands, like part and tempo changes
+    #[serde(default)]
+    pub engine: LatePolicy,
+}
+
+impl LateTriggerPolicies {
+    pub fn policy(&self, command: &Command) -> LatePolicy {
+        use LooperCommand::*;
+        match command {
+            Command::Looper(Record | Overdub | RecordOverdubPlay, _) => self.record,
+            Command::Looper(..) => self.looper,
+            _ => self.engine,
         }
     }
 }
@@ -128,10 +366,17 @@ pub struct MidiMapping {
     pub controller: u8,
     pub data: DataValue,
     pub command: Box<dyn Fn(CommandData) -> Command + Send>,
+    // replaces the engine's quantization mode for this mapping's command; written after the
+    // command name, like `Mute@Free`
+    pub quantization: Option<QuantizationMode>,
 }
 
 impl MidiMapping {
-    pub fn from_file(name: &str, file: &File) -> io::Result<Vec<MidiMapping>> {
+    pub fn from_file(
+        name: &str,
+        file: &File,
+        names: &mut Names,
+    ) -> io::Result<Vec<MidiMapping>> {
         let mut rdr = csv::ReaderBuilder::new()
             .delimiter(b'\t')
             .flexible(true)
@@ -144,7 +389,7 @@ impl MidiMapping {
         for result in rdr.records() {
             let record = result?;
 
-            match Self::from_record(&record) {
+            match Self::from_record(&record, names) {
                 Ok(mm) => mappings.push(mm),
                 Err(err) => {
                     caught_error = true;
@@ -171,7 +416,7 @@ impl MidiMapping {
         }
     }
 
-    fn from_record(record: &StringRecord) -> Result<MidiMapping, String> {
+    fn from_record(record: &StringRecord, names: &mut Names) -> Result<MidiMapping, String> {
         let channel = record.get(0).ok_or("No channel field".to_string())?;
 
         let channel = match channel {
@@ -202,16 +447,25 @@ impl MidiMapping {
 
         let args: Vec<&str> = record.iter().skip(4).collect();
 
-        let command = record
-            .get(3)
-            .ok_or("No command field".to_string())
-            .and_then(|c| Command::from_str(c, &args))?;
+        let field = record.get(3).ok_or("No command field".to_string())?;
+        let mut parts = field.splitn(2, '@');
+        let name = parts.next().unwrap_or_default();
+        let quantization = parts
+            .next()
+            .map(|q| {
+                QuantizationMode::from_str(q)
+                    .map_err(|e| format!("Invalid quantization for {}: {}", name, e))
+            })
+            .transpose()?;
+
+        let command = Command::from_str(name, &args, names)?;
 
         Ok(MidiMapping {
             channel,
             controller,
             data,
             command,
+            quantization,
         })
     }
 
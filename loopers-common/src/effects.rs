@@ -0,0 +1,370 @@
+use crate::api::CommandData;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(
+            EffectConfig::Filter {
+                kind: FilterKind::LowPass,
+                frequency: 500.0,
+                q: 0.707,
+                gain: 0.0,
+            },
+            EffectConfig::from_args("LowPass", &["500"]).unwrap()
+        );
+
+        assert_eq!(
+            EffectConfig::Delay {
+                time_ms: 300.0,
+                feedback: 0.5,
+                mix: 0.3,
+            },
+            EffectConfig::from_args("Delay", &["300", "0.5"]).unwrap()
+        );
+
+        assert!(EffectConfig::from_args("Delay", &["300", "2.0"]).is_err());
+        assert!(EffectConfig::from_args("Chorus", &[]).is_err());
+    }
+
+    #[test]
+    fn test_set_param() {
+        let mut config = EffectConfig::from_args("Reverb", &[]).unwrap();
+        assert!(config.set_param(EffectParam::Mix, 2.0));
+        assert_eq!(Some(1.0), config.param(EffectParam::Mix));
+        assert!(!config.set_param(EffectParam::Frequency, 100.0));
+        assert_eq!(None, config.param(EffectParam::Frequency));
+    }
+
+    #[test]
+    fn test_from_midi() {
+        assert_eq!(20.0, EffectParam::Frequency.from_midi(0));
+        assert!((EffectParam::Frequency.from_midi(127) - 20_000.0).abs() < 0.1);
+        assert_eq!(0.0, EffectParam::Mix.from_midi(0));
+        assert_eq!(1.0, EffectParam::Mix.from_midi(127));
+    }
+}
+
+/// The maximum number of effects in a single looper's chain
+pub const MAX_EFFECTS: usize = 8;
+
+/// The longest delay time supported by the delay effect, in milliseconds
+pub const MAX_DELAY_MS: f32 = 2000.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+impl FilterKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::LowPass => "LowPass",
+            FilterKind::HighPass => "HighPass",
+            FilterKind::BandPass => "BandPass",
+            FilterKind::Notch => "Notch",
+            FilterKind::Peak => "Peak",
+            FilterKind::LowShelf => "LowShelf",
+            FilterKind::HighShelf => "HighShelf",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum EffectParam {
+    // Hz
+    Frequency,
+    Q,
+    // dB, for peak and shelf filters
+    Gain,
+    // ms
+    Time,
+    Feedback,
+    // proportion of processed signal in the output, [0.0, 1.0]
+    Mix,
+    RoomSize,
+    Damping,
+    Bits,
+    // factor by which the sample rate is reduced
+    Downsample,
+}
+
+impl EffectParam {
+    pub fn range(&self) -> (f32, f32) {
+        match self {
+            EffectParam::Frequency => (20.0, 20_000.0),
+            EffectParam::Q => (0.1, 10.0),
+            EffectParam::Gain => (-24.0, 24.0),
+            EffectParam::Time => (1.0, MAX_DELAY_MS),
+            EffectParam::Feedback => (0.0, 0.95),
+            EffectParam::Mix => (0.0, 1.0),
+            EffectParam::RoomSize => (0.0, 1.0),
+            EffectParam::Damping => (0.0, 1.0),
+            EffectParam::Bits => (1.0, 16.0),
+            EffectParam::Downsample => (1.0, 32.0),
+        }
+    }
+
+    /// Maps a midi data value (0-127) onto the range of the parameter; frequency and Q are mapped
+    /// exponentially so that a controller sweeps them evenly by ear
+    pub fn from_midi(&self, data: u8) -> f32 {
+        let (min, max) = self.range();
+        let p = data.min(127) as f32 / 127.0;
+        match self {
+            EffectParam::Frequency | EffectParam::Q => min * (max / min).powf(p),
+            _ => min + p * (max - min),
+        }
+    }
+
+    fn clamp(&self, v: f32) -> f32 {
+        let (min, max) = self.range();
+        v.max(min).min(max)
+    }
+}
+
+impl FromStr for EffectParam {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "Frequency" => EffectParam::Frequency,
+            "Q" => EffectParam::Q,
+            "Gain" => EffectParam::Gain,
+            "Time" => EffectParam::Time,
+            "Feedback" => EffectParam::Feedback,
+            "Mix" => EffectParam::Mix,
+            "RoomSize" => EffectParam::RoomSize,
+            "Damping" => EffectParam::Damping,
+            "Bits" => EffectParam::Bits,
+            "Downsample" => EffectParam::Downsample,
+            _ => return Err(format!("{} is not a valid effect parameter", s)),
+        })
+    }
+}
+
+/// The type and settings of an effect in a looper's insert chain
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EffectConfig {
+    Filter {
+        kind: FilterKind,
+        frequency: f32,
+        q: f32,
+        gain: f32,
+    },
+    Delay {
+        time_ms: f32,
+        feedback: f32,
+        mix: f32,
+    },
+    Reverb {
+        room_size: f32,
+        damping: f32,
+        mix: f32,
+    },
+    Bitcrusher {
+        bits: f32,
+        downsample: f32,
+        mix: f32,
+    },
+}
+
+impl EffectConfig {
+    /// Parses an effect from its name (one of the filter kinds, `Delay`, `Reverb` or `Bitcrusher`)
+    /// followed by optional values for its parameters, in the order given by `params`
+    pub fn from_args(name: &str, args: &[&str]) -> Result<EffectConfig, String> {
+        let filter = |kind| EffectConfig::Filter {
+            kind,
+            frequency: 1000.0,
+            q: 0.707,
+            gain: 0.0,
+        };
+
+        let mut config = match name {
+            "LowPass" => filter(FilterKind::LowPass),
+            "HighPass" => filter(FilterKind::HighPass),
+            "BandPass" => filter(FilterKind::BandPass),
+            "Notch" => filter(FilterKind::Notch),
+            "Peak" => filter(FilterKind::Peak),
+            "LowShelf" => filter(FilterKind::LowShelf),
+            "HighShelf" => filter(FilterKind::HighShelf),
+            "Delay" => EffectConfig::Delay {
+                time_ms: 250.0,
+                feedback: 0.5,
+                mix: 0.3,
+            },
+            "Reverb" => EffectConfig::Reverb {
+                room_size: 0.5,
+                damping: 0.5,
+                mix: 0.3,
+            },
+            "Bitcrusher" => EffectConfig::Bitcrusher {
+                bits: 8.0,
+                downsample: 1.0,
+                mix: 1.0,
+            },
+            _ => return Err(format!("{} is not a valid effect", name)),
+        };
+
+        let params = config.params();
+        if args.len() > params.len() {
+            return Err(format!(
+                "{} takes at most {} parameters",
+                name,
+                params.len()
+            ));
+        }
+
+        for (param, arg) in params.iter().zip(args) {
+            let v = f32::from_str(arg)
+                .map_err(|_| format!("Invalid value for {:?}: '{}'", param, arg))?;
+            let (min, max) = param.range();
+            if v < min || v > max {
+                return Err(format!(
+                    "Value for {:?} must be between {} and {}",
+                    param, min, max
+                ));
+            }
+            config.set_param(*param, v);
+        }
+
+        Ok(config)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectConfig::Filter { kind, .. } => kind.name(),
+            EffectConfig::Delay { .. } => "Delay",
+            EffectConfig::Reverb { .. } => "Reverb",
+            EffectConfig::Bitcrusher { .. } => "Bitcrusher",
+        }
+    }
+
+    /// The parameters that apply to this effect
+    pub fn params(&self) -> &'static [EffectParam] {
+        use EffectParam::*;
+        match self {
+            EffectConfig::Filter { .. } => &[Frequency, Q, Gain],
+            EffectConfig::Delay { .. } => &[Time, Feedback, Mix],
+            EffectConfig::Reverb { .. } => &[RoomSize, Damping, Mix],
+            EffectConfig::Bitcrusher { .. } => &[Bits, Downsample, Mix],
+        }
+    }
+
+    pub fn param(&self, param: EffectParam) -> Option<f32> {
+        let mut config = *self;
+        config.param_mut(param).map(|v| *v)
+    }
+
+    /// Sets a parameter, clamping it to its valid range. Returns false if the parameter does not
+    /// apply to this effect.
+    pub fn set_param(&mut self, param: EffectParam, value: f32) -> bool {
+        match self.param_mut(param) {
+            Some(v) => {
+                *v = param.clamp(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn param_mut(&mut self, param: EffectParam) -> Option<&mut f32> {
+        use EffectParam::*;
+        match (self, param) {
+            (EffectConfig::Filter { frequency, .. }, Frequency) => Some(frequency),
+            (EffectConfig::Filter { q, .. }, Q) => Some(q),
+            (EffectConfig::Filter { gain, .. }, Gain) => Some(gain),
+            (EffectConfig::Delay { time_ms, .. }, Time) => Some(time_ms),
+            (EffectConfig::Delay { feedback, .. }, Feedback) => Some(feedback),
+            (EffectConfig::Delay { mix, .. }, Mix) => Some(mix),
+            (EffectConfig::Reverb { room_size, .. }, RoomSize) => Some(room_size),
+            (EffectConfig::Reverb { damping, .. }, Damping) => Some(damping),
+            (EffectConfig::Reverb { mix, .. }, Mix) => Some(mix),
+            (EffectConfig::Bitcrusher { bits, .. }, Bits) => Some(bits),
+            (EffectConfig::Bitcrusher { downsample, .. }, Downsample) => Some(downsample),
+            (EffectConfig::Bitcrusher { mix, .. }, Mix) => Some(mix),
+            _ => None,
+        }
+    }
+}
+
+/// A change to an insert effect chain
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EffectCommand {
+    Add(EffectConfig),
+    // index in the effect chain
+    Remove(u8),
+    Clear,
+    SetParam(u8, EffectParam, f32),
+}
+
+impl EffectCommand {
+    /// Parses one of the effect commands (AddEffect, RemoveEffect, ClearEffects or
+    /// SetEffectParam); `args` should not include the command's target
+    pub fn from_str(
+        command: &str,
+        args: &[&str],
+    ) -> Result<Box<dyn Fn(CommandData) -> EffectCommand + Send>, String> {
+        Ok(match command {
+            "AddEffect" => {
+                let name = args.get(0).ok_or(
+                    "AddEffect expects a target, an effect type and optional parameters".to_string(),
+                )?;
+                let effect = EffectConfig::from_args(name, &args[1..])?;
+                Box::new(move |_| EffectCommand::Add(effect))
+            }
+
+            "RemoveEffect" => {
+                let idx = args.get(0).and_then(|s| u8::from_str(s).ok()).ok_or(
+                    "RemoveEffect expects a target and the index of the effect in the chain"
+                        .to_string(),
+                )?;
+                Box::new(move |_| EffectCommand::Remove(idx))
+            }
+
+            "ClearEffects" => Box::new(|_| EffectCommand::Clear),
+
+            "SetEffectParam" => {
+                if args.len() != 3 {
+                    return Err("SetEffectParam expects a target, the index of the effect, \
+                        a parameter name and a value"
+                        .to_string());
+                }
+
+                let idx = u8::from_str(args[0])
+                    .map_err(|_| format!("Invalid effect index: '{}'", args[0]))?;
+                let param = EffectParam::from_str(args[1])?;
+
+                let arg = if args[2] == "$data" {
+                    None
+                } else {
+                    let f = f32::from_str(args[2])
+                        .map_err(|_| format!("Invalid value for SetEffectParam: '{}'", args[2]))?;
+                    let (min, max) = param.range();
+                    if f < min || f > max {
+                        return Err(format!(
+                            "Value for {:?} must be between {} and {}",
+                            param, min, max
+                        ));
+                    }
+                    Some(f)
+                };
+
+                Box::new(move |d| {
+                    EffectCommand::SetParam(idx, param, arg.unwrap_or(param.from_midi(d.data)))
+                })
+            }
+
+            _ => return Err(format!("{} is not a valid effect command", command)),
+        })
+    }
+}
@@ -1,12 +1,15 @@
 use crate::api::{
-    Command, FrameTime, LooperCommand, LooperMode, LooperSpeed, Part, PartSet, QuantizationMode,
+    Command, FrameTime, LooperCommand, LooperMode, LooperSpeed, OutputProtection, Part, PartSet,
+    QuantizationMode,
 };
-use crate::music::MetricStructure;
+use crate::music::{MetricStructure, MetronomePattern};
+use crate::song::Song;
 use arrayvec::ArrayVec;
 use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
 use std::borrow::Cow;
 use std::io;
 use std::io::{ErrorKind, Write};
+use std::sync::Arc;
 
 pub const WAVEFORM_DOWNSAMPLE: usize = 2048;
 
@@ -30,6 +33,46 @@ pub struct EngineStateSnapshot {
     pub input_levels: [u8; 2],
     pub looper_levels: [[u8; 2]; 64],
     pub metronome_volume: f32,
+    pub metronome_pattern: MetronomePattern,
+    // the index of the song section that's playing, if a song is loaded and hasn't ended
+    pub song_section: Option<usize>,
+    pub master_gain: f32,
+    pub output_protection: OutputProtection,
+    pub master_levels: [u8; 2],
+    pub master_meters: MasterMeters,
+}
+
+/// Measurements of the master output; levels are in dB and are `f32::NEG_INFINITY` for silence
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MasterMeters {
+    // true peak of each channel over the last processed buffer, in dBTP
+    pub true_peak: [f32; 2],
+    // highest true peak since the loudness measurement was last reset
+    pub max_true_peak: f32,
+    pub momentary_lufs: f32,
+    pub short_term_lufs: f32,
+    pub integrated_lufs: f32,
+    // current gain reduction applied by the limiter or clipper
+    pub gain_reduction: f32,
+}
+
+impl MasterMeters {
+    pub fn new() -> MasterMeters {
+        MasterMeters {
+            true_peak: [f32::NEG_INFINITY; 2],
+            max_true_peak: f32::NEG_INFINITY,
+            momentary_lufs: f32::NEG_INFINITY,
+            short_term_lufs: f32::NEG_INFINITY,
+            integrated_lufs: f32::NEG_INFINITY,
+            gain_reduction: 0.0,
+        }
+    }
+}
+
+impl Default for MasterMeters {
+    fn default() -> Self {
+        MasterMeters::new()
+    }
 }
 
 pub type Waveform = [Vec<f32>; 2];
@@ -46,6 +89,15 @@ pub struct LooperState {
     pub has_redos: bool,
 }
 
+/// Why a pending trigger left the queue
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TriggerRemoval {
+    Fired,
+    Cancelled,
+    // the queue was full, or the trigger's time had already passed
+    Dropped,
+}
+
 #[derive(Clone, Debug)]
 pub enum GuiCommand {
     StateSnapshot(EngineStateSnapshot),
@@ -63,6 +115,20 @@ pub enum GuiCommand {
 
     AddLoopTrigger(u32, FrameTime, LooperCommand),
     AddGlobalTrigger(FrameTime, Command),
+    LoopTriggerRemoved(u32, FrameTime, LooperCommand, TriggerRemoval),
+    GlobalTriggerRemoved(FrameTime, Command, TriggerRemoval),
+
+    SetSong(Song),
+    // the parts with their names, in order, sent when a session is loaded
+    SetParts(Vec<(Part, Arc<String>)>),
+    // a part has been added after the existing ones
+    PartAdded(Part, Arc<String>),
+    PartRemoved(Part),
+    // the names of the scenes, in order, sent when a session is loaded
+    SetScenes(Vec<Arc<String>>),
+    // a scene has been saved; if it's new, it goes after the existing ones
+    SceneSaved(Arc<String>),
+    SceneDeleted(Arc<String>),
 }
 
 #[derive(Clone)]
@@ -4,9 +4,11 @@ extern crate derive_more;
 
 pub mod api;
 pub mod config;
+pub mod effects;
 pub mod gui_channel;
 pub mod midi;
 pub mod music;
+pub mod song;
 
 pub fn clamp<T: PartialOrd + Copy>(v: T, min: T, max: T) -> T {
     assert!(min <= max);
@@ -32,4 +34,22 @@ pub trait Host<'a> {
     fn output_for_looper<'b>(&'b mut self, id: u32) -> Option<[&'b mut [f32]; 2]>
     where
         'a: 'b;
+
+    /// Returns the buffers for an aux bus's output, for hosts that expose the buses (e.g., so
+    /// that they can be processed by external effects)
+    fn output_for_aux_bus<'b>(&'b mut self, _bus: usize) -> Option<[&'b mut [f32]; 2]>
+    where
+        'a: 'b,
+    {
+        None
+    }
+
+    /// Returns the buffers for the count-in clicks, for hosts that can play them separately from
+    /// the metronome (e.g., to send them only to the performer's headphones)
+    fn output_for_count_in<'b>(&'b mut self) -> Option<[&'b mut [f32]; 2]>
+    where
+        'a: 'b,
+    {
+        None
+    }
 }
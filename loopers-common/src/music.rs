@@ -2,6 +2,7 @@ use crate::api::{get_sample_rate, FrameTime};
 use crate::clamp;
 use serde::{Deserialize, Serialize};
 use std::f32::consts::PI;
+use std::str::FromStr;
 
 #[cfg(test)]
 mod tests {
@@ -93,6 +94,112 @@ mod tests {
         let time = 176384;
         assert!(tempo.next_full_beat(FrameTime(time)).0 >= time);
     }
+
+    #[test]
+    fn test_accent_groups() {
+        assert_eq!(Ok(0b10101), MetronomePattern::accents_from_groups("2+2+3"));
+        assert_eq!(Ok(0b1001), MetronomePattern::accents_from_groups("3 + 3"));
+        assert_eq!(Ok(1), MetronomePattern::accents_from_groups("4"));
+        assert!(MetronomePattern::accents_from_groups("2+0+3").is_err());
+        assert!(MetronomePattern::accents_from_groups("2+x").is_err());
+        assert!(MetronomePattern::accents_from_groups("16+16+1").is_err());
+
+        let pattern = MetronomePattern {
+            subdivision: Subdivision::None,
+            accents: 0b10100,
+        };
+        // the first beat is always accented
+        assert!(pattern.is_accented(0));
+        assert!(!pattern.is_accented(1));
+        assert!(pattern.is_accented(2));
+        assert!(pattern.is_accented(4));
+        assert!(!pattern.is_accented(40));
+    }
+
+    #[test]
+    fn test_clicks() {
+        let tempo = Tempo::from_bpm(120f32);
+        let pattern = MetronomePattern {
+            subdivision: Subdivision::Triplets,
+            ..MetronomePattern::default()
+        };
+
+        assert_eq!(0, pattern.click(tempo, FrameTime(0)));
+        assert_eq!(0, pattern.click(tempo, FrameTime(7349)));
+        assert_eq!(1, pattern.click(tempo, FrameTime(7350)));
+        assert_eq!(3, pattern.click(tempo, FrameTime(22050)));
+        assert_eq!(-1, pattern.click(tempo, FrameTime(-1)));
+
+        assert_eq!(
+            tempo.beat(FrameTime(30000)),
+            MetronomePattern::default().click(tempo, FrameTime(30000))
+        );
+    }
+
+    #[test]
+    fn test_convert_time() {
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+
+        // a tempo change keeps the bar and beat, including the position within the beat
+        let slower = MetricStructure::new(4, 4, Tempo::from_bpm(90.0)).unwrap();
+        assert_eq!(FrameTime(235200), ms.convert_time(FrameTime(176400), &slower));
+        assert_eq!(
+            FrameTime(235200 + 14700),
+            ms.convert_time(FrameTime(176400 + 11025), &slower)
+        );
+        assert_eq!(FrameTime(176400), slower.convert_time(FrameTime(235200), &ms));
+
+        // a time signature change keeps the measure
+        let waltz = MetricStructure::new(3, 4, Tempo::from_bpm(120.0)).unwrap();
+        assert_eq!(FrameTime(132300), ms.convert_time(FrameTime(176400), &waltz));
+        assert_eq!(
+            FrameTime(132300 + 22050),
+            ms.convert_time(FrameTime(176400 + 22050), &waltz)
+        );
+
+        // including during the count-in
+        assert_eq!(FrameTime(-66150), ms.convert_time(FrameTime(-88200), &waltz));
+        assert_eq!(FrameTime(0), ms.convert_time(FrameTime(0), &slower));
+    }
+
+    #[test]
+    fn test_groove() {
+        let mut ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+        assert_eq!(FrameTime(11025), ms.grooved_time(1, 2));
+        assert_eq!(FrameTime(22050), ms.next_grooved(FrameTime(11026), 2));
+
+        // with 2:1 swing the second eighth of each beat is played two thirds of the way through
+        ms.groove.swing = 66;
+        assert_eq!(FrameTime(0), ms.grooved_time(0, 2));
+        assert_eq!(FrameTime(14553), ms.grooved_time(1, 2));
+        assert_eq!(FrameTime(36603), ms.grooved_time(3, 2));
+        assert_eq!(FrameTime(14553), ms.next_grooved(FrameTime(1), 2));
+        assert_eq!(FrameTime(22050), ms.next_grooved(FrameTime(14554), 2));
+        // positions that aren't on the grid are not moved
+        assert_eq!(FrameTime(7350), ms.grooved_time(1, 3));
+
+        // on a sixteenth grid, the second and fourth sixteenths are swung
+        ms.groove.grid = GrooveGrid::Sixteenths;
+        assert_eq!(FrameTime(5513 + 1764), ms.grooved_time(1, 4));
+        assert_eq!(FrameTime(11025), ms.grooved_time(2, 4));
+
+        // the template moves each step of the beat, including the beat itself
+        ms.groove = Groove {
+            template: [-10, 0, 20, 0],
+            ..Groove::default()
+        };
+        assert_eq!(FrameTime(22050 - 1103), ms.grooved_time(1, 1));
+        assert_eq!(FrameTime(22050 - 1103), ms.next_grooved(FrameTime(20000), 1));
+        assert_eq!(FrameTime(44100 - 1103), ms.next_grooved(FrameTime(22050), 1));
+
+        // steps never move past each other
+        ms.groove = Groove {
+            swing: Groove::MAX_SWING,
+            template: [0, 50, -50, 0],
+            grid: GrooveGrid::Eighths,
+        };
+        assert!(ms.grooved_time(1, 2) < ms.grooved_time(2, 2));
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -186,6 +293,8 @@ impl Tempo {
 pub struct SavedMetricStructure {
     pub time_signature: TimeSignature,
     pub tempo: SavedTempo,
+    #[serde(default)]
+    pub groove: Groove,
 }
 
 impl SavedMetricStructure {
@@ -198,11 +307,81 @@ impl SavedMetricStructure {
             _ => Err("Neither bpm nor samples_per_beat supplied".to_string())
         }?;
 
-        MetricStructure::new(
+        let ms = MetricStructure::new(
             self.time_signature.upper,
             self.time_signature.lower,
             bpm,
-        ).ok_or("Invalid time signature".to_string())
+        ).ok_or("Invalid time signature".to_string())?;
+
+        Ok(MetricStructure {
+            groove: self.groove,
+            ..ms
+        })
+    }
+}
+
+/// The grid of notes within each beat that swing and groove templates apply to
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GrooveGrid {
+    Eighths,
+    Sixteenths,
+}
+
+impl GrooveGrid {
+    pub fn steps_per_beat(&self) -> u8 {
+        match self {
+            GrooveGrid::Eighths => 2,
+            GrooveGrid::Sixteenths => 4,
+        }
+    }
+}
+
+/// Moves the steps of a grid away from their straight positions. Swing delays every second step,
+/// and the template adds an offset to each step of the beat.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Groove {
+    pub grid: GrooveGrid,
+    // the percentage of each pair of steps taken up by the first step; 50 is straight, and 66 is
+    // close to a triplet feel
+    pub swing: u8,
+    // the offset of each step of the beat, in percent of a step
+    pub template: [i8; Groove::MAX_STEPS],
+}
+
+impl Default for Groove {
+    fn default() -> Self {
+        Groove {
+            grid: GrooveGrid::Eighths,
+            swing: Groove::MIN_SWING,
+            template: [0; Groove::MAX_STEPS],
+        }
+    }
+}
+
+impl Groove {
+    pub const MIN_SWING: u8 = 50;
+    pub const MAX_SWING: u8 = 75;
+    pub const MAX_STEPS: usize = 4;
+    pub const MAX_TEMPLATE_OFFSET: i8 = 50;
+
+    pub fn is_straight(&self) -> bool {
+        self.swing <= Self::MIN_SWING && self.template.iter().all(|o| *o == 0)
+    }
+
+    /// The offset of the given step (counting from the start of the song) as a fraction of a
+    /// step. This is limited so that steps never move past each other.
+    pub fn step_offset(&self, step: i64) -> f64 {
+        let steps = self.grid.steps_per_beat() as i64;
+        let step_of_beat = step.rem_euclid(steps) as usize;
+
+        let swing = if step_of_beat % 2 == 1 {
+            2.0 * clamp(self.swing, Self::MIN_SWING, Self::MAX_SWING) as f64 / 100.0 - 1.0
+        } else {
+            0.0
+        };
+
+        let offset = swing + self.template[step_of_beat] as f64 / 100.0;
+        offset.clamp(-0.45, 0.5)
     }
 }
 
@@ -210,6 +389,7 @@ impl SavedMetricStructure {
 pub struct MetricStructure {
     pub time_signature: TimeSignature,
     pub tempo: Tempo,
+    pub groove: Groove,
 }
 
 impl MetricStructure {
@@ -218,14 +398,141 @@ impl MetricStructure {
         Some(MetricStructure {
             time_signature,
             tempo,
+            groove: Groove::default(),
         })
     }
 
+    /// Returns the time of position `n`, where each beat is divided into `per_beat` positions.
+    /// Positions that fall on a step of the groove grid are moved by the groove.
+    pub fn grooved_time(&self, n: i64, per_beat: i64) -> FrameTime {
+        let spb = self.tempo.samples_per_beat() as i64;
+        // rounds up, including for negative positions
+        let straight = -(-n * spb).div_euclid(per_beat);
+
+        let steps = self.groove.grid.steps_per_beat() as i64;
+        if self.groove.is_straight() || (n * steps) % per_beat != 0 {
+            return FrameTime(straight);
+        }
+
+        let step_len = spb as f64 / steps as f64;
+        let offset = self.groove.step_offset(n * steps / per_beat) * step_len;
+        FrameTime(straight + offset.round() as i64)
+    }
+
+    /// Returns the first position at or after `time`, where each beat is divided into
+    /// `per_beat` positions, following the groove
+    pub fn next_grooved(&self, time: FrameTime, per_beat: i64) -> FrameTime {
+        let spb = (self.tempo.samples_per_beat() as i64).max(1);
+        // the groove moves positions by less than a beat, so start searching a beat back
+        let mut n = (time.0 * per_beat).div_euclid(spb) - per_beat;
+        while self.grooved_time(n, per_beat) < time {
+            n += 1;
+        }
+        self.grooved_time(n, per_beat)
+    }
+
+    /// Converts `time` to the time with the same measure, beat, and position within the beat
+    /// under the metric structure `to`, so that changing the tempo or time signature doesn't move
+    /// the current position in the song
+    pub fn convert_time(&self, time: FrameTime, to: &MetricStructure) -> FrameTime {
+        let spb = (self.tempo.samples_per_beat() as i64).max(1);
+        let upper = self.time_signature.upper as i64;
+
+        let beat = time.0.div_euclid(spb);
+        let measure = beat.div_euclid(upper);
+        let beat_of_measure = beat.rem_euclid(upper);
+
+        let to_spb = (to.tempo.samples_per_beat() as i64).max(1);
+        let to_beat = measure * to.time_signature.upper as i64 + beat_of_measure;
+        let within_beat = time.0.rem_euclid(spb) as i128 * to_spb as i128 / spb as i128;
+
+        FrameTime(to_beat * to_spb + within_beat as i64)
+    }
+
     pub fn to_saved(&self) -> SavedMetricStructure {
         SavedMetricStructure {
             time_signature: self.time_signature,
             tempo: SavedTempo::new(self.tempo.bpm),
+            groove: self.groove,
+        }
+    }
+}
+
+/// The number of metronome clicks in each beat
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Subdivision {
+    None,
+    Eighths,
+    Triplets,
+    Sixteenths,
+}
+
+impl Subdivision {
+    pub fn clicks_per_beat(&self) -> u8 {
+        match self {
+            Subdivision::None => 1,
+            Subdivision::Eighths => 2,
+            Subdivision::Triplets => 3,
+            Subdivision::Sixteenths => 4,
+        }
+    }
+}
+
+/// Controls what the metronome plays within each measure: the subdivisions of each beat, and
+/// which beats are accented
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MetronomePattern {
+    pub subdivision: Subdivision,
+    // bit i is set if beat i of the measure is accented; the first beat is always accented
+    pub accents: u32,
+}
+
+impl Default for MetronomePattern {
+    fn default() -> Self {
+        MetronomePattern {
+            subdivision: Subdivision::None,
+            accents: 1,
+        }
+    }
+}
+
+impl MetronomePattern {
+    /// The number of beats of the measure that can be accented
+    pub const MAX_ACCENTED_BEATS: u8 = 32;
+
+    /// Parses a grouping of beats like `2+2+3` into an accent for the first beat of each group
+    pub fn accents_from_groups(groups: &str) -> Result<u32, String> {
+        let mut accents = 0u32;
+        let mut beat = 0u32;
+        for group in groups.split('+') {
+            let len = u8::from_str(group.trim())
+                .ok()
+                .filter(|l| *l > 0)
+                .ok_or(format!("Invalid beat group '{}' in {}", group.trim(), groups))?;
+
+            if beat >= Self::MAX_ACCENTED_BEATS as u32 {
+                return Err(format!(
+                    "Accent patterns can cover at most {} beats",
+                    Self::MAX_ACCENTED_BEATS
+                ));
+            }
+            accents |= 1 << beat;
+            beat += len as u32;
         }
+
+        Ok(accents)
+    }
+
+    pub fn is_accented(&self, beat_of_measure: u8) -> bool {
+        beat_of_measure == 0
+            || (beat_of_measure < Self::MAX_ACCENTED_BEATS
+                && self.accents & (1 << beat_of_measure) != 0)
+    }
+
+    /// Returns the index of the metronome click (counting subdivisions) at `time`
+    pub fn click(&self, tempo: Tempo, time: FrameTime) -> i64 {
+        let clicks = self.subdivision.clicks_per_beat() as i64;
+        (time.0 * clicks).div_euclid(tempo.samples_per_beat() as i64)
     }
 }
 
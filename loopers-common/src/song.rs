@@ -0,0 +1,257 @@
+use crate::music::{MetricStructure, Tempo, TimeSignature};
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "# a comment
+Intro   2   120   4/4   A
+
+Verse   8   -     -     B
+Bridge  4   96    3/4   -
+Outro   1   -     -     D
+";
+
+    #[test]
+    fn test_parse() {
+        let song = Song::parse(SONG).unwrap();
+        assert_eq!(4, song.sections.len());
+        assert_eq!(
+            SongSection {
+                name: "Bridge".to_string(),
+                measures: 4,
+                tempo: Some(96.0),
+                time_signature: TimeSignature::new(3, 4),
+                part: None,
+            },
+            song.sections[2]
+        );
+
+        assert_eq!(song, Song::parse(&song.to_text()).unwrap());
+
+        assert!(Song::parse("Intro").is_err());
+        assert!(Song::parse("Intro 0").is_err());
+        assert!(Song::parse("Intro 4 fast").is_err());
+        assert!(Song::parse("Intro 4 120 4/5").is_err());
+        assert_eq!(
+            Some(Arc::new("Chorus".to_string())),
+            Song::parse("Intro 4 120 4/4 Chorus").unwrap().sections[0].part
+        );
+        assert!(Song::parse("Intro 4 120 4/4 A extra").is_err());
+    }
+
+    #[test]
+    fn test_sections() {
+        let song = Song::parse(SONG).unwrap();
+        assert_eq!(15, song.measures());
+
+        // the count-in belongs to the first section
+        assert_eq!(Some(0), song.section_at(-1));
+        assert_eq!(Some(0), song.section_at(1));
+        assert_eq!(Some(1), song.section_at(2));
+        assert_eq!(Some(3), song.section_at(14));
+        assert_eq!(None, song.section_at(15));
+
+        assert_eq!(Some(1), song.next_section(0));
+        assert_eq!(Some(2), song.next_section(9));
+        assert_eq!(None, song.next_section(14));
+        assert_eq!(14, song.start_measure(3));
+
+        // settings carry over from earlier sections
+        let base = MetricStructure::new(4, 4, Tempo::from_bpm(100.0)).unwrap();
+        assert_eq!(
+            MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap(),
+            song.metric_structure(1, base)
+        );
+        assert_eq!(
+            MetricStructure::new(3, 4, Tempo::from_bpm(96.0)).unwrap(),
+            song.metric_structure(3, base)
+        );
+        assert_eq!("B", song.part(2).unwrap().as_str());
+        assert_eq!("D", song.part(3).unwrap().as_str());
+    }
+}
+
+/// The name of the song file in a session directory
+pub const SONG_FILE: &str = "song.txt";
+
+pub const SONG_FILE_HEADER: &str = "# Each line is a section of the song: a name, a number of measures, then \
+optionally a tempo (bpm),
+# a time signature, and a part to switch to. Use - to keep the value from the previous section.
+# Name\tMeasures\tTempo\tTime signature\tPart";
+
+/// A section of a song, which lasts for a number of measures. Sections that don't set a tempo,
+/// time signature, or part keep those of the sections before them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SongSection {
+    pub name: String,
+    pub measures: u32,
+    pub tempo: Option<f32>,
+    pub time_signature: Option<TimeSignature>,
+    // the name of the part
+    pub part: Option<Arc<String>>,
+}
+
+/// A planned song structure, made up of sections that the engine plays through in order starting
+/// from measure 0
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Song {
+    pub sections: Vec<SongSection>,
+}
+
+impl Song {
+    /// Parses a song from text with one section per line; see `SONG_FILE_HEADER` for the format
+    pub fn parse(text: &str) -> Result<Song, String> {
+        let mut sections = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            sections.push(
+                Self::parse_section(line).map_err(|e| format!("Line {}: {}", i + 1, e))?,
+            );
+        }
+
+        Ok(Song { sections })
+    }
+
+    fn parse_section(line: &str) -> Result<SongSection, String> {
+        fn optional<T, F: FnOnce(&str) -> Option<T>>(
+            field: Option<&str>,
+            parse: F,
+            error: &str,
+        ) -> Result<Option<T>, String> {
+            match field {
+                None | Some("-") => Ok(None),
+                Some(s) => parse(s).map(Some).ok_or(format!("{} is not a valid {}", s, error)),
+            }
+        }
+
+        let mut fields = line.split_whitespace();
+        let name = fields.next().unwrap_or_default().to_string();
+
+        let measures = fields
+            .next()
+            .and_then(|s| u32::from_str(s).ok())
+            .filter(|m| *m > 0)
+            .ok_or(format!("section {} needs a number of measures", name))?;
+
+        let tempo = optional(
+            fields.next(),
+            |s| f32::from_str(s).ok().filter(|t| *t > 0.0),
+            "tempo",
+        )?;
+
+        let time_signature = optional(
+            fields.next(),
+            |s| {
+                let mut parts = s.split('/');
+                let upper = u8::from_str(parts.next()?).ok()?;
+                let lower = u8::from_str(parts.next()?).ok()?;
+                TimeSignature::new(upper, lower)
+            },
+            "time signature",
+        )?;
+
+        let part = optional(fields.next(), |s| Some(Arc::new(s.to_string())), "part")?;
+
+        if let Some(extra) = fields.next() {
+            return Err(format!("unexpected {}", extra));
+        }
+
+        Ok(SongSection {
+            name,
+            measures,
+            tempo,
+            time_signature,
+            part,
+        })
+    }
+
+    /// Formats the song in the format read by `parse`
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", SONG_FILE_HEADER).unwrap();
+
+        for s in &self.sections {
+            writeln!(
+                text,
+                "{}\t{}\t{}\t{}\t{}",
+                s.name,
+                s.measures,
+                s.tempo.map(|t| t.to_string()).unwrap_or("-".to_string()),
+                s.time_signature
+                    .map(|ts| format!("{}/{}", ts.upper, ts.lower))
+                    .unwrap_or("-".to_string()),
+                s.part.as_ref().map(|p| p.as_str()).unwrap_or("-"),
+            )
+            .unwrap();
+        }
+
+        text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// The total length of the song, in measures
+    pub fn measures(&self) -> i64 {
+        self.sections.iter().map(|s| s.measures as i64).sum()
+    }
+
+    /// The measure at which the section with the given index starts
+    pub fn start_measure(&self, section: usize) -> i64 {
+        self.sections[..section]
+            .iter()
+            .map(|s| s.measures as i64)
+            .sum()
+    }
+
+    /// Returns the index of the section playing at `measure`, or None if the song has ended. The
+    /// count-in before measure 0 is part of the first section.
+    pub fn section_at(&self, measure: i64) -> Option<usize> {
+        let mut end = 0;
+        for (i, s) in self.sections.iter().enumerate() {
+            end += s.measures as i64;
+            if measure < end {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    /// Returns the index of the first section that starts after `measure`
+    pub fn next_section(&self, measure: i64) -> Option<usize> {
+        let next = self.section_at(measure)? + 1;
+        if next < self.sections.len() {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the metric structure in effect during a section, starting from `base` and applying
+    /// the changes of every section up to and including it
+    pub fn metric_structure(&self, section: usize, base: MetricStructure) -> MetricStructure {
+        self.sections[..=section]
+            .iter()
+            .fold(base, |ms, s| MetricStructure {
+                tempo: s.tempo.map(Tempo::from_bpm).unwrap_or(ms.tempo),
+                time_signature: s.time_signature.unwrap_or(ms.time_signature),
+                ..ms
+            })
+    }
+
+    /// Returns the part that should be playing during a section, if any section up to and
+    /// including it sets one
+    pub fn part(&self, section: usize) -> Option<&Arc<String>> {
+        self.sections[..=section].iter().rev().find_map(|s| s.part.as_ref())
+    }
+}
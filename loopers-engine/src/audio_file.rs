@@ -0,0 +1,356 @@
+use crate::error::SaveLoadError;
+use crate::flac::write_flac;
+use crate::sample::Sample;
+use loopers_common::config::SessionAudioFormat;
+use std::f64::consts::PI;
+use std::path::Path;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn sine(freq: f64, rate: usize, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn test_resample() {
+        assert_eq!(vec![0.1, 0.2, 0.3], resample(&[0.1, 0.2, 0.3], 44100, 44100));
+
+        for (from, to) in [(44100, 48000), (48000, 44100), (44100, 96000)].iter() {
+            let input = sine(440.0, *from, *from / 10);
+            let output = resample(&input, *from, *to);
+            assert_eq!(*to / 10, output.len());
+
+            // away from the edges, the output should be the same sine at the new rate
+            let expected = sine(440.0, *to, *to / 10);
+            for i in 100..output.len() - 100 {
+                assert!(
+                    (output[i] - expected[i]).abs() < 0.01,
+                    "{} -> {}: sample {} was {}, expected {}",
+                    from,
+                    to,
+                    i,
+                    output[i],
+                    expected[i]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_resample_filters_aliases() {
+        // a tone above the new nyquist frequency should be removed when downsampling
+        let input = sine(20_000.0, 48000, 4800);
+        let output = resample(&input, 48000, 22050);
+        assert!(output[100..output.len() - 100]
+            .iter()
+            .all(|v| v.abs() < 0.05));
+    }
+
+    #[test]
+    fn test_resample_loop() {
+        // a whole number of cycles, so the loop is seamless at both rates
+        let input = sine(450.0, 44100, 4410);
+        let output = resample_loop(&input, 44100, 48000);
+        assert_eq!(4800, output.len());
+
+        // unlike `resample`, the edges are as accurate as the middle
+        let expected = sine(450.0, 48000, 4800);
+        for (i, (v, e)) in output.iter().zip(&expected).enumerate() {
+            assert!((v - e).abs() < 0.01, "sample {} was {}, expected {}", i, v, e);
+        }
+    }
+
+    #[test]
+    fn test_write_formats() {
+        let dir = tempdir().unwrap();
+        let left = [0.0f32, 0.5, -0.5, 1.0, -1.0];
+        let right = [0.25f32, -0.25, 0.0, 0.75, -0.75];
+
+        for format in [
+            SessionAudioFormat::WavFloat,
+            SessionAudioFormat::Wav16,
+            SessionAudioFormat::Wav24,
+            SessionAudioFormat::Flac16,
+            SessionAudioFormat::Flac24,
+        ]
+        .iter()
+        {
+            let path = dir.path().join(format!("loop_{:?}.{}", format, format.extension()));
+            write_audio(&path, &[&left, &right], 48000, *format).unwrap();
+
+            let file = AudioFile::read(&path).unwrap();
+            assert_eq!(48000, file.sample_rate);
+            let tolerance = 2.0 / (1u64 << (format.bits_per_sample().min(24) - 1)) as f32;
+            for (c, expected) in file.channels.iter().zip(&[&left, &right]) {
+                assert_eq!(expected.len(), c.len());
+                for (v, e) in c.iter().zip(expected.iter()) {
+                    assert!((v - e).abs() < tolerance, "{:?}: {} != {}", format, v, e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_wav() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("click.wav");
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for v in &[0i16, 16384, -16384, i16::MAX] {
+            writer.write_sample(*v).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let file = AudioFile::read(&path).unwrap();
+        assert_eq!(22050, file.sample_rate);
+        assert_eq!(vec![vec![0.0, 0.5, -0.5, i16::MAX as f32 / 32768.0]], file.channels);
+
+        // mono files are played on both sides
+        let sample = file.into_sample(44100);
+        assert_eq!(8, sample.length());
+        assert_eq!(sample.buffer[0], sample.buffer[1]);
+
+        assert!(AudioFile::read(&dir.path().join("click.mp3")).is_err());
+    }
+}
+
+/// Audio decoded from a file, with samples scaled to [-1, 1]
+pub struct AudioFile {
+    pub sample_rate: usize,
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl AudioFile {
+    /// Reads a WAV or FLAC file, based on its extension
+    pub fn read(path: &Path) -> Result<AudioFile, SaveLoadError> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "wav" => Self::read_wav(path),
+            "flac" => Self::read_flac(path),
+            _ => Err(SaveLoadError::OtherError(format!(
+                "Unsupported audio file {} (expected .wav or .flac)",
+                path.to_string_lossy()
+            ))),
+        }
+    }
+
+    fn read_wav(path: &Path) -> Result<AudioFile, SaveLoadError> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        Ok(Self::deinterleave(
+            &samples,
+            spec.channels as usize,
+            spec.sample_rate as usize,
+        ))
+    }
+
+    fn read_flac(path: &Path) -> Result<AudioFile, SaveLoadError> {
+        let mut reader = claxon::FlacReader::open(path)?;
+        let info = reader.streaminfo();
+        let scale = (1u64 << (info.bits_per_sample - 1)) as f32;
+
+        let samples: Vec<f32> = reader
+            .samples()
+            .map(|s| s.map(|s| s as f32 / scale))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::deinterleave(
+            &samples,
+            info.channels as usize,
+            info.sample_rate as usize,
+        ))
+    }
+
+    fn deinterleave(samples: &[f32], channels: usize, sample_rate: usize) -> AudioFile {
+        let channels = channels.max(1);
+        let mut out = vec![Vec::with_capacity(samples.len() / channels); channels];
+        for frame in samples.chunks_exact(channels) {
+            for (c, v) in out.iter_mut().zip(frame) {
+                c.push(*v);
+            }
+        }
+
+        AudioFile {
+            sample_rate,
+            channels: out,
+        }
+    }
+
+    /// Converts the audio to a stereo sample at `sample_rate`. Mono audio is copied to both
+    /// channels, and any channels after the first two are ignored.
+    pub fn into_sample(self, sample_rate: usize) -> Sample {
+        self.into_sample_with(sample_rate, resample)
+    }
+
+    /// Like `into_sample`, for audio that is played as a loop (see `resample_loop`)
+    pub fn into_loop_sample(self, sample_rate: usize) -> Sample {
+        self.into_sample_with(sample_rate, resample_loop)
+    }
+
+    fn into_sample_with(
+        self,
+        sample_rate: usize,
+        resample: fn(&[f32], usize, usize) -> Vec<f32>,
+    ) -> Sample {
+        let from = self.sample_rate;
+        let mut channels = self
+            .channels
+            .into_iter()
+            .take(2)
+            .map(|c| resample(&c, from, sample_rate));
+
+        let left = channels.next().unwrap_or_default();
+        let right = channels.next().unwrap_or_else(|| left.clone());
+
+        let mut sample = Sample::new();
+        sample.record(&[&left, &right]);
+        sample
+    }
+}
+
+/// Writes audio to a file in the given format. PCM formats are rounded to the nearest step of
+/// their bit depth, with full-scale positive samples clipped to the largest value they can hold.
+///
+/// No dither is added, so that saving the same loop twice gives the same file. At 24 bits the
+/// rounding error is far below the noise floor of any recording, but at 16 bits it can be heard
+/// as distortion on very quiet material (like long reverb tails); use a 24-bit format or `WavFloat`
+/// for loops like that.
+pub fn write_audio(
+    path: &Path,
+    channels: &[&[f32]],
+    sample_rate: usize,
+    format: SessionAudioFormat,
+) -> Result<(), SaveLoadError> {
+    let len = channels.first().map(|c| c.len()).unwrap_or(0);
+    let bits = format.bits_per_sample();
+
+    if format == SessionAudioFormat::WavFloat {
+        let spec = hound::WavSpec {
+            channels: channels.len() as u16,
+            sample_rate: sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for i in 0..len {
+            for c in channels {
+                writer.write_sample(c[i])?;
+            }
+        }
+        writer.finalize()?;
+        return Ok(());
+    }
+
+    let scale = (1i64 << (bits - 1)) as f32;
+    // plain rounding, without dither (see above)
+    let to_int = |v: &f32| (v * scale).round().clamp(-scale, scale - 1.0) as i32;
+    let ints: Vec<Vec<i32>> = channels.iter().map(|c| c.iter().map(to_int).collect()).collect();
+
+    match format.extension() {
+        "flac" => {
+            let ints: Vec<&[i32]> = ints.iter().map(|c| &c[..]).collect();
+            write_flac(path, &ints, sample_rate as u32, bits)?;
+        }
+        _ => {
+            let spec = hound::WavSpec {
+                channels: channels.len() as u16,
+                sample_rate: sample_rate as u32,
+                bits_per_sample: bits as u16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = hound::WavWriter::create(path, spec)?;
+            for i in 0..len {
+                for c in &ints {
+                    writer.write_sample(c[i])?;
+                }
+            }
+            writer.finalize()?;
+        }
+    }
+
+    Ok(())
+}
+
+// the number of zero crossings of the sinc kernel on either side of each output sample
+const SINC_ZEROS: f64 = 16.0;
+
+/// Converts audio from one sample rate to another using windowed sinc interpolation. When
+/// downsampling, the kernel is widened so that it also removes frequencies above the new nyquist
+/// frequency.
+pub fn resample(input: &[f32], from: usize, to: usize) -> Vec<f32> {
+    resample_with(input, from, to, false)
+}
+
+/// Like `resample`, but treats the input as a loop: the kernel wraps around at the ends, so the
+/// start and end of the loop still join up smoothly at the new rate
+pub fn resample_loop(input: &[f32], from: usize, to: usize) -> Vec<f32> {
+    resample_with(input, from, to, true)
+}
+
+fn resample_with(input: &[f32], from: usize, to: usize, wrap: bool) -> Vec<f32> {
+    if from == to || from == 0 || to == 0 || input.is_empty() {
+        return input.to_vec();
+    }
+
+    let ratio = from as f64 / to as f64;
+    let cutoff = (1.0 / ratio).min(1.0);
+    let half_width = SINC_ZEROS / cutoff;
+    let len = (input.len() as u64 * to as u64 / from as u64) as usize;
+    let input_len = input.len() as i64;
+
+    (0..len)
+        .map(|i| {
+            let t = i as f64 * ratio;
+            let mut start = (t - half_width).ceil() as i64;
+            let mut end = (t + half_width).floor() as i64;
+            if !wrap {
+                start = start.max(0);
+                end = end.min(input_len - 1);
+            }
+
+            let mut v = 0.0;
+            for k in start..=end {
+                let x = input[k.rem_euclid(input_len) as usize];
+                let d = t - k as f64;
+                let window = 0.5 * (1.0 + (PI * d / half_width).cos());
+                v += x as f64 * cutoff * sinc(cutoff * d) * window;
+            }
+            v as f32
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
@@ -0,0 +1,291 @@
+use crate::effects::{Effect, EffectChain, EffectRack};
+use crate::error::SaveLoadError;
+use crate::plugin::{PluginChain, PluginRack, RackMessage};
+use crate::smoothing::SmoothedValue;
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use loopers_common::api::{FrameTime, SavedAuxBus, SavedPlugin};
+use loopers_common::config::Settings;
+use loopers_common::effects::EffectCommand;
+use loopers_common::gui_channel::GuiSender;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loopers_common::effects::{EffectConfig, EffectParam};
+
+    fn bus() -> AuxBus {
+        let settings = Settings {
+            parameter_ramp_ms: 0.0,
+            ..Settings::default()
+        };
+        AuxBus::new(0, &settings, GuiSender::disconnected())
+    }
+
+    #[test]
+    fn test_send_and_return() {
+        let mut bus = bus();
+        let mut send = SmoothedValue::new(0.5, 0);
+
+        bus.clear(4);
+        bus.mix(1, &[1.0, 1.0], &[-1.0, -1.0], &mut send);
+        bus.mix(0, &[1.0; 4], &[0.0; 4], &mut send);
+        assert_eq!(&[0.5, 1.0, 1.0, 0.5], &bus.left[..4]);
+        assert_eq!(&[0.0, -0.5, -0.5, 0.0], &bus.right[..4]);
+
+        bus.set_return_level(0.5);
+        let mut out_l = vec![1.0; 4];
+        let mut out_r = vec![0.0; 4];
+        bus.process(4);
+        bus.add_return(&mut out_l, &mut out_r);
+        assert_eq!(vec![1.25, 1.5, 1.5, 1.25], out_l);
+        assert_eq!(vec![0.0, -0.25, -0.25, 0.0], out_r);
+
+        // a silent send doesn't touch the bus
+        bus.clear(4);
+        bus.mix(0, &[1.0; 4], &[1.0; 4], &mut SmoothedValue::new(0.0, 0));
+        assert!(bus.left[..4].iter().chain(&bus.right[..4]).all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn test_effects() {
+        let mut bus = bus();
+        let (tx, rx) = bounded(1);
+
+        bus.send_message(AuxMessage::Effect(EffectCommand::Add(
+            EffectConfig::from_args("Bitcrusher", &["1"]).unwrap(),
+        )));
+        bus.send_message(AuxMessage::Effect(EffectCommand::SetParam(
+            0,
+            EffectParam::Mix,
+            0.5,
+        )));
+        bus.set_return_level(0.25);
+        bus.send_message(AuxMessage::Save(PathBuf::from("/tmp"), tx));
+
+        let saved = rx.recv().unwrap().unwrap();
+        assert_eq!(0.25, saved.return_level);
+        assert_eq!(
+            vec![EffectConfig::Bitcrusher {
+                bits: 1.0,
+                downsample: 1.0,
+                mix: 0.5
+            }],
+            saved.effects
+        );
+        assert!(saved.plugins.is_empty());
+
+        let mut send = SmoothedValue::new(1.0, 0);
+        bus.clear(2);
+        bus.mix(0, &[0.3, -0.7], &[0.0, 0.0], &mut send);
+        bus.process(2);
+        assert_eq!(&[0.15, -0.85], &bus.left[..2]);
+    }
+}
+
+#[derive(Debug)]
+pub enum AuxMessage {
+    Effect(EffectCommand),
+    // an effect removed from the chain by the audio thread, to be deallocated
+    DropEffect(Effect),
+    Plugin(RackMessage),
+    // mirrors the return level so that it can be saved
+    SetReturnLevel(f32),
+    Save(PathBuf, Sender<Result<SavedAuxBus, SaveLoadError>>),
+}
+
+// Owns the effect and plugin racks of an aux bus, handling changes to them off of the audio thread
+struct AuxBackend {
+    return_level: f32,
+    effects: EffectRack,
+    plugins: PluginRack,
+}
+
+impl AuxBackend {
+    fn handle(&mut self, msg: AuxMessage) {
+        match msg {
+            AuxMessage::Effect(command) => self.effects.handle(command),
+            AuxMessage::DropEffect(effect) => {
+                debug!("Dropping {:?}", effect);
+            }
+            AuxMessage::Plugin(msg) => self.plugins.handle(msg),
+            AuxMessage::SetReturnLevel(level) => {
+                self.return_level = level;
+            }
+            AuxMessage::Save(path, channel) => {
+                let saved = self.plugins.save(&path).map(|plugins| SavedAuxBus {
+                    return_level: self.return_level,
+                    effects: self.effects.configs().to_vec(),
+                    plugins,
+                });
+
+                if let Err(e) = channel.try_send(saved) {
+                    warn!("failed to respond to save request: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// A stereo bus that loopers can send part of their output to. The bus runs the mix of the sends
+/// through its own effects and plugins (typically a shared reverb or delay), then its output is
+/// added back into the main mix at the bus's return level.
+pub struct AuxBus {
+    pub index: usize,
+    return_level: SmoothedValue,
+
+    effects: EffectChain,
+    plugins: PluginChain,
+    channel: Sender<AuxMessage>,
+
+    left: Vec<f64>,
+    right: Vec<f64>,
+}
+
+impl AuxBus {
+    pub fn new(index: usize, settings: &Settings, gui_sender: GuiSender) -> AuxBus {
+        let ramp = FrameTime::from_ms(settings.parameter_ramp_ms.max(0.0) as f64).0 as u32;
+        let name = format!("aux_{}", index);
+        let (effect_rack, effects) = EffectRack::new(&name);
+        let (plugin_rack, plugins) = PluginRack::new(&name, gui_sender);
+
+        let mut backend = AuxBackend {
+            return_level: 1.0,
+            effects: effect_rack,
+            plugins: plugin_rack,
+        };
+
+        let (tx, rx) = bounded(100);
+        thread::spawn(move || {
+            while let Ok(msg) = rx.recv() {
+                backend.handle(msg);
+            }
+            debug!("channel closed, stopping");
+        });
+
+        AuxBus {
+            index,
+            return_level: SmoothedValue::new(1.0, ramp),
+            effects,
+            plugins,
+            channel: tx,
+            left: vec![0f64; 2048],
+            right: vec![0f64; 2048],
+        }
+    }
+
+    /// The channel controlling the bus's effects and plugins
+    pub fn channel(&self) -> Sender<AuxMessage> {
+        self.channel.clone()
+    }
+
+    pub fn send_message(&mut self, msg: AuxMessage) {
+        if let Err(e) = self.channel.try_send(msg) {
+            match e {
+                TrySendError::Full(msg) => error!("Failed to process {:?}: channel is full", msg),
+                TrySendError::Disconnected(_) => {
+                    error!("Channel for aux bus {} disconnected", self.index)
+                }
+            }
+        }
+    }
+
+    pub fn return_level(&self) -> f32 {
+        self.return_level.target()
+    }
+
+    pub fn set_return_level(&mut self, level: f32) {
+        self.return_level.set_target(level);
+        self.send_message(AuxMessage::SetReturnLevel(level));
+    }
+
+    pub fn set_parameter_ramp(&mut self, samples: u32) {
+        self.return_level.set_ramp_samples(samples);
+    }
+
+    /// Silences the bus for the next `frames` samples, growing the buffers if needed
+    pub fn clear(&mut self, frames: usize) {
+        if self.left.len() < frames {
+            self.left.resize(frames, 0.0);
+            self.right.resize(frames, 0.0);
+        }
+
+        self.left[..frames].iter_mut().for_each(|v| *v = 0.0);
+        self.right[..frames].iter_mut().for_each(|v| *v = 0.0);
+    }
+
+    /// Mixes a looper's output into the bus, starting `offset` samples into the current block,
+    /// at the level given by `send`
+    pub fn mix(&mut self, offset: usize, left: &[f64], right: &[f64], send: &mut SmoothedValue) {
+        if !send.is_ramping() && send.current() == 0.0 {
+            return;
+        }
+
+        let bus = self.left[offset..]
+            .iter_mut()
+            .zip(self.right[offset..].iter_mut());
+        for ((bl, br), (l, r)) in bus.zip(left.iter().zip(right)) {
+            let level = send.next_value() as f64;
+            *bl += *l * level;
+            *br += *r * level;
+        }
+    }
+
+    /// Runs the first `frames` samples of the bus through its effects and plugins
+    pub fn process(&mut self, frames: usize) {
+        let (left, right) = (&mut self.left[..frames], &mut self.right[..frames]);
+
+        let channel = &self.channel;
+        self.effects.process(left, right, |effect| {
+            if let Err(e) = channel.try_send(AuxMessage::DropEffect(effect)) {
+                warn!("failed to send effect to aux bus: {:?}", e);
+            }
+        });
+
+        self.plugins.process(left, right, |plugin| {
+            if let Err(e) = channel.try_send(AuxMessage::Plugin(RackMessage::Drop(plugin))) {
+                warn!("failed to send plugin to aux bus: {:?}", e);
+            }
+        });
+    }
+
+    /// The processed output of the bus, before the return level is applied
+    pub fn output(&self, frames: usize) -> [&[f64]; 2] {
+        [&self.left[..frames], &self.right[..frames]]
+    }
+
+    /// Adds the bus's processed output into `left` and `right` at the return level
+    pub fn add_return(&mut self, left: &mut [f64], right: &mut [f64]) {
+        let bus = self.left.iter().zip(&self.right);
+        for ((l, r), (bl, br)) in left.iter_mut().zip(right.iter_mut()).zip(bus) {
+            let level = self.return_level.next_value() as f64;
+            *l += *bl * level;
+            *r += *br * level;
+        }
+    }
+
+    /// Replaces the bus's settings with those from a saved session in `path`
+    pub fn restore(&mut self, saved: &SavedAuxBus, path: &Path) {
+        self.send_message(AuxMessage::Effect(EffectCommand::Clear));
+        self.send_message(AuxMessage::Plugin(RackMessage::Clear));
+
+        self.set_return_level(saved.return_level);
+        for effect in &saved.effects {
+            self.send_message(AuxMessage::Effect(EffectCommand::Add(*effect)));
+        }
+        for plugin in &saved.plugins {
+            self.send_message(AuxMessage::Plugin(RackMessage::Add(SavedPlugin {
+                state: plugin.state.as_ref().map(|s| path.join(s)),
+                ..plugin.clone()
+            })));
+        }
+    }
+
+    /// Removes all effects and plugins, and resets the return level
+    pub fn reset(&mut self) {
+        self.send_message(AuxMessage::Effect(EffectCommand::Clear));
+        self.send_message(AuxMessage::Plugin(RackMessage::Clear));
+        self.set_return_level(1.0);
+    }
+}
@@ -0,0 +1,662 @@
+use loopers_common::api::{get_sample_rate, FrameTime};
+use crossbeam_queue::ArrayQueue;
+use loopers_common::effects::{
+    EffectCommand, EffectConfig, EffectParam, FilterKind, MAX_DELAY_MS, MAX_EFFECTS,
+};
+use std::f64::consts::PI;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse(len: usize) -> (Vec<f64>, Vec<f64>) {
+        let mut l = vec![0.0; len];
+        let mut r = vec![0.0; len];
+        l[0] = 1.0;
+        r[0] = 1.0;
+        (l, r)
+    }
+
+    #[test]
+    fn test_low_pass_passes_dc() {
+        let mut effect = Effect::new(EffectConfig::from_args("LowPass", &["500"]).unwrap());
+        let mut l = vec![1.0; 4096];
+        let mut r = vec![1.0; 4096];
+        effect.process(&mut l, &mut r);
+        assert!((l[4095] - 1.0).abs() < 1e-6);
+
+        let mut effect = Effect::new(EffectConfig::from_args("HighPass", &["500"]).unwrap());
+        let mut l = vec![1.0; 4096];
+        let mut r = vec![1.0; 4096];
+        effect.process(&mut l, &mut r);
+        assert!(l[4095].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_delay() {
+        let time = FrameTime::from_ms(10.0).0 as usize;
+        let mut effect = Effect::new(EffectConfig::from_args("Delay", &["10", "0.5", "1"]).unwrap());
+
+        let (mut l, mut r) = impulse(time * 3);
+        effect.process(&mut l, &mut r);
+
+        assert_eq!(0.0, l[0]);
+        assert_eq!(1.0, l[time]);
+        assert_eq!(0.5, l[time * 2]);
+        assert_eq!(l, r);
+    }
+
+    #[test]
+    fn test_param_change() {
+        let time = FrameTime::from_ms(10.0).0 as usize;
+        let mut effect = Effect::new(EffectConfig::from_args("Delay", &["100", "0", "1"]).unwrap());
+        assert!(effect.set_param(EffectParam::Time, 10.0));
+        assert!(!effect.set_param(EffectParam::Bits, 4.0));
+
+        let (mut l, mut r) = impulse(time * 2);
+        effect.process(&mut l, &mut r);
+        assert_eq!(1.0, l[time]);
+    }
+
+    #[test]
+    fn test_bitcrusher() {
+        let mut effect =
+            Effect::new(EffectConfig::from_args("Bitcrusher", &["2", "2", "1"]).unwrap());
+        let mut l = vec![0.1, 0.6, -0.3, 0.9];
+        let mut r = l.clone();
+        effect.process(&mut l, &mut r);
+        assert_eq!(vec![0.0, 0.0, -0.5, -0.5], l);
+    }
+
+    #[test]
+    fn test_reverb_tail() {
+        let mut effect = Effect::new(EffectConfig::from_args("Reverb", &["0.8", "0.2", "1"]).unwrap());
+        let (mut l, mut r) = impulse(get_sample_rate());
+        effect.process(&mut l, &mut r);
+
+        // the reverb should still be ringing half a second later, and should not have blown up
+        let tail = &l[get_sample_rate() / 2..];
+        assert!(tail.iter().any(|v| v.abs() > 1e-4));
+        assert!(l.iter().chain(&r).all(|v| v.abs() < 1.0));
+    }
+
+    #[test]
+    fn test_rack() {
+        let (mut rack, mut chain) = EffectRack::new("test");
+        rack.handle(EffectCommand::Add(EffectConfig::from_args("Delay", &[]).unwrap()));
+        rack.handle(EffectCommand::Add(EffectConfig::from_args("Bitcrusher", &[]).unwrap()));
+        rack.handle(EffectCommand::SetParam(1, EffectParam::Bits, 4.0));
+        // not a parameter of the bitcrusher
+        rack.handle(EffectCommand::SetParam(1, EffectParam::Time, 100.0));
+        rack.handle(EffectCommand::Remove(0));
+        rack.handle(EffectCommand::Remove(3));
+
+        assert_eq!(
+            &[EffectConfig::Bitcrusher {
+                bits: 4.0,
+                downsample: 1.0,
+                mix: 1.0
+            }],
+            rack.configs()
+        );
+
+        let mut removed = vec![];
+        chain.process(&mut [0.0], &mut [0.0], |e| removed.push(e));
+        assert_eq!(1, chain.effects.len());
+        assert_eq!(1, removed.len());
+        assert_eq!(rack.configs()[0], *chain.effects[0].config());
+    }
+
+    #[test]
+    fn test_chain() {
+        let (_, mut chain) = EffectRack::new("test");
+        let mut removed = vec![];
+
+        for _ in 0..MAX_EFFECTS + 1 {
+            chain.apply(
+                EffectChange::Add(Effect::new(EffectConfig::from_args("Reverb", &[]).unwrap())),
+                |e| removed.push(e),
+            );
+        }
+        assert_eq!(MAX_EFFECTS, chain.effects.len());
+        assert_eq!(1, removed.len());
+
+        chain.apply(EffectChange::Remove(0), |e| removed.push(e));
+        chain.apply(EffectChange::Remove(MAX_EFFECTS), |e| removed.push(e));
+        assert_eq!(MAX_EFFECTS - 1, chain.effects.len());
+        assert_eq!(2, removed.len());
+
+        chain.apply(EffectChange::Clear, |e| removed.push(e));
+        assert_eq!(0, chain.effects.len());
+        assert_eq!(MAX_EFFECTS + 1, removed.len());
+    }
+}
+
+/// An audio processor that can be inserted into a looper's output. `process` and `configure` are
+/// called from the audio thread, so they must not allocate, lock or block; anything an effect
+/// needs should be allocated when it is created.
+pub trait Processor: Send {
+    /// Updates the processor's settings to match `config`, which will be of the same type that
+    /// the processor was created from
+    fn configure(&mut self, config: &EffectConfig);
+
+    /// Processes a buffer of stereo audio in place
+    fn process(&mut self, left: &mut [f64], right: &mut [f64]);
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    /// Creates a filter from normalized coefficients (i.e., with a0 = 1)
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    /// Updates the coefficients according to the formulas in the Audio EQ Cookbook, keeping the
+    /// filter's state so that it can be swept without clicks
+    pub fn design(&mut self, kind: FilterKind, frequency: f64, q: f64, gain_db: f64) {
+        let sample_rate = get_sample_rate() as f64;
+        let frequency = frequency.max(1.0).min(sample_rate * 0.49);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q.max(0.01));
+        let a = 10f64.powf(gain_db / 40.0);
+        let sq = 2.0 * a.sqrt() * alpha;
+
+        let (num, den) = match kind {
+            FilterKind::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::BandPass => (
+                [alpha, 0.0, -alpha],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::Notch => (
+                [1.0, -2.0 * cos, 1.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            FilterKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + sq),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sq),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + sq,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sq,
+                ],
+            ),
+            FilterKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + sq),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sq),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + sq,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sq,
+                ],
+            ),
+        };
+
+        self.b = [num[0] / den[0], num[1] / den[0], num[2] / den[0]];
+        self.a = [den[1] / den[0], den[2] / den[0]];
+    }
+
+    #[inline]
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+struct Filter {
+    filters: [Biquad; 2],
+}
+
+impl Processor for Filter {
+    fn configure(&mut self, config: &EffectConfig) {
+        if let EffectConfig::Filter {
+            kind,
+            frequency,
+            q,
+            gain,
+        } = *config
+        {
+            for f in &mut self.filters {
+                f.design(kind, frequency as f64, q as f64, gain as f64);
+            }
+        }
+    }
+
+    fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        for (c, buf) in [left, right].iter_mut().enumerate() {
+            for v in buf.iter_mut() {
+                *v = self.filters[c].process(*v);
+            }
+        }
+    }
+}
+
+struct Delay {
+    buffers: [Vec<f64>; 2],
+    pos: usize,
+    delay: usize,
+    feedback: f64,
+    mix: f64,
+}
+
+impl Delay {
+    fn new() -> Delay {
+        let len = FrameTime::from_ms(MAX_DELAY_MS as f64).0 as usize + 1;
+        Delay {
+            buffers: [vec![0.0; len], vec![0.0; len]],
+            pos: 0,
+            delay: 1,
+            feedback: 0.0,
+            mix: 0.0,
+        }
+    }
+}
+
+impl Processor for Delay {
+    fn configure(&mut self, config: &EffectConfig) {
+        if let EffectConfig::Delay {
+            time_ms,
+            feedback,
+            mix,
+        } = *config
+        {
+            let len = self.buffers[0].len();
+            self.delay = (FrameTime::from_ms(time_ms as f64).0 as usize).max(1).min(len - 1);
+            self.feedback = feedback as f64;
+            self.mix = mix as f64;
+        }
+    }
+
+    fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        let len = self.buffers[0].len();
+        for i in 0..left.len() {
+            let read = (self.pos + len - self.delay) % len;
+            for (c, buf) in [&mut *left, &mut *right].iter_mut().enumerate() {
+                let dry = buf[i];
+                let wet = self.buffers[c][read];
+                self.buffers[c][self.pos] = dry + wet * self.feedback;
+                buf[i] = dry * (1.0 - self.mix) + wet * self.mix;
+            }
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+}
+
+// A Schroeder/Moorer reverb in the style of Freeverb: parallel damped comb filters feeding a
+// series of allpass filters, with slightly different tunings for each channel
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const REVERB_INPUT_GAIN: f64 = 0.015;
+const REVERB_WET_GAIN: f64 = 3.0;
+
+struct Comb {
+    buffer: Vec<f64>,
+    pos: usize,
+    filter_store: f64,
+}
+
+impl Comb {
+    #[inline]
+    fn process(&mut self, input: f64, feedback: f64, damping: f64) -> f64 {
+        let output = self.buffer[self.pos];
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.buffer[self.pos] = input + self.filter_store * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f64>,
+    pos: usize,
+}
+
+impl Allpass {
+    #[inline]
+    fn process(&mut self, input: f64) -> f64 {
+        let buffered = self.buffer[self.pos];
+        self.buffer[self.pos] = input + buffered * 0.5;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    feedback: f64,
+    damping: f64,
+    mix: f64,
+}
+
+impl Reverb {
+    fn new() -> Reverb {
+        // the tunings are given for 44.1kHz
+        let scale = get_sample_rate() as f64 / 44100.0;
+        let size = |n: usize, spread: usize| (((n + spread) as f64 * scale) as usize).max(1);
+
+        let combs = |spread| {
+            COMB_TUNINGS
+                .iter()
+                .map(|n| Comb {
+                    buffer: vec![0.0; size(*n, spread)],
+                    pos: 0,
+                    filter_store: 0.0,
+                })
+                .collect()
+        };
+
+        let allpasses = |spread| {
+            ALLPASS_TUNINGS
+                .iter()
+                .map(|n| Allpass {
+                    buffer: vec![0.0; size(*n, spread)],
+                    pos: 0,
+                })
+                .collect()
+        };
+
+        Reverb {
+            combs: [combs(0), combs(STEREO_SPREAD)],
+            allpasses: [allpasses(0), allpasses(STEREO_SPREAD)],
+            feedback: 0.0,
+            damping: 0.0,
+            mix: 0.0,
+        }
+    }
+}
+
+impl Processor for Reverb {
+    fn configure(&mut self, config: &EffectConfig) {
+        if let EffectConfig::Reverb {
+            room_size,
+            damping,
+            mix,
+        } = *config
+        {
+            self.feedback = room_size as f64 * 0.28 + 0.7;
+            self.damping = damping as f64 * 0.4;
+            self.mix = mix as f64;
+        }
+    }
+
+    fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        for i in 0..left.len() {
+            let input = (left[i] + right[i]) * REVERB_INPUT_GAIN;
+
+            for (c, buf) in [&mut *left, &mut *right].iter_mut().enumerate() {
+                let mut wet = 0.0;
+                for comb in &mut self.combs[c] {
+                    wet += comb.process(input, self.feedback, self.damping);
+                }
+                for allpass in &mut self.allpasses[c] {
+                    wet = allpass.process(wet);
+                }
+
+                buf[i] = buf[i] * (1.0 - self.mix) + wet * REVERB_WET_GAIN * self.mix;
+            }
+        }
+    }
+}
+
+struct Bitcrusher {
+    step: f64,
+    downsample: usize,
+    mix: f64,
+    counter: usize,
+    held: [f64; 2],
+}
+
+impl Processor for Bitcrusher {
+    fn configure(&mut self, config: &EffectConfig) {
+        if let EffectConfig::Bitcrusher {
+            bits,
+            downsample,
+            mix,
+        } = *config
+        {
+            self.step = 2.0 / 2f64.powf(bits as f64);
+            self.downsample = (downsample.round() as usize).max(1);
+            self.mix = mix as f64;
+        }
+    }
+
+    fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        for i in 0..left.len() {
+            let sample = self.counter == 0;
+            self.counter = (self.counter + 1) % self.downsample;
+
+            for (c, buf) in [&mut *left, &mut *right].iter_mut().enumerate() {
+                if sample {
+                    self.held[c] = (buf[i] / self.step).round() * self.step;
+                }
+                buf[i] = buf[i] * (1.0 - self.mix) + self.held[c] * self.mix;
+            }
+        }
+    }
+}
+
+/// An effect in a looper's chain, along with its current settings
+pub struct Effect {
+    config: EffectConfig,
+    processor: Box<dyn Processor>,
+}
+
+impl Effect {
+    /// Creates a new effect, allocating all of the memory it needs; this should not be called
+    /// from the audio thread
+    pub fn new(config: EffectConfig) -> Effect {
+        let mut processor: Box<dyn Processor> = match config {
+            EffectConfig::Filter { .. } => Box::new(Filter {
+                filters: [Biquad::default(); 2],
+            }),
+            EffectConfig::Delay { .. } => Box::new(Delay::new()),
+            EffectConfig::Reverb { .. } => Box::new(Reverb::new()),
+            EffectConfig::Bitcrusher { .. } => Box::new(Bitcrusher {
+                step: 0.0,
+                downsample: 1,
+                mix: 0.0,
+                counter: 0,
+                held: [0.0; 2],
+            }),
+        };
+
+        processor.configure(&config);
+
+        Effect { config, processor }
+    }
+
+    pub fn config(&self) -> &EffectConfig {
+        &self.config
+    }
+
+    /// Returns false if the parameter doesn't apply to this effect
+    pub fn set_param(&mut self, param: EffectParam, value: f32) -> bool {
+        if self.config.set_param(param, value) {
+            self.processor.configure(&self.config);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        self.processor.process(left, right);
+    }
+}
+
+impl Debug for Effect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Effect<{:?}>", self.config)
+    }
+}
+
+/// A change to an effect chain. Changes are prepared off of the audio thread (so that any
+/// allocation happens there) and applied in order by the audio thread.
+#[derive(Debug)]
+pub enum EffectChange {
+    Add(Effect),
+    Remove(usize),
+    Clear,
+    SetParam(usize, EffectParam, f32),
+}
+
+/// A series of effects that a looper's or aux bus's output passes through. The chain never
+/// allocates; it is controlled by an `EffectRack`.
+pub struct EffectChain {
+    effects: Vec<Effect>,
+    changes: Arc<ArrayQueue<EffectChange>>,
+}
+
+impl EffectChain {
+    /// Applies a change to the chain. Effects that are removed are passed to `removed` so that
+    /// the caller can dispose of them off of the audio thread.
+    fn apply<F: FnMut(Effect)>(&mut self, change: EffectChange, mut removed: F) {
+        match change {
+            EffectChange::Add(effect) => {
+                if self.effects.len() < MAX_EFFECTS {
+                    self.effects.push(effect);
+                } else {
+                    removed(effect);
+                }
+            }
+            EffectChange::Remove(idx) => {
+                if idx < self.effects.len() {
+                    removed(self.effects.remove(idx));
+                }
+            }
+            EffectChange::Clear => {
+                while let Some(effect) = self.effects.pop() {
+                    removed(effect);
+                }
+            }
+            EffectChange::SetParam(idx, param, value) => {
+                if let Some(effect) = self.effects.get_mut(idx) {
+                    effect.set_param(param, value);
+                }
+            }
+        }
+    }
+
+    /// Applies any pending changes from the rack, then runs the buffers through the effects
+    pub fn process<F: FnMut(Effect)>(
+        &mut self,
+        left: &mut [f64],
+        right: &mut [f64],
+        mut removed: F,
+    ) {
+        while let Some(change) = self.changes.pop() {
+            self.apply(change, &mut removed);
+        }
+
+        for effect in &mut self.effects {
+            effect.process(left, right);
+        }
+    }
+}
+
+/// The configuration of an effect chain, which lives off of the audio thread. The rack validates
+/// commands and creates effects, then passes the changes on to its `EffectChain`.
+pub struct EffectRack {
+    name: String,
+    effects: Vec<EffectConfig>,
+    changes: Arc<ArrayQueue<EffectChange>>,
+}
+
+impl EffectRack {
+    /// Creates a new rack; `name` is used in log messages
+    pub fn new(name: &str) -> (EffectRack, EffectChain) {
+        let changes = Arc::new(ArrayQueue::new(MAX_EFFECTS * 8));
+
+        (
+            EffectRack {
+                name: name.to_string(),
+                effects: vec![],
+                changes: changes.clone(),
+            },
+            EffectChain {
+                effects: Vec::with_capacity(MAX_EFFECTS),
+                changes,
+            },
+        )
+    }
+
+    pub fn configs(&self) -> &[EffectConfig] {
+        &self.effects
+    }
+
+    pub fn handle(&mut self, command: EffectCommand) {
+        match command {
+            EffectCommand::Add(config) => {
+                if self.effects.len() >= MAX_EFFECTS {
+                    warn!("{} already has {} effects", self.name, MAX_EFFECTS);
+                } else if self.send(EffectChange::Add(Effect::new(config))) {
+                    self.effects.push(config);
+                }
+            }
+            EffectCommand::Remove(idx) => {
+                let idx = idx as usize;
+                if idx >= self.effects.len() {
+                    warn!("{} has no effect {}", self.name, idx);
+                } else if self.send(EffectChange::Remove(idx)) {
+                    self.effects.remove(idx);
+                }
+            }
+            EffectCommand::Clear => {
+                if self.send(EffectChange::Clear) {
+                    self.effects.clear();
+                }
+            }
+            EffectCommand::SetParam(idx, param, value) => {
+                let idx = idx as usize;
+                match self.effects.get_mut(idx) {
+                    Some(config) => {
+                        if config.set_param(param, value) {
+                            self.send(EffectChange::SetParam(idx, param, value));
+                        } else {
+                            warn!("{:?} does not apply to {}", param, config.name());
+                        }
+                    }
+                    None => warn!("{} has no effect {}", self.name, idx),
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, change: EffectChange) -> bool {
+        if let Err(change) = self.changes.push(change) {
+            warn!("Effect queue full in {}, dropping {:?}", self.name, change);
+            false
+        } else {
+            true
+        }
+    }
+}
@@ -3,6 +3,7 @@ use std::io;
 #[derive(Debug)]
 pub enum SaveLoadError {
     HoundError(hound::Error),
+    FlacError(claxon::Error),
     IOError(io::Error),
     OtherError(String),
     LooperSaveError(u32),
@@ -17,6 +18,12 @@ impl From<hound::Error> for SaveLoadError {
     }
 }
 
+impl From<claxon::Error> for SaveLoadError {
+    fn from(err: claxon::Error) -> Self {
+        SaveLoadError::FlacError(err)
+    }
+}
+
 impl From<io::Error> for SaveLoadError {
     fn from(err: io::Error) -> Self {
         SaveLoadError::IOError(err)
@@ -0,0 +1,372 @@
+//! A small FLAC encoder for saving loops. Each block is encoded with whichever fixed predictor
+//! (orders 0 to 4) gives the smallest rice-coded residual, which gets most of the compression
+//! that matters for recorded audio without the cost of full LPC analysis.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn decode(path: &Path) -> (claxon::metadata::StreamInfo, Vec<i32>) {
+        let mut reader = claxon::FlacReader::open(path).unwrap();
+        let info = reader.streaminfo();
+        let samples = reader.samples().map(|s| s.unwrap()).collect();
+        (info, samples)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempdir().unwrap();
+
+        for bits in [16u32, 24].iter() {
+            let max = (1i32 << (bits - 1)) - 1;
+            // a sine that's loud enough to clip, plus silence and a burst of noise, across
+            // several blocks with a short one at the end
+            let len = BLOCK_SIZE * 2 + 1000;
+            let left: Vec<i32> = (0..len)
+                .map(|i| {
+                    let v = (i as f64 * 0.01).sin() * 1.2 * max as f64;
+                    (v as i32).clamp(-max - 1, max)
+                })
+                .collect();
+            let right: Vec<i32> = (0..len)
+                .map(|i| match i {
+                    i if i < BLOCK_SIZE => 0,
+                    i => ((i as i64 * 2_654_435_761) % max as i64) as i32,
+                })
+                .collect();
+
+            let path = dir.path().join(format!("test_{}.flac", bits));
+            write_flac(&path, &[&left, &right], 48000, *bits).unwrap();
+
+            let (info, samples) = decode(&path);
+            assert_eq!(48000, info.sample_rate);
+            assert_eq!(2, info.channels);
+            assert_eq!(*bits, info.bits_per_sample);
+            assert_eq!(Some(len as u64), info.samples);
+
+            let expected: Vec<i32> = left
+                .iter()
+                .zip(&right)
+                .flat_map(|(l, r)| vec![*l, *r])
+                .collect();
+            assert_eq!(expected, samples);
+        }
+    }
+
+    #[test]
+    fn test_long_file() {
+        // frame numbers from 128 on take more than one byte in the frame header
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("long.flac");
+        let len = BLOCK_SIZE * 130 + 7;
+        let samples: Vec<i32> = (0..len).map(|i| ((i % 200) as i32 - 100) * 300).collect();
+        write_flac(&path, &[&samples], 44100, 16).unwrap();
+
+        let (info, decoded) = decode(&path);
+        assert_eq!(Some(len as u64), info.samples);
+        assert_eq!(samples, decoded);
+    }
+
+    #[test]
+    fn test_compresses_silence() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("silence.flac");
+        let silence = vec![0i32; 48000];
+        write_flac(&path, &[&silence, &silence], 48000, 16).unwrap();
+
+        assert!(std::fs::metadata(&path).unwrap().len() < 1000);
+        assert_eq!(vec![0; 96000], decode(&path).1);
+    }
+}
+
+// the number of frames in each FLAC block
+const BLOCK_SIZE: usize = 4096;
+
+const MAX_FIXED_ORDER: usize = 4;
+
+// rice parameters are written in 4 bits, and 0b1111 is reserved as an escape code
+const MAX_RICE_PARAM: u32 = 14;
+
+/// Writes the channels (which must all be the same length) as a FLAC file with samples of the
+/// given bit depth. Samples must already be in range for that depth.
+pub fn write_flac(
+    path: &Path,
+    channels: &[&[i32]],
+    sample_rate: u32,
+    bits_per_sample: u32,
+) -> io::Result<()> {
+    let len = channels.first().map(|c| c.len()).unwrap_or(0);
+    let mut out = BufWriter::new(File::create(path)?);
+
+    out.write_all(b"fLaC")?;
+
+    let mut info = BitWriter::new();
+    // last-metadata-block flag, STREAMINFO type and length
+    info.write(1, 1);
+    info.write(0, 7);
+    info.write(34, 24);
+    let block = BLOCK_SIZE.min(len).max(16) as u64;
+    info.write(block, 16);
+    info.write(block, 16);
+    // minimum and maximum frame sizes are unknown
+    info.write(0, 24);
+    info.write(0, 24);
+    info.write(sample_rate as u64, 20);
+    info.write(channels.len() as u64 - 1, 3);
+    info.write(bits_per_sample as u64 - 1, 5);
+    info.write(len as u64, 36);
+    // the MD5 signature is optional; zero means it wasn't computed
+    info.write(0, 64);
+    info.write(0, 64);
+    out.write_all(&info.finish())?;
+
+    for (frame, start) in (0..len).step_by(BLOCK_SIZE).enumerate() {
+        let end = (start + BLOCK_SIZE).min(len);
+        let blocks: Vec<&[i32]> = channels.iter().map(|c| &c[start..end]).collect();
+        out.write_all(&encode_frame(frame as u64, &blocks, bits_per_sample))?;
+    }
+
+    out.flush()
+}
+
+fn encode_frame(frame: u64, channels: &[&[i32]], bits_per_sample: u32) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let mut w = BitWriter::new();
+
+    // sync code, reserved bit and fixed blocking strategy
+    w.write(0b11111111111110, 14);
+    w.write(0, 1);
+    w.write(0, 1);
+    // block size is given as a 16-bit value at the end of the header
+    w.write(0b0111, 4);
+    // sample rate is taken from STREAMINFO
+    w.write(0, 4);
+    // independent channels
+    w.write(channels.len() as u64 - 1, 4);
+    // some decoders need the sample size in every frame, rather than just in STREAMINFO
+    let sample_size = match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    };
+    w.write(sample_size, 3);
+    w.write(0, 1);
+    w.write_utf8(frame);
+    w.write(block_size as u64 - 1, 16);
+    let crc = crc8(w.bytes());
+    w.write(crc as u64, 8);
+
+    for channel in channels {
+        encode_subframe(&mut w, channel, bits_per_sample);
+    }
+
+    let mut bytes = w.finish();
+    let crc = crc16(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    bytes
+}
+
+fn encode_subframe(w: &mut BitWriter, samples: &[i32], bits_per_sample: u32) {
+    let bps = bits_per_sample;
+
+    if samples.iter().all(|s| *s == samples[0]) {
+        w.write(0, 1);
+        w.write(0b000000, 6);
+        w.write(0, 1);
+        w.write_signed(samples[0] as i64, bps);
+        return;
+    }
+
+    // pick the fixed predictor with the smallest residual
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let param = rice_param(&residual);
+            let bits = order as u64 * bps as u64 + 10 + rice_bits(&residual, param);
+            (bits, order, residual, param)
+        })
+        .min_by_key(|(bits, ..)| *bits);
+
+    match best {
+        Some((bits, order, residual, param)) if bits < samples.len() as u64 * bps as u64 => {
+            w.write(0, 1);
+            w.write(0b001000 | order as u64, 6);
+            w.write(0, 1);
+            for s in &samples[..order] {
+                w.write_signed(*s as i64, bps);
+            }
+
+            // rice coding with 4-bit parameters, in a single partition
+            w.write(0, 2);
+            w.write(0, 4);
+            w.write(param as u64, 4);
+            for r in residual {
+                let u = zigzag(r);
+                w.write_unary(u >> param);
+                w.write(u & ((1 << param) - 1), param);
+            }
+        }
+        _ => {
+            w.write(0, 1);
+            w.write(0b000001, 6);
+            w.write(0, 1);
+            for s in samples {
+                w.write_signed(*s as i64, bps);
+            }
+        }
+    }
+}
+
+// the prediction error of the fixed polynomial predictor of the given order
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i64> {
+    let s = |i: usize| samples[i] as i64;
+    (order..samples.len())
+        .map(|i| match order {
+            0 => s(i),
+            1 => s(i) - s(i - 1),
+            2 => s(i) - 2 * s(i - 1) + s(i - 2),
+            3 => s(i) - 3 * s(i - 1) + 3 * s(i - 2) - s(i - 3),
+            _ => s(i) - 4 * s(i - 1) + 6 * s(i - 2) - 4 * s(i - 3) + s(i - 4),
+        })
+        .collect()
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+// estimates the best rice parameter from the mean of the residual
+fn rice_param(residual: &[i64]) -> u32 {
+    let sum: u64 = residual.iter().map(|r| zigzag(*r)).sum();
+    let mean = sum / residual.len().max(1) as u64;
+    (64 - mean.leading_zeros()).min(MAX_RICE_PARAM)
+}
+
+fn rice_bits(residual: &[i64], param: u32) -> u64 {
+    residual
+        .iter()
+        .map(|r| (zigzag(*r) >> param) + 1 + param as u64)
+        .sum()
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: vec![],
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    // writes the low `bits` bits of value, most significant first
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+
+        if bits == 0 {
+            return;
+        }
+
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        let mut zeros = zeros;
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    // frame numbers are written with the same variable-length scheme as UTF-8, extended to 36 bits
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+
+        let continuation_bytes = match value {
+            v if v < 0x800 => 1,
+            v if v < 0x10000 => 2,
+            v if v < 0x200000 => 3,
+            v if v < 0x4000000 => 4,
+            v if v < 0x80000000 => 5,
+            _ => 6,
+        };
+
+        let first_bits = 6 - continuation_bytes;
+        // the first byte starts with a one for each byte in the sequence, then a zero
+        let prefix = (0xFF00u64 >> (continuation_bytes + 1)) & 0xFF;
+        self.write(
+            prefix | ((value >> (6 * continuation_bytes)) & ((1 << first_bits) - 1)),
+            8,
+        );
+        for i in (0..continuation_bytes).rev() {
+            self.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+        }
+    }
+
+    // the bytes written so far, which doesn't include any partial byte
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // pads the output with zeros to a whole number of bytes
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+        self.bytes
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, b| {
+        (0..8).fold(crc ^ b, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, b| {
+        (0..8).fold(crc ^ ((*b as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
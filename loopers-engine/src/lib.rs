@@ -6,40 +6,900 @@ extern crate log;
 use std::collections::VecDeque;
 use std::fs::{create_dir_all, read_to_string, File};
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::ops::Range;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{Receiver, Sender};
 
-use loopers_common::api::QuantizationMode::Free;
 use loopers_common::api::{
-    get_sample_rate, set_sample_rate, Command, FrameTime, LooperCommand, LooperMode, LooperTarget,
-    Part, PartSet, QuantizationMode, SavedSession,
+    get_sample_rate, set_sample_rate, Command, FrameTime, InsertTarget, LooperCommand, LooperMode,
+    LooperTarget, Names, Part, PartSet, QuantizationMode, SavedPlugin, Scene, SceneLooper,
+    AUX_BUS_COUNT, DEFAULT_PARTS, MAX_MASTER_GAIN_DB, MAX_NAMES, MIN_MASTER_GAIN_DB,
 };
-use loopers_common::config::{Config, MidiMapping, FILE_HEADER};
+use loopers_common::config::{Config, LatePolicy, MidiMapping, Settings, FILE_HEADER};
 use loopers_common::gui_channel::{
-    EngineState, EngineStateSnapshot, GuiCommand, GuiSender, LogMessage,
+    EngineState, EngineStateSnapshot, GuiCommand, GuiSender, LogMessage, TriggerRemoval,
 };
 use loopers_common::midi::MidiEvent;
 use loopers_common::music::*;
+use loopers_common::song::Song;
 use loopers_common::Host;
 
+use crate::aux_bus::{AuxBus, AuxMessage};
 use crate::error::SaveLoadError;
-use crate::looper::Looper;
-use crate::metronome::Metronome;
-use crate::sample::Sample;
+use crate::looper::{LoopTiming, Looper};
+use crate::master::MasterBus;
+use crate::metronome::{Metronome, MetronomeSounds, SoundLoader, DEFAULT_SOUND};
+use crate::song_loader::SongLoader;
+use crate::plugin::RackMessage;
 use crate::session::{SaveSessionData, SessionSaver};
+use crate::session_loader::{LoadedSession, SessionLoader};
+use crate::tap_tempo::TapTempo;
 use crate::trigger::{Trigger, TriggerCondition};
 
+mod audio_file;
+mod aux_bus;
+mod clap_ffi;
+mod effects;
 mod error;
+mod flac;
 pub mod looper;
 pub mod metronome;
 pub mod sample;
 pub mod session;
+mod master;
+mod meter;
+mod plugin;
+mod session_loader;
+mod smoothing;
+mod song_loader;
+mod tap_tempo;
 mod trigger;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+    use loopers_common::api::{Group, OutputProtection, SavedSession};
+    use loopers_common::config::SessionAudioFormat;
+    use loopers_common::gui_channel::GuiReceiver;
+    use std::path::Path;
+
+    const BLOCK: usize = 512;
+
+    struct TestHost {
+        count_in: Option<[Vec<f32>; 2]>,
+    }
+
+    impl<'a> Host<'a> for TestHost {
+        fn add_looper(&mut self, _id: u32) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn remove_looper(&mut self, _id: u32) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn output_for_looper<'b>(&'b mut self, _id: u32) -> Option<[&'b mut [f32]; 2]>
+        where
+            'a: 'b,
+        {
+            None
+        }
+
+        fn output_for_count_in<'b>(&'b mut self) -> Option<[&'b mut [f32]; 2]>
+        where
+            'a: 'b,
+        {
+            self.count_in.as_mut().map(|[l, r]| [&mut l[..], &mut r[..]])
+        }
+    }
+
+    struct TestEngine {
+        engine: Engine,
+        host: TestHost,
+        gui: GuiReceiver,
+        // the gui updates sent so far, other than the state snapshots
+        updates: Vec<GuiCommand>,
+        metronome: [Vec<f32>; 2],
+    }
+
+    impl TestEngine {
+        fn new() -> TestEngine {
+            let mut host = TestHost { count_in: None };
+            let (gui_sender, gui) = GuiSender::new();
+            let (_, command_input) = bounded(1);
+            let mut engine = Engine::with_config(
+                &mut host,
+                Config::new(),
+                gui_sender,
+                command_input,
+                vec![0.5; 64],
+                vec![1.0; 64],
+                44100,
+            );
+
+            // the tests run the looper backends themselves, so that they know when the loopers
+            // have caught up with the engine
+            engine.loopers.clear();
+
+            let mut test = TestEngine {
+                engine,
+                host,
+                gui,
+                updates: vec![],
+                metronome: [vec![0.0; BLOCK], vec![0.0; BLOCK]],
+            };
+            test.add_looper(0);
+            test
+        }
+
+        fn add_looper(&mut self, id: u32) {
+            let mut looper = Looper::new(id, PartSet::new(), self.engine.gui_sender.clone());
+            looper.set_time(FrameTime(self.engine.time));
+            self.engine.session_saver.add_looper(&looper);
+            self.engine.loopers.push(looper);
+            self.engine.id_counter = self.engine.id_counter.max(id + 1);
+            self.process_backends();
+        }
+
+        fn looper(&self, id: u32) -> &Looper {
+            self.engine.loopers.iter().find(|l| l.id == id).unwrap()
+        }
+
+        fn command(&mut self, command: Command) {
+            self.engine.handle_command(&mut self.host, &command, false, None);
+            self.process_backends();
+            self.receive_updates();
+        }
+
+        fn receive_updates(&mut self) {
+            while let Ok(update) = self.gui.cmd_channel.try_recv() {
+                if !matches!(update, GuiCommand::StateSnapshot(_)) {
+                    self.updates.push(update);
+                }
+            }
+        }
+
+        fn process_backends(&mut self) {
+            for l in &mut self.engine.loopers {
+                if let Some(backend) = &mut l.backend {
+                    backend.process_until_done();
+                }
+            }
+        }
+
+        // processes a block of a constant input
+        fn process(&mut self, input: f32) {
+            let input = vec![input; BLOCK];
+            let mut out = [vec![0.0; BLOCK], vec![0.0; BLOCK]];
+            self.metronome.iter_mut().for_each(|c| c.fill(0.0));
+            if let Some(count_in) = &mut self.host.count_in {
+                count_in.iter_mut().for_each(|c| c.fill(0.0));
+            }
+
+            let [out_l, out_r] = &mut out;
+            let [met_l, met_r] = &mut self.metronome;
+            self.engine.process(
+                &mut self.host,
+                [&input, &input],
+                out_l,
+                out_r,
+                [met_l, met_r],
+                BLOCK as u64,
+                &[],
+            );
+
+            self.process_backends();
+            self.receive_updates();
+        }
+
+        // processes blocks until the time reaches `time`, which is at most a block away
+        fn process_until(&mut self, time: i64, input: f32) {
+            while self.engine.time < time {
+                self.process(input);
+            }
+        }
+    }
+
+    fn peak(buf: &[f32]) -> f32 {
+        buf.iter().fold(0.0, |a, b| a.max(b.abs()))
+    }
+
+    #[test]
+    fn test_count_in_length() {
+        let mut test = TestEngine::new();
+        let measure = test.engine.measure_len().0;
+        assert_eq!(88200, measure);
+        assert_eq!(-measure, test.engine.time);
+
+        test.command(Command::SetCountIn(2));
+        assert_eq!(-2 * measure, test.engine.time);
+
+        test.command(Command::SetCountIn(0));
+        assert_eq!(0, test.engine.time);
+
+        // the count-in only applies when starting from stopped
+        test.command(Command::SetCountIn(1));
+        test.command(Command::Start);
+        test.process(0.0);
+        test.command(Command::SetCountIn(3));
+        assert_eq!(-measure + BLOCK as i64, test.engine.time);
+    }
+
+    #[test]
+    fn test_record_after_count_in() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetQuantizationMode(QuantizationMode::Free));
+        test.command(Command::Start);
+        test.process(0.5);
+
+        // the recording waits for the end of the count-in, even though it isn't quantized
+        test.command(Command::Looper(LooperCommand::Record, LooperTarget::Id(0)));
+        test.process_until(-(BLOCK as i64), 0.5);
+        assert_eq!(LooperMode::Playing, test.looper(0).mode());
+
+        test.process_until(BLOCK as i64 * 4, 0.5);
+        assert_eq!(LooperMode::Recording, test.looper(0).mode());
+        assert_eq!(test.engine.time as u64, test.looper(0).length());
+    }
+
+    #[test]
+    fn test_separate_count_in_output() {
+        for separate in [false, true] {
+            let mut test = TestEngine::new();
+            test.engine.config.settings.separate_count_in_output = separate;
+            test.host.count_in = Some([vec![0.0; BLOCK], vec![0.0; BLOCK]]);
+            test.command(Command::Start);
+
+            let mut count_in_peak = 0f32;
+            let mut metronome_peak = 0f32;
+            while test.engine.time + (BLOCK as i64) <= 0 {
+                test.process(0.0);
+                count_in_peak = count_in_peak.max(peak(&test.host.count_in.as_ref().unwrap()[0]));
+                metronome_peak = metronome_peak.max(peak(&test.metronome[0]));
+            }
+
+            if separate {
+                assert!(count_in_peak > 0.0);
+                assert_eq!(0.0, metronome_peak);
+            } else {
+                assert_eq!(0.0, count_in_peak);
+                assert!(metronome_peak > 0.0);
+            }
+
+            // the block that crosses the end of the count-in is split between the outputs, and
+            // the downbeat goes to the metronome
+            let split = -test.engine.time as usize;
+            test.process(0.0);
+            let count_in = &test.host.count_in.as_ref().unwrap()[0];
+            assert_eq!(0.0, peak(&count_in[split..]));
+            assert_eq!(0.0, peak(&test.metronome[0][..split]));
+            assert!(peak(&test.metronome[0][split..]) > 0.0);
+
+            for _ in 0..4 {
+                test.process(0.0);
+                assert_eq!(0.0, peak(&test.host.count_in.as_ref().unwrap()[0]));
+            }
+        }
+    }
+
+    // the updates about triggers that have been sent to the gui
+    fn trigger_updates(test: &TestEngine) -> Vec<String> {
+        test.updates
+            .iter()
+            .filter_map(|u| match u {
+                GuiCommand::AddLoopTrigger(id, time, lc) => {
+                    Some(format!("add {} {} {:?}", id, time.0, lc))
+                }
+                GuiCommand::AddGlobalTrigger(time, c) => Some(format!("add {} {:?}", time.0, c)),
+                GuiCommand::LoopTriggerRemoved(id, time, lc, removal) => {
+                    Some(format!("{:?} {} {} {:?}", removal, id, time.0, lc))
+                }
+                GuiCommand::GlobalTriggerRemoved(time, c, removal) => {
+                    Some(format!("{:?} {} {:?}", removal, time.0, c))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_second_press_cancels_trigger() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        test.command(Command::Start);
+        test.process(0.5);
+
+        let record = Command::Looper(LooperCommand::Record, LooperTarget::Id(0));
+        test.command(record.clone());
+        assert_eq!(1, test.engine.triggers.len());
+
+        test.command(record.clone());
+        assert!(test.engine.triggers.is_empty());
+        assert_eq!(
+            vec!["add 0 88200 Record", "Cancelled 0 88200 Record"],
+            trigger_updates(&test)
+        );
+
+        test.process_until(88200 + BLOCK as i64, 0.5);
+        assert_eq!(LooperMode::Playing, test.looper(0).mode());
+
+        // a press after the trigger has fired queues a new one
+        test.command(record.clone());
+        test.process_until(88200 * 2 + BLOCK as i64, 0.5);
+        test.command(record);
+        assert_eq!(LooperMode::Recording, test.looper(0).mode());
+        assert_eq!(1, test.engine.triggers.len());
+    }
+
+    #[test]
+    fn test_quantization_override() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        test.command(Command::Start);
+        test.process(0.5);
+
+        let record = Command::Looper(LooperCommand::Record, LooperTarget::Id(0));
+        test.engine.handle_command(&mut test.host, &record, false, Some(QuantizationMode::Free));
+        test.process_backends();
+        assert!(test.engine.triggers.is_empty());
+        assert_eq!(LooperMode::Recording, test.looper(0).mode());
+
+        // the override only applies to that command
+        test.command(Command::Looper(LooperCommand::Play, LooperTarget::Id(0)));
+        assert_eq!(1, test.engine.triggers.len());
+        assert_eq!(QuantizationMode::Measure, test.engine.sync_mode);
+
+        test.engine.handle_command(
+            &mut test.host,
+            &Command::SetTempoBPM(90.0),
+            false,
+            Some(QuantizationMode::Phrase(2)),
+        );
+        assert_eq!(
+            FrameTime(88200 * 2),
+            test.engine.triggers.back().unwrap().triggered_at()
+        );
+    }
+
+    #[test]
+    fn test_master_loop_recording() {
+        let mut test = TestEngine::new();
+        test.add_looper(1);
+        test.command(Command::SetCountIn(0));
+        test.command(Command::Start);
+        test.process(0.5);
+
+        // record a one-measure master loop
+        test.command(Command::Looper(LooperCommand::Record, LooperTarget::Id(0)));
+        test.process_until(88200 + BLOCK as i64, 0.5);
+        test.command(Command::Looper(LooperCommand::Play, LooperTarget::Id(0)));
+        test.process_until(88200 * 2 + BLOCK as i64, 0.5);
+        assert_eq!(88200, test.looper(0).length());
+
+        let master = Some(QuantizationMode::MasterLoop(0));
+        let command = |test: &mut TestEngine, lc| {
+            let c = Command::Looper(lc, LooperTarget::Id(1));
+            test.engine.handle_command(&mut test.host, &c, false, master);
+            test.process_backends();
+        };
+
+        // the recording starts when the master loop restarts
+        command(&mut test, LooperCommand::Record);
+        assert_eq!(FrameTime(88200 * 3), test.engine.triggers[0].triggered_at());
+        test.process_until(88200 * 3 + 30000, 0.5);
+        assert_eq!(LooperMode::Recording, test.looper(1).mode());
+
+        // and stopping it after 30000 frames rounds it up to half of the master loop
+        command(&mut test, LooperCommand::Play);
+        assert_eq!(
+            FrameTime(88200 * 3 + 44100),
+            test.engine.triggers[0].triggered_at()
+        );
+        test.process_until(88200 * 4, 0.5);
+        assert_eq!(LooperMode::Playing, test.looper(1).mode());
+        assert_eq!(44100, test.looper(1).length());
+    }
+
+    #[test]
+    fn test_tempo_change_keeps_loops_aligned() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        test.command(Command::Start);
+        test.process(0.5);
+
+        // record a one-measure loop
+        test.command(Command::Looper(LooperCommand::Record, LooperTarget::Id(0)));
+        test.process_until(88200 + BLOCK as i64, 0.5);
+        test.command(Command::Looper(LooperCommand::Play, LooperTarget::Id(0)));
+        test.process_until(88200 * 2 + 30000, 0.5);
+        let before = test.looper(0).loop_timing().unwrap();
+        assert_eq!(88200, before.period);
+        let time = test.engine.time;
+        let position = (time - before.offset.0).rem_euclid(88200);
+
+        // halve the tempo in the middle of the loop
+        test.command(Command::SetQuantizationMode(QuantizationMode::Free));
+        test.command(Command::SetTempoBPM(60.0));
+        test.process(0.5);
+        assert_eq!(time * 2 + BLOCK as i64, test.engine.time);
+
+        // the loop is stretched to a measure at the new tempo, and carries on from the same point
+        let measure = test.engine.measure_len().0;
+        assert_eq!(176400, measure);
+        let after = test.looper(0).loop_timing().unwrap();
+        assert_eq!(176400, test.looper(0).length());
+        assert_eq!(176400, after.period);
+        assert_eq!(position * 2, (time * 2 - after.offset.0).rem_euclid(176400));
+
+        // so it repeats at the start of each measure of the new grid
+        assert_eq!(0, after.offset.0.rem_euclid(measure));
+        test.process_until(measure * 3, 0.5);
+        assert_eq!(after, test.looper(0).loop_timing().unwrap());
+    }
+
+    #[test]
+    fn test_trigger_removal_events() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        test.command(Command::Start);
+        test.process(0.0);
+
+        // fired
+        test.command(Command::SetTempoBPM(100.0));
+        test.process_until(88200, 0.0);
+        assert_eq!(100.0, test.engine.metric_structure.tempo.bpm());
+        assert_eq!(
+            vec!["add 88200 SetTempoBPM(100.0)", "Fired 88200 SetTempoBPM(100.0)"],
+            trigger_updates(&test)
+        );
+        test.updates.clear();
+
+        // cancelled
+        test.command(Command::Looper(LooperCommand::Record, LooperTarget::Id(0)));
+        test.command(Command::CancelTriggers(Some(LooperTarget::Id(0))));
+        let measure = test.engine.measure_len().0;
+        let next = (test.engine.time / measure + 1) * measure;
+        assert_eq!(
+            vec![
+                format!("add 0 {} Record", next),
+                format!("Cancelled 0 {} Record", next)
+            ],
+            trigger_updates(&test)
+        );
+        test.updates.clear();
+
+        // dropped because the queue is full
+        let capacity = test.engine.triggers.capacity();
+        for i in 0..=capacity {
+            test.command(Command::SetTempoBPM(60.0 + i as f32));
+        }
+        assert_eq!(capacity, test.engine.triggers.len());
+        assert_eq!(
+            format!("Dropped {} SetTempoBPM(60.0)", next),
+            trigger_updates(&test)[capacity]
+        );
+        test.command(Command::CancelTriggers(None));
+        test.updates.clear();
+
+        // dropped because it was missed
+        test.engine.config.settings.late_triggers.engine = LatePolicy::Drop;
+        let time = test.engine.time;
+        test.engine.triggers.push_back(Trigger::new(
+            TriggerCondition::LoopEnd(FrameTime(time - 10000), 1_000_000),
+            Command::SetTempoBPM(90.0),
+            test.engine.metric_structure,
+            FrameTime(time - 10000),
+        ));
+        test.process(0.0);
+        assert_eq!(100.0, test.engine.metric_structure.tempo.bpm());
+        let missed = trigger_updates(&test);
+        assert_eq!(vec![format!("Dropped {} SetTempoBPM(90.0)", time - 10000)], missed);
+    }
+
+    const SONG: &str = "Intro 1 120 4/4 A\nVerse 2 90 3/4 B\n";
+
+    fn set_song(test: &mut TestEngine, text: &str) {
+        let song = Song::parse(text).unwrap();
+        test.engine.set_song(Arc::new(song.clone()), song);
+        test.receive_updates();
+    }
+
+    #[test]
+    fn test_song_sections() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        set_song(&mut test, SONG);
+        test.command(Command::Start);
+
+        // the changes for the next section are only queued in the block where it starts
+        test.process_until(88200 - BLOCK as i64, 0.0);
+        assert!(test.engine.triggers.is_empty());
+        assert_eq!(Part::A, test.engine.current_part);
+
+        test.process_until(88200 + BLOCK as i64, 0.0);
+        assert_eq!(
+            vec![
+                "add 88200 SetTimeSignature(3, 4)",
+                "add 88200 SetTempoBPM(90.0)",
+                "add 88200 GoToPart(Part(1))",
+                "Fired 88200 SetTimeSignature(3, 4)",
+                // the new time signature moves the grid, and the tempo change with it
+                "Fired 66150 SetTempoBPM(90.0)",
+                "Fired 88200 GoToPart(Part(1))",
+            ],
+            trigger_updates(&test)
+        );
+        assert!(test.engine.triggers.is_empty());
+        assert_eq!(
+            MetricStructure::new(3, 4, Tempo::from_bpm(90.0)).unwrap(),
+            test.engine.metric_structure
+        );
+        assert_eq!(Part::B, test.engine.current_part);
+        assert_eq!(1, test.engine.current_measure());
+    }
+
+    #[test]
+    fn test_set_song_keeps_user_triggers() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        set_song(&mut test, SONG);
+        test.command(Command::Start);
+        test.process(0.0);
+
+        test.engine.schedule_song_section(88200);
+        test.command(Command::SetTempoBPM(100.0));
+        assert_eq!(4, test.engine.triggers.len());
+        test.updates.clear();
+
+        // replacing the song cancels the changes it queued, but not the tempo change from the user
+        set_song(&mut test, "Intro 4 120 4/4 A\n");
+        assert_eq!(1, test.engine.triggers.len());
+        assert_eq!(Command::SetTempoBPM(100.0), test.engine.triggers[0].command);
+        assert_eq!(
+            vec![
+                "Cancelled 88200 SetTimeSignature(3, 4)",
+                "Cancelled 88200 SetTempoBPM(90.0)",
+                "Cancelled 88200 GoToPart(Part(1))",
+            ],
+            trigger_updates(&test)
+        );
+
+        test.process_until(88200 + BLOCK as i64, 0.0);
+        assert_eq!(100.0, test.engine.metric_structure.tempo.bpm());
+        assert_eq!(Part::A, test.engine.current_part);
+    }
+
+    #[test]
+    fn test_tap_tempo_keeps_queued_tempo_changes() {
+        let mut test = TestEngine::new();
+        test.command(Command::SetCountIn(0));
+        set_song(&mut test, SONG);
+        test.command(Command::Start);
+        test.process(0.0);
+
+        test.engine.schedule_song_section(88200);
+        test.engine.handle_command(
+            &mut test.host,
+            &Command::SetTempoBPM(100.0),
+            false,
+            Some(QuantizationMode::Phrase(2)),
+        );
+        test.engine.set_tapped_tempo(110.0);
+        test.receive_updates();
+        test.updates.clear();
+
+        // a new tapped tempo only replaces the last one
+        test.engine.set_tapped_tempo(115.0);
+        test.receive_updates();
+        assert_eq!(
+            vec!["Cancelled 88200 SetTempoBPM(110.0)", "add 88200 SetTempoBPM(115.0)"],
+            trigger_updates(&test)
+        );
+        let tempos: Vec<_> = test
+            .engine
+            .triggers
+            .iter()
+            .filter_map(|t| match t.command {
+                Command::SetTempoBPM(bpm) => Some(bpm),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![90.0, 100.0, 115.0], tempos);
+
+        // so the song's tempo change still fires with its section
+        test.updates.clear();
+        test.process_until(88200 + BLOCK as i64, 0.0);
+        assert!(trigger_updates(&test).contains(&"Fired 66150 SetTempoBPM(90.0)".to_string()));
+    }
+
+    #[test]
+    fn test_add_and_remove_part() {
+        let mut test = TestEngine::new();
+        test.add_looper(0);
+        test.receive_updates();
+
+        let name = Arc::new("Bridge".to_string());
+        test.command(Command::AddPart(name.clone()));
+        // adding a part that already exists doesn't change anything
+        test.command(Command::AddPart(name.clone()));
+        test.command(Command::AddPart(Arc::new("no spaces".to_string())));
+        assert_eq!(vec![Part::A, Part::B, Part::C, Part::D, Part(4)], *test.engine.parts);
+
+        test.command(Command::Looper(LooperCommand::AddToPart(Part(4)), LooperTarget::Id(0)));
+        test.process_backends();
+        assert!(test.looper(0).parts.contains(Part(4)));
+
+        test.command(Command::RemovePart(Part(4)));
+        test.process_backends();
+        assert!(!test.looper(0).parts.contains(Part(4)));
+        assert_eq!(vec![Part::A, Part::B, Part::C, Part::D], *test.engine.parts);
+
+        test.receive_updates();
+        let added: Vec<_> = test
+            .updates
+            .iter()
+            .filter_map(|u| match u {
+                GuiCommand::PartAdded(part, name) => Some((*part, name.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![(Part(4), "Bridge")], added);
+        assert!(test
+            .updates
+            .iter()
+            .any(|u| matches!(u, GuiCommand::PartRemoved(Part(4)))));
+    }
+
+    #[test]
+    fn test_group_target() {
+        let mut test = TestEngine::new();
+        test.add_looper(1);
+        test.add_looper(2);
+
+        let drums = Arc::make_mut(&mut test.engine.names).group("drums").unwrap();
+        for id in [0, 2] {
+            test.command(Command::Looper(LooperCommand::AddToGroup(drums), LooperTarget::Id(id)));
+        }
+        assert!(test.looper(0).groups.contains(drums));
+        assert!(!test.looper(1).groups.contains(drums));
+
+        let levels = |test: &TestEngine| [0, 1, 2].map(|id| test.looper(id).level);
+        test.command(Command::Looper(LooperCommand::SetLevel(0.5), LooperTarget::Group(drums)));
+        assert_eq!([0.5, 1.0, 0.5], levels(&test));
+
+        test.command(Command::Looper(LooperCommand::RemoveFromGroup(drums), LooperTarget::Id(2)));
+        assert!(!test.looper(2).groups.contains(drums));
+        test.command(Command::Looper(LooperCommand::SetLevel(0.25), LooperTarget::Group(drums)));
+        assert_eq!([0.25, 1.0, 0.5], levels(&test));
+    }
+
+    #[test]
+    fn test_cancel_group_triggers() {
+        let mut test = TestEngine::new();
+        test.add_looper(1);
+        test.command(Command::SetCountIn(0));
+        test.command(Command::Start);
+        test.process(0.5);
+
+        let drums = Arc::make_mut(&mut test.engine.names).group("drums").unwrap();
+        test.command(Command::Looper(LooperCommand::AddToGroup(drums), LooperTarget::Id(1)));
+
+        test.command(Command::Looper(LooperCommand::Record, LooperTarget::Id(0)));
+        test.command(Command::Looper(LooperCommand::Record, LooperTarget::Group(drums)));
+        assert_eq!(2, test.engine.triggers.len());
+
+        test.command(Command::CancelTriggers(Some(LooperTarget::Group(drums))));
+        assert_eq!(
+            vec!["add 0 88200 Record", "add 1 88200 Record", "Cancelled 1 88200 Record"],
+            trigger_updates(&test)
+        );
+    }
+
+    fn saved_session(test: &TestEngine) -> SavedSession {
+        SavedSession {
+            save_time: 0,
+            metronome_volume: 100,
+            metronome_sound: None,
+            metronome_pattern: MetronomePattern::default(),
+            metric_structure: test.engine.metric_structure.to_saved(),
+            sync_mode: QuantizationMode::Measure,
+            sample_rate: 44100,
+            master_gain: 0.0,
+            output_protection: OutputProtection::default(),
+            master_plugins: vec![],
+            aux_buses: vec![],
+            song: None,
+            parts: vec![],
+            scenes: vec![],
+            loopers: vec![],
+        }
+    }
+
+    fn load_session(test: &mut TestEngine, session: &SavedSession, dir: &Path) {
+        let path = dir.join("project.loopers");
+        std::fs::write(&path, serde_json::to_string(session).unwrap()).unwrap();
+        test.updates.clear();
+        test.command(Command::LoadSession(Arc::new(path)));
+
+        // the session is read on the loader thread, and restored by a later block
+        for _ in 0..1000 {
+            if test.updates.iter().any(|u| matches!(u, GuiCommand::SetScenes(_))) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            test.process(0.0);
+        }
+        panic!("session was not loaded");
+    }
+
+    #[test]
+    fn test_load_session_loopers() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // record a loop, and save it as a looper in a new part and a group
+        let mut looper = Looper::new(3, PartSet::new(), GuiSender::disconnected());
+        let input = vec![0.5f32; 1000];
+        looper.transition_to(LooperMode::Recording);
+        looper.backend.as_mut().unwrap().process_until_done();
+        looper.process_input(0, &[&input, &input], Part::A);
+        looper.backend.as_mut().unwrap().process_until_done();
+        looper.transition_to(LooperMode::Playing);
+        let backend = looper.backend.as_mut().unwrap();
+        backend.process_until_done();
+        let mut saved = backend
+            .serialize(dir.path(), SessionAudioFormat::WavFloat, &Names::default())
+            .unwrap();
+        saved.parts = vec![Arc::new("Verse".to_string())];
+        saved.groups = vec![Arc::new("drums".to_string())];
+
+        // sessions from older versions have no sample rate, and aren't resampled
+        let session = SavedSession {
+            sample_rate: 0,
+            loopers: vec![saved],
+            ..saved_session(&TestEngine::new())
+        };
+
+        let mut test = TestEngine::new();
+        load_session(&mut test, &session, dir.path());
+        assert_eq!(vec![3], test.engine.loopers.iter().map(|l| l.id).collect::<Vec<_>>());
+        assert_eq!(4, test.engine.id_counter);
+
+        let verse = Part(test.engine.names.parts.get("Verse").unwrap());
+        let drums = Group(test.engine.names.groups.get("drums").unwrap());
+        let restored = test.looper(3);
+        assert_eq!(1000, restored.length());
+        assert!(restored.parts.contains(verse) && !restored.parts.contains(Part::A));
+        assert!(restored.groups.contains(drums));
+        assert_eq!(vec![verse], *test.engine.parts);
+    }
+
+    fn scene_state(test: &TestEngine, id: u32) -> (LooperMode, f32, f32) {
+        let l = test.looper(id);
+        (l.local_mode(), l.level, l.pan)
+    }
+
+    #[test]
+    fn test_save_and_recall_scene() {
+        let mut test = TestEngine::new();
+        test.add_looper(1);
+        test.command(Command::SetCountIn(0));
+        test.command(Command::SetQuantizationMode(QuantizationMode::Free));
+        test.command(Command::Start);
+        for id in 0..2 {
+            test.command(Command::Looper(LooperCommand::Record, LooperTarget::Id(id)));
+            test.process(0.5);
+            test.command(Command::Looper(LooperCommand::Play, LooperTarget::Id(id)));
+        }
+        test.command(Command::SetQuantizationMode(QuantizationMode::Measure));
+
+        test.command(Command::Looper(LooperCommand::SetLevel(0.5), LooperTarget::Id(0)));
+        test.command(Command::Looper(LooperCommand::SetPan(-0.5), LooperTarget::Id(1)));
+        test.command(Command::Looper(LooperCommand::Mute, LooperTarget::Id(1)));
+        let name = Arc::new("Verse".to_string());
+        test.command(Command::SaveScene(name.clone()));
+        assert_eq!(1, test.engine.scenes.len());
+        assert!(test
+            .updates
+            .iter()
+            .any(|u| matches!(u, GuiCommand::SceneSaved(n) if *n == name)));
+
+        test.command(Command::Looper(LooperCommand::SetLevel(1.0), LooperTarget::All));
+        test.command(Command::Looper(LooperCommand::SetPan(0.0), LooperTarget::All));
+        test.command(Command::Looper(LooperCommand::Play, LooperTarget::Id(1)));
+        test.process_until(88200 + BLOCK as i64, 0.0);
+        assert_eq!((LooperMode::Playing, 1.0, 0.0), scene_state(&test, 1));
+
+        // the scene is recalled at the next measure
+        test.command(Command::RecallScene(name.clone()));
+        assert_eq!((LooperMode::Playing, 1.0, 0.0), scene_state(&test, 1));
+        test.process_until(88200 * 2 + BLOCK as i64, 0.0);
+        assert_eq!((LooperMode::Playing, 0.5, 0.0), scene_state(&test, 0));
+        assert_eq!((LooperMode::Muted, 1.0, -0.5), scene_state(&test, 1));
+
+        // saving under the same name replaces the scene, without changing the scenes that a
+        // session save that's in progress is writing
+        let saving = test.engine.scenes.clone();
+        test.command(Command::Looper(LooperCommand::SetLevel(0.25), LooperTarget::Id(0)));
+        test.command(Command::SaveScene(Arc::new("Verse".to_string())));
+        assert_eq!(1, test.engine.scenes.len());
+        assert_eq!(0.25, test.engine.scenes[0].loopers[0].level);
+        assert_eq!(0.5, saving[0].loopers[0].level);
+        drop(saving);
+
+        test.command(Command::DeleteScene(name));
+        assert!(test.engine.scenes.is_empty());
+        assert_eq!(SCENE_CAPACITY, test.engine.scene_pool.len());
+    }
+
+    #[test]
+    fn test_scenes_saved_with_session() {
+        let mut test = TestEngine::new();
+        test.add_looper(1);
+        test.command(Command::Looper(LooperCommand::SetLevel(0.5), LooperTarget::Id(0)));
+        test.command(Command::Looper(LooperCommand::SetPan(0.5), LooperTarget::Id(1)));
+        test.command(Command::SaveScene(Arc::new("Chorus".to_string())));
+
+        let session = SavedSession {
+            parts: vec![Arc::new("A".to_string()), Arc::new("Bridge".to_string())],
+            scenes: test.engine.scenes.to_vec(),
+            ..saved_session(&test)
+        };
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut loaded = TestEngine::new();
+        load_session(&mut loaded, &session, dir.path());
+        loaded.add_looper(0);
+        loaded.add_looper(1);
+        loaded.receive_updates();
+        assert_eq!(test.engine.scenes, loaded.engine.scenes);
+        // parts are saved by name, and new names are given the next free id
+        assert_eq!(vec![Part::A, Part(4)], *loaded.engine.parts);
+        assert!(loaded.updates.iter().any(|u| matches!(
+            u,
+            GuiCommand::SetScenes(names) if names.len() == 1 && *names[0] == "Chorus"
+        )));
+
+        loaded.engine.handle_command(
+            &mut loaded.host,
+            &Command::RecallSceneByIndex(0),
+            false,
+            Some(QuantizationMode::Free),
+        );
+        loaded.process_backends();
+        assert_eq!((LooperMode::Playing, 0.5, 0.0), scene_state(&loaded, 0));
+        assert_eq!((LooperMode::Playing, 1.0, 0.5), scene_state(&loaded, 1));
+    }
+
+    #[test]
+    fn test_load_song() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.txt");
+        std::fs::write(&path, SONG).unwrap();
+
+        let mut test = TestEngine::new();
+        test.command(Command::LoadSong(Arc::new(path)));
+
+        // the song is read on the loader thread, and picked up by a later block
+        for _ in 0..1000 {
+            if !test.engine.song.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            test.process(0.0);
+        }
+        assert_eq!(Song::parse(SONG).unwrap(), *test.engine.song);
+        assert!(test
+            .updates
+            .iter()
+            .any(|u| matches!(u, GuiCommand::SetSong(s) if *s == *test.engine.song)));
+    }
+}
+
 pub struct Engine {
     config: Config,
 
@@ -49,20 +909,47 @@ pub struct Engine {
 
     metric_structure: MetricStructure,
 
-    command_input: Receiver<Command>,
+    command_input: Receiver<(Command, Option<QuantizationMode>)>,
 
     gui_sender: GuiSender,
 
     loopers: Vec<Looper>,
     active: u32,
 
+    // The parts, names, song and scenes are shared with the session saver, so that saving doesn't
+    // copy them on the audio thread. They're changed with `Arc::make_mut`, which only copies them
+    // if a save is still using them.
     current_part: Part,
+    // the parts of the session, in the order NextPart and PreviousPart move through them
+    parts: Arc<Vec<Part>>,
+    // the names of the parts, which the loopers and commands refer to by id
+    names: Arc<Names>,
 
     sync_mode: QuantizationMode,
 
     metronome: Option<Metronome>,
+    metronome_sounds: SoundLoader,
+    metronome_pattern: MetronomePattern,
+
+    aux_buses: Vec<AuxBus>,
+    // the buses' channels, for the session saver
+    aux_bus_channels: Arc<Vec<Sender<AuxMessage>>>,
+
+    master: MasterBus,
 
     triggers: VecDeque<Trigger>,
+    tap_tempo: TapTempo,
+
+    session_loader: SessionLoader,
+
+    song: Arc<Song>,
+    song_loader: SongLoader,
+    // the song section whose changes have been queued as triggers
+    song_trigger: Option<usize>,
+
+    scenes: Arc<Vec<Scene>>,
+    // empty lists for new scenes, so that saving one doesn't allocate
+    scene_pool: Vec<Vec<SceneLooper>>,
 
     id_counter: u32,
 
@@ -76,6 +963,11 @@ pub struct Engine {
     looper_peaks: [[f32; 2]; 64],
 }
 
+// the number of scenes, and of loopers in each, that are preallocated; saving more than this
+// allocates on the audio thread
+const SCENE_CAPACITY: usize = 128;
+const SCENE_LOOPER_CAPACITY: usize = 64;
+
 #[allow(dead_code)]
 const THRESHOLD: f32 = 0.05;
 
@@ -101,7 +993,11 @@ pub fn read_config() -> Result<Config, String> {
     let mut config = Config::new();
 
     match File::open(&mapping_path) {
-        Ok(file) => match MidiMapping::from_file(&mapping_path.to_string_lossy(), &file) {
+        Ok(file) => match MidiMapping::from_file(
+            &mapping_path.to_string_lossy(),
+            &file,
+            &mut config.names,
+        ) {
             Ok(mms) => config.midi_mappings.extend(mms),
             Err(e) => {
                 return Err(format!("Failed to load midi mappings: {:?}", e));
@@ -116,6 +1012,22 @@ pub fn read_config() -> Result<Config, String> {
         Err(_) => {}
     }
 
+    let mut settings_path = dirs::config_dir().unwrap_or_default();
+    settings_path.push("loopers/settings.toml");
+
+    match read_to_string(&settings_path) {
+        Ok(contents) => match toml::from_str::<Settings>(&contents) {
+            Ok(settings) => config.settings = settings,
+            Err(e) => {
+                return Err(format!("Failed to load settings: {}", e));
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(format!("Failed to read settings: {}", e));
+        }
+    }
+
     Ok(config)
 }
 
@@ -123,14 +1035,12 @@ impl Engine {
     pub fn new<'a, H: Host<'a>>(
         host: &mut H,
         mut gui_sender: GuiSender,
-        command_input: Receiver<Command>,
+        command_input: Receiver<(Command, Option<QuantizationMode>)>,
         beat_normal: Vec<f32>,
         beat_emphasis: Vec<f32>,
         restore: bool,
         sample_rate: usize,
     ) -> Engine {
-        let metric_structure = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
-
         let config = match read_config() {
             Ok(config) => config,
             Err(err) => {
@@ -145,6 +1055,57 @@ impl Engine {
             }
         };
 
+        let engine = Self::with_config(
+            host,
+            config,
+            gui_sender,
+            command_input,
+            beat_normal,
+            beat_emphasis,
+            sample_rate,
+        );
+
+        if restore {
+            let restore_fn = || -> Result<(), SaveLoadError> {
+                let config_path = last_session_path()?;
+                let restore_path = read_to_string(config_path)?;
+                info!("Restoring from {}", restore_path);
+                engine
+                    .session_loader
+                    .load(Arc::new(PathBuf::from(restore_path)));
+                Ok(())
+            };
+
+            if let Err(err) = restore_fn() {
+                warn!("Failed to restore existing session {:?}", err);
+            }
+        }
+
+        engine
+    }
+
+    fn with_config<'a, H: Host<'a>>(
+        host: &mut H,
+        mut config: Config,
+        gui_sender: GuiSender,
+        command_input: Receiver<(Command, Option<QuantizationMode>)>,
+        beat_normal: Vec<f32>,
+        beat_emphasis: Vec<f32>,
+        sample_rate: usize,
+    ) -> Engine {
+        let metric_structure = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+
+        set_sample_rate(sample_rate);
+
+        let master = MasterBus::new(&config.settings, gui_sender.clone());
+        let sounds = MetronomeSounds::built_in(&beat_normal, &beat_emphasis);
+        let aux_buses: Vec<AuxBus> = (0..AUX_BUS_COUNT)
+            .map(|i| AuxBus::new(i, &config.settings, gui_sender.clone()))
+            .collect();
+        let aux_bus_channels = Arc::new(aux_buses.iter().map(|b| b.channel()).collect());
+
+        let names = std::mem::take(&mut config.names);
+
         let mut engine = Engine {
             config,
 
@@ -159,18 +1120,36 @@ impl Engine {
             loopers: vec![Looper::new(0, PartSet::new(), gui_sender.clone()).start()],
             active: 0,
             current_part: Part::A,
+            parts: {
+                let mut parts = Vec::with_capacity(MAX_NAMES);
+                parts.extend_from_slice(&DEFAULT_PARTS);
+                Arc::new(parts)
+            },
+            names: Arc::new(names),
 
             sync_mode: QuantizationMode::Measure,
 
             id_counter: 1,
 
-            metronome: Some(Metronome::new(
-                metric_structure,
-                Sample::from_mono(&beat_normal),
-                Sample::from_mono(&beat_emphasis),
-            )),
+            metronome: Some(Metronome::with_sounds(metric_structure, sounds.clone())),
+            metronome_sounds: SoundLoader::spawn(sounds),
+            metronome_pattern: MetronomePattern::default(),
+
+            aux_buses,
+            aux_bus_channels,
+
+            master,
 
             triggers: VecDeque::with_capacity(128),
+            tap_tempo: TapTempo::new(),
+            song: Arc::new(Song::default()),
+            song_loader: SongLoader::spawn(),
+            session_loader: SessionLoader::spawn(gui_sender.clone()),
+            song_trigger: None,
+            scenes: Arc::new(Vec::with_capacity(SCENE_CAPACITY)),
+            scene_pool: (0..SCENE_CAPACITY)
+                .map(|_| Vec::with_capacity(SCENE_LOOPER_CAPACITY))
+                .collect(),
 
             session_saver: SessionSaver::new(gui_sender),
 
@@ -183,47 +1162,172 @@ impl Engine {
             looper_peaks: [[0.0; 2]; 64],
         };
 
-        set_sample_rate(sample_rate);
+        let ramp = engine.parameter_ramp_samples();
+        if let Some(m) = &mut engine.metronome {
+            m.set_parameter_ramp(ramp);
+        }
+        for bus in &mut engine.aux_buses {
+            bus.set_parameter_ramp(ramp);
+        }
 
         engine.reset();
 
-        for l in &engine.loopers {
+        for l in &mut engine.loopers {
+            l.set_parameter_ramp(ramp);
             engine.session_saver.add_looper(l);
             if let Err(e) = host.add_looper(l.id) {
                 error!("Failed to add host port for looper {}: {}", l.id, e);
             }
         }
 
-        if restore {
-            let mut restore_fn = || {
-                let config_path = last_session_path()?;
-                let restore_path = read_to_string(config_path)?;
-                info!("Restoring from {}", restore_path);
-                engine.load_session(host, Path::new(&restore_path))
-            };
+        engine
+    }
 
-            if let Err(err) = restore_fn() {
-                warn!("Failed to restore existing session {:?}", err);
-            }
-        }
+    fn parameter_ramp_samples(&self) -> u32 {
+        FrameTime::from_ms(self.config.settings.parameter_ramp_ms.max(0.0) as f64).0 as u32
+    }
 
-        engine
+    fn add_trigger(triggers: &mut VecDeque<Trigger>, gui_sender: &mut GuiSender, t: Trigger) {
+        Self::make_room_for_trigger(triggers, gui_sender);
+        Self::send_trigger_added(gui_sender, &t);
+        triggers.push_back(t);
+    }
+
+    // like add_trigger, but keeps the queue in time order for triggers that may come before ones
+    // that are already queued
+    fn insert_trigger(triggers: &mut VecDeque<Trigger>, gui_sender: &mut GuiSender, t: Trigger) {
+        Self::make_room_for_trigger(triggers, gui_sender);
+        Self::send_trigger_added(gui_sender, &t);
+
+        let idx = triggers
+            .iter()
+            .position(|o| o.triggered_at() > t.triggered_at())
+            .unwrap_or(triggers.len());
+        triggers.insert(idx, t);
     }
 
-    fn add_trigger(triggers: &mut VecDeque<Trigger>, t: Trigger) {
+    // queues a trigger for a command from the user. If the same command is already waiting for
+    // the same time (e.g., because a pedal was pressed twice), that trigger is cancelled instead.
+    fn add_or_cancel_trigger(
+        triggers: &mut VecDeque<Trigger>,
+        gui_sender: &mut GuiSender,
+        t: Trigger,
+    ) {
+        let cancelled = Self::remove_triggers(triggers, gui_sender, TriggerRemoval::Cancelled, |o| {
+            o.command == t.command && o.triggered_at() == t.triggered_at()
+        });
+
+        if cancelled == 0 {
+            Self::add_trigger(triggers, gui_sender, t);
+        }
+    }
+
+    // drops the oldest triggers if the queue is full
+    fn make_room_for_trigger(triggers: &mut VecDeque<Trigger>, gui_sender: &mut GuiSender) {
         while triggers.len() >= triggers.capacity() {
-            triggers.pop_front();
+            if let Some(t) = triggers.pop_front() {
+                let mut log = LogMessage::error();
+                if let Err(e) = write!(log, "Too many pending triggers; dropped {:?}", t.command) {
+                    error!("Failed to report dropped trigger: {}", e);
+                } else {
+                    gui_sender.send_log(log);
+                }
+                Self::send_trigger_removed(gui_sender, &t, TriggerRemoval::Dropped);
+            }
         }
+    }
 
-        triggers.push_back(t);
+    // removes the pending triggers that match `pred`, returning how many were removed
+    fn remove_triggers<F: FnMut(&Trigger) -> bool>(
+        triggers: &mut VecDeque<Trigger>,
+        gui_sender: &mut GuiSender,
+        removal: TriggerRemoval,
+        mut pred: F,
+    ) -> usize {
+        let mut removed = 0;
+        triggers.retain(|t| {
+            if pred(t) {
+                Self::send_trigger_removed(gui_sender, t, removal);
+                removed += 1;
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    fn send_trigger_added(gui_sender: &mut GuiSender, t: &Trigger) {
+        gui_sender.send_update(match &t.command {
+            Command::Looper(lc, LooperTarget::Id(id)) => {
+                GuiCommand::AddLoopTrigger(*id, t.triggered_at(), *lc)
+            }
+            command => GuiCommand::AddGlobalTrigger(t.triggered_at(), command.clone()),
+        });
+    }
+
+    fn send_trigger_removed(gui_sender: &mut GuiSender, t: &Trigger, removal: TriggerRemoval) {
+        gui_sender.send_update(match &t.command {
+            Command::Looper(lc, LooperTarget::Id(id)) => {
+                GuiCommand::LoopTriggerRemoved(*id, t.triggered_at(), *lc, removal)
+            }
+            command => GuiCommand::GlobalTriggerRemoved(t.triggered_at(), command.clone(), removal),
+        });
+    }
+
+    // cancels the pending triggers of the targeted loopers
+    fn cancel_loop_triggers(&mut self, target: LooperTarget) {
+        let id = match target {
+            LooperTarget::All | LooperTarget::Group(_) => None,
+            LooperTarget::Id(id) => Some(id),
+            LooperTarget::Selected => Some(self.active),
+            LooperTarget::Index(idx) => {
+                match self.loopers.iter().filter(|l| !l.deleted).nth(idx as usize) {
+                    Some(l) => Some(l.id),
+                    None => return,
+                }
+            }
+        };
+
+        let loopers = &self.loopers;
+        Self::remove_triggers(
+            &mut self.triggers,
+            &mut self.gui_sender,
+            TriggerRemoval::Cancelled,
+            |t| match (&t.command, target) {
+                (Command::Looper(_, LooperTarget::Id(l)), LooperTarget::Group(group)) => loopers
+                    .iter()
+                    .any(|looper| looper.id == *l && looper.groups.contains(group)),
+                (Command::Looper(_, LooperTarget::Id(l)), _) => id.map(|id| id == *l).unwrap_or(true),
+                _ => false,
+            },
+        );
+    }
+
+    fn cancel_all_triggers(&mut self) {
+        Self::remove_triggers(
+            &mut self.triggers,
+            &mut self.gui_sender,
+            TriggerRemoval::Cancelled,
+            |_| true,
+        );
+        // the changes for the next song section will be queued again
+        self.song_trigger = None;
     }
 
     fn reset(&mut self) {
         if let Some(m) = &mut self.metronome {
             m.reset();
         }
-        self.triggers.clear();
-        self.set_time(FrameTime(-(self.measure_len().0 as i64)));
+        self.cancel_all_triggers();
+
+        // go back to the start of the song, if there is one
+        if !self.song.is_empty() {
+            self.use_metric_structure(self.song.metric_structure(0, self.metric_structure));
+            self.go_to_song_part(0);
+        }
+
+        self.set_time(self.count_in_start());
         for l in &mut self.loopers {
             l.handle_command(LooperCommand::Play);
         }
@@ -243,32 +1347,93 @@ impl Engine {
             for i in 0..self.config.midi_mappings.len() {
                 let mm = &self.config.midi_mappings[i];
                 if let Some(c) = mm.command_for_event(e) {
-                    self.handle_command(host, &c, false);
+                    let quantization = mm.quantization;
+                    self.handle_command(host, &c, false, quantization);
                 }
             }
         }
     }
 
+    // the condition for commands quantized with `sync_mode`, or None if they take effect
+    // immediately. Loop-relative modes fall back to the next measure if there's no suitable loop,
+    // except for MasterLoop, where commands are free until the master loop has been recorded.
+    fn trigger_condition(&self, sync_mode: QuantizationMode) -> Option<TriggerCondition> {
+        let loop_end = |timing: Option<LoopTiming>| {
+            timing
+                .map(|t| TriggerCondition::LoopEnd(t.offset, t.period))
+                .unwrap_or(TriggerCondition::Measure)
+        };
+
+        match sync_mode {
+            QuantizationMode::Free => None,
+            QuantizationMode::Subdivision => Some(TriggerCondition::Subdivision),
+            QuantizationMode::Beat => Some(TriggerCondition::Beat),
+            QuantizationMode::Measure => Some(TriggerCondition::Measure),
+            QuantizationMode::Phrase(measures) => Some(TriggerCondition::Phrase(measures)),
+            QuantizationMode::LoopEnd(idx) => Some(loop_end(
+                self.loopers
+                    .iter()
+                    .filter(|l| !l.deleted)
+                    .nth(idx as usize)
+                    .and_then(|l| l.loop_timing()),
+            )),
+            QuantizationMode::LongestLoop => Some(loop_end(
+                self.loopers
+                    .iter()
+                    .filter(|l| !l.deleted && l.parts[self.current_part])
+                    .filter(|l| l.mode() != LooperMode::Muted)
+                    .filter_map(|l| l.loop_timing())
+                    .max_by_key(|t| t.period),
+            )),
+            QuantizationMode::MasterLoop(idx) => self
+                .loopers
+                .iter()
+                .filter(|l| !l.deleted)
+                .nth(idx as usize)
+                .and_then(|l| l.loop_timing())
+                .map(|t| TriggerCondition::MasterLoop(t.offset, t.period)),
+        }
+    }
+
     // possibly convert a loop command into a trigger
     fn trigger_from_command(
         ms: MetricStructure,
-        sync_mode: QuantizationMode,
+        condition: Option<TriggerCondition>,
         time: FrameTime,
         lc: LooperCommand,
-        target: LooperTarget,
         looper: &Looper,
     ) -> Option<Trigger> {
-        let trigger_condition = match sync_mode {
-            Free => if time.0 < 0 { Some(TriggerCondition::Beat) } else { None },
-            QuantizationMode::Beat => Some(TriggerCondition::Beat),
-            QuantizationMode::Measure => Some(TriggerCondition::Measure),
-        }?;
+        // the trigger applies to the looper that was targeted when the command was issued
+        let target = LooperTarget::Id(looper.id);
+
+        let trigger_condition = match condition {
+            Some(condition) => condition,
+            // unquantized commands issued during the count-in wait for the first beat
+            None if time.0 < 0 => TriggerCondition::Beat,
+            None => return None,
+        };
+
+        // recordings against a master loop end once they fit it, rather than at its next start
+        let trigger_condition = match (trigger_condition, looper.mode()) {
+            (TriggerCondition::MasterLoop(offset, period), LooperMode::Recording) => {
+                TriggerCondition::master_loop_recording_end(
+                    offset,
+                    period,
+                    looper.recording_start(),
+                    time,
+                )
+            }
+            (condition, _) => condition,
+        };
 
         use LooperCommand::*;
         match (looper.length() == 0, looper.mode(), lc) {
             // SetLevel and SetPan should apply immediately
             (_, _, SetLevel(_)) => None,
             (_, _, SetPan(_)) => None,
+            // as should changes to the effect chain and sends
+            (_, _, Effect(_)) => None,
+            (_, _, SetSend(..)) => None,
 
             (_, _, Record)
             | (_, LooperMode::Recording, _)
@@ -289,11 +1454,21 @@ impl Engine {
         }
     }
 
-    fn handle_loop_command(&mut self, lc: LooperCommand, target: LooperTarget, triggered: bool) {
+    fn handle_loop_command(
+        &mut self,
+        lc: LooperCommand,
+        target: LooperTarget,
+        triggered: bool,
+        sync_mode: QuantizationMode,
+    ) {
         debug!("Handling loop command: {:?} for {:?}", lc, target);
 
+        if let LooperCommand::AddToPart(part) = lc {
+            self.add_part(part);
+        }
+
         let ms = self.metric_structure;
-        let sync_mode = self.sync_mode;
+        let condition = self.trigger_condition(sync_mode);
         let time = FrameTime(self.time);
         let triggers = &mut self.triggers;
         let gui_sender = &mut self.gui_sender;
@@ -301,10 +1476,9 @@ impl Engine {
         fn handle_or_trigger(
             triggered: bool,
             ms: MetricStructure,
-            sync_mode: QuantizationMode,
+            condition: Option<TriggerCondition>,
             time: FrameTime,
             lc: LooperCommand,
-            target: LooperTarget,
             looper: &mut Looper,
             triggers: &mut VecDeque<Trigger>,
             gui_sender: &mut GuiSender,
@@ -312,15 +1486,9 @@ impl Engine {
             if triggered {
                 looper.handle_command(lc);
             } else if let Some(trigger) =
-                Engine::trigger_from_command(ms, sync_mode, time, lc, target, looper)
+                Engine::trigger_from_command(ms, condition, time, lc, looper)
             {
-                Engine::add_trigger(triggers, trigger.clone());
-
-                gui_sender.send_update(GuiCommand::AddLoopTrigger(
-                    looper.id,
-                    trigger.triggered_at(),
-                    lc,
-                ));
+                Engine::add_or_cancel_trigger(triggers, gui_sender, trigger);
             } else {
                 looper.handle_command(lc);
             }
@@ -330,9 +1498,7 @@ impl Engine {
         match target {
             LooperTarget::Id(id) => {
                 if let Some(l) = self.loopers.iter_mut().find(|l| l.id == id) {
-                    handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
-                    );
+                    handle_or_trigger(triggered, ms, condition, time, lc, l, triggers, gui_sender);
                 } else {
                     warn!(
                         "Could not find looper with id {} while handling command {:?}",
@@ -349,26 +1515,20 @@ impl Engine {
                     .next()
                 {
                     selected = Some(l.id);
-                    handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
-                    );
+                    handle_or_trigger(triggered, ms, condition, time, lc, l, triggers, gui_sender);
                 } else {
                     warn!("No looper at index {} while handling command {:?}", idx, lc);
                 }
             }
             LooperTarget::All => {
                 for l in &mut self.loopers {
-                    handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
-                    );
+                    handle_or_trigger(triggered, ms, condition, time, lc, l, triggers, gui_sender);
                 }
             }
             LooperTarget::Selected => {
                 let active = self.active;
                 if let Some(l) = self.loopers.iter_mut().find(|l| l.id == active) {
-                    handle_or_trigger(
-                        triggered, ms, sync_mode, time, lc, target, l, triggers, gui_sender,
-                    );
+                    handle_or_trigger(triggered, ms, condition, time, lc, l, triggers, gui_sender);
                 } else {
                     error!(
                         "selected looper {} not found while handling command {:?}",
@@ -376,6 +1536,15 @@ impl Engine {
                     );
                 }
             }
+            LooperTarget::Group(group) => {
+                for l in self
+                    .loopers
+                    .iter_mut()
+                    .filter(|l| !l.deleted && l.groups.contains(group))
+                {
+                    handle_or_trigger(triggered, ms, condition, time, lc, l, triggers, gui_sender);
+                }
+            }
         };
 
         if let Some(id) = selected {
@@ -383,83 +1552,135 @@ impl Engine {
         }
     }
 
-    fn load_session<'a, H: Host<'a>>(
+    // switches to a session that the session loader has read, whose loopers are ready to start
+    fn restore_session<'a, H: Host<'a>>(
         &mut self,
         host: &mut H,
-        path: &Path,
+        loaded: LoadedSession,
     ) -> Result<(), SaveLoadError> {
-        let mut file = File::open(&path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        let dir = path.parent().unwrap();
-
-        let mut session: SavedSession = serde_json::from_str(&contents).map_err(|err| {
-            warn!("Found invalid SavedSession during load: {:?}", err);
-            // TODO: improve these error messages
-            SaveLoadError::OtherError("Failed to restore session; file is invalid".to_string())
-        })?;
-
-        if session.sample_rate != get_sample_rate() {
-            let mut error = LogMessage::error();
-            if let Err(_) = write!(
-                &mut error,
-                "Session was saved with sample rate {}, but system rate \
-            is set to {}, playback will be affected",
-                session.sample_rate,
-                get_sample_rate()
-            ) {
-                error!("Different sample rate");
-            };
-            self.gui_sender.send_log(error);
-        }
-
-        debug!("Restoring session: {:?}", session);
+        let LoadedSession {
+            mut session,
+            dir,
+            mut loopers,
+        } = loaded;
+        let dir = dir.as_path();
 
         self.metric_structure = session.metric_structure.to_ms()
             .map_err(|e| SaveLoadError::OtherError(e))?;
+        if let Some(metronome) = &mut self.metronome {
+            metronome.set_metric_structure(self.metric_structure);
+        }
         self.sync_mode = session.sync_mode;
 
+        match &session.song {
+            Some(file) => self.song_loader.load(Arc::new(dir.join(file))),
+            None => self.song_loader.clear(),
+        }
+
+        self.set_scenes(std::mem::take(&mut session.scenes));
+
+        // parts are saved by name, and take their ids from the engine's table
+        let parts = Arc::make_mut(&mut self.parts);
+        let names = Arc::make_mut(&mut self.names);
+        parts.clear();
+        for name in &session.parts {
+            match names.parts.intern(name) {
+                Ok(id) if !parts.contains(&Part(id)) => parts.push(Part(id)),
+                Ok(_) => {}
+                Err(e) => warn!("Skipping part from session: {}", e),
+            }
+        }
+
         if let Some(metronome) = &mut self.metronome {
             metronome.set_volume((session.metronome_volume as f32 / 100.0).min(1.0).max(0.0));
         }
+        self.metronome_sounds
+            .load(session.metronome_sound.as_deref().unwrap_or(DEFAULT_SOUND));
+        self.set_metronome_pattern(session.metronome_pattern);
+
+        self.master.set_gain(session.master_gain.clamp(MIN_MASTER_GAIN_DB, MAX_MASTER_GAIN_DB));
+        self.master.set_output_protection(session.output_protection);
+
+        self.master.send_plugin_message(RackMessage::Clear);
+        for plugin in &session.master_plugins {
+            self.master.send_plugin_message(RackMessage::Add(SavedPlugin {
+                state: plugin.state.as_ref().map(|s| dir.join(s)),
+                ..plugin.clone()
+            }));
+        }
+
+        for (i, bus) in self.aux_buses.iter_mut().enumerate() {
+            match session.aux_buses.get(i) {
+                Some(saved) => bus.restore(saved, dir),
+                None => bus.reset(),
+            }
+        }
 
         for l in &self.loopers {
             self.session_saver.remove_looper(l.id);
             self.gui_sender.send_update(GuiCommand::RemoveLooper(l.id));
         }
-        self.loopers.clear();
-
-        session.loopers.sort_by_key(|l| l.id);
 
-        for l in session.loopers {
-            debug!("Restoring looper {}", l.id);
-            let looper = Looper::from_serialized(&l, dir, self.gui_sender.clone())?.start();
-            self.session_saver.add_looper(&looper);
+        for (looper, saved) in loopers.iter_mut().zip(&session.loopers) {
+            let names = Arc::make_mut(&mut self.names);
+            let parts = names.part_set(&saved.parts);
+            let groups = names.group_set(&saved.groups);
+            looper.set_membership(parts, groups);
+            looper.set_parameter_ramp(self.parameter_ramp_samples());
+            looper.start_backend();
+            self.session_saver.add_looper(looper);
             if let Err(e) = host.add_looper(looper.id) {
                 error!("Failed to create host port for looper {}: {}", looper.id, e);
             }
-            self.loopers.push(looper);
         }
 
+        // the replaced loopers (and what's left of the session) are dropped on the loader thread,
+        // after their plugins have been stopped here and sent back to be destroyed by their racks
+        let mut old = std::mem::replace(&mut self.loopers, loopers);
+        for l in &mut old {
+            l.release_plugins();
+        }
+        self.session_loader.dispose(session, old);
+
         self.id_counter = self.loopers.iter().map(|l| l.id).max().unwrap_or(0) + 1;
 
+        // make sure every part that's in use is in the list
+        let parts = Arc::make_mut(&mut self.parts);
+        for l in &self.loopers {
+            for part in l.parts.iter() {
+                if !parts.contains(&part) {
+                    parts.push(part);
+                }
+            }
+        }
+        if parts.is_empty() {
+            parts.extend_from_slice(&DEFAULT_PARTS);
+        }
+        if !self.parts.contains(&self.current_part) {
+            self.current_part = self.parts[0];
+        }
+        self.send_parts();
+
         self.reset();
 
         Ok(())
     }
 
+    // Handles a command from the user or from a trigger. Commands are quantized with the engine's
+    // sync mode unless `quantization` overrides it (e.g., for a midi mapping with its own mode).
     fn handle_command<'a, H: Host<'a>>(
         &mut self,
         host: &mut H,
         command: &Command,
         triggered: bool,
+        quantization: Option<QuantizationMode>,
     ) {
+        // `sync_mode` is None for commands that only wait for the triggers queued before them
         fn trigger_or_run<F>(
             engine: &mut Engine,
             command: &Command,
             triggered: bool,
-            queued: bool,
+            sync_mode: Option<QuantizationMode>,
             f: F,
         ) where
             F: FnOnce(&mut Engine),
@@ -469,12 +1690,9 @@ impl Engine {
                 return;
             }
 
-            let trigger_condition = match (queued, engine.sync_mode) {
-                (true, _) => TriggerCondition::Immediate,
-                (false, QuantizationMode::Free) => TriggerCondition::Immediate,
-                (false, QuantizationMode::Beat) => TriggerCondition::Beat,
-                (false, QuantizationMode::Measure) => TriggerCondition::Measure,
-            };
+            let trigger_condition = sync_mode
+                .and_then(|mode| engine.trigger_condition(mode))
+                .unwrap_or(TriggerCondition::Immediate);
 
             let trigger = Trigger::new(
                 trigger_condition,
@@ -490,17 +1708,15 @@ impl Engine {
                 return;
             }
 
-            Engine::add_trigger(&mut engine.triggers, trigger.clone());
-            engine.gui_sender.send_update(GuiCommand::AddGlobalTrigger(
-                trigger.triggered_at(),
-                trigger.command,
-            ));
+            Engine::add_or_cancel_trigger(&mut engine.triggers, &mut engine.gui_sender, trigger);
         }
 
+        let sync_mode = quantization.unwrap_or(self.sync_mode);
+
         use Command::*;
         match command {
             Looper(lc, target) => {
-                self.handle_loop_command(*lc, *target, triggered);
+                self.handle_loop_command(*lc, *target, triggered, sync_mode);
             }
             Start => {
                 self.state = EngineState::Active;
@@ -533,12 +1749,13 @@ impl Engine {
             SetTime(time) => self.set_time(*time),
             AddLooper => {
                 // TODO: make this non-allocating
-                let looper = crate::Looper::new(
+                let mut looper = crate::Looper::new(
                     self.id_counter,
                     PartSet::with(self.current_part),
                     self.gui_sender.clone(),
                 )
                 .start();
+                looper.set_parameter_ramp(self.parameter_ramp_samples());
                 self.session_saver.add_looper(&looper);
                 self.loopers.push(looper);
                 self.active = self.id_counter;
@@ -566,7 +1783,7 @@ impl Engine {
                 }
             }
             SelectNextLooper | SelectPreviousLooper => {
-                trigger_or_run(self, command, triggered, true, |engine| {
+                trigger_or_run(self, command, triggered, None, |engine| {
                     if let Some((i, _)) = engine
                         .loopers
                         .iter()
@@ -612,55 +1829,54 @@ impl Engine {
                 });
             }
             PreviousPart => {
-                trigger_or_run(self, command, triggered, false, |engine| {
-                    let original = engine.current_part;
-                    loop {
-                        engine.current_part = match engine.current_part {
-                            Part::A => Part::D,
-                            Part::B => Part::A,
-                            Part::C => Part::B,
-                            Part::D => Part::C,
-                        };
-                        if engine
-                            .loopers
-                            .iter()
-                            .any(|l| !l.deleted && l.parts[engine.current_part])
-                            || engine.current_part == original
-                        {
-                            break;
-                        }
-                    }
-                    engine.select_first_in_part();
+                trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                    engine.step_part(-1);
                 });
             }
             NextPart => {
-                trigger_or_run(self, command, triggered, false, |engine| {
-                    let original = engine.current_part;
-                    loop {
-                        engine.current_part = match engine.current_part {
-                            Part::A => Part::B,
-                            Part::B => Part::C,
-                            Part::C => Part::D,
-                            Part::D => Part::A,
-                        };
-                        if engine
-                            .loopers
-                            .iter()
-                            .any(|l| !l.deleted && l.parts[engine.current_part])
-                            || engine.current_part == original
-                        {
-                            break;
-                        }
-                    }
-                    engine.select_first_in_part();
+                trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                    engine.step_part(1);
                 });
             }
             GoToPart(part) => {
-                trigger_or_run(self, command, triggered, false, |engine| {
+                trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                    engine.add_part(*part);
                     engine.current_part = *part;
                     engine.select_first_in_part();
                 });
             }
+            AddPart(name) => match Arc::make_mut(&mut self.names).parts.intern(name) {
+                Ok(id) => self.add_part(Part(id)),
+                Err(e) => {
+                    let mut log = LogMessage::error();
+                    if let Err(e) = write!(log, "{}", e) {
+                        error!("Failed to report part error: {}", e);
+                    } else {
+                        self.gui_sender.send_log(log);
+                    }
+                }
+            },
+            RemovePart(part) => self.remove_part(*part),
+            SaveScene(name) => self.save_scene(name),
+            RecallScene(name) => match self.scenes.iter().position(|s| s.name == *name) {
+                Some(idx) => trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                    engine.recall_scene(idx);
+                }),
+                None => self.report_missing_scene(name),
+            },
+            RecallSceneByIndex(idx) => {
+                let idx = *idx as usize;
+                if idx < self.scenes.len() {
+                    trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                        engine.recall_scene(idx);
+                    });
+                } else {
+                    self.report_missing_scene(idx);
+                }
+            }
+            DeleteScene(name) => self.delete_scene(name),
+            CancelTriggers(Some(target)) => self.cancel_loop_triggers(*target),
+            CancelTriggers(None) => self.cancel_all_triggers(),
             SetQuantizationMode(sync_mode) => {
                 self.sync_mode = *sync_mode;
             }
@@ -672,18 +1888,27 @@ impl Engine {
                         .as_ref()
                         .map(|m| (m.get_volume() * 100.0) as u8)
                         .unwrap_or(100),
+                    metronome_sound: self.metronome.as_ref().map(|m| m.sound_name().clone()),
+                    metronome_pattern: self.metronome_pattern,
                     sync_mode: self.sync_mode,
+                    master_gain: self.master.gain(),
+                    output_protection: self.master.output_protection(),
+                    master_plugins: self.master.plugin_rack(),
+                    aux_buses: self.aux_bus_channels.clone(),
+                    song: self.song.clone(),
+                    parts: self.parts.clone(),
+                    names: self.names.clone(),
+                    scenes: self.scenes.clone(),
                     path: Arc::clone(path),
                     sample_rate: get_sample_rate(),
+                    audio_format: self.config.settings.session_audio_format,
                 }) {
                     error!("Failed to save session {:?}", e);
                 }
             }
-            LoadSession(path) => {
-                if let Err(e) = self.load_session(host, path) {
-                    error!("Failed to load session {:?}", e);
-                }
-            }
+            LoadSession(path) => self.session_loader.load(path.clone()),
+            LoadSong(path) => self.song_loader.load(path.clone()),
+            ClearSong => self.song_loader.clear(),
             SetMetronomeLevel(l) => {
                 if *l <= 100 {
                     if let Some(metronome) = &mut self.metronome {
@@ -693,23 +1918,498 @@ impl Engine {
                     error!("Invalid metronome volume; must be between 0 and 100");
                 }
             }
+            SetMetronomeSound(name) => {
+                self.metronome_sounds.load(name);
+            }
+            SetMetronomeSubdivision(subdivision) => {
+                self.set_metronome_pattern(MetronomePattern {
+                    subdivision: *subdivision,
+                    ..self.metronome_pattern
+                });
+            }
+            SetAccentPattern(accents) => {
+                self.set_metronome_pattern(MetronomePattern {
+                    accents: *accents,
+                    ..self.metronome_pattern
+                });
+            }
+            SetCountIn(measures) => {
+                self.config.settings.count_in_measures = *measures;
+                // if the transport hasn't started yet, move it to the start of the new count-in
+                if self.state == EngineState::Stopped {
+                    self.set_time(self.count_in_start());
+                }
+            }
             SetTempoBPM(bpm) => {
-                self.metric_structure.tempo = Tempo::from_bpm(*bpm);
-                if let Some(met) = &mut self.metronome {
-                    met.set_metric_structure(self.metric_structure);
+                trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                    engine.set_tempo(*bpm);
+                });
+            }
+            TapTempo => {
+                if let Some(bpm) = self.tap_tempo.tap(Instant::now()) {
+                    self.set_tapped_tempo(bpm);
                 }
-                self.reset();
             }
             SetTimeSignature(upper, lower) => {
                 if let Some(ts) = TimeSignature::new(*upper, *lower) {
-                    self.metric_structure.time_signature = ts;
-                    if let Some(met) = &mut self.metronome {
-                        met.set_metric_structure(self.metric_structure);
-                    }
-                    self.reset();
+                    trigger_or_run(self, command, triggered, Some(sync_mode), |engine| {
+                        engine.set_metric_structure(MetricStructure {
+                            time_signature: ts,
+                            ..engine.metric_structure
+                        });
+                    });
+                } else {
+                    error!("Invalid time signature {}/{}", upper, lower);
+                }
+            }
+            SetSwing(swing, grid) => {
+                let groove = Groove {
+                    swing: *swing,
+                    grid: *grid,
+                    ..self.metric_structure.groove
+                };
+                self.set_groove(groove);
+            }
+            SetGrooveTemplate(template) => {
+                let groove = Groove {
+                    template: *template,
+                    ..self.metric_structure.groove
+                };
+                self.set_groove(groove);
+            }
+            SetMasterGain(gain) => {
+                if (MIN_MASTER_GAIN_DB..=MAX_MASTER_GAIN_DB).contains(gain) {
+                    self.master.set_gain(*gain);
+                } else {
+                    error!(
+                        "Invalid master gain; must be between {} and {}",
+                        MIN_MASTER_GAIN_DB, MAX_MASTER_GAIN_DB
+                    );
+                }
+            }
+            SetOutputProtection(protection) => {
+                self.master.set_output_protection(*protection);
+            }
+            ResetLoudness => {
+                self.master.reset_loudness();
+            }
+            AddPlugin(target, plugin) => {
+                self.send_to_plugin_racks(*target, || RackMessage::Add((**plugin).clone()));
+            }
+            RemovePlugin(target, idx) => {
+                self.send_to_plugin_racks(*target, || RackMessage::Remove(*idx as usize));
+            }
+            SetPluginParam(target, idx, id, value) => {
+                self.send_to_plugin_racks(*target, || {
+                    RackMessage::SetParam(*idx as usize, *id, *value)
+                });
+            }
+            SetAuxReturnLevel(bus, level) => {
+                if let Some(bus) = self.aux_buses.get_mut(*bus as usize) {
+                    bus.set_return_level(*level);
+                }
+            }
+            AuxEffect(bus, command) => {
+                if let Some(bus) = self.aux_buses.get_mut(*bus as usize) {
+                    bus.send_message(AuxMessage::Effect(*command));
+                }
+            }
+        }
+    }
+
+    fn send_to_plugin_racks<F: Fn() -> RackMessage>(&mut self, target: InsertTarget, msg: F) {
+        let looper = match target {
+            InsertTarget::Master => {
+                self.master.send_plugin_message(msg());
+                return;
+            }
+            InsertTarget::Aux(bus) => {
+                match self.aux_buses.get_mut(bus as usize) {
+                    Some(bus) => bus.send_message(AuxMessage::Plugin(msg())),
+                    None => warn!("No aux bus {} for plugin command", bus),
+                }
+                return;
+            }
+            InsertTarget::Looper(LooperTarget::All) => {
+                for l in self.loopers.iter_mut().filter(|l| !l.deleted) {
+                    l.send_plugin_message(msg());
+                }
+                return;
+            }
+            InsertTarget::Looper(LooperTarget::Group(group)) => {
+                for l in self
+                    .loopers
+                    .iter_mut()
+                    .filter(|l| !l.deleted && l.groups.contains(group))
+                {
+                    l.send_plugin_message(msg());
                 }
+                return;
+            }
+            InsertTarget::Looper(LooperTarget::Id(id)) => {
+                self.loopers.iter_mut().find(|l| l.id == id)
+            }
+            InsertTarget::Looper(LooperTarget::Index(idx)) => self
+                .loopers
+                .iter_mut()
+                .filter(|l| !l.deleted)
+                .nth(idx as usize),
+            InsertTarget::Looper(LooperTarget::Selected) => {
+                let active = self.active;
+                self.loopers.iter_mut().find(|l| l.id == active)
+            }
+        };
+
+        match looper {
+            Some(l) => l.send_plugin_message(msg()),
+            None => warn!("Could not find looper {:?} for plugin command", target),
+        }
+    }
+
+    // switches the metric structure without moving the time
+    fn use_metric_structure(&mut self, metric_structure: MetricStructure) {
+        self.metric_structure = metric_structure;
+        if let Some(met) = &mut self.metronome {
+            met.set_metric_structure(metric_structure);
+        }
+
+        for t in &mut self.triggers {
+            t.set_metric_structure(metric_structure);
+        }
+    }
+
+    // the groove never moves measures or the current time, so it applies immediately
+    fn set_groove(&mut self, groove: Groove) {
+        self.use_metric_structure(MetricStructure {
+            groove,
+            ..self.metric_structure
+        });
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.set_metric_structure(MetricStructure {
+            tempo: Tempo::from_bpm(bpm),
+            ..self.metric_structure
+        });
+    }
+
+    // Changes the tempo or time signature while keeping the current measure and beat. The loopers
+    // keep playing from the same point in their loops, stretched to the new tempo so that they stay
+    // aligned with the grid, and pending triggers move to the same measure and beat under the new
+    // metric structure.
+    fn set_metric_structure(&mut self, metric_structure: MetricStructure) {
+        let old = self.metric_structure;
+        if old == metric_structure {
+            return;
+        }
+        self.use_metric_structure(metric_structure);
+
+        let time = old.convert_time(FrameTime(self.time), &metric_structure);
+        for l in &mut self.loopers {
+            l.change_metric_structure(FrameTime(self.time), old, metric_structure);
+        }
+
+        let delta = FrameTime(time.0 - self.time);
+        if delta.0 != 0 {
+            self.time = time.0;
+            for t in &mut self.triggers {
+                t.shift_time(delta);
+            }
+        }
+    }
+
+    // applies a tempo from TapTempo at the next measure, replacing any tapped tempo that's still
+    // waiting to be applied (but not tempo changes queued by the user or the song)
+    fn set_tapped_tempo(&mut self, bpm: f32) {
+        if self.state == EngineState::Stopped {
+            self.set_tempo(bpm);
+            return;
+        }
+
+        Self::remove_triggers(
+            &mut self.triggers,
+            &mut self.gui_sender,
+            TriggerRemoval::Cancelled,
+            |t| t.from_tap_tempo,
+        );
+
+        let mut trigger = Trigger::new(
+            TriggerCondition::Measure,
+            Command::SetTempoBPM(bpm),
+            self.metric_structure,
+            FrameTime(self.time),
+        );
+        trigger.from_tap_tempo = true;
+
+        Engine::add_trigger(&mut self.triggers, &mut self.gui_sender, trigger);
+    }
+
+    // switches to a new song; `gui_copy` is sent to the GUI, so that the song isn't cloned here
+    fn set_song(&mut self, song: Arc<Song>, gui_copy: Song) {
+        let old = std::mem::replace(&mut self.song, song);
+        self.song_loader.dispose(old);
+        self.gui_sender.send_update(GuiCommand::SetSong(gui_copy));
+
+        // drop the changes queued for the old song, leaving any that the user queued
+        if self.song_trigger.take().is_some() {
+            Self::remove_triggers(
+                &mut self.triggers,
+                &mut self.gui_sender,
+                TriggerRemoval::Cancelled,
+                |t| t.from_song,
+            );
+        }
+
+        // pick up the song from the section at the current time
+        if let Some(section) = self.song.section_at(self.current_measure()) {
+            self.set_metric_structure(self.song.metric_structure(section, self.metric_structure));
+            self.go_to_song_part(section);
+        }
+    }
+
+    // captures the mix state of the loopers as a scene, replacing any scene with the same name
+    fn save_scene(&mut self, name: &Arc<String>) {
+        let scenes = Arc::make_mut(&mut self.scenes);
+        let existing = scenes.iter().position(|s| s.name == *name);
+        let mut loopers = match existing {
+            Some(idx) => std::mem::take(&mut scenes[idx].loopers),
+            None => self
+                .scene_pool
+                .pop()
+                .unwrap_or_else(|| Vec::with_capacity(SCENE_LOOPER_CAPACITY)),
+        };
+
+        loopers.clear();
+        loopers.extend(self.loopers.iter().filter(|l| !l.deleted).map(|l| SceneLooper {
+            id: l.id,
+            mode: match l.local_mode() {
+                LooperMode::Muted => LooperMode::Muted,
+                LooperMode::Soloed => LooperMode::Soloed,
+                _ => LooperMode::Playing,
+            },
+            level: l.level,
+            pan: l.pan,
+            speed: l.speed,
+        }));
+
+        match existing {
+            Some(idx) => scenes[idx].loopers = loopers,
+            None => {
+                scenes.push(Scene {
+                    name: name.clone(),
+                    loopers,
+                });
+                self.gui_sender.send_update(GuiCommand::SceneSaved(name.clone()));
+            }
+        }
+    }
+
+    fn delete_scene(&mut self, name: &Arc<String>) {
+        if let Some(idx) = self.scenes.iter().position(|s| s.name == *name) {
+            let mut scene = Arc::make_mut(&mut self.scenes).remove(idx);
+            scene.loopers.clear();
+            self.scene_pool.push(scene.loopers);
+            self.gui_sender.send_update(GuiCommand::SceneDeleted(name.clone()));
+        }
+    }
+
+    // replaces the scenes with those from a session, topping the pool back up for new ones
+    fn set_scenes(&mut self, mut scenes: Vec<Scene>) {
+        scenes.reserve(SCENE_CAPACITY);
+        self.scenes = Arc::new(scenes);
+        let spare = SCENE_CAPACITY.saturating_sub(self.scene_pool.len());
+        self.scene_pool
+            .extend((0..spare).map(|_| Vec::with_capacity(SCENE_LOOPER_CAPACITY)));
+
+        self.gui_sender.send_update(GuiCommand::SetScenes(
+            self.scenes.iter().map(|s| s.name.clone()).collect(),
+        ));
+    }
+
+    // returns the loopers in the scene to their saved state. Loopers that have been added since the
+    // scene was saved are left alone, as are loopers that are recording.
+    fn recall_scene(&mut self, idx: usize) {
+        for sl in &self.scenes[idx].loopers {
+            let looper = match self.loopers.iter_mut().find(|l| l.id == sl.id && !l.deleted) {
+                Some(l) => l,
+                None => continue,
+            };
+
+            if looper.level != sl.level {
+                looper.handle_command(LooperCommand::SetLevel(sl.level));
+            }
+            if looper.pan != sl.pan {
+                looper.handle_command(LooperCommand::SetPan(sl.pan));
+            }
+            if looper.speed != sl.speed {
+                looper.handle_command(LooperCommand::SetSpeed(sl.speed));
+            }
+
+            let mode = looper.local_mode();
+            if mode != sl.mode && mode != LooperMode::Recording && mode != LooperMode::Overdubbing {
+                looper.handle_command(match sl.mode {
+                    LooperMode::Muted => LooperCommand::Mute,
+                    LooperMode::Soloed => LooperCommand::Solo,
+                    _ => LooperCommand::Play,
+                });
+            }
+        }
+    }
+
+    fn report_missing_scene(&mut self, name: impl std::fmt::Display) {
+        let mut log = LogMessage::error();
+        if let Err(e) = write!(log, "No scene {}", name) {
+            error!("Failed to report missing scene: {}", e);
+        } else {
+            self.gui_sender.send_log(log);
+        }
+    }
+
+    fn current_measure(&self) -> i64 {
+        let beat = self.metric_structure.tempo.beat(FrameTime(self.time));
+        beat.div_euclid(self.metric_structure.time_signature.upper as i64)
+    }
+
+    fn go_to_song_part(&mut self, section: usize) {
+        if let Some(part) = self.song_part(section) {
+            self.add_part(part);
+            self.current_part = part;
+            self.select_first_in_part();
+        }
+    }
+
+    // the part that the song plays during a section, which is looked up by name
+    fn song_part(&mut self, section: usize) -> Option<Part> {
+        let name = self.song.part(section)?;
+        if let Some(id) = self.names.parts.get(name) {
+            return Some(Part(id));
+        }
+
+        match Arc::make_mut(&mut self.names).parts.intern(name) {
+            Ok(id) => Some(Part(id)),
+            Err(e) => {
+                warn!("Ignoring part in song: {}", e);
+                None
+            }
+        }
+    }
+
+    // queues the changes for the next section of the song if it starts within the next `frames`
+    fn schedule_song_section(&mut self, frames: u64) {
+        let next = match self.song.next_section(self.current_measure()) {
+            Some(next) => next,
+            None => return,
+        };
+
+        if self.song_trigger == Some(next) {
+            return;
+        }
+
+        let ms = self.metric_structure;
+        let start = FrameTime(
+            self.song.start_measure(next)
+                * ms.time_signature.upper as i64
+                * ms.tempo.samples_per_beat() as i64,
+        );
+        if start.0 >= self.time + frames as i64 {
+            return;
+        }
+
+        self.song_trigger = Some(next);
+        let part = match self.song.sections[next].part {
+            Some(_) => self.song_part(next),
+            None => None,
+        };
+        let section = &self.song.sections[next];
+        let commands = [
+            section
+                .time_signature
+                .map(|ts| Command::SetTimeSignature(ts.upper, ts.lower)),
+            section.tempo.map(Command::SetTempoBPM),
+            part.map(Command::GoToPart),
+        ];
+
+        for command in commands.iter().flatten() {
+            let mut trigger = Trigger::new(TriggerCondition::Measure, command.clone(), ms, start);
+            trigger.from_song = true;
+            Engine::insert_trigger(&mut self.triggers, &mut self.gui_sender, trigger);
+        }
+    }
+
+    fn set_metronome_pattern(&mut self, pattern: MetronomePattern) {
+        self.metronome_pattern = pattern;
+        if let Some(met) = &mut self.metronome {
+            met.set_pattern(pattern);
+        }
+    }
+
+    // moves `steps` through the list of parts, skipping those with no loopers
+    fn step_part(&mut self, steps: isize) {
+        let count = self.parts.len() as isize;
+        let start = self
+            .parts
+            .iter()
+            .position(|p| *p == self.current_part)
+            .unwrap_or(0) as isize;
+
+        for i in 1..=count {
+            let part = self.parts[(start + i * steps).rem_euclid(count) as usize];
+            if self.loopers.iter().any(|l| !l.deleted && l.parts[part]) || i == count {
+                self.current_part = part;
+                break;
+            }
+        }
+        self.select_first_in_part();
+    }
+
+    fn add_part(&mut self, part: Part) {
+        if let (false, Some(name)) = (self.parts.contains(&part), self.names.parts.name(part.0)) {
+            Arc::make_mut(&mut self.parts).push(part);
+            self.gui_sender
+                .send_update(GuiCommand::PartAdded(part, name.clone()));
+        }
+    }
+
+    fn remove_part(&mut self, part: Part) {
+        // there always needs to be at least one part
+        if self.parts.len() < 2 || !self.parts.contains(&part) {
+            return;
+        }
+
+        Arc::make_mut(&mut self.parts).retain(|p| *p != part);
+        let first = self.parts[0];
+
+        for l in self.loopers.iter_mut().filter(|l| l.parts[part]) {
+            if l.parts.len() == 1 {
+                l.handle_command(LooperCommand::AddToPart(first));
             }
+            l.handle_command(LooperCommand::RemoveFromPart(part));
+        }
+
+        // we're not going back to the removed part
+        Self::remove_triggers(
+            &mut self.triggers,
+            &mut self.gui_sender,
+            TriggerRemoval::Cancelled,
+            |t| t.command == Command::GoToPart(part),
+        );
+
+        if self.current_part == part {
+            self.current_part = first;
+            self.select_first_in_part();
         }
+
+        self.gui_sender.send_update(GuiCommand::PartRemoved(part));
+    }
+
+    fn send_parts(&mut self) {
+        let names = &self.names;
+        self.gui_sender.send_update(GuiCommand::SetParts(
+            self.parts
+                .iter()
+                .filter_map(|p| names.parts.name(p.0).map(|n| (*p, n.clone())))
+                .collect(),
+        ));
     }
 
     // selects the first looper in the part, unless the current selection is already in the part
@@ -729,6 +2429,12 @@ impl Engine {
         }
     }
 
+    // the time the transport starts from after a reset, leaving room for the count-in before the
+    // first measure
+    fn count_in_start(&self) -> FrameTime {
+        FrameTime(-(self.measure_len().0 * self.config.settings.count_in_measures as i64))
+    }
+
     // returns length
     fn measure_len(&self) -> FrameTime {
         let bps = self.metric_structure.tempo.bpm() as f32 / 60.0;
@@ -738,8 +2444,29 @@ impl Engine {
         FrameTime::from_ms(mspm as f64)
     }
 
+    fn report_late_trigger(&mut self, trigger: &Trigger, time: FrameTime, policy: LatePolicy) {
+        let late_ms = FrameTime(time.0 - trigger.triggered_at().0).to_ms();
+        let action = match policy {
+            LatePolicy::Drop => "dropped it",
+            LatePolicy::Fire => "running it now",
+            LatePolicy::Defer => "deferring it to the next boundary",
+        };
+
+        let mut log = LogMessage::error();
+        if let Err(e) = write!(
+            log,
+            "Missed {:?} by {:.0} ms; {}",
+            trigger.command, late_ms, action
+        ) {
+            error!("Failed to report late trigger: {}", e);
+        } else {
+            self.gui_sender.send_log(log);
+        }
+    }
+
     fn set_time(&mut self, time: FrameTime) {
         self.time = time.0;
+        self.song_trigger = None;
         for l in &mut self.loopers {
             l.set_time(time);
         }
@@ -767,6 +2494,12 @@ impl Engine {
 
                     looper.process_output(time, &mut o, self.current_part, solo);
 
+                    // run the output through the looper's insert effects, then feed the aux
+                    // buses from the result
+                    let [l, r] = o;
+                    looper.process_effects(l, r);
+                    looper.process_sends(idx_range.start, l, r, &mut self.aux_buses);
+
                     // copy the output to the looper input in the host, if we can find one
                     if let Some([l, r]) = host.output_for_looper(looper.id) {
                         l.iter_mut()
@@ -819,6 +2552,15 @@ impl Engine {
     }
 
     fn process_loopers<'a, H: Host<'a>>(&mut self, host: &mut H, in_bufs: &[&[f32]], frames: u64, solo: bool) {
+        let rounding = self
+            .config
+            .settings
+            .loop_length_rounding
+            .unit(&self.metric_structure);
+        for looper in self.loopers.iter_mut() {
+            looper.set_length_rounding(rounding);
+        }
+
         let mut time = self.time;
         let mut idx = 0usize;
 
@@ -832,7 +2574,7 @@ impl Engine {
 
         let mut time = time as u64;
 
-        let next_time = (self.time + frames as i64) as u64;
+        let mut next_time = (self.time + frames as i64) as u64;
         while time < next_time {
             if let Some(_) = self
                 .triggers
@@ -842,20 +2584,39 @@ impl Engine {
                 .filter(|t| t.triggered_at().0 < next_time as i64)
             {
                 // The unwrap is safe due to the preceding peek
-                let trigger = self.triggers.pop_front().unwrap();
+                let mut trigger = self.triggers.pop_front().unwrap();
 
-                let trigger_at = trigger.triggered_at();
+                let mut trigger_at = trigger.triggered_at();
                 // we'll process up to this time, then trigger the trigger
 
                 if trigger_at != FrameTime(0) && trigger_at.0 < time as i64 {
-                    // we failed to trigger, but don't know if it's safe to trigger late. so we'll
-                    // just ignore it. there might be better solutions for specific triggers, but
-                    // hopefully this is rare.
+                    // we failed to trigger on time (usually because of an xrun). whether it's
+                    // safe to trigger late depends on the command, so that's up to the user.
+                    let policy = self.config.settings.late_triggers.policy(&trigger.command);
                     error!(
-                        "missed trigger for time {} (cur time = {})",
-                        trigger_at.0, time
+                        "missed trigger for time {} (cur time = {}); policy is {:?}",
+                        trigger_at.0, time, policy
                     );
-                    continue;
+                    self.report_late_trigger(&trigger, FrameTime(time as i64), policy);
+
+                    match policy {
+                        LatePolicy::Drop => {
+                            Self::send_trigger_removed(
+                                &mut self.gui_sender,
+                                &trigger,
+                                TriggerRemoval::Dropped,
+                            );
+                            continue;
+                        }
+                        LatePolicy::Fire => {
+                            trigger_at = FrameTime(time as i64);
+                        }
+                        LatePolicy::Defer => {
+                            trigger.defer(FrameTime(time as i64));
+                            Self::insert_trigger(&mut self.triggers, &mut self.gui_sender, trigger);
+                            continue;
+                        }
+                    }
                 }
 
                 // we know that trigger_at is non-negative from the previous condition
@@ -882,7 +2643,15 @@ impl Engine {
                     idx = idx_range.end;
                 }
 
-                self.handle_command(host, &trigger.command, true);
+                // the command sees the time it was triggered at; it may also move the time (e.g.,
+                // by changing the tempo), in which case the rest of the block continues from the
+                // new time
+                self.time = time as i64;
+                Self::send_trigger_removed(&mut self.gui_sender, &trigger, TriggerRemoval::Fired);
+                self.handle_command(host, &trigger.command, true, None);
+                time = self.time as u64;
+                self.time -= idx as i64;
+                next_time = (self.time + frames as i64) as u64;
             } else {
                 // there are no more triggers for this period, so just process the rest and finish
                 self.perform_looper_io(
@@ -938,6 +2707,42 @@ impl Engine {
         d as u8
     }
 
+    // Plays the metronome for this block. When the count-in has its own output, the clicks before
+    // time 0 go there and the rest to the metronome output.
+    fn play_metronome<'a, H: Host<'a>>(
+        &mut self,
+        host: &mut H,
+        met_bufs: &mut [&mut [f32]; 2],
+        frames: usize,
+    ) {
+        let metronome = match &mut self.metronome {
+            Some(m) => m,
+            None => return,
+        };
+
+        let mut split = 0;
+        if self.config.settings.separate_count_in_output && self.time <= 0 {
+            if let Some([l, r]) = host.output_for_count_in() {
+                split = ((-self.time).max(0) as usize).min(frames);
+                metronome.advance(
+                    FrameTime(self.time),
+                    &mut [&mut l[..split], &mut r[..split]],
+                );
+
+                // don't let the tail of the last count-in click leak into the metronome output
+                if self.time + frames as i64 > 0 {
+                    metronome.reset();
+                }
+            }
+        }
+
+        let [l, r] = met_bufs;
+        metronome.advance(
+            FrameTime(self.time + split as i64),
+            &mut [&mut l[split..], &mut r[split..]],
+        );
+    }
+
     // Step 1: Convert midi events to commands
     // Step 2: Handle commands
     // Step 3: Play current samples
@@ -956,11 +2761,57 @@ impl Engine {
         // Convert midi events to commands
         self.commands_from_midi(host, midi_events);
 
+        // Switch to any session that has finished loading
+        while let Some(result) = self.session_loader.poll() {
+            if let Err(e) = result.and_then(|loaded| self.restore_session(host, loaded)) {
+                let mut log = LogMessage::error();
+                if let Err(e) = write!(log, "Failed to load session: {:?}", e) {
+                    error!("Failed to report session error: {}", e);
+                } else {
+                    self.gui_sender.send_log(log);
+                }
+            }
+        }
+
+        // Switch to any song that has finished loading
+        while let Some(result) = self.song_loader.poll() {
+            match result {
+                Ok(loaded) => self.set_song(loaded.song, loaded.gui_copy),
+                Err(e) => {
+                    let mut log = LogMessage::error();
+                    if let Err(e) = write!(log, "Failed to load song: {}", e) {
+                        error!("Failed to report song error: {}", e);
+                    } else {
+                        self.gui_sender.send_log(log);
+                    }
+                }
+            }
+        }
+
+        // Switch to any metronome sounds that have finished loading
+        while let Some(result) = self.metronome_sounds.poll() {
+            match (result, &mut self.metronome) {
+                (Ok(sounds), Some(metronome)) => {
+                    let old = metronome.set_sounds(sounds);
+                    self.metronome_sounds.dispose(old);
+                }
+                (Ok(sounds), None) => self.metronome_sounds.dispose(sounds),
+                (Err(e), _) => {
+                    let mut log = LogMessage::error();
+                    if let Err(e) = write!(log, "{}", e) {
+                        error!("Failed to report metronome sound error: {}", e);
+                    } else {
+                        self.gui_sender.send_log(log);
+                    }
+                }
+            }
+        }
+
         // Handle commands from the gui
         loop {
             match self.command_input.try_recv() {
-                Ok(c) => {
-                    self.handle_command(host, &c, false);
+                Ok((c, quantization)) => {
+                    self.handle_command(host, &c, false, quantization);
                 }
                 Err(_) => break,
             }
@@ -987,6 +2838,10 @@ impl Engine {
             self.output_right.push(0.0);
         }
 
+        for bus in &mut self.aux_buses {
+            bus.clear(frames as usize);
+        }
+
         // copy the input to the output for monitoring
         // TODO: should probably make this behavior configurable
         for (i, (l, r)) in in_bufs[0].iter().zip(in_bufs[1]).enumerate() {
@@ -1004,17 +2859,38 @@ impl Engine {
             .any(|l| l.parts[self.current_part] && !l.deleted && l.mode() == LooperMode::Soloed);
 
         if self.state == EngineState::Active {
+            self.schedule_song_section(frames);
+
             // process the loopers
             self.process_loopers(host, &in_bufs, frames, solo);
 
             // Play the metronome
-            if let Some(metronome) = &mut self.metronome {
-                metronome.advance(&mut met_bufs);
-            }
+            self.play_metronome(host, &mut met_bufs, frames as usize);
 
             self.time += frames as i64;
         }
 
+        // the buses are processed even when we're stopped so that effect tails can ring out
+        for bus in &mut self.aux_buses {
+            bus.process(frames as usize);
+
+            if let Some([l, r]) = host.output_for_aux_bus(bus.index) {
+                let [bl, br] = bus.output(frames as usize);
+                l.iter_mut().zip(bl).for_each(|(a, b)| *a = *b as f32);
+                r.iter_mut().zip(br).for_each(|(a, b)| *a = *b as f32);
+            }
+
+            bus.add_return(
+                &mut self.output_left[..frames as usize],
+                &mut self.output_right[..frames as usize],
+            );
+        }
+
+        self.master.process(
+            &mut self.output_left[..frames as usize],
+            &mut self.output_right[..frames as usize],
+        );
+
         for i in 0..frames as usize {
             out_l[i] = self.output_left[i] as f32;
         }
@@ -1046,6 +2922,19 @@ impl Engine {
                     .as_ref()
                     .map(|m| m.get_volume())
                     .unwrap_or(0.0),
+                metronome_pattern: self.metronome_pattern,
+                song_section: if self.song.is_empty() {
+                    None
+                } else {
+                    self.song.section_at(self.current_measure())
+                },
+                master_gain: self.master.gain(),
+                output_protection: self.master.output_protection(),
+                master_levels: Self::compute_peaks(&[
+                    &out_l[..frames as usize],
+                    &out_r[..frames as usize],
+                ]),
+                master_meters: self.master.meters(),
             }));
     }
 }
@@ -6,15 +6,23 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
+use crate::audio_file::{resample_loop, write_audio, AudioFile};
+use crate::aux_bus::AuxBus;
+use crate::effects::{Effect, EffectChain, EffectRack};
 use crate::error::SaveLoadError;
+use crate::plugin::{PluginChain, PluginRack, RackMessage};
+use crate::smoothing::SmoothedValue;
 use loopers_common::api::{
-    FrameTime, LooperCommand, LooperMode, LooperSpeed, Part, PartSet, SavedLooper,
+    get_sample_rate, FrameTime, GroupSet, LooperCommand, LooperMode, LooperSpeed, Names, Part,
+    PartSet, SavedLooper, SavedPlugin, AUX_BUS_COUNT,
 };
+use loopers_common::config::{SessionAudioFormat, Settings};
+use loopers_common::effects::EffectCommand;
 use loopers_common::gui_channel::GuiCommand::{AddNewSample, AddOverdubSample};
 use loopers_common::gui_channel::{
     GuiCommand, GuiSender, LooperState, Waveform, WAVEFORM_DOWNSAMPLE,
 };
-use loopers_common::music::PanLaw;
+use loopers_common::music::{MetricStructure, PanLaw};
 use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
 use std::mem::swap;
@@ -26,6 +34,7 @@ use std::sync::atomic::Ordering;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use loopers_common::effects::{EffectConfig, EffectParam};
     use std::f32::consts::PI;
     use tempfile::tempdir;
 
@@ -190,6 +199,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_level_ramp() {
+        install_test_logger();
+
+        let mut l = looper_for_test();
+        l.set_parameter_ramp(4);
+
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+
+        let input_left = vec![1f32; TRANSFER_BUF_SIZE];
+        let input_right = vec![-1f32; TRANSFER_BUF_SIZE];
+        l.process_input(0, &[&input_left, &input_right], Part::A);
+        process_until_done(&mut l);
+
+        l.transition_to(LooperMode::Playing);
+        process_until_done(&mut l);
+        l.handle_command(LooperCommand::SetLevel(0.0));
+        process_until_done(&mut l);
+
+        let mut o_l = vec![0f64; TRANSFER_BUF_SIZE];
+        let mut o_r = vec![0f64; TRANSFER_BUF_SIZE];
+        l.process_output(
+            FrameTime(input_left.len() as i64),
+            &mut [&mut o_l, &mut o_r],
+            Part::A,
+            false,
+        );
+        process_until_done(&mut l);
+
+        assert_eq!(vec![0.75, 0.5, 0.25, 0.0, 0.0], o_l[0..5].to_vec());
+        assert_eq!(vec![-0.75, -0.5, -0.25, 0.0, 0.0], o_r[0..5].to_vec());
+    }
+
     #[test]
     fn test_overdub() {
         install_test_logger();
@@ -448,6 +491,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_round_length() {
+        install_test_logger();
+
+        let record = |speed: LooperSpeed, len: usize| {
+            let input = vec![0.5f32; len];
+            let mut l = looper_for_test();
+            l.set_length_rounding(Some(1000));
+            l.handle_command(LooperCommand::SetSpeed(speed));
+            l.transition_to(LooperMode::Recording);
+            process_until_done(&mut l);
+            l.process_input(0, &[&input, &input], Part::A);
+            process_until_done(&mut l);
+            l.handle_command(LooperCommand::Play);
+            process_until_done(&mut l);
+            verify_mode(&l, LooperMode::Playing);
+            l.length()
+        };
+
+        // loops are rounded to the nearest multiple of the unit
+        assert_eq!(1000, record(LooperSpeed::One, 1300));
+
+        // which is measured in output, so at half speed it takes half as many samples
+        assert_eq!(1500, record(LooperSpeed::Half, 1300));
+        assert_eq!(2000, record(LooperSpeed::Double, 1300));
+
+        // a recording that a quantized command ended can still drift a few samples off the grid
+        assert_eq!(1000, record(LooperSpeed::One, 1003));
+    }
+
     #[test]
     fn test_offset() {
         install_test_logger();
@@ -683,6 +756,58 @@ mod tests {
         }
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_incremental_save() {
+        use std::os::unix::fs::MetadataExt;
+
+        install_test_logger();
+
+        let dir = tempdir().unwrap();
+        let input_left = vec![0.5f32; 64];
+        let input_right = vec![-0.5f32; 64];
+
+        let mut l = Looper::new(7, PartSet::new(), GuiSender::disconnected());
+        l.transition_to(LooperMode::Recording);
+        process_until_done(&mut l);
+        l.process_input(0, &[&input_left, &input_right], Part::A);
+        process_until_done(&mut l);
+        l.transition_to(LooperMode::Overdubbing);
+        process_until_done(&mut l);
+
+        let save = |l: &mut Looper, name: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            let (tx, rx) = bounded(1);
+            l.channel()
+                .send(ControlMessage::Serialize(
+                    path.clone(),
+                    SessionAudioFormat::Flac16,
+                    Arc::new(Names::default()),
+                    tx,
+                ))
+                .unwrap();
+            process_until_done(l);
+            let state = rx.recv().unwrap().unwrap();
+            state
+                .samples
+                .iter()
+                .map(|s| std::fs::metadata(path.join(s)).unwrap().nlink())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![1, 1], save(&mut l, "first"));
+
+        // only the second layer has changed, so the first is linked to the previous save's file
+        l.backend.as_mut().unwrap().samples[1].buffer[0][0] = 0.25;
+        assert_eq!(vec![2, 1], save(&mut l, "second"));
+
+        // saving again to the same directory doesn't need to write anything
+        assert_eq!(vec![2, 1], save(&mut l, "second"));
+
+        assert_eq!(vec![3, 2], save(&mut l, "third"));
+    }
+
     #[test]
     fn test_serialization() {
         install_test_logger();
@@ -718,14 +843,24 @@ mod tests {
 
         let (tx, rx) = bounded(1);
         l.channel()
-            .send(ControlMessage::Serialize(dir.path().to_path_buf(), tx))
+            .send(ControlMessage::Serialize(
+                dir.path().to_path_buf(),
+                SessionAudioFormat::WavFloat,
+                Arc::new(Names::default()),
+                tx,
+            ))
             .unwrap();
         process_until_done(&mut l);
 
         let state = rx.recv().unwrap().unwrap();
 
-        let deserialized =
-            Looper::from_serialized(&state, dir.path(), GuiSender::disconnected()).unwrap();
+        let deserialized = Looper::from_serialized(
+            &state,
+            dir.path(),
+            get_sample_rate(),
+            GuiSender::disconnected(),
+        )
+        .unwrap();
 
         assert_eq!(l.id, deserialized.id);
 
@@ -741,6 +876,67 @@ mod tests {
             assert!((b1.samples[1].buffer[0][i] - b2.samples[1].buffer[0][i]).abs() < 0.00001);
             assert!((b1.samples[1].buffer[0][i] - b2.samples[1].buffer[1][i]).abs() < 0.00001);
         }
+
+        // a session saved at twice the current rate is resampled to half the length
+        let resampled = Looper::from_serialized(
+            &state,
+            dir.path(),
+            get_sample_rate() * 2,
+            GuiSender::disconnected(),
+        )
+        .unwrap();
+        let b3 = resampled.backend.as_ref().unwrap();
+        assert_eq!(b1.samples[0].length() / 2, b3.samples[0].length());
+    }
+
+    #[test]
+    fn test_effects() {
+        let mut l = Looper::new(5, PartSet::new(), GuiSender::disconnected());
+
+        l.handle_command(LooperCommand::Effect(EffectCommand::Add(
+            EffectConfig::from_args("Reverb", &[]).unwrap(),
+        )));
+        l.handle_command(LooperCommand::Effect(EffectCommand::Add(
+            EffectConfig::from_args("Bitcrusher", &["1"]).unwrap(),
+        )));
+        l.handle_command(LooperCommand::Effect(EffectCommand::SetParam(1, EffectParam::Mix, 0.5)));
+        l.handle_command(LooperCommand::Effect(EffectCommand::SetParam(
+            1,
+            EffectParam::Time,
+            100.0,
+        )));
+        l.handle_command(LooperCommand::Effect(EffectCommand::Remove(0)));
+        l.handle_command(LooperCommand::Effect(EffectCommand::Remove(4)));
+        process_until_done(&mut l);
+
+        assert_eq!(
+            vec![EffectConfig::Bitcrusher {
+                bits: 1.0,
+                downsample: 1.0,
+                mix: 0.5
+            }],
+            l.backend.as_ref().unwrap().effects.configs()
+        );
+
+        let mut left = vec![0.3, -0.7];
+        let mut right = vec![0.0, 0.0];
+        l.process_effects(&mut left, &mut right);
+        assert_eq!(vec![0.15, -0.85], left);
+
+        // removed effects are handed back to the backend to be dropped
+        assert!(l
+            .backend
+            .as_ref()
+            .unwrap()
+            .channel
+            .try_iter()
+            .any(|m| matches!(m, ControlMessage::DropEffect(_))));
+
+        l.handle_command(LooperCommand::Effect(EffectCommand::Clear));
+        process_until_done(&mut l);
+        l.process_effects(&mut left, &mut right);
+        assert_eq!(vec![0.15, -0.85], left);
+        assert!(l.backend.as_ref().unwrap().effects.configs().is_empty());
     }
 }
 
@@ -802,18 +998,33 @@ pub enum ControlMessage {
     InputDataReady { id: u64, size: usize },
     TransitionTo(LooperMode),
     SetTime(FrameTime),
+    // the time at which the tempo or time signature changes, and the old and new metric structures
+    ChangeMetricStructure(FrameTime, MetricStructure, MetricStructure),
     ReadOutput(FrameTime),
     Shutdown,
-    Serialize(PathBuf, Sender<Result<SavedLooper, SaveLoadError>>),
+    Serialize(
+        PathBuf,
+        SessionAudioFormat,
+        Arc<Names>,
+        Sender<Result<SavedLooper, SaveLoadError>>,
+    ),
     Deleted,
     Clear,
     SetSpeed(LooperSpeed),
     SetPan(f32),
     SetLevel(f32),
     SetParts(PartSet),
+    SetGroups(GroupSet),
+    // the length (in frames) that new loops are rounded to a multiple of
+    SetLengthRounding(Option<u64>),
     Undo,
     Redo,
     StopOutput,
+    SetSend(usize, f32),
+    Effect(EffectCommand),
+    // an effect removed from the chain by the audio thread, to be deallocated by the backend
+    DropEffect(Effect),
+    Plugin(RackMessage),
 }
 
 const TRANSFER_BUF_SIZE: usize = 16;
@@ -957,15 +1168,26 @@ impl Debug for LooperChange {
     }
 }
 
+/// Where a loop repeats on the engine's timeline: it restarts at `offset` and every `period`
+/// frames before and after it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LoopTiming {
+    pub offset: FrameTime,
+    pub period: u64,
+}
+
 pub struct LooperBackend {
     pub id: u32,
     pub samples: Vec<Sample>,
     pub mode: Arc<Atomic<LooperMode>>,
     pub length: Arc<Atomic<u64>>,
+    // shared with the engine so that it can quantize commands to the end of the loop
+    timing: Arc<Atomic<LoopTiming>>,
     pub speed: LooperSpeed,
     pub pan: f32,
     pub level: f32,
     pub parts: PartSet,
+    groups: GroupSet,
     pub deleted: bool,
 
     offset: FrameTime,
@@ -983,6 +1205,13 @@ pub struct LooperBackend {
     in_queue: Arc<ArrayQueue<TransferBuf<f32>>>,
     out_queue: Arc<ArrayQueue<TransferBuf<f64>>>,
 
+    pub sends: [f32; AUX_BUS_COUNT],
+
+    // the configuration of the effect chain, which is mirrored by the audio thread's EffectChain
+    effects: EffectRack,
+
+    plugins: PluginRack,
+
     gui_sender: GuiSender,
 
     // Visible for benchmark
@@ -995,14 +1224,38 @@ pub struct LooperBackend {
 
     should_output: bool,
     gui_needs_reset: bool,
+
+    length_rounding: Option<u64>,
+
+    // the update that adds the looper to the gui. It's sent when the looper is started, so that
+    // loopers restored off of the audio thread don't appear before the ones they replace are gone.
+    announcement: Option<GuiCommand>,
+
+    // the layers written by the last save, which later saves link to if they haven't changed
+    saved_layers: Vec<SavedLayer>,
+}
+
+// a layer that was saved to disk, identified by the hash of its audio and the format it was
+// written in
+struct SavedLayer {
+    hash: u64,
+    format: SessionAudioFormat,
+    sample_rate: usize,
+    path: PathBuf,
 }
 
 impl LooperBackend {
     fn start(mut self) {
+        if let Some(update) = self.announcement.take() {
+            self.gui_sender.send_update(update);
+        }
+
         thread::spawn(move || loop {
             match self.channel.recv() {
                 Ok(msg) => {
-                    if !self.handle_msg(msg) {
+                    let cont = self.handle_msg(msg);
+                    self.publish_timing();
+                    if !cont {
                         break;
                     }
                 }
@@ -1018,12 +1271,25 @@ impl LooperBackend {
         loop {
             let msg = self.channel.try_recv();
             match msg {
-                Ok(msg) => self.handle_msg(msg),
+                Ok(msg) => {
+                    self.handle_msg(msg);
+                    self.publish_timing();
+                }
                 Err(_) => break,
             };
         }
     }
 
+    fn publish_timing(&self) {
+        self.timing.store(
+            LoopTiming {
+                offset: self.offset,
+                period: self.length_in_samples(true),
+            },
+            Ordering::Relaxed,
+        );
+    }
+
     fn current_state(&self) -> LooperState {
         LooperState {
             mode: self.mode(),
@@ -1106,6 +1372,20 @@ impl LooperBackend {
                 self.in_time = time;
                 self.should_output = true;
             }
+            ControlMessage::ChangeMetricStructure(old_time, from, to) => {
+                let time = from.convert_time(old_time, &to);
+                self.change_metric_structure(old_time, time, &from, &to);
+                self.out_time = FrameTime(time.0.max(0));
+                self.in_time = time;
+                self.should_output = true;
+
+                self.gui_sender
+                    .send_update(GuiCommand::SetLoopLengthAndOffset(
+                        self.id,
+                        self.length_in_samples(false),
+                        self.offset,
+                    ));
+            }
             ControlMessage::ReadOutput(time) => {
                 self.out_time = FrameTime(self.out_time.0.max(time.0));
             }
@@ -1119,8 +1399,8 @@ impl LooperBackend {
                     .send_update(GuiCommand::RemoveLooper(self.id));
                 return false;
             }
-            ControlMessage::Serialize(path, channel) => {
-                let result = self.serialize(&path);
+            ControlMessage::Serialize(path, format, names, channel) => {
+                let result = self.serialize(&path, format, &names);
                 if let Err(e) = channel.try_send(result) {
                     warn!("failed to respond to serialize request: {:?}", e);
                 }
@@ -1148,6 +1428,12 @@ impl LooperBackend {
                     self.id, self.current_state()
                 ));
             },
+            ControlMessage::SetGroups(groups) => {
+                self.groups = groups;
+            }
+            ControlMessage::SetLengthRounding(unit) => {
+                self.length_rounding = unit;
+            }
             ControlMessage::Undo => {
                 info!("Performing Undo on queue: {:?}", self.undo_queue);
 
@@ -1174,6 +1460,20 @@ impl LooperBackend {
             ControlMessage::StopOutput => {
                 self.should_output = false;
             }
+            ControlMessage::SetSend(bus, level) => {
+                if let Some(send) = self.sends.get_mut(bus) {
+                    *send = level;
+                }
+            }
+            ControlMessage::Effect(command) => {
+                self.effects.handle(command);
+            }
+            ControlMessage::DropEffect(effect) => {
+                debug!("Dropping {:?}", effect);
+            }
+            ControlMessage::Plugin(msg) => {
+                self.plugins.handle(msg);
+            }
         }
 
         if self.should_output {
@@ -1254,6 +1554,10 @@ impl LooperBackend {
         // of wasted data
         self.out_time = self.in_time;
 
+        if let Some(unit) = self.length_rounding {
+            self.round_length(unit);
+        }
+
         self.add_change(LooperChange::UnClear);
 
         // send our final length to the gui
@@ -1265,6 +1569,97 @@ impl LooperBackend {
             ));
     }
 
+    // Keeps the loop aligned with the grid when the metric structure changes at `old_time`, which
+    // becomes `time`. If the tempo changes, the loop is resampled to the same number of beats at
+    // the new tempo (which also changes its pitch, like a tape played at a different speed) and
+    // its offset is moved so that it carries on from the same point. Otherwise only the offset
+    // moves, by the same amount as the time.
+    fn change_metric_structure(
+        &mut self,
+        old_time: FrameTime,
+        time: FrameTime,
+        from: &MetricStructure,
+        to: &MetricStructure,
+    ) {
+        let from_spb = from.tempo.samples_per_beat();
+        let to_spb = to.tempo.samples_per_beat();
+        let period = self.length_in_samples(true) as i64;
+
+        if from_spb == to_spb
+            || from_spb == 0
+            || to_spb == 0
+            || period == 0
+            || self.mode() == LooperMode::Recording
+        {
+            self.offset.0 += time.0 - old_time.0;
+            return;
+        }
+
+        let position = (old_time - self.offset).0.rem_euclid(period);
+
+        let stretch = |s: &mut Sample| {
+            for b in s.buffer.iter_mut() {
+                *b = resample_loop(b, from_spb as usize, to_spb as usize);
+            }
+        };
+
+        for s in self.samples.iter_mut() {
+            stretch(s);
+        }
+
+        // the layers that can be brought back by undo and redo need to fit the new length too
+        for change in self.undo_queue.iter_mut().chain(self.redo_queue.iter_mut()) {
+            match change {
+                LooperChange::PopSample(s) => stretch(s),
+                LooperChange::Clear { samples, offset, .. } => {
+                    samples.iter_mut().for_each(stretch);
+                    *offset = from.convert_time(*offset, to);
+                }
+                LooperChange::PushSample | LooperChange::UnClear => {}
+            }
+        }
+
+        let len = self.samples.first().map(|s| s.length()).unwrap_or(0);
+        debug!(
+            "stretching loop {} from {} to {} samples",
+            self.id,
+            self.length.load(Ordering::Relaxed),
+            len
+        );
+        self.length.store(len, Ordering::Relaxed);
+        self.xfade_samples_left = 0;
+        self.gui_needs_reset = true;
+
+        let new_period = self.length_in_samples(true) as i64;
+        let new_position = (position as i128 * new_period as i128 / period as i128) as i64;
+        self.offset = FrameTime(time.0 - new_position);
+    }
+
+    // pads or trims the new loop to the nearest whole multiple of `unit` frames of output, so that
+    // it stays in phase with the metronome however long it plays. The end of the loop is then
+    // crossfaded with the audio from before the recording started, as usual.
+    fn round_length(&mut self, unit: u64) {
+        let unit = match self.speed {
+            LooperSpeed::Half => unit / 2,
+            LooperSpeed::One => unit,
+            LooperSpeed::Double => unit * 2,
+        };
+
+        if unit == 0 {
+            return;
+        }
+
+        if let Some(s) = self.samples.last_mut() {
+            let len = s.length();
+            let rounded = ((len + unit / 2) / unit).max(1) * unit;
+            if rounded != len {
+                debug!("rounding loop {} from {} to {} samples", self.id, len, rounded);
+                s.resize(rounded, CROSS_FADE_SAMPLES);
+                self.length.store(rounded, Ordering::Relaxed);
+            }
+        }
+    }
+
     // state transition functions
     fn handle_crossfades(&mut self, _next_state: LooperMode) {
         debug!("handling crossfade");
@@ -1540,38 +1935,75 @@ impl LooperBackend {
         }
     }
 
-    pub fn serialize(&self, path: &Path) -> Result<SavedLooper, SaveLoadError> {
-        let spec = hound::WavSpec {
-            channels: 2,
-            sample_rate: 44100,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
-        };
-
+    pub fn serialize(
+        &mut self,
+        path: &Path,
+        format: SessionAudioFormat,
+        names: &Names,
+    ) -> Result<SavedLooper, SaveLoadError> {
+        let sample_rate = get_sample_rate();
         let mut saved = SavedLooper {
             id: self.id,
             mode: self.mode(),
-            parts: self.parts,
+            parts: self
+                .parts
+                .iter()
+                .filter_map(|p| names.parts.name(p.0).cloned())
+                .collect(),
+            groups: self
+                .groups
+                .iter()
+                .filter_map(|g| names.groups.name(g.0).cloned())
+                .collect(),
             speed: self.speed,
             pan: self.pan,
             level: self.level,
             samples: Vec::with_capacity(self.samples.len()),
             offset_samples: self.offset.0,
+            effects: self.effects.configs().to_vec(),
+            plugins: self.plugins.save(path)?,
+            sends: self.sends.to_vec(),
         };
 
+        let mut saved_layers = Vec::with_capacity(self.samples.len());
         for (i, s) in self.samples.iter().enumerate() {
-            let name = format!("loop_{}_{}.wav", self.id, i);
+            let name = format!("loop_{}_{}.{}", self.id, i, format.extension());
             let p = path.join(&name);
-            let mut writer = hound::WavWriter::create(&p, spec.clone())?;
+            let hash = s.content_hash();
+
+            // layers that haven't changed since the last save are linked to the file from that
+            // save instead of being written again, which keeps frequent saves cheap
+            let previous = self.saved_layers.iter().find(|l| {
+                l.hash == hash && l.format == format && l.sample_rate == sample_rate
+            });
+            let reused = match previous {
+                Some(l) if l.path == p => p.exists(),
+                Some(l) => std::fs::hard_link(&l.path, &p)
+                    .or_else(|_| std::fs::copy(&l.path, &p).map(|_| ()))
+                    .map_err(|e| debug!("could not reuse {:?}: {}", l.path, e))
+                    .is_ok(),
+                None => false,
+            };
 
-            for j in 0..s.length() as usize {
-                writer.write_sample(s.buffer[0][j])?;
-                writer.write_sample(s.buffer[1][j])?;
+            if !reused {
+                // a file already at this path (from an earlier save in the same second) may be
+                // linked to another session's, so it has to be replaced rather than overwritten
+                if p.exists() {
+                    std::fs::remove_file(&p)?;
+                }
+                write_audio(&p, &[&s.buffer[0], &s.buffer[1]], sample_rate, format)?;
             }
-            writer.finalize()?;
+
+            saved_layers.push(SavedLayer {
+                hash,
+                format,
+                sample_rate,
+                path: p,
+            });
             // use the relative path so that the directory can be moved and still be valid
             saved.samples.push(PathBuf::from(name));
         }
+        self.saved_layers = saved_layers;
 
         Ok(saved)
     }
@@ -1584,23 +2016,38 @@ pub struct Looper {
     pub id: u32,
     pub deleted: bool,
     pub parts: PartSet,
+    pub groups: GroupSet,
     pub pan: f32,
     pub level: f32,
+    pub speed: LooperSpeed,
 
     pub pan_law: PanLaw,
 
+    // post-fader send levels for each aux bus
+    pub sends: [f32; AUX_BUS_COUNT],
+
+    // the values actually applied to the output, which follow pan, level and sends with a short
+    // ramp
+    smoothed_pan: SmoothedValue,
+    smoothed_level: SmoothedValue,
+    smoothed_sends: [SmoothedValue; AUX_BUS_COUNT],
 
     // this is pretty hacky -- we sometimes need a way to see the mode that has been just set on the
     // looper, before it's had a chance to make it to the backend
     local_mode: Option<LooperMode>,
     mode: Arc<Atomic<LooperMode>>,
     length: Arc<Atomic<u64>>,
+    timing: Arc<Atomic<LoopTiming>>,
     pub backend: Option<LooperBackend>,
     msg_counter: u64,
+    length_rounding: Option<u64>,
     out_queue: Arc<ArrayQueue<TransferBuf<f32>>>,
     in_queue: Arc<ArrayQueue<TransferBuf<f64>>>,
     channel: Sender<ControlMessage>,
 
+    effects: EffectChain,
+    plugins: PluginChain,
+
     in_progress_output: Option<TransferBuf<f64>>,
 
     last_time: FrameTime,
@@ -1628,11 +2075,16 @@ impl Looper {
         level: f32,
         offset: FrameTime,
         samples: Vec<Sample>,
-        mut gui_sender: GuiSender,
+        gui_sender: GuiSender,
     ) -> Looper {
         debug!("Creating new looper with samples {}", id);
         let record_queue = Arc::new(ArrayQueue::new(512 * 1024 / TRANSFER_BUF_SIZE));
         let play_queue = Arc::new(ArrayQueue::new(512 * 1024 / TRANSFER_BUF_SIZE));
+        let (effect_rack, effect_chain) = EffectRack::new(&format!("Looper {}", id));
+        // sends are restored by from_serialized
+        let sends = [0f32; AUX_BUS_COUNT];
+        let (plugin_rack, plugin_chain) =
+            PluginRack::new(&format!("looper_{}", id), gui_sender.clone());
 
         let (s, r) = bounded(1000);
 
@@ -1649,16 +2101,16 @@ impl Looper {
             has_redos: false,
         };
 
-        if samples.is_empty() {
-            gui_sender.send_update(GuiCommand::AddLooper(id, state));
+        let announcement = if samples.is_empty() {
+            GuiCommand::AddLooper(id, state)
         } else {
-            gui_sender.send_update(GuiCommand::AddLooperWithSamples(
+            GuiCommand::AddLooperWithSamples(
                 id,
                 length,
                 Box::new(compute_waveform(&samples, WAVEFORM_DOWNSAMPLE)),
                 state,
-            ));
-        }
+            )
+        };
 
         let mode = Arc::new(Atomic::new(LooperMode::Playing));
         let length = Arc::new(Atomic::new(samples.get(0)
@@ -1670,10 +2122,12 @@ impl Looper {
             samples,
             mode: mode.clone(),
             length: length.clone(),
+            timing: Arc::new(Atomic::new(LoopTiming { offset, period: 0 })),
             speed,
             pan,
             level,
             parts,
+            groups: GroupSet::default(),
             deleted: false,
             offset,
             enable_crossfading: true,
@@ -1689,6 +2143,9 @@ impl Looper {
             xfade_sample_idx: 0,
             in_queue: record_queue.clone(),
             out_queue: play_queue.clone(),
+            sends,
+            effects: effect_rack,
+            plugins: plugin_rack,
             gui_sender,
             channel: r,
             waveform_generator: WaveformGenerator::new(id),
@@ -1696,22 +2153,40 @@ impl Looper {
             redo_queue: VecDeque::new(),
             should_output: true,
             gui_needs_reset: false,
+            length_rounding: None,
+            announcement: Some(announcement),
+            saved_layers: vec![],
         };
+        backend.publish_timing();
+        let timing = backend.timing.clone();
+
+        let ramp_samples =
+            FrameTime::from_ms(Settings::default().parameter_ramp_ms as f64).0 as u32;
 
         Looper {
             id,
             backend: Some(backend),
             parts,
+            groups: GroupSet::default(),
             pan,
             level,
+            speed,
             pan_law: PanLaw::Neg4_5,
+            smoothed_pan: SmoothedValue::new(pan, ramp_samples),
+            smoothed_level: SmoothedValue::new(level, ramp_samples),
+            sends,
+            smoothed_sends: sends.map(|s| SmoothedValue::new(s, ramp_samples)),
             deleted: false,
             msg_counter: 0,
+            length_rounding: None,
             in_queue: play_queue.clone(),
             out_queue: record_queue.clone(),
             channel: s,
+            effects: effect_chain,
+            plugins: plugin_chain,
             mode,
             length,
+            timing,
 
             in_progress_output: None,
 
@@ -1720,57 +2195,105 @@ impl Looper {
         }
     }
 
+    /// Restores a looper from a session saved at `sample_rate`, resampling its loops if the engine
+    /// is running at a different rate. The looper starts out in the first part; its saved parts
+    /// and groups are names, which the engine resolves and applies with `set_membership`.
     pub fn from_serialized(
         state: &SavedLooper,
         path: &Path,
+        sample_rate: usize,
         gui_output: GuiSender,
     ) -> Result<Looper, SaveLoadError> {
+        let rate = get_sample_rate();
+
         let mut samples = vec![];
         for sample_path in &state.samples {
-            let mut reader = hound::WavReader::open(&path.join(sample_path))?;
-
-            let mut sample = Sample::new();
-            let mut left = Vec::with_capacity(reader.len() as usize / 2);
-            let mut right = Vec::with_capacity(reader.len() as usize / 2);
-
-            for (i, s) in reader.samples().enumerate() {
-                if i % 2 == 0 {
-                    left.push(s?);
-                } else {
-                    right.push(s?);
-                }
-            }
-
-            sample.record(&[&left, &right]);
-            samples.push(sample);
+            let mut file = AudioFile::read(&path.join(sample_path))?;
+            // older versions labelled every file as 44.1k, whatever rate it was recorded at, so
+            // the session's rate is the one to trust
+            file.sample_rate = sample_rate;
+            samples.push(file.into_loop_sample(rate));
         }
 
-        Ok(Self::new_with_samples(
+        let offset = if sample_rate == rate || sample_rate == 0 {
+            state.offset_samples
+        } else {
+            (state.offset_samples as i128 * rate as i128 / sample_rate as i128) as i64
+        };
+
+        let mut looper = Self::new_with_samples(
             state.id,
-            state.parts,
+            PartSet::new(),
             state.speed,
             state.pan,
             state.level,
-            FrameTime(state.offset_samples),
+            FrameTime(offset),
             samples,
             gui_output,
-        ))
+        );
+
+        for (bus, level) in state.sends.iter().enumerate().take(AUX_BUS_COUNT) {
+            looper.handle_command(LooperCommand::SetSend(bus as u8, *level));
+        }
+
+        for effect in &state.effects {
+            looper.handle_command(LooperCommand::Effect(EffectCommand::Add(*effect)));
+        }
+
+        for plugin in &state.plugins {
+            looper.send_plugin_message(RackMessage::Add(SavedPlugin {
+                state: plugin.state.as_ref().map(|s| path.join(s)),
+                ..plugin.clone()
+            }));
+        }
+
+        Ok(looper)
     }
 
     pub fn channel(&self) -> Sender<ControlMessage> {
         self.channel.clone()
     }
 
+    pub fn send_plugin_message(&mut self, msg: RackMessage) {
+        self.send_to_backend(ControlMessage::Plugin(msg));
+    }
+
+    /// Sets the length (in frames) that loops are rounded to a multiple of when recording ends
+    pub fn set_length_rounding(&mut self, unit: Option<u64>) {
+        if self.length_rounding != unit {
+            self.length_rounding = unit;
+            self.send_to_backend(ControlMessage::SetLengthRounding(unit));
+        }
+    }
+
+    /// Sets the number of samples over which pan and level changes are ramped
+    pub fn set_parameter_ramp(&mut self, samples: u32) {
+        self.smoothed_pan.set_ramp_samples(samples);
+        self.smoothed_level.set_ramp_samples(samples);
+        for send in &mut self.smoothed_sends {
+            send.set_ramp_samples(samples);
+        }
+    }
+
     pub fn start(mut self) -> Self {
-        let mut backend: Option<LooperBackend> = None;
-        std::mem::swap(&mut backend, &mut self.backend);
+        self.start_backend();
+        self
+    }
 
-        match backend {
+    /// Starts the backend thread, for loopers that are already in place
+    pub fn start_backend(&mut self) {
+        match self.backend.take() {
             Some(backend) => backend.start(),
             _ => warn!("looper already started!"),
         }
+    }
 
-        self
+    /// Sets the parts and groups of a restored looper, once the engine has looked up their names
+    pub fn set_membership(&mut self, parts: PartSet, groups: GroupSet) {
+        self.parts = parts;
+        self.groups = groups;
+        self.send_to_backend(ControlMessage::SetParts(parts));
+        self.send_to_backend(ControlMessage::SetGroups(groups));
     }
 
     fn send_to_backend(&mut self, message: ControlMessage) -> bool {
@@ -1802,13 +2325,40 @@ impl Looper {
         self.length.load(Ordering::Relaxed)
     }
 
-    pub fn set_time(&mut self, time: FrameTime) {
+    /// Where the loop repeats on the engine's timeline, or None if it is empty or still being
+    /// recorded
+    pub fn loop_timing(&self) -> Option<LoopTiming> {
+        let timing = self.timing.load(Ordering::Relaxed);
+        if timing.period == 0 || self.mode() == LooperMode::Recording {
+            None
+        } else {
+            Some(timing)
+        }
+    }
+
+    /// When the current recording began, or None if the looper isn't recording or hasn't
+    /// recorded anything yet
+    pub fn recording_start(&self) -> Option<FrameTime> {
+        let timing = self.timing.load(Ordering::Relaxed);
+        if timing.period == 0 || self.mode() != LooperMode::Recording {
+            None
+        } else {
+            Some(timing.offset)
+        }
+    }
+
+    // discards the output that was read ahead for the old time
+    fn clear_output(&mut self) {
         loop {
             if self.in_queue.pop().is_none() {
                 break;
             }
         }
         self.in_progress_output = None;
+    }
+
+    pub fn set_time(&mut self, time: FrameTime) {
+        self.clear_output();
 
         if self.mode() == LooperMode::Recording && time < FrameTime(0) {
             // we will clear our buffer
@@ -1818,6 +2368,19 @@ impl Looper {
         self.send_to_backend(ControlMessage::SetTime(time));
     }
 
+    /// Changes the tempo or time signature at `time` from `from` to `to`. The looper moves to the
+    /// same measure and beat under the new metric structure, and its loop is stretched to the new
+    /// tempo so that it stays aligned with the grid.
+    pub fn change_metric_structure(
+        &mut self,
+        time: FrameTime,
+        from: MetricStructure,
+        to: MetricStructure,
+    ) {
+        self.clear_output();
+        self.send_to_backend(ControlMessage::ChangeMetricStructure(time, from, to));
+    }
+
     fn clear_queue(&mut self) {
         self.set_time(self.last_time)
     }
@@ -1836,6 +2399,7 @@ impl Looper {
             }
 
             SetSpeed(speed) => {
+                self.speed = speed;
                 self.send_to_backend(ControlMessage::StopOutput);
                 self.send_to_backend(ControlMessage::SetSpeed(speed));
                 self.clear_queue();
@@ -1843,29 +2407,42 @@ impl Looper {
 
             SetPan(pan) => {
                 self.pan = pan;
+                self.smoothed_pan.set_target(pan);
                 self.send_to_backend(ControlMessage::SetPan(pan));
             }
 
             SetLevel(level) => {
                 self.level = level;
+                self.smoothed_level.set_target(level);
                 self.send_to_backend(ControlMessage::SetLevel(level));
             }
 
             AddToPart(part) => {
-                self.parts[part] = true;
+                self.parts.insert(part);
                 self.send_to_backend(ControlMessage::SetParts(self.parts));
             }
             RemoveFromPart(part) => {
-                self.parts[part] = false;
-                if self.parts.is_empty() {
-                    // don't allow the user to clear all parts
-                    self.parts[part] = true;
-                } else {
+                // don't allow the user to clear all parts
+                if self.parts.contains(part) && self.parts.len() > 1 {
+                    self.parts.remove(part);
                     self.send_to_backend(ControlMessage::SetParts(self.parts));
                 }
             }
+            AddToGroup(group) => {
+                if !self.groups.contains(group) {
+                    self.groups.insert(group);
+                    self.send_to_backend(ControlMessage::SetGroups(self.groups));
+                }
+            }
+            RemoveFromGroup(group) => {
+                if self.groups.contains(group) {
+                    self.groups.remove(group);
+                    self.send_to_backend(ControlMessage::SetGroups(self.groups));
+                }
+            }
             Delete => {
                 self.deleted = true;
+                self.release_plugins();
                 self.send_to_backend(ControlMessage::Deleted);
             }
             RecordOverdubPlay => {
@@ -1888,6 +2465,59 @@ impl Looper {
                 self.send_to_backend(ControlMessage::Redo);
                 self.clear_queue();
             }
+            Effect(command) => {
+                self.send_to_backend(ControlMessage::Effect(command));
+            }
+            SetSend(bus, level) => {
+                let bus = bus as usize;
+                if bus < AUX_BUS_COUNT {
+                    self.sends[bus] = level;
+                    self.smoothed_sends[bus].set_target(level);
+                    self.send_to_backend(ControlMessage::SetSend(bus, level));
+                }
+            }
+        }
+    }
+
+    // Runs the looper's output through its effect chain and then its plugins, after applying any
+    // changes to them that have been prepared by the backend
+    pub fn process_effects(&mut self, left: &mut [f64], right: &mut [f64]) {
+        let channel = &self.channel;
+        self.effects.process(left, right, |effect| {
+            if let Err(e) = channel.try_send(ControlMessage::DropEffect(effect)) {
+                warn!("failed to send effect to backend: {:?}", e);
+            }
+        });
+
+        self.plugins.process(left, right, |plugin| {
+            if let Err(e) = channel.try_send(ControlMessage::Plugin(RackMessage::Drop(plugin))) {
+                warn!("failed to send plugin to backend: {:?}", e);
+            }
+        });
+    }
+
+    /// Hands the looper's plugins back to the backend to be destroyed there; this must happen
+    /// before the backend is stopped, as the looper itself may be dropped on the audio thread
+    pub fn release_plugins(&mut self) {
+        let channel = &self.channel;
+        self.plugins.clear(|plugin| {
+            if let Err(e) = channel.try_send(ControlMessage::Plugin(RackMessage::Drop(plugin))) {
+                warn!("failed to send plugin to backend: {:?}", e);
+            }
+        });
+    }
+
+    // Mixes the looper's processed output into the aux buses at its send levels, starting
+    // `offset` samples into the current block
+    pub fn process_sends(
+        &mut self,
+        offset: usize,
+        left: &[f64],
+        right: &[f64],
+        buses: &mut [AuxBus],
+    ) {
+        for (send, bus) in self.smoothed_sends.iter_mut().zip(buses) {
+            bus.mix(offset, left, right, send);
         }
     }
 
@@ -1959,16 +2589,22 @@ impl Looper {
         let mut waiting = 1_000;
         let backoff = crossbeam_utils::Backoff::new();
 
-        // this only really needs to be updated when the pan changes, so we don't need to do this
-        // for every buffer
-        let pan_l = self.pan_law.left(self.pan);
-        let pan_r = self.pan_law.right(self.pan);
+        // the pan law only needs to be recomputed while the pan is ramping
+        let mut pan_l = self.pan_law.left(self.smoothed_pan.current());
+        let mut pan_r = self.pan_law.right(self.smoothed_pan.current());
 
         while out_idx < outputs[0].len() {
             if let Some((l, r)) = self.output_for_t(time) {
+                if self.smoothed_pan.is_ramping() {
+                    let pan = self.smoothed_pan.next_value();
+                    pan_l = self.pan_law.left(pan);
+                    pan_r = self.pan_law.right(pan);
+                }
+                let level = self.smoothed_level.next_value();
+
                 if self.should_output(part, solo) {
-                    outputs[0][out_idx] += l * pan_l as f64 * self.level as f64;
-                    outputs[1][out_idx] += r * pan_r as f64 * self.level as f64;
+                    outputs[0][out_idx] += l * pan_l as f64 * level as f64;
+                    outputs[1][out_idx] += r * pan_r as f64 * level as f64;
                 }
             } else if waiting > 0 && self.mode() != LooperMode::Recording {
                 backoff.spin();
@@ -2061,6 +2697,7 @@ impl Looper {
 
 impl Drop for Looper {
     fn drop(&mut self) {
+        self.release_plugins();
         if let Err(_) = self.channel.send(ControlMessage::Shutdown) {
             warn!("failed to shutdown backend because queue was full");
         }
@@ -0,0 +1,332 @@
+use crate::meter::{from_db, to_db, LoudnessMeter, TruePeakMeter};
+use crate::plugin::{PluginChain, PluginRack, RackMessage};
+use crate::smoothing::SmoothedValue;
+use loopers_common::api::{FrameTime, OutputProtection, MIN_MASTER_GAIN_DB};
+use loopers_common::config::Settings;
+use loopers_common::gui_channel::{GuiSender, MasterMeters};
+use crossbeam_channel::{Sender, TrySendError};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master(protection: OutputProtection) -> MasterBus {
+        let settings = Settings {
+            parameter_ramp_ms: 0.0,
+            ..Settings::default()
+        };
+        let mut m = MasterBus::new(&settings, GuiSender::disconnected());
+        m.set_output_protection(protection);
+        m
+    }
+
+    #[test]
+    fn test_gain() {
+        let mut m = master(OutputProtection::Off);
+        m.set_gain(-6.0);
+
+        let mut l = vec![1.0f64; 64];
+        let mut r = vec![-1.0f64; 64];
+        m.process(&mut l, &mut r);
+
+        for (l, r) in l.iter().zip(&r) {
+            assert!((l - 0.501).abs() < 0.001);
+            assert!((r + 0.501).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_min_gain_is_silent() {
+        let mut m = master(OutputProtection::Off);
+        m.set_gain(MIN_MASTER_GAIN_DB);
+
+        let mut l = vec![1.0f64; 64];
+        let mut r = vec![1.0f64; 64];
+        m.process(&mut l, &mut r);
+
+        assert!(l.iter().chain(&r).all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn test_limiter_holds_ceiling() {
+        let mut m = master(OutputProtection::Limiter);
+        let ceiling = from_db(Settings::default().limiter_ceiling_db);
+
+        let mut l: Vec<f64> = (0..4096).map(|i| (i as f64 / 10.0).sin() * 3.0).collect();
+        let mut r = l.clone();
+        m.process(&mut l, &mut r);
+
+        assert!(l.iter().chain(&r).all(|v| v.abs() <= ceiling));
+        assert!(m.meters().gain_reduction < -6.0);
+
+        // quiet signals pass through unchanged, after the look-ahead delay
+        let mut m = master(OutputProtection::Limiter);
+        let input: Vec<f64> = (0..4096).map(|i| (i as f64 / 10.0).sin() * 0.5).collect();
+        let mut l = input.clone();
+        let mut r = input.clone();
+        m.process(&mut l, &mut r);
+
+        let delay = m.limiter.delay.len();
+        for i in delay..input.len() {
+            assert!((l[i] - input[i - delay]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_soft_clip() {
+        assert_eq!(0.2, soft_clip(0.2, 1.0));
+        assert_eq!(-0.2, soft_clip(-0.2, 1.0));
+        assert!(soft_clip(10.0, 1.0) <= 1.0);
+        assert!(soft_clip(10.0, 1.0) > 0.99);
+        assert!(soft_clip(-10.0, 1.0) >= -1.0);
+        assert!(soft_clip(0.8, 1.0) < 0.8);
+        assert!(soft_clip(0.8, 1.0) > soft_clip(0.7, 1.0));
+    }
+}
+
+const LIMITER_LOOKAHEAD_MS: f64 = 1.5;
+
+// the portion of the ceiling below which the soft clipper leaves the signal untouched
+const SOFT_CLIP_KNEE: f64 = 0.5;
+
+/// Saturates `v` smoothly so that it never exceeds `ceiling`; values below the knee are unchanged
+/// and the curve above it is continuous in both value and slope
+pub fn soft_clip(v: f64, ceiling: f64) -> f64 {
+    let x = v.abs() / ceiling;
+    if x <= SOFT_CLIP_KNEE {
+        return v;
+    }
+
+    let range = 1.0 - SOFT_CLIP_KNEE;
+    let y = SOFT_CLIP_KNEE + range * ((x - SOFT_CLIP_KNEE) / range).tanh();
+    v.signum() * y * ceiling
+}
+
+/// A stereo-linked look-ahead brickwall limiter. The signal is delayed by the look-ahead time so
+/// that the gain can be brought down smoothly before a peak arrives; the gain curve is the minimum
+/// gain required over the look-ahead window, with an exponential release, averaged over the same
+/// window.
+struct Limiter {
+    ceiling: f64,
+    release: f64,
+
+    // delayed input, and the gain required by each of the samples in it
+    delay: Vec<[f64; 2]>,
+    required: Vec<f64>,
+    pos: usize,
+
+    held: f64,
+    average: Vec<f64>,
+    average_sum: f64,
+
+    gain: f64,
+}
+
+impl Limiter {
+    fn new(ceiling: f64, release_ms: f32) -> Limiter {
+        let lookahead = (FrameTime::from_ms(LIMITER_LOOKAHEAD_MS).0 as usize).max(1);
+        let release_samples = FrameTime::from_ms(release_ms.max(0.0) as f64).0.max(1) as f64;
+
+        Limiter {
+            ceiling,
+            release: 1.0 - (-1.0 / release_samples).exp(),
+            delay: vec![[0.0; 2]; lookahead],
+            required: vec![1.0; lookahead],
+            pos: 0,
+            held: 1.0,
+            average: vec![1.0; lookahead],
+            average_sum: lookahead as f64,
+            gain: 1.0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, l: f64, r: f64) -> (f64, f64) {
+        let len = self.delay.len();
+
+        let peak = l.abs().max(r.abs());
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        // the sample leaving the delay line is still included here, so that every gain value
+        // averaged for it has taken it into account
+        let min = self.required.iter().fold(required, |a, b| a.min(*b));
+
+        let [out_l, out_r] = self.delay[self.pos];
+        self.delay[self.pos] = [l, r];
+        self.required[self.pos] = required;
+
+        self.held = if min < self.held {
+            min
+        } else {
+            self.held + (min - self.held) * self.release
+        };
+
+        self.average_sum += self.held - self.average[self.pos];
+        self.average[self.pos] = self.held;
+        self.gain = (self.average_sum / len as f64).min(1.0);
+
+        self.pos = (self.pos + 1) % len;
+
+        // the averaged gain always reaches the required gain by the time a peak leaves the delay
+        // line, but we clamp anyway to guard against rounding errors
+        (
+            (out_l * self.gain).max(-self.ceiling).min(self.ceiling),
+            (out_r * self.gain).max(-self.ceiling).min(self.ceiling),
+        )
+    }
+
+    // keeps the delay line filled while the limiter is not in use, so that enabling it doesn't
+    // play back stale audio
+    #[inline]
+    fn bypass(&mut self, l: f64, r: f64) {
+        self.delay[self.pos] = [l, r];
+        self.required[self.pos] = 1.0;
+        self.average_sum += 1.0 - self.average[self.pos];
+        self.average[self.pos] = 1.0;
+        self.pos = (self.pos + 1) % self.delay.len();
+        self.held = 1.0;
+        self.gain = 1.0;
+    }
+}
+
+/// The final stage of the output: runs the mixed signal through the master plugin inserts, applies
+/// the master gain and output protection, then meters the result
+pub struct MasterBus {
+    gain_db: f32,
+    gain: SmoothedValue,
+    protection: OutputProtection,
+    ceiling: f64,
+    limiter: Limiter,
+
+    true_peak: [TruePeakMeter; 2],
+    loudness: LoudnessMeter,
+    meters: MasterMeters,
+
+    plugins: PluginChain,
+    plugin_rack: Sender<RackMessage>,
+}
+
+impl MasterBus {
+    pub fn new(settings: &Settings, gui_sender: GuiSender) -> MasterBus {
+        let ceiling = from_db(settings.limiter_ceiling_db.min(0.0));
+        let ramp = FrameTime::from_ms(settings.parameter_ramp_ms.max(0.0) as f64).0 as u32;
+        let (plugin_rack, plugins) = PluginRack::spawn("master", gui_sender);
+
+        MasterBus {
+            gain_db: 0.0,
+            gain: SmoothedValue::new(1.0, ramp),
+            protection: OutputProtection::Off,
+            ceiling,
+            limiter: Limiter::new(ceiling, settings.limiter_release_ms),
+            true_peak: [TruePeakMeter::new(), TruePeakMeter::new()],
+            loudness: LoudnessMeter::new(),
+            meters: MasterMeters::new(),
+            plugins,
+            plugin_rack,
+        }
+    }
+
+    /// The channel controlling the master plugin inserts
+    pub fn plugin_rack(&self) -> Sender<RackMessage> {
+        self.plugin_rack.clone()
+    }
+
+    pub fn send_plugin_message(&mut self, msg: RackMessage) {
+        if let Err(e) = self.plugin_rack.try_send(msg) {
+            match e {
+                TrySendError::Full(msg) => error!("Failed to process {:?}: channel is full", msg),
+                TrySendError::Disconnected(_) => error!("Master plugin channel disconnected"),
+            }
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain_db
+    }
+
+    pub fn set_gain(&mut self, gain_db: f32) {
+        self.gain_db = gain_db;
+        self.gain.set_target(if gain_db <= MIN_MASTER_GAIN_DB {
+            0.0
+        } else {
+            from_db(gain_db) as f32
+        });
+    }
+
+    pub fn output_protection(&self) -> OutputProtection {
+        self.protection
+    }
+
+    pub fn set_output_protection(&mut self, protection: OutputProtection) {
+        self.protection = protection;
+    }
+
+    pub fn reset_loudness(&mut self) {
+        self.loudness.reset();
+        self.meters.max_true_peak = f32::NEG_INFINITY;
+    }
+
+    pub fn meters(&self) -> MasterMeters {
+        self.meters
+    }
+
+    pub fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        let rack = &self.plugin_rack;
+        self.plugins.process(left, right, |plugin| {
+            if let Err(e) = rack.try_send(RackMessage::Drop(plugin)) {
+                warn!("failed to send plugin to rack: {:?}", e);
+            }
+        });
+
+        let mut peaks = [0f32; 2];
+        let mut gain_reduction = 1f64;
+
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let gain = self.gain.next_value() as f64;
+            let (in_l, in_r) = (*l * gain, *r * gain);
+
+            let (out_l, out_r) = match self.protection {
+                OutputProtection::Off => {
+                    self.limiter.bypass(in_l, in_r);
+                    (in_l, in_r)
+                }
+                OutputProtection::Limiter => {
+                    let out = self.limiter.process(in_l, in_r);
+                    gain_reduction = gain_reduction.min(self.limiter.gain);
+                    out
+                }
+                OutputProtection::SoftClip => {
+                    self.limiter.bypass(in_l, in_r);
+                    let out = (soft_clip(in_l, self.ceiling), soft_clip(in_r, self.ceiling));
+                    let peak = in_l.abs().max(in_r.abs());
+                    if peak > 0.0 {
+                        gain_reduction = gain_reduction.min(out.0.abs().max(out.1.abs()) / peak);
+                    }
+                    out
+                }
+            };
+
+            *l = out_l;
+            *r = out_r;
+
+            peaks[0] = peaks[0].max(self.true_peak[0].process(out_l));
+            peaks[1] = peaks[1].max(self.true_peak[1].process(out_r));
+        }
+
+        self.loudness.process(left, right);
+
+        let true_peak = [to_db(peaks[0] as f64), to_db(peaks[1] as f64)];
+        self.meters = MasterMeters {
+            true_peak,
+            max_true_peak: self.meters.max_true_peak.max(true_peak[0]).max(true_peak[1]),
+            momentary_lufs: self.loudness.momentary(),
+            short_term_lufs: self.loudness.short_term(),
+            integrated_lufs: self.loudness.integrated(),
+            gain_reduction: to_db(gain_reduction),
+        };
+    }
+}
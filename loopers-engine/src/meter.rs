@@ -0,0 +1,339 @@
+use crate::effects::Biquad;
+use loopers_common::api::get_sample_rate;
+use std::f64::consts::PI;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, amp: f64, phase: f64, len: usize) -> Vec<f64> {
+        let sr = get_sample_rate() as f64;
+        (0..len)
+            .map(|i| amp * (2.0 * PI * freq * i as f64 / sr + phase).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // a quarter-sample-rate sine sampled 45 degrees off its peaks never hits a sample at full
+        // amplitude, but its true peak is still 0 dBTP
+        let input = sine(get_sample_rate() as f64 / 4.0, 1.0, PI / 4.0, 4800);
+        let sample_peak = input.iter().fold(0f64, |a, b| a.max(b.abs()));
+        assert!(sample_peak < 0.71);
+
+        let mut meter = TruePeakMeter::new();
+        let mut peak = 0f32;
+        for v in &input {
+            peak = peak.max(meter.process(*v));
+        }
+        assert!((peak - 1.0).abs() < 0.05, "true peak was {}", peak);
+    }
+
+    #[test]
+    fn test_loudness_of_reference_sine() {
+        // per BS.1770, a 1 kHz sine in both channels reads as its level in dBFS
+        let amp = 10f64.powf(-20.0 / 20.0);
+        let input = sine(1000.0, amp, 0.0, get_sample_rate() * 5);
+
+        let mut meter = LoudnessMeter::new();
+        meter.process(&input, &input);
+
+        assert!((meter.momentary() + 20.0).abs() < 0.1, "{}", meter.momentary());
+        assert!((meter.short_term() + 20.0).abs() < 0.1, "{}", meter.short_term());
+        assert!((meter.integrated() + 20.0).abs() < 0.1, "{}", meter.integrated());
+    }
+
+    #[test]
+    fn test_loudness_gating() {
+        let amp = 10f64.powf(-20.0 / 20.0);
+        let loud = sine(1000.0, amp, 0.0, get_sample_rate() * 5);
+        let silence = vec![0f64; get_sample_rate() * 5];
+
+        let mut meter = LoudnessMeter::new();
+        meter.process(&loud, &loud);
+        meter.process(&silence, &silence);
+
+        // silence is removed by the absolute gate, so it doesn't pull down the integrated value
+        assert!((meter.integrated() + 20.0).abs() < 0.2, "{}", meter.integrated());
+        assert!(meter.momentary() < -70.0);
+
+        meter.reset();
+        assert_eq!(f32::NEG_INFINITY, meter.integrated());
+    }
+}
+
+/// Converts a linear amplitude or gain to decibels
+pub fn to_db(v: f64) -> f32 {
+    (20.0 * v.log10()) as f32
+}
+
+/// Converts decibels to a linear amplitude or gain
+pub fn from_db(db: f32) -> f64 {
+    10f64.powf(db as f64 / 20.0)
+}
+
+const TRUE_PEAK_PHASES: usize = 4;
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Estimates the true (inter-sample) peak of a signal by 4x oversampling it, as described in
+/// ITU-R BS.1770-4 Annex 2
+pub struct TruePeakMeter {
+    coefficients: [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES],
+    history: [f64; TRUE_PEAK_TAPS],
+    pos: usize,
+}
+
+impl TruePeakMeter {
+    pub fn new() -> TruePeakMeter {
+        // a Hann-windowed sinc interpolation filter, split into one polyphase branch per
+        // oversampled point
+        let len = TRUE_PEAK_PHASES * TRUE_PEAK_TAPS;
+        let center = (len - 1) as f64 / 2.0;
+        let mut coefficients = [[0f64; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES];
+        for (phase, cs) in coefficients.iter_mut().enumerate() {
+            for (tap, c) in cs.iter_mut().enumerate() {
+                let n = (tap * TRUE_PEAK_PHASES + phase) as f64;
+                let x = (n - center) / TRUE_PEAK_PHASES as f64;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                let window = 0.5 - 0.5 * (2.0 * PI * (n + 0.5) / len as f64).cos();
+                *c = sinc * window;
+            }
+
+            // normalize each branch to unity gain at DC
+            let sum: f64 = cs.iter().sum();
+            for c in cs.iter_mut() {
+                *c /= sum;
+            }
+        }
+
+        TruePeakMeter {
+            coefficients,
+            history: [0.0; TRUE_PEAK_TAPS],
+            pos: 0,
+        }
+    }
+
+    /// Adds a sample to the meter, returning the highest absolute value of the oversampled signal
+    /// around it
+    #[inline]
+    pub fn process(&mut self, v: f64) -> f32 {
+        self.pos = (self.pos + 1) % TRUE_PEAK_TAPS;
+        self.history[self.pos] = v;
+
+        let mut peak = 0f64;
+        for cs in &self.coefficients {
+            let mut acc = 0.0;
+            let mut idx = self.pos;
+            for c in cs {
+                acc += c * self.history[idx];
+                idx = if idx == 0 { TRUE_PEAK_TAPS - 1 } else { idx - 1 };
+            }
+            peak = peak.max(acc.abs());
+        }
+
+        peak as f32
+    }
+}
+
+/// The two-stage "K" frequency weighting from BS.1770 (a high shelf modelling the acoustic effect
+/// of the head followed by a high pass), with coefficients computed for the current sample rate
+#[derive(Copy, Clone)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> KWeighting {
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting { shelf, high_pass }
+    }
+
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+// loudness is measured over 100ms sub-blocks; momentary loudness covers 4 of these, short-term 30
+const SUB_BLOCKS_MOMENTARY: usize = 4;
+const SUB_BLOCKS_SHORT_TERM: usize = 30;
+
+// gating blocks are binned into a histogram so that integrated loudness can be measured over an
+// unbounded length of time without allocating
+const HISTOGRAM_MIN_LUFS: f64 = -70.0;
+const HISTOGRAM_MAX_LUFS: f64 = 10.0;
+const HISTOGRAM_BINS_PER_LU: f64 = 10.0;
+const HISTOGRAM_BINS: usize =
+    ((HISTOGRAM_MAX_LUFS - HISTOGRAM_MIN_LUFS) * HISTOGRAM_BINS_PER_LU) as usize;
+
+fn energy_to_lufs(energy: f64) -> f32 {
+    if energy <= 0.0 {
+        f32::NEG_INFINITY
+    } else {
+        (-0.691 + 10.0 * energy.log10()) as f32
+    }
+}
+
+/// Measures momentary, short-term and integrated loudness of a stereo signal according to
+/// ITU-R BS.1770-4 / EBU R 128
+pub struct LoudnessMeter {
+    weighting: [KWeighting; 2],
+
+    sub_block_len: usize,
+    sub_block_pos: usize,
+    sub_block_sum: f64,
+
+    // mean square energies of the most recent sub blocks
+    sub_blocks: [f64; SUB_BLOCKS_SHORT_TERM],
+    sub_block_idx: usize,
+    sub_block_count: usize,
+
+    histogram_counts: Vec<u64>,
+    histogram_energy: Vec<f64>,
+    integrated: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> LoudnessMeter {
+        let sample_rate = get_sample_rate() as f64;
+        LoudnessMeter {
+            weighting: [KWeighting::new(sample_rate); 2],
+            sub_block_len: ((sample_rate / 10.0) as usize).max(1),
+            sub_block_pos: 0,
+            sub_block_sum: 0.0,
+            sub_blocks: [0.0; SUB_BLOCKS_SHORT_TERM],
+            sub_block_idx: 0,
+            sub_block_count: 0,
+            histogram_counts: vec![0; HISTOGRAM_BINS],
+            histogram_energy: vec![0.0; HISTOGRAM_BINS],
+            integrated: f32::NEG_INFINITY,
+        }
+    }
+
+    pub fn process(&mut self, left: &[f64], right: &[f64]) {
+        for (l, r) in left.iter().zip(right) {
+            let l = self.weighting[0].process(*l);
+            let r = self.weighting[1].process(*r);
+            self.sub_block_sum += l * l + r * r;
+            self.sub_block_pos += 1;
+
+            if self.sub_block_pos == self.sub_block_len {
+                self.finish_sub_block();
+            }
+        }
+    }
+
+    fn finish_sub_block(&mut self) {
+        self.sub_block_idx = (self.sub_block_idx + 1) % SUB_BLOCKS_SHORT_TERM;
+        self.sub_blocks[self.sub_block_idx] = self.sub_block_sum / self.sub_block_len as f64;
+        self.sub_block_count += 1;
+        self.sub_block_pos = 0;
+        self.sub_block_sum = 0.0;
+
+        // each new sub block completes a 400ms gating block, overlapping the previous by 75%
+        if self.sub_block_count >= SUB_BLOCKS_MOMENTARY {
+            let energy = self.mean_energy(SUB_BLOCKS_MOMENTARY);
+            let lufs = energy_to_lufs(energy) as f64;
+            if lufs > HISTOGRAM_MIN_LUFS {
+                let bin = ((lufs - HISTOGRAM_MIN_LUFS) * HISTOGRAM_BINS_PER_LU) as usize;
+                let bin = bin.min(HISTOGRAM_BINS - 1);
+                self.histogram_counts[bin] += 1;
+                self.histogram_energy[bin] += energy;
+                self.integrated = self.compute_integrated();
+            }
+        }
+    }
+
+    fn mean_energy(&self, blocks: usize) -> f64 {
+        let blocks = blocks.min(self.sub_block_count);
+        if blocks == 0 {
+            return 0.0;
+        }
+
+        let mut sum = 0.0;
+        for i in 0..blocks {
+            let idx = (self.sub_block_idx + SUB_BLOCKS_SHORT_TERM - i) % SUB_BLOCKS_SHORT_TERM;
+            sum += self.sub_blocks[idx];
+        }
+        sum / blocks as f64
+    }
+
+    fn compute_integrated(&self) -> f32 {
+        // blocks below the absolute gate of -70 LUFS were never added to the histogram
+        let count: u64 = self.histogram_counts.iter().sum();
+        if count == 0 {
+            return f32::NEG_INFINITY;
+        }
+        let energy: f64 = self.histogram_energy.iter().sum();
+
+        // relative gate: ignore blocks more than 10 LU below the ungated loudness
+        let threshold = energy_to_lufs(energy / count as f64) as f64 - 10.0;
+        let first_bin = ((threshold - HISTOGRAM_MIN_LUFS) * HISTOGRAM_BINS_PER_LU)
+            .max(0.0)
+            .ceil() as usize;
+
+        let count: u64 = self.histogram_counts[first_bin.min(HISTOGRAM_BINS)..].iter().sum();
+        if count == 0 {
+            return f32::NEG_INFINITY;
+        }
+        let energy: f64 = self.histogram_energy[first_bin.min(HISTOGRAM_BINS)..].iter().sum();
+
+        energy_to_lufs(energy / count as f64)
+    }
+
+    /// Loudness over the last 400ms, in LUFS
+    pub fn momentary(&self) -> f32 {
+        if self.sub_block_count < SUB_BLOCKS_MOMENTARY {
+            return f32::NEG_INFINITY;
+        }
+        energy_to_lufs(self.mean_energy(SUB_BLOCKS_MOMENTARY))
+    }
+
+    /// Loudness over the last 3 seconds (or as much of that as has been measured), in LUFS
+    pub fn short_term(&self) -> f32 {
+        if self.sub_block_count < SUB_BLOCKS_MOMENTARY {
+            return f32::NEG_INFINITY;
+        }
+        energy_to_lufs(self.mean_energy(SUB_BLOCKS_SHORT_TERM))
+    }
+
+    /// Gated loudness since the meter was last reset, in LUFS
+    pub fn integrated(&self) -> f32 {
+        self.integrated
+    }
+
+    pub fn reset(&mut self) {
+        for c in self.histogram_counts.iter_mut() {
+            *c = 0;
+        }
+        for e in self.histogram_energy.iter_mut() {
+            *e = 0.0;
+        }
+        self.integrated = f32::NEG_INFINITY;
+    }
+}
@@ -1,14 +1,22 @@
 use crate::sample::PlayOutput::Done;
 use crate::sample::{Sample, SamplePlayer};
+use crate::smoothing::SmoothedValue;
 use crate::MetricStructure;
 
-use loopers_common::api::FrameTime;
+use crate::audio_file::{resample, AudioFile};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use loopers_common::api::{get_sample_rate, FrameTime};
+use loopers_common::music::MetronomePattern;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread;
 
 #[cfg(test)]
 mod tests {
-    use loopers_common::music::Tempo;
+    use loopers_common::music::{Subdivision, Tempo};
     use super::*;
+    use std::fs::create_dir_all;
+    use tempfile::tempdir;
 
     fn sample(v: f32, n: usize) -> Sample {
         Sample {
@@ -27,89 +35,436 @@ mod tests {
             MetricStructure::new(3, 4,
                                  Tempo::from_bpm(bpm)).unwrap(), normal, emphasis);
 
-        assert_eq!(0, met.time.0);
+        let mut time = FrameTime(0);
 
         let mut l = vec![1f32; 2];
         let mut r = vec![-1f32; 2];
         // play first half of emphasis beat
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![3f32, 3.0], l);
         assert_eq!(vec![-3f32, -3.0], r);
-        assert_eq!(2, met.time.0);
+        assert_eq!(2, time.0);
 
         // play second half of emphasis beat
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![5f32, 5.0], l);
         assert_eq!(vec![-5f32, -5.0], r);
-        assert_eq!(4, met.time.0);
+        assert_eq!(4, time.0);
 
         // play nothing
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![5f32, 5.0], l);
         assert_eq!(vec![-5f32, -5.0], r);
-        assert_eq!(6, met.time.0);
+        assert_eq!(6, time.0);
 
         // play nothing
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![5f32, 5.0], l);
         assert_eq!(vec![-5f32, -5.0], r);
-        assert_eq!(8, met.time.0);
+        assert_eq!(8, time.0);
 
         // play first half of normal beat
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![6f32, 6.0], l);
         assert_eq!(vec![-6f32, -6.0], r);
-        assert_eq!(10, met.time.0);
+        assert_eq!(10, time.0);
 
         // play second half of normal beat
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![7f32, 7.0], l);
         assert_eq!(vec![-7f32, -7.0], r);
-        assert_eq!(12, met.time.0);
+        assert_eq!(12, time.0);
 
         // play nothing twice
         for _ in 0..2 {
-            met.advance(&mut [&mut l, &mut r]);
+            met.advance(time, &mut [&mut l, &mut r]);
+            time.0 += l.len() as i64;
             assert_eq!(vec![7f32, 7.0], l);
             assert_eq!(vec![-7f32, -7.0], r);
         }
-        assert_eq!(16, met.time.0);
+        assert_eq!(16, time.0);
 
         // play first half of normal beat
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![8f32, 8.0], l);
         assert_eq!(vec![-8f32, -8.0], r);
-        assert_eq!(18, met.time.0);
+        assert_eq!(18, time.0);
 
         // play second half of normal beat
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![9f32, 9.0], l);
         assert_eq!(vec![-9f32, -9.0], r);
-        assert_eq!(20, met.time.0);
+        assert_eq!(20, time.0);
 
         // play nothing twice
         for _ in 0..2 {
-            met.advance(&mut [&mut l, &mut r]);
+            met.advance(time, &mut [&mut l, &mut r]);
+            time.0 += l.len() as i64;
             assert_eq!(vec![9f32, 9.0], l);
             assert_eq!(vec![-9f32, -9.0], r);
         }
-        assert_eq!(24, met.time.0);
+        assert_eq!(24, time.0);
 
         // and now we should be back to emphasis
-        met.advance(&mut [&mut l, &mut r]);
+        met.advance(time, &mut [&mut l, &mut r]);
+        time.0 += l.len() as i64;
         assert_eq!(vec![11f32, 11.0], l);
         assert_eq!(vec![-11f32, -11.0], r);
-        assert_eq!(26, met.time.0);
+        assert_eq!(26, time.0);
+    }
+
+    #[test]
+    fn test_pattern() {
+        let bpm = 60_000f32 / FrameTime(8).to_ms() as f32;
+        let mut met = Metronome::with_sounds(
+            MetricStructure::new(5, 8, Tempo::from_bpm(bpm)).unwrap(),
+            MetronomeSounds::new("Test", sample(1.0, 1), sample(2.0, 1), sample(3.0, 1)),
+        );
+        met.set_pattern(MetronomePattern {
+            subdivision: Subdivision::Eighths,
+            accents: MetronomePattern::accents_from_groups("3+2").unwrap(),
+        });
+
+        // play through two measures, four samples at a time (i.e., one click per call)
+        let mut time = FrameTime(0);
+        let mut clicks = vec![];
+        for _ in 0..20 {
+            let mut l = vec![0f32; 4];
+            let mut r = vec![0f32; 4];
+            met.advance(time, &mut [&mut l, &mut r]);
+            time.0 += l.len() as i64;
+            clicks.push(l[0] * 2.0);
+        }
+
+        assert_eq!(
+            vec![
+                2.0, 3.0, 1.0, 3.0, 1.0, 3.0, 2.0, 3.0, 1.0, 3.0,
+                2.0, 3.0, 1.0, 3.0, 1.0, 3.0, 2.0, 3.0, 1.0, 3.0,
+            ],
+            clicks
+        );
+    }
+
+    fn write_click(path: &Path, v: f32) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: get_sample_rate() as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for _ in 0..4 {
+            writer.write_sample(v).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_load_sound_pack() {
+        let dir = tempdir().unwrap();
+        let defaults = MetronomeSounds::new(
+            DEFAULT_SOUND,
+            sample(1.0, 4),
+            sample(2.0, 4),
+            sample(1.0, 4),
+        );
+
+        create_dir_all(dir.path().join("Clave")).unwrap();
+        write_click(&dir.path().join("Clave/beat.wav"), 0.25);
+        write_click(&dir.path().join("Clave/subdivision.wav"), 0.125);
+
+        let sounds = MetronomeSounds::load(&dir.path().join("Clave"), "Clave", &defaults).unwrap();
+        assert_eq!("Clave", *sounds.name);
+        assert_eq!(vec![0.25; 4], sounds.beat.buffer[0]);
+        // the beat sound is used for missing sounds
+        assert_eq!(vec![0.25; 4], sounds.emphasis.buffer[1]);
+        assert_eq!(vec![0.125; 4], sounds.subdivision.buffer[0]);
+
+        assert!(MetronomeSounds::load(&dir.path().join("Cowbell"), "Cowbell", &defaults).is_err());
+
+        // a pack without a beat sound falls back to the default
+        create_dir_all(dir.path().join("Accent")).unwrap();
+        write_click(&dir.path().join("Accent/emphasis.wav"), 0.5);
+        let sounds =
+            MetronomeSounds::load(&dir.path().join("Accent"), "Accent", &defaults).unwrap();
+        assert_eq!(vec![0.5; 4], sounds.emphasis.buffer[0]);
+        assert_eq!(vec![1.0; 4], sounds.beat.buffer[0]);
+    }
+
+    #[test]
+    fn test_set_sounds() {
+        let bpm = 60_000f32 / FrameTime(8).to_ms() as f32;
+        let mut met = Metronome::new(
+            MetricStructure::new(4, 4, Tempo::from_bpm(bpm)).unwrap(),
+            sample(1.0, 4),
+            sample(2.0, 4),
+        );
+
+        let mut l = vec![0f32; 2];
+        let mut r = vec![0f32; 2];
+        met.advance(FrameTime(0), &mut [&mut l, &mut r]);
+        assert_eq!(vec![1.0f32, 1.0], l);
+
+        let old = met.set_sounds(MetronomeSounds::new(
+            "Clave",
+            sample(3.0, 4),
+            sample(4.0, 4),
+            sample(3.0, 4),
+        ));
+        assert_eq!(DEFAULT_SOUND, *old.name);
+        assert_eq!("Clave", met.sound_name().as_str());
+
+        // the click that was already playing finishes with the old sound, and the next beat uses
+        // the new one
+        let mut l = vec![0f32; 8];
+        let mut r = vec![0f32; 8];
+        met.advance(FrameTime(2), &mut [&mut l, &mut r]);
+        assert_eq!(vec![1.0f32, 1.0, 0.0, 0.0, 0.0, 0.0, 1.5, 1.5], l);
+    }
+
+    #[test]
+    fn test_sample_accurate() {
+        let bpm = 60_000f32 / FrameTime(8).to_ms() as f32;
+        let mut met = Metronome::new(
+            MetricStructure::new(4, 4, Tempo::from_bpm(bpm)).unwrap(),
+            sample(1.0, 2),
+            sample(2.0, 2),
+        );
+        met.set_pattern(MetronomePattern {
+            subdivision: Subdivision::Eighths,
+            ..MetronomePattern::default()
+        });
+
+        // several clicks can start within a single block, each at its exact frame
+        let mut l = vec![0f32; 12];
+        let mut r = vec![0f32; 12];
+        met.advance(FrameTime(-3), &mut [&mut l, &mut r]);
+        assert_eq!(
+            vec![0.0f32, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.5],
+            l
+        );
+
+        // after a jump in time (e.g., from SetTime) the clicks follow the new time
+        met.reset();
+        let mut l = vec![0f32; 4];
+        let mut r = vec![0f32; 4];
+        met.advance(FrameTime(38), &mut [&mut l, &mut r]);
+        assert_eq!(vec![0.0f32, 0.0, 0.5, 0.5], l);
+
+        // and with a different tempo the clicks are placed according to it
+        met.set_metric_structure(MetricStructure::new(4, 4, Tempo::from_bpm(bpm / 2.0)).unwrap());
+        met.reset();
+        let mut l = vec![0f32; 8];
+        let mut r = vec![0f32; 8];
+        met.advance(FrameTime(60), &mut [&mut l, &mut r]);
+        assert_eq!(vec![0.0f32, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0], l);
+    }
+
+    #[test]
+    fn test_groove() {
+        let bpm = 60_000f32 / FrameTime(8).to_ms() as f32;
+        let mut ms = MetricStructure::new(4, 4, Tempo::from_bpm(bpm)).unwrap();
+        ms.groove.swing = 75;
+        let mut met = Metronome::new(ms, sample(1.0, 1), sample(2.0, 1));
+        met.set_pattern(MetronomePattern {
+            subdivision: Subdivision::Eighths,
+            ..MetronomePattern::default()
+        });
+
+        // the offbeat eighth is played three quarters of the way through the beat
+        let mut l = vec![0f32; 16];
+        let mut r = vec![0f32; 16];
+        met.advance(FrameTime(0), &mut [&mut l, &mut r]);
+        assert_eq!(
+            vec![
+                1.0f32, 0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0,
+                0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0,
+            ],
+            l
+        );
+    }
+}
+
+/// The name of the built-in metronome sounds
+pub const DEFAULT_SOUND: &str = "Default";
+
+// the sample rate of the built-in clicks
+const DEFAULT_SOUND_RATE: usize = 44100;
+
+const SOUND_EXTENSIONS: [&str; 2] = ["wav", "flac"];
+
+/// Returns the directory that holds metronome sound packs. Each pack is a directory containing
+/// audio files named `beat`, `emphasis` (for the first beat of the measure), and `subdivision`
+/// (for clicks between beats), in WAV or FLAC format.
+pub fn sound_pack_dir() -> PathBuf {
+    let mut path = dirs::config_dir().unwrap_or_default();
+    path.push("loopers/metronome");
+    path
+}
+
+/// The set of samples that the metronome plays
+#[derive(Clone)]
+pub struct MetronomeSounds {
+    // shared with the session saver, so that saving doesn't copy it
+    pub name: Arc<String>,
+    pub beat: Arc<Sample>,
+    pub emphasis: Arc<Sample>,
+    pub subdivision: Arc<Sample>,
+}
+
+impl MetronomeSounds {
+    pub fn new(name: &str, beat: Sample, emphasis: Sample, subdivision: Sample) -> MetronomeSounds {
+        MetronomeSounds {
+            name: Arc::new(name.to_string()),
+            beat: Arc::new(beat),
+            emphasis: Arc::new(emphasis),
+            subdivision: Arc::new(subdivision),
+        }
+    }
+
+    /// The built-in sounds, from the mono clicks compiled into the binary
+    pub fn built_in(beat: &[f32], emphasis: &[f32]) -> MetronomeSounds {
+        let rate = get_sample_rate();
+        let beat = Sample::from_mono(&resample(beat, DEFAULT_SOUND_RATE, rate));
+        let emphasis = Sample::from_mono(&resample(emphasis, DEFAULT_SOUND_RATE, rate));
+
+        MetronomeSounds {
+            name: Arc::new(DEFAULT_SOUND.to_string()),
+            subdivision: Arc::new(beat.clone()),
+            beat: Arc::new(beat),
+            emphasis: Arc::new(emphasis),
+        }
+    }
+
+    /// Loads a sound pack from `dir`. Sounds missing from the pack fall back to the pack's beat
+    /// sound, or to the corresponding sound in `defaults` if the pack has no beat sound.
+    pub fn load(dir: &Path, name: &str, defaults: &MetronomeSounds) -> Result<MetronomeSounds, String> {
+        if !dir.is_dir() {
+            return Err(format!(
+                "No metronome sound pack found at {}",
+                dir.to_string_lossy()
+            ));
+        }
+
+        let read = |sound: &str| -> Result<Option<Arc<Sample>>, String> {
+            let path = SOUND_EXTENSIONS
+                .iter()
+                .map(|ext| dir.join(format!("{}.{}", sound, ext)))
+                .find(|p| p.exists());
+
+            match path {
+                Some(path) => AudioFile::read(&path)
+                    .map(|f| Some(Arc::new(f.into_sample(get_sample_rate()))))
+                    .map_err(|e| {
+                        format!("Failed to read {}: {:?}", path.to_string_lossy(), e)
+                    }),
+                None => Ok(None),
+            }
+        };
+
+        let beat = read("beat")?;
+        let emphasis = read("emphasis")?;
+        let subdivision = read("subdivision")?;
+
+        if beat.is_none() && emphasis.is_none() && subdivision.is_none() {
+            return Err(format!(
+                "Metronome sound pack {} does not contain any sounds",
+                dir.to_string_lossy()
+            ));
+        }
+
+        Ok(MetronomeSounds {
+            name: Arc::new(name.to_string()),
+            emphasis: emphasis
+                .or_else(|| beat.clone())
+                .unwrap_or_else(|| defaults.emphasis.clone()),
+            subdivision: subdivision
+                .or_else(|| beat.clone())
+                .unwrap_or_else(|| defaults.subdivision.clone()),
+            beat: beat.unwrap_or_else(|| defaults.beat.clone()),
+        })
+    }
+}
+
+enum LoaderMessage {
+    Load(String),
+    // sounds that have been replaced, to be deallocated off of the audio thread
+    Drop(MetronomeSounds),
+}
+
+/// Loads metronome sound packs on a background thread, so that the audio thread never has to
+/// touch the disk
+pub struct SoundLoader {
+    requests: Sender<LoaderMessage>,
+    loaded: Receiver<Result<MetronomeSounds, String>>,
+}
+
+impl SoundLoader {
+    pub fn spawn(defaults: MetronomeSounds) -> SoundLoader {
+        let (request_tx, request_rx) = bounded(10);
+        let (loaded_tx, loaded_rx) = bounded(10);
+
+        thread::spawn(move || {
+            while let Ok(msg) = request_rx.recv() {
+                match msg {
+                    LoaderMessage::Load(name) => {
+                        let result = if name == DEFAULT_SOUND {
+                            Ok(defaults.clone())
+                        } else {
+                            MetronomeSounds::load(&sound_pack_dir().join(&name), &name, &defaults)
+                        };
+
+                        if loaded_tx.send(result).is_err() {
+                            break;
+                        }
+                    }
+                    LoaderMessage::Drop(sounds) => {
+                        debug!("Dropping metronome sounds {}", sounds.name);
+                    }
+                }
+            }
+            debug!("channel closed, stopping");
+        });
+
+        SoundLoader {
+            requests: request_tx,
+            loaded: loaded_rx,
+        }
+    }
+
+    /// Requests that the named sound pack be loaded; the result is returned by `poll`
+    pub fn load(&self, name: &str) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Load(name.to_string())) {
+            error!("Failed to request metronome sound {}: {}", name, e);
+        }
+    }
+
+    /// Returns the next sound pack that has finished loading, if any
+    pub fn poll(&self) -> Option<Result<MetronomeSounds, String>> {
+        self.loaded.try_recv().ok()
+    }
+
+    pub fn dispose(&self, sounds: MetronomeSounds) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Drop(sounds)) {
+            warn!("failed to send metronome sounds to loader: {}", e);
+        }
     }
 }
 
 pub struct Metronome {
     metric_structure: MetricStructure,
-    beat_normal: Arc<Sample>,
-    beat_emphasis: Arc<Sample>,
-    time: FrameTime,
+    pattern: MetronomePattern,
+    sounds: MetronomeSounds,
     player: Option<SamplePlayer>,
-    volume: f32,
+    volume: SmoothedValue,
 }
 
 impl Metronome {
@@ -118,16 +473,22 @@ impl Metronome {
         beat_normal: Sample,
         beat_emphasis: Sample,
     ) -> Metronome {
-        let beat_emphasis = Arc::new(beat_emphasis);
-        let player = SamplePlayer::new(beat_emphasis.clone());
+        let sounds = MetronomeSounds::new(
+            DEFAULT_SOUND,
+            beat_normal.clone(),
+            beat_emphasis,
+            beat_normal,
+        );
+        Self::with_sounds(metric_structure, sounds)
+    }
 
+    pub fn with_sounds(metric_structure: MetricStructure, sounds: MetronomeSounds) -> Metronome {
         Metronome {
             metric_structure,
-            beat_normal: Arc::new(beat_normal),
-            beat_emphasis,
-            time: FrameTime(0),
-            player: Some(player),
-            volume: 1.0,
+            pattern: MetronomePattern::default(),
+            sounds,
+            player: None,
+            volume: SmoothedValue::new(1.0, 0),
         }
     }
 
@@ -135,55 +496,103 @@ impl Metronome {
         self.metric_structure = ms;
     }
 
+    pub fn set_pattern(&mut self, pattern: MetronomePattern) {
+        self.pattern = pattern;
+    }
+
+    pub fn sound_name(&self) -> &Arc<String> {
+        &self.sounds.name
+    }
+
+    /// Replaces the sounds used for subsequent clicks, returning the previous ones so that they
+    /// can be dropped off of the audio thread
+    pub fn set_sounds(&mut self, sounds: MetronomeSounds) -> MetronomeSounds {
+        std::mem::replace(&mut self.sounds, sounds)
+    }
+
     pub fn get_volume(&self) -> f32 {
-        self.volume
+        self.volume.target()
     }
 
     pub fn set_volume(&mut self, volume: f32) {
-        self.volume = volume;
+        self.volume.set_target(volume);
     }
 
-    fn beat(&self) -> i64 {
-        self.metric_structure.tempo.beat(self.time)
+    /// Sets the number of samples over which volume changes are ramped
+    pub fn set_parameter_ramp(&mut self, samples: u32) {
+        self.volume.set_ramp_samples(samples);
     }
 
+    // the time at which the given click (counting subdivisions) starts; without a groove this is
+    // the first frame for which MetronomePattern::click returns the click
+    fn click_time(&self, click: i64) -> i64 {
+        let clicks = self.pattern.subdivision.clicks_per_beat() as i64;
+        self.metric_structure.grooved_time(click, clicks).0
+    }
+
+    fn sound_for(&self, click: i64) -> Arc<Sample> {
+        let clicks = self.pattern.subdivision.clicks_per_beat() as i64;
+        let beat = click.div_euclid(clicks);
+        let beat_of_measure = self.metric_structure.time_signature.beat_of_measure(beat);
+
+        if click.rem_euclid(clicks) != 0 {
+            self.sounds.subdivision.clone()
+        } else if self.pattern.is_accented(beat_of_measure) {
+            self.sounds.emphasis.clone()
+        } else {
+            self.sounds.beat.clone()
+        }
+    }
+
+    /// Stops any click that is currently playing
     pub fn reset(&mut self) {
-        self.time = FrameTime(0);
-        self.player = Some(SamplePlayer::new(self.beat_emphasis.clone()))
+        self.player = None;
     }
 
-    pub fn advance(&mut self, out: &mut [&mut [f32]; 2]) {
+    /// Mixes the metronome into `out`, which covers the block starting at `time`. Clicks are
+    /// started at the exact frame of each beat (or subdivision), computed from `time` so that the
+    /// metronome follows any jumps in the engine's time.
+    pub fn advance(&mut self, time: FrameTime, out: &mut [&mut [f32]; 2]) {
         assert_eq!(out[0].len(), out[1].len());
+        let len = out[0].len();
 
-        // TODO: it would be more accurate to do this analytically, i.e., use the current
-        //   time without relying on the exact timing of the calls
-        if let Some(player) = &mut self.player {
-            if player.play(out, self.volume / 2.0) == Done {
-                self.player = None;
-            }
+        // the groove can move clicks by up to a beat, so start looking for the next one a beat back
+        let clicks = self.pattern.subdivision.clicks_per_beat() as i64;
+        let mut next_click = self.pattern.click(self.metric_structure.tempo, time) - clicks;
+        while self.click_time(next_click) < time.0 {
+            next_click += 1;
         }
 
-        let len = out[0].len();
-
-        let cur_beat = self.beat();
-        self.time.0 += len as i64;
-        let next_beat = self.beat();
+        let mut pos = 0;
+        loop {
+            let start = ((self.click_time(next_click) - time.0).max(pos as i64) as usize).min(len);
+            self.play(out, pos, start);
+            pos = start;
 
-        // println!("{} -> {} / {} -> {}", cur_beat, next_beat, self.time.0 - len as i64, self.time.0);
+            if start >= len {
+                break;
+            }
 
-        if next_beat != cur_beat {
-            let sample = if self
-                .metric_structure
-                .time_signature
-                .beat_of_measure(next_beat)
-                == 0
-            {
-                self.beat_emphasis.clone()
-            } else {
-                self.beat_normal.clone()
-            };
+            self.player = Some(SamplePlayer::new(self.sound_for(next_click)));
+            next_click += 1;
+        }
+    }
 
-            self.player = Some(SamplePlayer::new(sample));
+    // plays the current click (if any) into out[from..to]
+    fn play(&mut self, out: &mut [&mut [f32]; 2], from: usize, to: usize) {
+        let mut ramped = 0;
+        if let Some(player) = &mut self.player {
+            let [l, r] = out;
+            let volume = &mut self.volume;
+            let done = player.play_with_gain(&mut [&mut l[from..to], &mut r[from..to]], || {
+                ramped += 1;
+                volume.next_value() / 2.0
+            });
+            if done == Done {
+                self.player = None;
+            }
         }
+        // keep the ramp moving even when nothing is playing
+        self.volume.skip(to - from - ramped);
     }
 }
@@ -0,0 +1,1176 @@
+use crate::clap_ffi::*;
+use crate::error::SaveLoadError;
+use crossbeam_channel::{bounded, Sender};
+use crossbeam_queue::ArrayQueue;
+use libloading::Library;
+use loopers_common::api::{get_sample_rate, PluginParamValue, SavedPlugin};
+use loopers_common::gui_channel::{GuiSender, LogMessage};
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr, CString};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // A simple gain plugin, built into the test binary so that we can exercise the host without
+    // needing any plugins installed
+    mod gain {
+        use super::super::*;
+        use std::mem::size_of;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        pub const GAIN_PARAM: u32 = 3;
+
+        struct Descriptor(clap_plugin_descriptor);
+        unsafe impl Sync for Descriptor {}
+
+        static DESCRIPTOR: Descriptor = Descriptor(clap_plugin_descriptor {
+            clap_version: CLAP_VERSION,
+            id: b"loopers.test.gain\0".as_ptr() as *const c_char,
+            name: b"Test Gain\0".as_ptr() as *const c_char,
+            vendor: ptr::null(),
+            url: ptr::null(),
+            manual_url: ptr::null(),
+            support_url: ptr::null(),
+            version: ptr::null(),
+            description: ptr::null(),
+            features: ptr::null(),
+        });
+
+        pub static ENTRY: clap_plugin_entry = clap_plugin_entry {
+            clap_version: CLAP_VERSION,
+            init: Some(init),
+            deinit: Some(deinit),
+            get_factory: Some(get_factory),
+        };
+
+        // the same plugin, but counting how often its entry is initialized and deinitialized
+        pub static COUNTED_ENTRY: clap_plugin_entry = clap_plugin_entry {
+            clap_version: CLAP_VERSION,
+            init: Some(counted_init),
+            deinit: Some(counted_deinit),
+            get_factory: Some(get_factory),
+        };
+
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+        static DEINITS: AtomicUsize = AtomicUsize::new(0);
+
+        pub fn counts() -> (usize, usize) {
+            (INITS.load(Ordering::SeqCst), DEINITS.load(Ordering::SeqCst))
+        }
+
+        static FACTORY: clap_plugin_factory = clap_plugin_factory {
+            get_plugin_count: Some(get_plugin_count),
+            get_plugin_descriptor: Some(get_plugin_descriptor),
+            create_plugin: Some(create_plugin),
+        };
+
+        static PARAMS: clap_plugin_params = clap_plugin_params {
+            count: Some(params_count),
+            get_info: Some(params_get_info),
+            get_value: None,
+            value_to_text: None,
+            text_to_value: None,
+            flush: None,
+        };
+
+        static STATE: clap_plugin_state = clap_plugin_state {
+            save: Some(state_save),
+            load: Some(state_load),
+        };
+
+        unsafe extern "C" fn init(_: *const c_char) -> bool {
+            true
+        }
+
+        unsafe extern "C" fn deinit() {}
+
+        unsafe extern "C" fn counted_init(_: *const c_char) -> bool {
+            INITS.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+
+        unsafe extern "C" fn counted_deinit() {
+            DEINITS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe extern "C" fn get_factory(id: *const c_char) -> *const c_void {
+            if CStr::from_ptr(id).to_bytes_with_nul() == CLAP_PLUGIN_FACTORY_ID {
+                &FACTORY as *const clap_plugin_factory as *const c_void
+            } else {
+                ptr::null()
+            }
+        }
+
+        unsafe extern "C" fn get_plugin_count(_: *const clap_plugin_factory) -> u32 {
+            1
+        }
+
+        unsafe extern "C" fn get_plugin_descriptor(
+            _: *const clap_plugin_factory,
+            _: u32,
+        ) -> *const clap_plugin_descriptor {
+            &DESCRIPTOR.0
+        }
+
+        unsafe extern "C" fn create_plugin(
+            _: *const clap_plugin_factory,
+            _: *const clap_host,
+            _: *const c_char,
+        ) -> *const clap_plugin {
+            Box::into_raw(Box::new(clap_plugin {
+                desc: &DESCRIPTOR.0,
+                plugin_data: Box::into_raw(Box::new(1.0f64)) as *mut c_void,
+                init: Some(plugin_init),
+                destroy: Some(plugin_destroy),
+                activate: Some(plugin_activate),
+                deactivate: None,
+                start_processing: Some(plugin_start_processing),
+                stop_processing: None,
+                reset: None,
+                process: Some(plugin_process),
+                get_extension: Some(plugin_get_extension),
+                on_main_thread: None,
+            }))
+        }
+
+        unsafe fn gain(plugin: *const clap_plugin) -> &'static mut f64 {
+            &mut *((*plugin).plugin_data as *mut f64)
+        }
+
+        unsafe extern "C" fn plugin_init(_: *const clap_plugin) -> bool {
+            true
+        }
+
+        unsafe extern "C" fn plugin_destroy(plugin: *const clap_plugin) {
+            drop(Box::from_raw((*plugin).plugin_data as *mut f64));
+            drop(Box::from_raw(plugin as *mut clap_plugin));
+        }
+
+        unsafe extern "C" fn plugin_activate(_: *const clap_plugin, _: f64, _: u32, _: u32) -> bool {
+            true
+        }
+
+        unsafe extern "C" fn plugin_start_processing(_: *const clap_plugin) -> bool {
+            true
+        }
+
+        unsafe extern "C" fn plugin_process(
+            plugin: *const clap_plugin,
+            process: *const clap_process,
+        ) -> i32 {
+            let process = &*process;
+            let events = &*process.in_events;
+            for i in 0..events.size.unwrap()(events) {
+                let header = events.get.unwrap()(events, i);
+                if (*header).type_ == CLAP_EVENT_PARAM_VALUE {
+                    let event = &*(header as *const clap_event_param_value);
+                    assert_eq!(GAIN_PARAM, event.param_id);
+                    *gain(plugin) = event.value;
+                }
+            }
+
+            let input = &*process.audio_inputs;
+            let output = &*process.audio_outputs;
+            for c in 0..2 {
+                let input = *input.data32.add(c);
+                let output = *output.data32.add(c);
+                for i in 0..process.frames_count as usize {
+                    *output.add(i) = *input.add(i) * *gain(plugin) as f32;
+                }
+            }
+
+            // CLAP_PROCESS_CONTINUE
+            1
+        }
+
+        unsafe extern "C" fn plugin_get_extension(
+            _: *const clap_plugin,
+            id: *const c_char,
+        ) -> *const c_void {
+            match CStr::from_ptr(id).to_bytes_with_nul() {
+                id if id == CLAP_EXT_PARAMS => &PARAMS as *const _ as *const c_void,
+                id if id == CLAP_EXT_STATE => &STATE as *const _ as *const c_void,
+                _ => ptr::null(),
+            }
+        }
+
+        unsafe extern "C" fn params_count(_: *const clap_plugin) -> u32 {
+            1
+        }
+
+        unsafe extern "C" fn params_get_info(
+            _: *const clap_plugin,
+            _: u32,
+            info: *mut clap_param_info,
+        ) -> bool {
+            (*info).id = GAIN_PARAM;
+            (*info).min_value = 0.0;
+            (*info).max_value = 2.0;
+            (*info).default_value = 1.0;
+            true
+        }
+
+        unsafe extern "C" fn state_save(
+            plugin: *const clap_plugin,
+            stream: *const clap_ostream,
+        ) -> bool {
+            let bytes = gain(plugin).to_le_bytes();
+            (*stream).write.unwrap()(stream, bytes.as_ptr() as *const c_void, size_of::<f64>() as u64)
+                == size_of::<f64>() as i64
+        }
+
+        unsafe extern "C" fn state_load(
+            plugin: *const clap_plugin,
+            stream: *const clap_istream,
+        ) -> bool {
+            let mut bytes = [0u8; 8];
+            if (*stream).read.unwrap()(stream, bytes.as_mut_ptr() as *mut c_void, 8) != 8 {
+                return false;
+            }
+            *gain(plugin) = f64::from_le_bytes(bytes);
+            true
+        }
+    }
+
+    fn load(state: Option<PathBuf>) -> Arc<PluginHandle> {
+        let plugin = SavedPlugin {
+            path: PathBuf::from("gain.clap"),
+            id: None,
+            state,
+        };
+        Arc::new(PluginHandle::from_entry(&gain::ENTRY, &plugin).unwrap())
+    }
+
+    #[test]
+    fn test_process_and_params() {
+        let handle = load(None);
+        assert_eq!("Test Gain", handle.name());
+        assert_eq!(Some(0.5), handle.param_value(gain::GAIN_PARAM, PluginParamValue::Plain(0.5)));
+        assert_eq!(Some(2.0), handle.param_value(gain::GAIN_PARAM, PluginParamValue::Plain(5.0)));
+        assert_eq!(
+            Some(1.0),
+            handle.param_value(gain::GAIN_PARAM, PluginParamValue::Normalized(0.5))
+        );
+        assert_eq!(None, handle.param_value(1, PluginParamValue::Plain(0.5)));
+
+        let mut instance = PluginInstance::new(handle);
+
+        // buffers longer than the maximum block size are processed in pieces
+        let mut l = vec![0.5f64; MAX_BLOCK + 10];
+        let mut r = vec![-0.5f64; MAX_BLOCK + 10];
+        instance.process(&mut l, &mut r);
+        assert!(l.iter().all(|v| *v == 0.5));
+        assert!(r.iter().all(|v| *v == -0.5));
+
+        instance.queue_param(gain::GAIN_PARAM, 0.5);
+        instance.process(&mut l, &mut r);
+        assert!(l.iter().all(|v| *v == 0.25));
+        assert!(r.iter().all(|v| *v == -0.25));
+    }
+
+    #[test]
+    fn test_library_initialized_once() {
+        let plugin = SavedPlugin {
+            path: PathBuf::from("counted_gain.clap"),
+            id: None,
+            state: None,
+        };
+
+        let first = PluginHandle::from_entry(&gain::COUNTED_ENTRY, &plugin).unwrap();
+        let second = PluginHandle::from_entry(&gain::COUNTED_ENTRY, &plugin).unwrap();
+        assert_eq!((1, 0), gain::counts());
+
+        // the library stays initialized while any of its plugins are alive
+        drop(first);
+        assert_eq!((1, 0), gain::counts());
+        drop(second);
+        assert_eq!((1, 1), gain::counts());
+
+        // and is initialized again if it's reopened
+        let third = PluginHandle::from_entry(&gain::COUNTED_ENTRY, &plugin).unwrap();
+        assert_eq!((2, 1), gain::counts());
+        drop(third);
+        assert_eq!((2, 2), gain::counts());
+    }
+
+    #[test]
+    fn test_rack() {
+        let (mut rack, mut chain) = PluginRack::new("test", GuiSender::disconnected());
+        rack.add(load(None), SavedPlugin {
+            path: PathBuf::from("gain.clap"),
+            id: None,
+            state: None,
+        });
+        rack.handle(RackMessage::SetParam(0, gain::GAIN_PARAM, PluginParamValue::Normalized(0.25)));
+        // invalid indices and parameters are ignored
+        rack.handle(RackMessage::SetParam(1, gain::GAIN_PARAM, PluginParamValue::Plain(0.0)));
+        rack.handle(RackMessage::SetParam(0, 1, PluginParamValue::Plain(0.0)));
+
+        let mut l = vec![1.0f64; 16];
+        let mut r = vec![1.0f64; 16];
+        let mut removed = vec![];
+        chain.process(&mut l, &mut r, |p| removed.push(p));
+        assert!(l.iter().chain(&r).all(|v| *v == 0.5));
+
+        // save and restore the plugin state
+        let dir = tempdir().unwrap();
+        let saved = rack.save(dir.path()).unwrap();
+        assert_eq!(1, saved.len());
+        let state = saved[0].state.clone().unwrap();
+        assert_eq!(PathBuf::from("plugin_test_0.state"), state);
+
+        let mut instance = PluginInstance::new(load(Some(dir.path().join(state))));
+        let mut l = vec![1.0f64; 16];
+        let mut r = vec![1.0f64; 16];
+        instance.process(&mut l, &mut r);
+        assert!(l.iter().chain(&r).all(|v| *v == 0.5));
+
+        rack.handle(RackMessage::Remove(0));
+        chain.process(&mut l, &mut r, |p| removed.push(p));
+        assert_eq!(1, removed.len());
+        assert!(rack.save(dir.path()).unwrap().is_empty());
+
+        // clearing the chain hands every plugin back, rather than destroying them in place
+        rack.add(load(None), SavedPlugin {
+            path: PathBuf::from("gain.clap"),
+            id: None,
+            state: None,
+        });
+        chain.process(&mut l, &mut r, |p| removed.push(p));
+        assert_eq!(1, removed.len());
+        chain.clear(|p| removed.push(p));
+        assert_eq!(2, removed.len());
+        let mut l = vec![1.0f64; 16];
+        let mut r = vec![1.0f64; 16];
+        chain.process(&mut l, &mut r, |p| removed.push(p));
+        assert!(l.iter().chain(&r).all(|v| *v == 1.0));
+    }
+}
+
+/// The maximum number of plugins in a single insert chain
+pub const MAX_PLUGINS: usize = 8;
+
+// the largest number of frames passed to a plugin at once; longer buffers are split up
+const MAX_BLOCK: usize = 2048;
+
+// the number of parameter changes that can be queued for a plugin between process calls
+const MAX_PENDING_EVENTS: usize = 64;
+
+unsafe extern "C" fn host_get_extension(_: *const clap_host, _: *const c_char) -> *const c_void {
+    ptr::null()
+}
+
+unsafe extern "C" fn host_request(_: *const clap_host) {}
+
+lazy_static! {
+    // the libraries that plugins are currently loaded from, by path
+    static ref LIBRARIES: Mutex<HashMap<PathBuf, Arc<PluginLibrary>>> = Mutex::new(HashMap::new());
+}
+
+/// A CLAP library that plugins have been created from. The spec only allows the entry to be
+/// initialized once per library, so every plugin loaded from the same path shares one of these;
+/// it's initialized when first opened and deinitialized once the last of its plugins is destroyed.
+struct PluginLibrary {
+    entry: *const clap_plugin_entry,
+    // keeps the entry's code loaded, so must be dropped after deinit
+    _library: Option<Library>,
+}
+
+unsafe impl Send for PluginLibrary {}
+unsafe impl Sync for PluginLibrary {}
+
+impl PluginLibrary {
+    // Returns the library at `path`, calling `load` to find its entry if it isn't already open
+    fn open<F>(path: &Path, load: F) -> Result<Arc<PluginLibrary>, String>
+    where
+        F: FnOnce() -> Result<(*const clap_plugin_entry, Option<Library>), String>,
+    {
+        let mut libraries = LIBRARIES.lock().unwrap();
+        if let Some(library) = libraries.get(path) {
+            return Ok(library.clone());
+        }
+
+        let (entry, library) = load()?;
+        let e = unsafe { &*entry };
+        if !e.clap_version.is_compatible() {
+            return Err(format!("Unsupported CLAP version {:?}", e.clap_version));
+        }
+
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| "Invalid plugin path".to_string())?;
+
+        if !unsafe { e.init.ok_or("Missing init")?(c_path.as_ptr()) } {
+            return Err(format!("Failed to initialize {}", path.to_string_lossy()));
+        }
+
+        let library = Arc::new(PluginLibrary {
+            entry,
+            _library: library,
+        });
+        libraries.insert(path.to_path_buf(), library.clone());
+        Ok(library)
+    }
+
+    // Gives up a reference to the library, closing it if no other plugins are using it. This
+    // happens under the lock so that the library can't be reopened before it's been deinitialized.
+    fn release(path: &Path, library: Arc<PluginLibrary>) {
+        let mut libraries = LIBRARIES.lock().unwrap();
+        drop(library);
+        if libraries.get(path).map(|l| Arc::strong_count(l) == 1).unwrap_or(false) {
+            libraries.remove(path);
+        }
+    }
+
+    fn entry(&self) -> &clap_plugin_entry {
+        unsafe { &*self.entry }
+    }
+}
+
+impl Drop for PluginLibrary {
+    fn drop(&mut self) {
+        if let Some(deinit) = self.entry().deinit {
+            unsafe { deinit() };
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct ParamInfo {
+    id: u32,
+    min: f64,
+    max: f64,
+}
+
+/// A loaded and activated CLAP plugin. Processing is done on the audio thread through a
+/// `PluginInstance`; everything else (loading, saving state and destroying the plugin) happens on
+/// the thread that owns the rack, which acts as the plugin's main thread.
+pub struct PluginHandle {
+    plugin: *const clap_plugin,
+    // the plugin holds on to the host, so it needs to stay at the same address
+    _host: Box<clap_host>,
+    activated: bool,
+    name: String,
+    // the channel counts of the main input and output ports
+    channels: [u32; 2],
+    params: Vec<ParamInfo>,
+    path: PathBuf,
+    // the library the plugin was created from, released once the plugin has been destroyed
+    library: Option<Arc<PluginLibrary>>,
+}
+
+// The plugin's functions are only called from the threads the CLAP spec allows
+unsafe impl Send for PluginHandle {}
+unsafe impl Sync for PluginHandle {}
+
+impl PluginHandle {
+    pub fn load(plugin: &SavedPlugin) -> Result<PluginHandle, String> {
+        let library = PluginLibrary::open(&plugin.path, || {
+            let library = unsafe { Library::new(&plugin.path) }
+                .map_err(|e| format!("Failed to load {}: {}", plugin.path.to_string_lossy(), e))?;
+
+            let entry = unsafe { library.get::<*const clap_plugin_entry>(b"clap_entry\0") }
+                .map(|e| *e)
+                .map_err(|e| {
+                    format!("{} is not a CLAP plugin: {}", plugin.path.to_string_lossy(), e)
+                })?;
+
+            Ok((entry, Some(library)))
+        })?;
+
+        Self::new(library, plugin)
+    }
+
+    #[cfg(test)]
+    fn from_entry(
+        entry: *const clap_plugin_entry,
+        plugin: &SavedPlugin,
+    ) -> Result<PluginHandle, String> {
+        Self::new(PluginLibrary::open(&plugin.path, || Ok((entry, None)))?, plugin)
+    }
+
+    fn new(library: Arc<PluginLibrary>, plugin: &SavedPlugin) -> Result<PluginHandle, String> {
+        let plugin_ptr = unsafe { Self::create(library.entry(), plugin) };
+        let (plugin_ptr, host) = match plugin_ptr {
+            Ok(p) => p,
+            Err(err) => {
+                PluginLibrary::release(&plugin.path, library);
+                return Err(err);
+            }
+        };
+
+        let name = unsafe { CStr::from_ptr((*(*plugin_ptr).desc).name) }
+            .to_string_lossy()
+            .to_string();
+
+        // from here on, dropping the handle will clean up the plugin
+        let mut handle = PluginHandle {
+            plugin: plugin_ptr,
+            _host: host,
+            activated: false,
+            name,
+            channels: [2, 2],
+            params: vec![],
+            path: plugin.path.clone(),
+            library: Some(library),
+        };
+
+        unsafe {
+            handle.init_ports()?;
+            handle.init_params();
+        }
+
+        if let Some(state) = &plugin.state {
+            handle.load_state(state)?;
+        }
+
+        let p = unsafe { &*handle.plugin };
+        if !unsafe {
+            p.activate.ok_or("Missing activate")?(
+                handle.plugin,
+                get_sample_rate() as f64,
+                1,
+                MAX_BLOCK as u32,
+            )
+        } {
+            return Err(format!("Failed to activate {}", handle.name));
+        }
+        handle.activated = true;
+
+        Ok(handle)
+    }
+
+    unsafe fn create(
+        entry: &clap_plugin_entry,
+        plugin: &SavedPlugin,
+    ) -> Result<(*const clap_plugin, Box<clap_host>), String> {
+        let factory = entry.get_factory.ok_or("Missing get_factory")?(
+            CLAP_PLUGIN_FACTORY_ID.as_ptr() as *const c_char,
+        ) as *const clap_plugin_factory;
+        if factory.is_null() {
+            return Err("Plugin does not have a plugin factory".to_string());
+        }
+        let f = &*factory;
+
+        let count = f.get_plugin_count.ok_or("Missing get_plugin_count")?(factory);
+        let get_descriptor = f.get_plugin_descriptor.ok_or("Missing get_plugin_descriptor")?;
+        let descriptor = (0..count)
+            .map(|i| get_descriptor(factory, i))
+            .filter(|d| !d.is_null())
+            .find(|d| match &plugin.id {
+                Some(id) => CStr::from_ptr((**d).id).to_string_lossy() == id.as_str(),
+                None => true,
+            })
+            .ok_or_else(|| match &plugin.id {
+                Some(id) => format!("No plugin with id {}", id),
+                None => "No plugins found".to_string(),
+            })?;
+
+        let host = Box::new(clap_host {
+            clap_version: CLAP_VERSION,
+            host_data: ptr::null_mut(),
+            name: b"loopers\0".as_ptr() as *const c_char,
+            vendor: b"loopers\0".as_ptr() as *const c_char,
+            url: b"https://github.com/mwylde/loopers\0".as_ptr() as *const c_char,
+            version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            get_extension: Some(host_get_extension),
+            request_restart: Some(host_request),
+            request_process: Some(host_request),
+            request_callback: Some(host_request),
+        });
+
+        let plugin = f.create_plugin.ok_or("Missing create_plugin")?(
+            factory,
+            &*host,
+            (*descriptor).id,
+        );
+        if plugin.is_null() {
+            return Err("Failed to create plugin".to_string());
+        }
+
+        if !(*plugin).init.map(|init| init(plugin)).unwrap_or(false) {
+            if let Some(destroy) = (*plugin).destroy {
+                destroy(plugin);
+            }
+            return Err("Failed to initialize plugin".to_string());
+        }
+
+        Ok((plugin, host))
+    }
+
+    unsafe fn extension<T>(&self, id: &[u8]) -> Option<&T> {
+        let get = (*self.plugin).get_extension?;
+        let ext = get(self.plugin, id.as_ptr() as *const c_char) as *const T;
+        ext.as_ref()
+    }
+
+    unsafe fn init_ports(&mut self) -> Result<(), String> {
+        let ports = match self.extension::<clap_plugin_audio_ports>(CLAP_EXT_AUDIO_PORTS) {
+            Some(ports) => ports,
+            // plugins without the extension are assumed to be stereo
+            None => return Ok(()),
+        };
+
+        let (count, get) = match (ports.count, ports.get) {
+            (Some(count), Some(get)) => (count, get),
+            _ => return Ok(()),
+        };
+
+        for (i, is_input) in [true, false].iter().enumerate() {
+            if count(self.plugin, *is_input) == 0 {
+                return Err(format!(
+                    "{} has no audio {}",
+                    self.name,
+                    if *is_input { "input" } else { "output" }
+                ));
+            }
+
+            let mut info: clap_audio_port_info = std::mem::zeroed();
+            if get(self.plugin, 0, *is_input, &mut info) {
+                if info.channel_count == 0 || info.channel_count > 2 {
+                    return Err(format!(
+                        "{} has {} channels, but only mono and stereo plugins are supported",
+                        self.name, info.channel_count
+                    ));
+                }
+                self.channels[i] = info.channel_count;
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn init_params(&mut self) {
+        let params = match self.extension::<clap_plugin_params>(CLAP_EXT_PARAMS) {
+            Some(params) => params,
+            None => return,
+        };
+
+        if let (Some(count), Some(get_info)) = (params.count, params.get_info) {
+            for i in 0..count(self.plugin) {
+                let mut info: clap_param_info = std::mem::zeroed();
+                if get_info(self.plugin, i, &mut info) {
+                    self.params.push(ParamInfo {
+                        id: info.id,
+                        min: info.min_value,
+                        max: info.max_value,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Converts a value for the given parameter into the plugin's units, clamping it to the
+    /// parameter's range. Returns None if the plugin has no such parameter.
+    pub fn param_value(&self, id: u32, value: PluginParamValue) -> Option<f64> {
+        let info = self.params.iter().find(|p| p.id == id)?;
+        Some(match value {
+            PluginParamValue::Plain(v) => v.max(info.min).min(info.max),
+            PluginParamValue::Normalized(v) => {
+                info.min + (v as f64).clamp(0.0, 1.0) * (info.max - info.min)
+            }
+        })
+    }
+
+    /// Writes the plugin's state to the given file, returning false if the plugin does not
+    /// support saving its state
+    pub fn save_state(&self, path: &Path) -> Result<bool, SaveLoadError> {
+        unsafe extern "C" fn write(
+            stream: *const clap_ostream,
+            buffer: *const c_void,
+            size: u64,
+        ) -> i64 {
+            let out = &mut *((*stream).ctx as *mut Vec<u8>);
+            out.extend_from_slice(std::slice::from_raw_parts(buffer as *const u8, size as usize));
+            size as i64
+        }
+
+        let save = match unsafe { self.extension::<clap_plugin_state>(CLAP_EXT_STATE) }
+            .and_then(|s| s.save)
+        {
+            Some(save) => save,
+            None => return Ok(false),
+        };
+
+        let mut data: Vec<u8> = vec![];
+        let stream = clap_ostream {
+            ctx: &mut data as *mut Vec<u8> as *mut c_void,
+            write: Some(write),
+        };
+
+        if !unsafe { save(self.plugin, &stream) } {
+            return Err(SaveLoadError::OtherError(format!(
+                "Failed to save state of {}",
+                self.name
+            )));
+        }
+
+        fs::write(path, &data)?;
+        Ok(true)
+    }
+
+    fn load_state(&self, path: &Path) -> Result<(), String> {
+        unsafe extern "C" fn read(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64 {
+            let input = &mut *((*stream).ctx as *mut &[u8]);
+            let n = (size as usize).min(input.len());
+            ptr::copy_nonoverlapping(input.as_ptr(), buffer as *mut u8, n);
+            *input = &input[n..];
+            n as i64
+        }
+
+        let load = unsafe { self.extension::<clap_plugin_state>(CLAP_EXT_STATE) }
+            .and_then(|s| s.load)
+            .ok_or(format!("{} does not support loading state", self.name))?;
+
+        let data = fs::read(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+        let mut remaining = &data[..];
+        let stream = clap_istream {
+            ctx: &mut remaining as *mut &[u8] as *mut c_void,
+            read: Some(read),
+        };
+
+        if unsafe { load(self.plugin, &stream) } {
+            Ok(())
+        } else {
+            Err(format!("Failed to load state of {}", self.name))
+        }
+    }
+}
+
+impl Drop for PluginHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let plugin = &*self.plugin;
+            if self.activated {
+                if let Some(deactivate) = plugin.deactivate {
+                    deactivate(self.plugin);
+                }
+            }
+            if let Some(destroy) = plugin.destroy {
+                destroy(self.plugin);
+            }
+        }
+
+        if let Some(library) = self.library.take() {
+            PluginLibrary::release(&self.path, library);
+        }
+    }
+}
+
+unsafe fn event_list<'a>(list: *const clap_input_events) -> &'a Vec<clap_event_param_value> {
+    &*((*list).ctx as *const Vec<clap_event_param_value>)
+}
+
+unsafe extern "C" fn events_size(list: *const clap_input_events) -> u32 {
+    event_list(list).len() as u32
+}
+
+unsafe extern "C" fn events_get(list: *const clap_input_events, index: u32) -> *const clap_event_header {
+    match event_list(list).get(index as usize) {
+        Some(event) => &event.header,
+        None => ptr::null(),
+    }
+}
+
+// we don't do anything with events sent by plugins
+unsafe extern "C" fn events_try_push(_: *const clap_output_events, _: *const clap_event_header) -> bool {
+    true
+}
+
+/// The audio thread's side of a plugin: owns the buffers used to pass audio to and from the
+/// plugin, and the parameter changes waiting to be sent to it
+pub struct PluginInstance {
+    handle: Arc<PluginHandle>,
+    processing: bool,
+    steady_time: i64,
+    input: [Vec<f32>; 2],
+    output: [Vec<f32>; 2],
+    events: Vec<clap_event_param_value>,
+}
+
+// The event list only holds raw pointers that the host never dereferences
+unsafe impl Send for PluginInstance {}
+
+impl PluginInstance {
+    pub fn new(handle: Arc<PluginHandle>) -> PluginInstance {
+        PluginInstance {
+            handle,
+            processing: false,
+            steady_time: 0,
+            input: [vec![0.0; MAX_BLOCK], vec![0.0; MAX_BLOCK]],
+            output: [vec![0.0; MAX_BLOCK], vec![0.0; MAX_BLOCK]],
+            events: Vec::with_capacity(MAX_PENDING_EVENTS),
+        }
+    }
+
+    /// Queues a parameter change (in the plugin's units) to be sent with the next process call
+    pub fn queue_param(&mut self, id: u32, value: f64) {
+        if self.events.len() >= MAX_PENDING_EVENTS {
+            warn!("too many pending parameter changes for {}", self.handle.name);
+            return;
+        }
+
+        self.events.push(clap_event_param_value {
+            header: clap_event_header {
+                size: std::mem::size_of::<clap_event_param_value>() as u32,
+                time: 0,
+                space_id: CLAP_CORE_EVENT_SPACE_ID,
+                type_: CLAP_EVENT_PARAM_VALUE,
+                flags: 0,
+            },
+            param_id: id,
+            cookie: ptr::null_mut(),
+            note_id: -1,
+            port_index: -1,
+            channel: -1,
+            key: -1,
+            value,
+        });
+    }
+
+    pub fn process(&mut self, left: &mut [f64], right: &mut [f64]) {
+        let plugin = unsafe { &*self.handle.plugin };
+
+        if !self.processing {
+            self.processing = plugin
+                .start_processing
+                .map(|start| unsafe { start(self.handle.plugin) })
+                .unwrap_or(true);
+            if !self.processing {
+                return;
+            }
+        }
+
+        let process = match plugin.process {
+            Some(process) => process,
+            None => return,
+        };
+
+        let [in_channels, out_channels] = self.handle.channels;
+
+        let mut start = 0;
+        while start < left.len() {
+            let n = (left.len() - start).min(MAX_BLOCK);
+            let l = &mut left[start..start + n];
+            let r = &mut right[start..start + n];
+
+            for i in 0..n {
+                if in_channels == 1 {
+                    self.input[0][i] = ((l[i] + r[i]) / 2.0) as f32;
+                } else {
+                    self.input[0][i] = l[i] as f32;
+                    self.input[1][i] = r[i] as f32;
+                }
+            }
+
+            let mut in_ptrs = [self.input[0].as_mut_ptr(), self.input[1].as_mut_ptr()];
+            let mut out_ptrs = [self.output[0].as_mut_ptr(), self.output[1].as_mut_ptr()];
+
+            let audio_in = clap_audio_buffer {
+                data32: in_ptrs.as_mut_ptr(),
+                data64: ptr::null_mut(),
+                channel_count: in_channels,
+                latency: 0,
+                constant_mask: 0,
+            };
+            let mut audio_out = clap_audio_buffer {
+                data32: out_ptrs.as_mut_ptr(),
+                data64: ptr::null_mut(),
+                channel_count: out_channels,
+                latency: 0,
+                constant_mask: 0,
+            };
+
+            let in_events = clap_input_events {
+                ctx: &self.events as *const Vec<clap_event_param_value> as *mut c_void,
+                size: Some(events_size),
+                get: Some(events_get),
+            };
+            let out_events = clap_output_events {
+                ctx: ptr::null_mut(),
+                try_push: Some(events_try_push),
+            };
+
+            let status = unsafe {
+                process(
+                    self.handle.plugin,
+                    &clap_process {
+                        steady_time: self.steady_time,
+                        frames_count: n as u32,
+                        transport: ptr::null(),
+                        audio_inputs: &audio_in,
+                        audio_outputs: &mut audio_out,
+                        audio_inputs_count: 1,
+                        audio_outputs_count: 1,
+                        in_events: &in_events,
+                        out_events: &out_events,
+                    },
+                )
+            };
+
+            self.events.clear();
+            self.steady_time += n as i64;
+
+            if status != CLAP_PROCESS_ERROR {
+                let right_channel = if out_channels == 1 { 0 } else { 1 };
+                for i in 0..n {
+                    l[i] = self.output[0][i] as f64;
+                    r[i] = self.output[right_channel][i] as f64;
+                }
+            }
+
+            start += n;
+        }
+    }
+
+    // stop_processing must be called from the audio thread
+    fn stop(&mut self) {
+        if self.processing {
+            if let Some(stop) = unsafe { &*self.handle.plugin }.stop_processing {
+                unsafe { stop(self.handle.plugin) };
+            }
+            self.processing = false;
+        }
+    }
+}
+
+impl Drop for PluginInstance {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Debug for PluginInstance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PluginInstance<{}>", self.handle.name)
+    }
+}
+
+/// A change to a plugin chain, prepared by its rack and applied in order by the audio thread
+#[derive(Debug)]
+pub enum PluginChange {
+    Add(PluginInstance),
+    Remove(usize),
+    Clear,
+    SetParam(usize, u32, f64),
+}
+
+/// The audio thread's side of a plugin rack
+pub struct PluginChain {
+    plugins: Vec<PluginInstance>,
+    changes: Arc<ArrayQueue<PluginChange>>,
+}
+
+impl PluginChain {
+    /// Applies any pending changes, then runs the buffers through the plugins. Plugins that are
+    /// removed are passed to `removed` so that the caller can hand them back to the rack to be
+    /// destroyed.
+    pub fn process<F: FnMut(PluginInstance)>(
+        &mut self,
+        left: &mut [f64],
+        right: &mut [f64],
+        mut removed: F,
+    ) {
+        while let Some(change) = self.changes.pop() {
+            match change {
+                PluginChange::Add(plugin) => {
+                    if self.plugins.len() < MAX_PLUGINS {
+                        self.plugins.push(plugin);
+                    } else {
+                        removed(plugin);
+                    }
+                }
+                PluginChange::Remove(idx) => {
+                    if idx < self.plugins.len() {
+                        let mut plugin = self.plugins.remove(idx);
+                        plugin.stop();
+                        removed(plugin);
+                    }
+                }
+                PluginChange::Clear => self.clear(&mut removed),
+                PluginChange::SetParam(idx, id, value) => {
+                    if let Some(plugin) = self.plugins.get_mut(idx) {
+                        plugin.queue_param(id, value);
+                    }
+                }
+            }
+        }
+
+        for plugin in &mut self.plugins {
+            plugin.process(left, right);
+        }
+    }
+
+    /// Stops all of the plugins and passes them to `removed`, so that they can be destroyed by the
+    /// rack before the chain is dropped. Plugins must not be destroyed on the audio thread.
+    pub fn clear<F: FnMut(PluginInstance)>(&mut self, mut removed: F) {
+        while let Some(mut plugin) = self.plugins.pop() {
+            plugin.stop();
+            removed(plugin);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RackMessage {
+    // plugin state paths must be absolute
+    Add(SavedPlugin),
+    Remove(usize),
+    Clear,
+    SetParam(usize, u32, PluginParamValue),
+    Save(PathBuf, Sender<Result<Vec<SavedPlugin>, SaveLoadError>>),
+    // a plugin removed by the audio thread, to be destroyed
+    Drop(PluginInstance),
+}
+
+/// Manages a chain of plugin inserts (either for a looper or for the master output). The rack
+/// loads and destroys plugins and saves their state, and passes changes on to its `PluginChain`
+/// on the audio thread.
+pub struct PluginRack {
+    name: String,
+    plugins: Vec<(SavedPlugin, Arc<PluginHandle>)>,
+    changes: Arc<ArrayQueue<PluginChange>>,
+    gui_sender: GuiSender,
+}
+
+impl PluginRack {
+    /// Creates a new rack; `name` is used to name the files holding plugin state
+    pub fn new(name: &str, gui_sender: GuiSender) -> (PluginRack, PluginChain) {
+        let changes = Arc::new(ArrayQueue::new(MAX_PLUGINS * 8));
+
+        (
+            PluginRack {
+                name: name.to_string(),
+                plugins: vec![],
+                changes: changes.clone(),
+                gui_sender,
+            },
+            PluginChain {
+                plugins: Vec::with_capacity(MAX_PLUGINS),
+                changes,
+            },
+        )
+    }
+
+    /// Creates a rack that runs on its own thread, controlled through the returned channel
+    pub fn spawn(name: &str, gui_sender: GuiSender) -> (Sender<RackMessage>, PluginChain) {
+        let (mut rack, chain) = Self::new(name, gui_sender);
+        let (tx, rx) = bounded(100);
+
+        thread::spawn(move || {
+            while let Ok(msg) = rx.recv() {
+                rack.handle(msg);
+            }
+            debug!("channel closed, stopping");
+        });
+
+        (tx, chain)
+    }
+
+    pub fn handle(&mut self, msg: RackMessage) {
+        match msg {
+            RackMessage::Add(plugin) => {
+                if self.plugins.len() >= MAX_PLUGINS {
+                    self.report(format!("Can't add more than {} plugins", MAX_PLUGINS));
+                    return;
+                }
+
+                match PluginHandle::load(&plugin) {
+                    Ok(handle) => self.add(Arc::new(handle), plugin),
+                    Err(e) => self.report(e),
+                }
+            }
+            RackMessage::Remove(idx) => {
+                if idx >= self.plugins.len() {
+                    warn!("No plugin at index {} in {}", idx, self.name);
+                } else if self.send(PluginChange::Remove(idx)) {
+                    self.plugins.remove(idx);
+                }
+            }
+            RackMessage::Clear => {
+                if self.send(PluginChange::Clear) {
+                    self.plugins.clear();
+                }
+            }
+            RackMessage::SetParam(idx, id, value) => {
+                match self.plugins.get(idx).map(|(_, h)| h.param_value(id, value)) {
+                    Some(Some(v)) => {
+                        self.send(PluginChange::SetParam(idx, id, v));
+                    }
+                    Some(None) => warn!("Plugin {} has no parameter {}", idx, id),
+                    None => warn!("No plugin at index {} in {}", idx, self.name),
+                }
+            }
+            RackMessage::Save(path, channel) => {
+                if let Err(e) = channel.try_send(self.save(&path)) {
+                    warn!("failed to respond to save request: {:?}", e);
+                }
+            }
+            RackMessage::Drop(plugin) => {
+                debug!("Dropping {:?}", plugin);
+            }
+        }
+    }
+
+    fn add(&mut self, handle: Arc<PluginHandle>, mut plugin: SavedPlugin) {
+        info!("Loaded plugin {} into {}", handle.name(), self.name);
+        if self.send(PluginChange::Add(PluginInstance::new(handle.clone()))) {
+            plugin.state = None;
+            self.plugins.push((plugin, handle));
+        }
+    }
+
+    fn send(&mut self, change: PluginChange) -> bool {
+        if let Err(change) = self.changes.push(change) {
+            warn!("Plugin queue full in {}, dropping {:?}", self.name, change);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Saves the state of each plugin into `dir`
+    pub fn save(&self, dir: &Path) -> Result<Vec<SavedPlugin>, SaveLoadError> {
+        let mut saved = Vec::with_capacity(self.plugins.len());
+        for (i, (plugin, handle)) in self.plugins.iter().enumerate() {
+            let name = format!("plugin_{}_{}.state", self.name, i);
+            let state = if handle.save_state(&dir.join(&name))? {
+                // use the relative path so that the directory can be moved and still be valid
+                Some(PathBuf::from(name))
+            } else {
+                None
+            };
+
+            saved.push(SavedPlugin {
+                state,
+                ..plugin.clone()
+            });
+        }
+
+        Ok(saved)
+    }
+
+    fn report(&mut self, message: String) {
+        error!("{}", message);
+        let mut log = LogMessage::error();
+        if let Err(e) = write!(log, "{}", message) {
+            error!("Failed to write error message: {}", e);
+        } else {
+            self.gui_sender.send_log(log);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{Debug, Formatter};
+use std::hash::Hasher;
 use loopers_common::api::LooperSpeed;
 use itertools::Itertools;
 
@@ -151,6 +153,27 @@ mod tests {
             r
         );
     }
+
+    #[test]
+    fn test_content_hash() {
+        let mut sample = Sample::from_mono(&[1.0f32; 5000]);
+        let hash = sample.content_hash();
+        assert_eq!(hash, sample.clone().content_hash());
+
+        sample.buffer[1][4999] = 0.0;
+        assert_ne!(hash, sample.content_hash());
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut sample = Sample::from_mono(&[2.0f32; 6]);
+        sample.resize(4, 2);
+        assert_eq!(vec![1.0f32; 4], sample.buffer[0]);
+
+        sample.resize(6, 2);
+        assert_eq!(6, sample.length());
+        assert_eq!(vec![1.0f32, 1.0, 0.5, 0.0, 0.0, 0.0], sample.buffer[1]);
+    }
 }
 
 #[allow(dead_code)]
@@ -258,6 +281,40 @@ impl Sample {
         }
     }
 
+    /// Changes the length of the sample by trimming its end or padding it with silence. When
+    /// padding, the end of the existing audio is faded out over `fade_size` samples so that it
+    /// doesn't stop abruptly.
+    pub fn resize(&mut self, len: u64, fade_size: usize) {
+        let old_len = self.length() as usize;
+        let len = len as usize;
+        let fade_size = fade_size.min(old_len);
+
+        for b in self.buffer.iter_mut() {
+            if len > old_len {
+                for (i, v) in b[old_len - fade_size..].iter_mut().enumerate() {
+                    *v *= linear(1.0 - (i + 1) as f32 / fade_size as f32);
+                }
+            }
+            b.resize(len, 0.0);
+        }
+    }
+
+    /// A hash of the audio in the sample, used to tell whether it has changed since it was saved
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut bytes = [0u8; 4096];
+        for b in self.buffer.iter() {
+            hasher.write_u64(b.len() as u64);
+            for chunk in b.chunks(bytes.len() / 4) {
+                for (v, out) in chunk.iter().zip(bytes.chunks_exact_mut(4)) {
+                    out.copy_from_slice(&v.to_le_bytes());
+                }
+                hasher.write(&bytes[..chunk.len() * 4]);
+            }
+        }
+        hasher.finish()
+    }
+
     pub fn clear(&mut self) {
         for b in self.buffer.iter_mut() {
             b.iter_mut().for_each(|m| *m = 0.0);
@@ -315,6 +372,16 @@ impl SamplePlayer {
     }
 
     pub fn play(&mut self, out: &mut [&mut [f32]; 2], volume: f32) -> PlayOutput {
+        self.play_with_gain(out, || volume)
+    }
+
+    /// Like `play`, but calls `gain` once per output frame to determine the volume, which allows
+    /// the volume to change smoothly while the sample is playing
+    pub fn play_with_gain<F: FnMut() -> f32>(
+        &mut self,
+        out: &mut [&mut [f32]; 2],
+        mut gain: F,
+    ) -> PlayOutput {
         for i in 0..out[0].len() {
             let t = self.time + i;
 
@@ -322,6 +389,7 @@ impl SamplePlayer {
                 return PlayOutput::Done;
             }
 
+            let volume = gain();
             out[0][i] += self.sample.buffer[0][t] * volume;
             out[1][i] += self.sample.buffer[1][t] * volume;
         }
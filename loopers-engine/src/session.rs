@@ -10,9 +10,16 @@ use std::path::PathBuf;
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::aux_bus::AuxMessage;
 use crate::error::SaveLoadError;
-use loopers_common::api::{QuantizationMode, SavedSession};
+use crate::plugin::RackMessage;
+use loopers_common::api::{
+    Names, OutputProtection, Part, QuantizationMode, SavedSession, Scene,
+};
+use loopers_common::config::SessionAudioFormat;
 use loopers_common::gui_channel::{GuiSender, LogMessage};
+use loopers_common::music::MetronomePattern;
+use loopers_common::song::{Song, SONG_FILE};
 use std::sync::Arc;
 
 const LOOPER_SAVE_TIMEOUT: Duration = Duration::from_secs(10);
@@ -20,9 +27,20 @@ const LOOPER_SAVE_TIMEOUT: Duration = Duration::from_secs(10);
 pub struct SaveSessionData {
     pub metric_structure: MetricStructure,
     pub metronome_volume: u8,
+    pub metronome_sound: Option<Arc<String>>,
+    pub metronome_pattern: MetronomePattern,
     pub sync_mode: QuantizationMode,
+    pub master_gain: f32,
+    pub output_protection: OutputProtection,
+    pub master_plugins: Sender<RackMessage>,
+    pub aux_buses: Arc<Vec<Sender<AuxMessage>>>,
+    pub song: Arc<Song>,
+    pub parts: Arc<Vec<Part>>,
+    pub names: Arc<Names>,
+    pub scenes: Arc<Vec<Scene>>,
     pub path: Arc<PathBuf>,
     pub sample_rate: usize,
+    pub audio_format: SessionAudioFormat,
 }
 
 pub enum SessionCommand {
@@ -83,20 +101,52 @@ impl SessionSaver {
 
         create_dir_all(&path)?;
 
+        let names = sd.names;
         let mut session = SavedSession {
             save_time: now.timestamp_millis(),
             metric_structure: sd.metric_structure.to_saved(),
             metronome_volume: sd.metronome_volume,
+            metronome_sound: sd.metronome_sound.as_deref().cloned(),
+            metronome_pattern: sd.metronome_pattern,
             sync_mode: sd.sync_mode,
             sample_rate: sd.sample_rate,
+            master_gain: sd.master_gain,
+            output_protection: sd.output_protection,
+            master_plugins: vec![],
+            aux_buses: Vec::with_capacity(sd.aux_buses.len()),
+            song: None,
+            parts: sd
+                .parts
+                .iter()
+                .filter_map(|p| names.parts.name(p.0).cloned())
+                .collect(),
+            scenes: sd.scenes.to_vec(),
             loopers: Vec::with_capacity(loopers.len()),
         };
 
+        let (tx, master_plugins) = bounded(1);
+        sd.master_plugins
+            .send(RackMessage::Save(path.clone(), tx))
+            .map_err(|_| SaveLoadError::ChannelClosed)?;
+
+        let mut aux_buses = vec![];
+        for bus in sd.aux_buses.iter() {
+            let (tx, rx) = bounded(1);
+            bus.send(AuxMessage::Save(path.clone(), tx))
+                .map_err(|_| SaveLoadError::ChannelClosed)?;
+            aux_buses.push(rx);
+        }
+
         let mut channels = vec![];
         for (id, l) in loopers.iter() {
             let (tx, rx) = bounded(1);
 
-            l.send(looper::ControlMessage::Serialize(path.clone(), tx))
+            l.send(looper::ControlMessage::Serialize(
+                path.clone(),
+                sd.audio_format,
+                names.clone(),
+                tx,
+            ))
                 .map_err(|_f| SaveLoadError::LooperSaveError(*id))?;
 
             channels.push((*id, rx));
@@ -115,6 +165,22 @@ impl SessionSaver {
                 .ok_or(SaveLoadError::LooperTimeoutError)?;
         }
 
+        session.master_plugins = master_plugins
+            .recv_timeout(timeout)
+            .map_err(|_| SaveLoadError::OtherError("Failed to save master plugins".to_string()))??;
+
+        for (i, rx) in aux_buses.into_iter().enumerate() {
+            session.aux_buses.push(rx.recv_timeout(timeout).map_err(|_| {
+                SaveLoadError::OtherError(format!("Failed to save aux bus {}", i))
+            })??);
+        }
+
+        if !sd.song.is_empty() {
+            let mut file = File::create(path.join(SONG_FILE))?;
+            write!(file, "{}", sd.song.to_text())?;
+            session.song = Some(SONG_FILE.to_string());
+        }
+
         path.push("project.loopers");
         let mut file = File::create(&path)?;
 
@@ -0,0 +1,135 @@
+use crate::error::SaveLoadError;
+use crate::looper::Looper;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use loopers_common::api::{get_sample_rate, SavedSession};
+use loopers_common::gui_channel::{GuiSender, LogMessage};
+use std::fs::read_to_string;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+enum LoaderMessage {
+    Load(Arc<PathBuf>),
+    // the remains of a session that has been restored, and the loopers it replaced, to be
+    // deallocated off of the audio thread
+    Drop(Box<SavedSession>, Vec<Looper>),
+}
+
+/// A session that has been read by the `SessionLoader`, with its loops decoded (and resampled to
+/// the engine's sample rate) into loopers that are ready to be started
+pub struct LoadedSession {
+    // boxed so that it can be sent back to be dropped without copying it around
+    pub session: Box<SavedSession>,
+    // the directory the session was saved to, which its other files are relative to
+    pub dir: PathBuf,
+    // the restored loopers, in the same order as `session.loopers`
+    pub loopers: Vec<Looper>,
+}
+
+/// Reads sessions and decodes their loops on a background thread, so that loading a session
+/// doesn't interrupt the audio thread
+pub struct SessionLoader {
+    requests: Sender<LoaderMessage>,
+    loaded: Receiver<Result<LoadedSession, SaveLoadError>>,
+}
+
+impl SessionLoader {
+    pub fn spawn(mut gui_sender: GuiSender) -> SessionLoader {
+        let (request_tx, request_rx) = bounded(10);
+        let (loaded_tx, loaded_rx) = bounded(10);
+
+        thread::spawn(move || {
+            while let Ok(msg) = request_rx.recv() {
+                match msg {
+                    LoaderMessage::Load(path) => {
+                        let result = read_session(&path, &mut gui_sender);
+                        if loaded_tx.send(result).is_err() {
+                            break;
+                        }
+                    }
+                    LoaderMessage::Drop(session, loopers) => {
+                        debug!(
+                            "Dropping {} restored and {} replaced loopers",
+                            session.loopers.len(),
+                            loopers.len()
+                        );
+                    }
+                }
+            }
+            debug!("channel closed, stopping");
+        });
+
+        SessionLoader {
+            requests: request_tx,
+            loaded: loaded_rx,
+        }
+    }
+
+    /// Requests that the session at `path` be loaded; the result is returned by `poll`
+    pub fn load(&self, path: Arc<PathBuf>) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Load(path)) {
+            error!("Failed to request session: {}", e);
+        }
+    }
+
+    /// Returns the next session that has finished loading, if any
+    pub fn poll(&self) -> Option<Result<LoadedSession, SaveLoadError>> {
+        self.loaded.try_recv().ok()
+    }
+
+    pub fn dispose(&self, session: Box<SavedSession>, loopers: Vec<Looper>) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Drop(session, loopers)) {
+            warn!("failed to send session to loader: {}", e);
+        }
+    }
+}
+
+fn read_session(path: &Path, gui_sender: &mut GuiSender) -> Result<LoadedSession, SaveLoadError> {
+    let contents = read_to_string(path)?;
+    let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+    let mut session: SavedSession = serde_json::from_str(&contents).map_err(|err| {
+        warn!("Found invalid SavedSession during load: {:?}", err);
+        // TODO: improve these error messages
+        SaveLoadError::OtherError("Failed to restore session; file is invalid".to_string())
+    })?;
+
+    // sessions from older versions don't record their sample rate
+    if session.sample_rate != 0 && session.sample_rate != get_sample_rate() {
+        let mut log = LogMessage::new();
+        if write!(
+            &mut log,
+            "Resampling session from {} Hz to {} Hz",
+            session.sample_rate,
+            get_sample_rate()
+        )
+        .is_err()
+        {
+            error!("Different sample rate");
+        } else {
+            gui_sender.send_log(log);
+        }
+    }
+
+    debug!("Restoring session: {:?}", session);
+
+    session.loopers.sort_by_key(|l| l.id);
+
+    let mut loopers = Vec::with_capacity(session.loopers.len());
+    for l in &session.loopers {
+        debug!("Restoring looper {}", l.id);
+        loopers.push(Looper::from_serialized(
+            l,
+            &dir,
+            session.sample_rate,
+            gui_sender.clone(),
+        )?);
+    }
+
+    Ok(LoadedSession {
+        session: Box::new(session),
+        dir,
+        loopers,
+    })
+}
@@ -0,0 +1,130 @@
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ramp() {
+        let mut v = SmoothedValue::new(0.0, 4);
+        assert_eq!(0.0, v.next_value());
+
+        v.set_target(1.0);
+        assert!(v.is_ramping());
+        assert_eq!(0.25, v.next_value());
+        assert_eq!(0.5, v.next_value());
+        assert_eq!(0.75, v.next_value());
+        assert_eq!(1.0, v.next_value());
+        assert!(!v.is_ramping());
+        assert_eq!(1.0, v.next_value());
+    }
+
+    #[test]
+    fn test_retarget_mid_ramp() {
+        let mut v = SmoothedValue::new(0.0, 4);
+        v.set_target(1.0);
+        v.next_value();
+        v.next_value();
+
+        // ramps from where we currently are, not from the previous start
+        v.set_target(0.0);
+        assert_eq!(0.375, v.next_value());
+        assert_eq!(0.25, v.next_value());
+        assert_eq!(0.125, v.next_value());
+        assert_eq!(0.0, v.next_value());
+    }
+
+    #[test]
+    fn test_skip() {
+        let mut v = SmoothedValue::new(1.0, 8);
+        v.set_target(0.0);
+        v.skip(4);
+        assert_eq!(0.5, v.current());
+        v.skip(100);
+        assert_eq!(0.0, v.current());
+        assert!(!v.is_ramping());
+    }
+
+    #[test]
+    fn test_no_ramp() {
+        let mut v = SmoothedValue::new(0.0, 0);
+        v.set_target(0.5);
+        assert!(!v.is_ramping());
+        assert_eq!(0.5, v.next_value());
+    }
+}
+
+/// A parameter that moves linearly to a new target value over a fixed number of samples rather
+/// than jumping to it immediately, which avoids zipper noise when the value is being swept (for
+/// example from an expression pedal).
+#[derive(Clone, Copy, Debug)]
+pub struct SmoothedValue {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: u32,
+    ramp_samples: u32,
+}
+
+impl SmoothedValue {
+    pub fn new(value: f32, ramp_samples: u32) -> SmoothedValue {
+        SmoothedValue {
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+            ramp_samples,
+        }
+    }
+
+    pub fn set_ramp_samples(&mut self, ramp_samples: u32) {
+        self.ramp_samples = ramp_samples;
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+        if self.ramp_samples == 0 {
+            self.current = target;
+            self.remaining = 0;
+        } else {
+            self.step = (target - self.current) / self.ramp_samples as f32;
+            self.remaining = self.ramp_samples;
+        }
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Advances the ramp by one sample and returns the new value
+    #[inline]
+    pub fn next_value(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                // land exactly on the target to avoid accumulating rounding error
+                self.current = self.target;
+            } else {
+                self.current += self.step;
+            }
+        }
+        self.current
+    }
+
+    /// Advances the ramp by `samples` without producing values
+    pub fn skip(&mut self, samples: usize) {
+        if samples as u64 >= self.remaining as u64 {
+            self.current = self.target;
+            self.remaining = 0;
+        } else {
+            self.remaining -= samples as u32;
+            self.current += self.step * samples as f32;
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crossbeam_channel::{bounded, Receiver, Sender};
+use loopers_common::song::Song;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+enum LoaderMessage {
+    Load(Arc<PathBuf>),
+    // replaces the song with an empty one
+    Clear,
+    // songs that have been replaced, to be deallocated off of the audio thread
+    Drop(Arc<Song>),
+}
+
+/// A song that has been read by the `SongLoader`, along with a copy for the GUI so that the audio
+/// thread doesn't need to clone it. The engine's copy is shared with the session saver.
+pub struct LoadedSong {
+    pub song: Arc<Song>,
+    pub gui_copy: Song,
+}
+
+/// Reads and parses song files on a background thread, so that the audio thread never has to
+/// touch the disk
+pub struct SongLoader {
+    requests: Sender<LoaderMessage>,
+    loaded: Receiver<Result<LoadedSong, String>>,
+}
+
+impl SongLoader {
+    pub fn spawn() -> SongLoader {
+        let (request_tx, request_rx) = bounded(10);
+        let (loaded_tx, loaded_rx) = bounded(10);
+
+        thread::spawn(move || {
+            while let Ok(msg) = request_rx.recv() {
+                match msg {
+                    LoaderMessage::Load(path) => {
+                        let result = read_song(&path).map(|song| LoadedSong {
+                            gui_copy: song.clone(),
+                            song: Arc::new(song),
+                        });
+
+                        if loaded_tx.send(result).is_err() {
+                            break;
+                        }
+                    }
+                    LoaderMessage::Clear => {
+                        let empty = LoadedSong {
+                            song: Arc::new(Song::default()),
+                            gui_copy: Song::default(),
+                        };
+
+                        if loaded_tx.send(Ok(empty)).is_err() {
+                            break;
+                        }
+                    }
+                    LoaderMessage::Drop(song) => {
+                        debug!("Dropping song with {} sections", song.sections.len());
+                    }
+                }
+            }
+            debug!("channel closed, stopping");
+        });
+
+        SongLoader {
+            requests: request_tx,
+            loaded: loaded_rx,
+        }
+    }
+
+    /// Requests that the song at `path` be loaded; the result is returned by `poll`
+    pub fn load(&self, path: Arc<PathBuf>) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Load(path)) {
+            error!("Failed to request song: {}", e);
+        }
+    }
+
+    /// Requests an empty song, which is returned by `poll` like a loaded one
+    pub fn clear(&self) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Clear) {
+            error!("Failed to request empty song: {}", e);
+        }
+    }
+
+    /// Returns the next song that has finished loading, if any
+    pub fn poll(&self) -> Option<Result<LoadedSong, String>> {
+        self.loaded.try_recv().ok()
+    }
+
+    pub fn dispose(&self, song: Arc<Song>) {
+        if let Err(e) = self.requests.try_send(LoaderMessage::Drop(song)) {
+            warn!("failed to send song to loader: {}", e);
+        }
+    }
+}
+
+fn read_song(path: &Path) -> Result<Song, String> {
+    let text = read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.to_string_lossy(), e))?;
+    Song::parse(&text)
+}
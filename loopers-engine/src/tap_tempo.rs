@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap_at(tapper: &mut TapTempo, start: Instant, ms: &[u64]) -> Option<f32> {
+        let mut bpm = None;
+        for t in ms {
+            bpm = tapper.tap(start + Duration::from_millis(*t));
+        }
+        bpm
+    }
+
+    #[test]
+    fn test_steady_taps() {
+        let start = Instant::now();
+        let mut tapper = TapTempo::new();
+
+        assert_eq!(None, tap_at(&mut tapper, start, &[0]));
+        assert_eq!(None, tap_at(&mut tapper, start, &[500]));
+        assert_eq!(Some(120.0), tap_at(&mut tapper, start, &[1000]));
+        assert_eq!(Some(120.0), tap_at(&mut tapper, start, &[1500, 2000]));
+    }
+
+    #[test]
+    fn test_rejects_outliers() {
+        let start = Instant::now();
+        let mut tapper = TapTempo::new();
+
+        // a tap that comes much too late is ignored when averaging
+        let bpm = tap_at(&mut tapper, start, &[0, 600, 1200, 2200, 2800, 3400]).unwrap();
+        assert!((bpm - 100.0).abs() < 0.01, "bpm was {}", bpm);
+    }
+
+    #[test]
+    fn test_uses_recent_taps() {
+        let start = Instant::now();
+        let mut tapper = TapTempo::new();
+
+        // after a pause, tapping starts over
+        tap_at(&mut tapper, start, &[0, 1000, 2000, 3000]);
+        assert_eq!(None, tap_at(&mut tapper, start, &[6000, 6500]));
+        assert_eq!(Some(120.0), tap_at(&mut tapper, start, &[7000]));
+
+        // and only the last few taps are considered, so the tempo can be changed gradually
+        let ms: Vec<u64> = (1..=MAX_TAPS as u64).map(|i| 7000 + i * 400).collect();
+        assert_eq!(Some(150.0), tap_at(&mut tapper, start, &ms));
+    }
+}
+
+// the most recent taps that are used to compute the tempo
+const MAX_TAPS: usize = 8;
+
+// the fewest taps that produce a tempo
+const MIN_TAPS: usize = 3;
+
+// taps further apart than this (30 bpm) start a new tempo
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// intervals that differ from the median by more than this fraction are ignored
+const OUTLIER_TOLERANCE: f64 = 0.25;
+
+/// Computes a tempo from the times at which the user taps
+pub struct TapTempo {
+    taps: [Option<Instant>; MAX_TAPS],
+    count: usize,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        TapTempo {
+            taps: [None; MAX_TAPS],
+            count: 0,
+        }
+    }
+
+    /// Records a tap, returning the tempo in BPM once there are enough taps to compute one
+    pub fn tap(&mut self, time: Instant) -> Option<f32> {
+        let timed_out = match self.count.checked_sub(1).and_then(|i| self.taps[i]) {
+            Some(last) => time.saturating_duration_since(last) > TAP_TIMEOUT,
+            None => true,
+        };
+        if timed_out {
+            self.count = 0;
+        }
+
+        if self.count == MAX_TAPS {
+            self.taps.rotate_left(1);
+            self.count -= 1;
+        }
+        self.taps[self.count] = Some(time);
+        self.count += 1;
+
+        if self.count < MIN_TAPS {
+            return None;
+        }
+
+        let mut intervals = [0f64; MAX_TAPS - 1];
+        let n = self.count - 1;
+        for (i, w) in self.taps[..self.count].windows(2).enumerate() {
+            intervals[i] = w[1]?.saturating_duration_since(w[0]?).as_secs_f64();
+        }
+
+        let mut sorted = intervals;
+        sorted[..n].sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = (sorted[(n - 1) / 2] + sorted[n / 2]) / 2.0;
+
+        let (sum, count) = intervals[..n]
+            .iter()
+            .filter(|i| (*i - median).abs() <= median * OUTLIER_TOLERANCE)
+            .fold((0.0, 0), |(sum, count), i| (sum + i, count + 1));
+
+        if count == 0 || sum <= 0.0 {
+            return None;
+        }
+
+        Some((60.0 * count as f64 / sum) as f32)
+    }
+}
@@ -6,7 +6,7 @@ use std::cmp::Ordering;
 mod tests {
     use crate::trigger::{Trigger, TriggerCondition};
     use loopers_common::api::{Command, FrameTime};
-    use loopers_common::music::{MetricStructure, Tempo, TimeSignature};
+    use loopers_common::music::{Groove, GrooveGrid, MetricStructure, Tempo};
     use proptest::prelude::*;
 
     fn correct_measure_trigger(trigger: &Trigger) -> FrameTime {
@@ -28,10 +28,7 @@ mod tests {
 
     #[test]
     fn test_measure_trigger() {
-        let ms = MetricStructure {
-            tempo: Tempo::from_bpm(120.0),
-            time_signature: TimeSignature::new(4, 4).unwrap(),
-        };
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
 
         let t = Trigger::new(TriggerCondition::Measure, Command::Start, ms, FrameTime(0));
 
@@ -62,10 +59,7 @@ mod tests {
 
     #[test]
     fn test_beat_trigger() {
-        let ms = MetricStructure {
-            tempo: Tempo::from_bpm(120.0),
-            time_signature: TimeSignature::new(4, 4).unwrap(),
-        };
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
 
         let t = Trigger::new(TriggerCondition::Beat, Command::Start, ms, FrameTime(0));
 
@@ -76,13 +70,165 @@ mod tests {
         assert_eq!(FrameTime(22050), t.triggered_at);
     }
 
+    #[test]
+    fn test_change_metric_structure() {
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+        let mut t = Trigger::new(TriggerCondition::Measure, Command::Start, ms, FrameTime(1));
+        assert_eq!(FrameTime(88200), t.triggered_at());
+
+        // the trigger stays on the start of the next measure
+        let slower = MetricStructure::new(4, 4, Tempo::from_bpm(90.0)).unwrap();
+        t.set_metric_structure(slower);
+        assert_eq!(FrameTime(117600), t.triggered_at());
+
+        let waltz = MetricStructure::new(3, 4, Tempo::from_bpm(90.0)).unwrap();
+        t.set_metric_structure(waltz);
+        assert_eq!(FrameTime(88200), t.triggered_at());
+
+        let mut t = Trigger::new(TriggerCondition::Immediate, Command::Start, ms, FrameTime(1));
+        t.set_metric_structure(slower);
+        assert_eq!(FrameTime(0), t.triggered_at());
+    }
+
+    #[test]
+    fn test_swung_triggers() {
+        let mut ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+        ms.groove = Groove {
+            swing: 66,
+            ..Groove::default()
+        };
+
+        // beats are not moved by swing, but the eighths between them are
+        let t = Trigger::new(TriggerCondition::Beat, Command::Start, ms, FrameTime(1));
+        assert_eq!(FrameTime(22050), t.triggered_at());
+
+        let t = Trigger::new(TriggerCondition::Subdivision, Command::Start, ms, FrameTime(1));
+        assert_eq!(FrameTime(14553), t.triggered_at());
+
+        let t = Trigger::new(TriggerCondition::Subdivision, Command::Start, ms, FrameTime(14554));
+        assert_eq!(FrameTime(22050), t.triggered_at());
+
+        let t = Trigger::new(TriggerCondition::Subdivision, Command::Start, ms, FrameTime(-100));
+        assert_eq!(FrameTime(0), t.triggered_at());
+
+        // a groove template can move the beats themselves, but measures stay in place
+        ms.groove.template = [10, 0, 0, 0];
+        let t = Trigger::new(TriggerCondition::Beat, Command::Start, ms, FrameTime(1));
+        assert_eq!(FrameTime(1103), t.triggered_at());
+        let t = Trigger::new(TriggerCondition::Beat, Command::Start, ms, FrameTime(1104));
+        assert_eq!(FrameTime(22050 + 1103), t.triggered_at());
+
+        let mut t = Trigger::new(TriggerCondition::Measure, Command::Start, ms, FrameTime(1));
+        assert_eq!(FrameTime(88200), t.triggered_at());
+
+        // changing the groove moves pending triggers onto the new grid
+        let mut t2 = Trigger::new(TriggerCondition::Subdivision, Command::Start, ms, FrameTime(1));
+        ms.groove = Groove {
+            grid: GrooveGrid::Sixteenths,
+            ..Groove::default()
+        };
+        t.set_metric_structure(ms);
+        t2.set_metric_structure(ms);
+        assert_eq!(FrameTime(88200), t.triggered_at());
+        assert_eq!(FrameTime(5513), t2.triggered_at());
+    }
+
+    #[test]
+    fn test_phrase_trigger() {
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+
+        let t = Trigger::new(TriggerCondition::Phrase(4), Command::Start, ms, FrameTime(88200));
+        assert_eq!(FrameTime(88200 * 4), t.triggered_at());
+
+        let t = Trigger::new(TriggerCondition::Phrase(2), Command::Start, ms, FrameTime(88200 * 2));
+        assert_eq!(FrameTime(88200 * 2), t.triggered_at());
+
+        let t = Trigger::new(TriggerCondition::Phrase(8), Command::Start, ms, FrameTime(-100));
+        assert_eq!(FrameTime(0), t.triggered_at());
+
+        // phrases stay in place when the tempo changes
+        let mut t = Trigger::new(TriggerCondition::Phrase(4), Command::Start, ms, FrameTime(1));
+        t.set_metric_structure(MetricStructure::new(4, 4, Tempo::from_bpm(90.0)).unwrap());
+        assert_eq!(FrameTime(117600 * 4), t.triggered_at());
+    }
+
+    #[test]
+    fn test_defer_trigger() {
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+        let measure = ms.tempo.samples_per_beat() as i64 * 4;
+
+        let mut t = Trigger::new(TriggerCondition::Measure, Command::Start, ms, FrameTime(100));
+        assert_eq!(FrameTime(measure), t.triggered_at());
+
+        t.defer(FrameTime(measure + 10));
+        assert_eq!(FrameTime(measure * 2), t.triggered_at());
+
+        t.defer(FrameTime(measure * 3));
+        assert_eq!(FrameTime(measure * 3), t.triggered_at());
+    }
+
+    #[test]
+    fn test_loop_end_trigger() {
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+        let condition = TriggerCondition::LoopEnd(FrameTime(1000), 50000);
+
+        let t = Trigger::new(condition, Command::Start, ms, FrameTime(2000));
+        assert_eq!(FrameTime(51000), t.triggered_at());
+
+        let t = Trigger::new(condition, Command::Start, ms, FrameTime(101000));
+        assert_eq!(FrameTime(101000), t.triggered_at());
+
+        let t = Trigger::new(condition, Command::Start, ms, FrameTime(0));
+        assert_eq!(FrameTime(1000), t.triggered_at());
+
+        // the loop doesn't follow tempo changes, but it does follow shifts in time
+        let mut t = Trigger::new(condition, Command::Start, ms, FrameTime(2000));
+        t.set_metric_structure(MetricStructure::new(4, 4, Tempo::from_bpm(90.0)).unwrap());
+        assert_eq!(FrameTime(51000), t.triggered_at());
+        t.shift_time(FrameTime(500));
+        assert_eq!(FrameTime(51500), t.triggered_at());
+        assert_eq!(TriggerCondition::LoopEnd(FrameTime(1500), 50000), t.condition);
+    }
+
+    #[test]
+    fn test_master_loop_recording_end() {
+        let ms = MetricStructure::new(4, 4, Tempo::from_bpm(120.0)).unwrap();
+        let end = |period: u64, recorded: i64| {
+            let start = FrameTime(1000);
+            let time = FrameTime(start.0 + recorded);
+            let condition =
+                TriggerCondition::master_loop_recording_end(start, period, Some(start), time);
+            Trigger::new(condition, Command::Start, ms, time).triggered_at().0 - start.0
+        };
+
+        // shorter loops are rounded up to a fraction of the master loop
+        assert_eq!(10000, end(80000, 100));
+        assert_eq!(20000, end(80000, 15000));
+        assert_eq!(40000, end(80000, 40000));
+        assert_eq!(80000, end(80000, 50000));
+
+        // and longer ones to a multiple of it
+        assert_eq!(160000, end(80000, 80001));
+        assert_eq!(240000, end(80000, 230000));
+
+        // fractions that don't divide the master loop evenly are skipped
+        assert_eq!(15001, end(60004, 100));
+        assert_eq!(60001, end(60001, 100));
+
+        // with nothing recorded yet, the recording started at the master loop's last restart
+        let condition = TriggerCondition::master_loop_recording_end(
+            FrameTime(1000),
+            80000,
+            None,
+            FrameTime(81500),
+        );
+        assert_eq!(TriggerCondition::LoopEnd(FrameTime(81000), 10000), condition);
+    }
+
     proptest! {
         #[test]
         fn test_measure_trigger_prop(tempo in 1f32..220.0, lower in 2u8..32, upper in 1u8..7, time in -10i64..100_000_000) {
-            let ms = MetricStructure {
-                tempo: Tempo::from_bpm(tempo),
-                time_signature: TimeSignature::new(lower, 2u8.pow(upper as u32)).unwrap(),
-            };
+            let ms = MetricStructure::new(lower, 2u8.pow(upper as u32), Tempo::from_bpm(tempo)).unwrap();
 
             let t = Trigger::new(TriggerCondition::Measure,
                                  Command::Start, ms, FrameTime(time));
@@ -98,12 +244,60 @@ pub enum TriggerCondition {
     Immediate,
     Measure,
     Beat,
+    // the next step of the groove grid
+    Subdivision,
+    // the start of the next phrase of the given number of measures, counting from measure 0
+    Phrase(u8),
+    // the end of a loop that restarts at the given offset every `period` frames
+    LoopEnd(FrameTime, u64),
+    // the start of the master loop, which restarts at the given offset every `period` frames
+    MasterLoop(FrameTime, u64),
+}
+
+// the smallest fraction of the master loop that a loop can be recorded to, as a power of two
+const MAX_MASTER_LOOP_DIVISION: u32 = 3;
+
+impl TriggerCondition {
+    /// The condition for ending a recording that began at `start` so that the new loop is a whole
+    /// multiple of the master loop's `period`, or a fraction (down to 1/8) of it. Only fractions
+    /// that divide the period evenly are used, so the loop doesn't drift against the master.
+    /// Without a `start` (when nothing has been recorded yet), the recording is taken to have
+    /// begun at the master loop's last restart before `time`.
+    pub fn master_loop_recording_end(
+        offset: FrameTime,
+        period: u64,
+        start: Option<FrameTime>,
+        time: FrameTime,
+    ) -> Self {
+        let period = period.max(1);
+        let start = start.unwrap_or_else(|| {
+            FrameTime(time.0 - (time.0 - offset.0).rem_euclid(period as i64))
+        });
+        let recorded = (time.0 - start.0).max(0) as u64;
+
+        let length = if recorded <= period {
+            (0..=MAX_MASTER_LOOP_DIVISION)
+                .rev()
+                .filter(|d| period.is_multiple_of(1 << d))
+                .map(|d| period >> d)
+                .find(|l| *l >= recorded)
+                .unwrap_or(period)
+        } else {
+            period * recorded.div_ceil(period)
+        };
+
+        TriggerCondition::LoopEnd(start, length)
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Trigger {
     pub condition: TriggerCondition,
     pub command: Command,
+    // whether the trigger was queued by the song, rather than by the user
+    pub from_song: bool,
+    // whether the trigger applies a tempo from TapTempo, which the next tapped tempo replaces
+    pub from_tap_tempo: bool,
     metric_structure: MetricStructure,
     start_time: FrameTime,
     triggered_at: FrameTime,
@@ -120,6 +314,8 @@ impl Trigger {
         Trigger {
             condition,
             command,
+            from_song: false,
+            from_tap_tempo: false,
             metric_structure,
             start_time,
             triggered_at,
@@ -149,25 +345,99 @@ impl Trigger {
                 }
             }
             TriggerCondition::Beat => {
+                if start_time.0 < 0 {
+                    FrameTime(0)
+                } else {
+                    metric_structure.next_grooved(start_time, 1)
+                }
+            }
+            TriggerCondition::Subdivision => {
+                if start_time.0 < 0 {
+                    FrameTime(0)
+                } else {
+                    let steps = metric_structure.groove.grid.steps_per_beat() as i64;
+                    metric_structure.next_grooved(start_time, steps)
+                }
+            }
+            TriggerCondition::Phrase(measures) => {
                 if start_time.0 < 0 {
                     FrameTime(0)
                 } else {
                     let spb = metric_structure.tempo.samples_per_beat() as i64;
-                    let rem = start_time.0 % spb;
+                    let samples_per_phrase = spb
+                        * metric_structure.time_signature.upper as i64
+                        * (measures.max(1) as i64);
+                    let rem = start_time.0 % samples_per_phrase;
 
                     if rem == 0 {
                         start_time
                     } else {
-                        FrameTime(start_time.0 + (spb - rem))
+                        FrameTime(start_time.0 + (samples_per_phrase - rem))
                     }
                 }
             }
+            TriggerCondition::LoopEnd(offset, period)
+            | TriggerCondition::MasterLoop(offset, period) => {
+                let period = period.max(1) as i64;
+                let rem = (start_time.0 - offset.0).rem_euclid(period);
+
+                if rem == 0 {
+                    start_time
+                } else {
+                    FrameTime(start_time.0 + (period - rem))
+                }
+            }
         }
     }
 
     pub fn triggered_at(&self) -> FrameTime {
         self.triggered_at
     }
+
+    /// Moves the trigger to the same measure and beat under a new tempo or time signature. If the
+    /// groove changes, the trigger moves to the next position of the new groove instead.
+    pub fn set_metric_structure(&mut self, metric_structure: MetricStructure) {
+        let old = self.metric_structure;
+        self.start_time = old.convert_time(self.start_time, &metric_structure);
+        match self.condition {
+            // these don't depend on the metric structure
+            TriggerCondition::Immediate
+            | TriggerCondition::LoopEnd(..)
+            | TriggerCondition::MasterLoop(..) => {}
+            _ if old.groove == metric_structure.groove => {
+                self.triggered_at = old.convert_time(self.triggered_at, &metric_structure);
+            }
+            _ => {
+                self.triggered_at =
+                    Self::compute_triggered_at(self.condition, metric_structure, self.start_time);
+            }
+        }
+        self.metric_structure = metric_structure;
+    }
+
+    /// Moves the trigger to the next position matching its condition at or after `time`, for
+    /// triggers that were missed
+    pub fn defer(&mut self, time: FrameTime) {
+        self.start_time = time;
+        self.triggered_at = Self::compute_triggered_at(self.condition, self.metric_structure, time);
+    }
+
+    /// Moves triggers at the end of a loop along with the loop, when the engine shifts the loopers
+    /// by `delta` (see `Looper::shift_time`). Other triggers are unaffected, as they are moved by
+    /// `set_metric_structure`.
+    pub fn shift_time(&mut self, delta: FrameTime) {
+        match self.condition {
+            TriggerCondition::LoopEnd(offset, period) => {
+                self.condition = TriggerCondition::LoopEnd(FrameTime(offset.0 + delta.0), period);
+            }
+            TriggerCondition::MasterLoop(offset, period) => {
+                self.condition =
+                    TriggerCondition::MasterLoop(FrameTime(offset.0 + delta.0), period);
+            }
+            _ => return,
+        }
+        self.triggered_at = FrameTime(self.triggered_at.0 + delta.0);
+    }
 }
 
 impl Eq for Trigger {}
@@ -6,7 +6,7 @@ use crate::widgets::{
 };
 use loopers_common::api::{
     get_sample_rate, Command, FrameTime, LooperCommand, LooperMode, LooperSpeed, LooperTarget,
-    Part, QuantizationMode, PARTS,
+    OutputProtection, Part, QuantizationMode, MAX_MASTER_GAIN_DB, MIN_MASTER_GAIN_DB,
 };
 use loopers_common::gui_channel::EngineState;
 use loopers_common::music::{MetricStructure, TimeSignature};
@@ -303,7 +303,7 @@ impl MainPage {
     pub fn min_size(&self, data: &AppData) -> Size {
         let mut parts = HashMap::new();
         for l in data.loopers.values() {
-            for part in PARTS.iter() {
+            for (part, _) in data.parts.iter() {
                 if l.parts[*part] {
                     *parts.entry(part).or_insert(0) += 1;
                 }
@@ -442,9 +442,12 @@ impl MainPage {
 
         let mut bottom = h as f32;
 
+        // make room for the song timeline if there is one
+        let song_height = if data.song.is_empty() { 0.0 } else { SONG_VIEW_HEIGHT + 4.0 };
+
         // draw the message view if one exists
         canvas.save();
-        canvas.translate((0.0, bottom - 90.0));
+        canvas.translate((0.0, bottom - 90.0 - song_height));
         LogMessageView::draw(canvas, data).width;
         canvas.restore();
 
@@ -458,8 +461,15 @@ impl MainPage {
             bottom -= 30.0;
         };
 
-        canvas.save();
         let bar_height = 30.0;
+        if song_height > 0.0 {
+            canvas.save();
+            canvas.translate((10.0, bottom - bar_height - song_height));
+            SongView::draw(canvas, data, w as f32 - 20.0);
+            canvas.restore();
+        }
+
+        canvas.save();
         canvas.translate(Vector::new(0.0, bottom - bar_height));
         self.bottom_bar.draw(
             data,
@@ -476,20 +486,24 @@ impl MainPage {
 
 struct BottomBarView {
     tempo_view: TempoView,
+    tap_tempo_button: ControlButton,
     metronome_view: MetronomeView,
     metronome_button: MetronomeButton,
     time_view: TimeView,
     peak_view: PeakMeterView,
+    master_view: MasterView,
 }
 
 impl BottomBarView {
     fn new() -> Self {
         Self {
             tempo_view: TempoView::new(),
+            tap_tempo_button: ControlButton::new("tap", Color::from_rgb(78, 78, 78), None, 22.0),
             metronome_view: MetronomeView::new(),
             metronome_button: MetronomeButton::new(),
             time_view: TimeView::new(),
             peak_view: PeakMeterView::new(30),
+            master_view: MasterView::new(),
         }
     }
 
@@ -507,6 +521,19 @@ impl BottomBarView {
         canvas.save();
         canvas.translate((size.width.round() + 20.0, 0.0));
 
+        let size = self.tap_tempo_button.draw(
+            canvas,
+            false,
+            false,
+            |button| {
+                if button == MouseButton::Left {
+                    controller.send_command(Command::TapTempo, "Failed to tap tempo");
+                }
+            },
+            last_event,
+        );
+        canvas.translate((size.width.round() + 20.0, 0.0));
+
         let size = self
             .metronome_view
             .draw(h, data, canvas, controller, last_event);
@@ -520,14 +547,181 @@ impl BottomBarView {
         let size = self.time_view.draw(h, data, canvas, controller, last_event);
         canvas.translate((size.width.round() + 20.0, 0.0));
 
-        self.peak_view
+        let size = self.peak_view
             .draw(canvas, data.engine_state.input_levels, None, 160.0, h,
                   |_| {}, last_event);
+        canvas.translate((size.width.round() + 20.0, 0.0));
+
+        self.master_view.draw(h, data, canvas, controller, last_event);
 
         canvas.restore();
     }
 }
 
+fn format_db(db: f32) -> String {
+    if db.is_finite() {
+        format!("{:.1}", db)
+    } else {
+        "-inf".to_string()
+    }
+}
+
+struct MasterView {
+    peak_view: PeakMeterView,
+    protection_buttons: Vec<(OutputProtection, ControlButton)>,
+    loudness_view: LoudnessView,
+}
+
+impl MasterView {
+    fn new() -> Self {
+        let c = Color::from_rgb(78, 78, 78);
+        Self {
+            peak_view: PeakMeterView::new(30),
+            protection_buttons: vec![
+                (OutputProtection::Off, ControlButton::new("off", c, None, 22.0)),
+                (OutputProtection::Limiter, ControlButton::new("limit", c, None, 22.0)),
+                (OutputProtection::SoftClip, ControlButton::new("clip", c, None, 22.0)),
+            ],
+            loudness_view: LoudnessView::new(),
+        }
+    }
+
+    fn draw(
+        &mut self,
+        h: f32,
+        data: &AppData,
+        canvas: &mut Canvas,
+        controller: &mut Controller,
+        last_event: Option<GuiEvent>,
+    ) -> Size {
+        let range = MAX_MASTER_GAIN_DB - MIN_MASTER_GAIN_DB;
+        let level = (data.engine_state.master_gain - MIN_MASTER_GAIN_DB) / range;
+
+        let mut x = 0.0;
+        canvas.save();
+
+        let size = self.peak_view.draw(
+            canvas,
+            data.engine_state.master_levels,
+            Some(level),
+            160.0,
+            h,
+            |level| {
+                controller.send_command(
+                    Command::SetMasterGain(MIN_MASTER_GAIN_DB + level * range),
+                    "Failed to set master gain",
+                )
+            },
+            last_event,
+        );
+        canvas.translate((size.width.round() + 10.0, 0.0));
+        x += size.width.round() + 10.0;
+
+        for (protection, button) in &mut self.protection_buttons {
+            let protection = *protection;
+            let size = button.draw(
+                canvas,
+                data.engine_state.output_protection == protection,
+                false,
+                |button| {
+                    if button == MouseButton::Left {
+                        controller.send_command(
+                            Command::SetOutputProtection(protection),
+                            "Failed to set output protection",
+                        );
+                    }
+                },
+                last_event,
+            );
+            canvas.translate((size.width + 5.0, 0.0));
+            x += size.width + 5.0;
+        }
+
+        canvas.translate((15.0, 0.0));
+        x += 15.0;
+
+        x += self.loudness_view.draw(h, data, canvas, controller, last_event).width;
+
+        canvas.restore();
+
+        Size::new(x, h)
+    }
+}
+
+struct LoudnessView {
+    button_state: ButtonState,
+}
+
+impl LoudnessView {
+    fn new() -> Self {
+        Self {
+            button_state: ButtonState::Default,
+        }
+    }
+
+    fn draw(
+        &mut self,
+        h: f32,
+        data: &AppData,
+        canvas: &mut Canvas,
+        controller: &mut Controller,
+        last_event: Option<GuiEvent>,
+    ) -> Size {
+        let meters = &data.engine_state.master_meters;
+
+        let mut text = format!(
+            "S {}  I {} LUFS  TP {}",
+            format_db(meters.short_term_lufs),
+            format_db(meters.integrated_lufs),
+            format_db(meters.max_true_peak),
+        );
+        if meters.gain_reduction < -0.1 {
+            text.push_str(&format!("  GR {:.1}", meters.gain_reduction));
+        }
+
+        let font = Font::new(Typeface::default(), 14.0);
+        let width = font.measure_str(&text, None).0;
+        let bounds = Rect::new(0.0, 0.0, width, h);
+
+        // clicking the readout starts a new loudness measurement
+        self.handle_event(
+            canvas,
+            &bounds,
+            |button| {
+                if button == MouseButton::Left {
+                    controller.send_command(Command::ResetLoudness, "Failed to reset loudness");
+                }
+            },
+            last_event,
+        );
+
+        let mut text_paint = Paint::default();
+        text_paint.set_anti_alias(true);
+        text_paint.set_color(match self.button_state {
+            ButtonState::Default => Color::from_rgb(200, 200, 200),
+            _ => Color::WHITE,
+        });
+        if meters.max_true_peak > 0.0 {
+            text_paint.set_color(Color::RED);
+        }
+
+        let blob = TextBlob::new(&text, &font).unwrap();
+        canvas.draw_text_blob(&blob, Point::new(0.0, h - 12.0), &text_paint);
+
+        Size::new(width, h)
+    }
+}
+
+impl Button for LoudnessView {
+    fn set_state(&mut self, state: ButtonState) {
+        self.button_state = state;
+    }
+
+    fn get_state(&self) -> ButtonState {
+        self.button_state
+    }
+}
+
 struct TempoView {
     button_state: ButtonState,
     edit_state: TextEditState,
@@ -710,13 +904,19 @@ impl MetronomeView {
             .time_signature
             .beat_of_measure(current_beat);
 
+        let pattern = data.engine_state.metronome_pattern;
+        let clicks = pattern.subdivision.clicks_per_beat() as i64;
+        let click_of_beat = pattern
+            .click(data.engine_state.metric_structure.tempo, data.engine_state.time)
+            .rem_euclid(clicks);
+
         let mut x = 0.0;
 
         let mut paint = Paint::default();
         paint.set_anti_alias(true);
         let font = Font::new(Typeface::default(), 20.0);
 
-        let beat_color = if beat_of_measure == 0 {
+        let beat_color = if pattern.is_accented(beat_of_measure) {
             color_for_mode(LooperMode::Playing)
         } else {
             Color::from_rgb(255, 239, 0)
@@ -728,12 +928,29 @@ impl MetronomeView {
             for beat in 0..upper {
                 if beat == beat_of_measure {
                     paint.set_color(beat_color);
+                } else if pattern.is_accented(beat) {
+                    paint.set_color(Color::from_rgb(170, 170, 170));
                 } else {
                     paint.set_color(Color::from_rgb(128, 128, 128));
                 }
 
                 let radius = 10.0;
                 canvas.draw_circle(Point::new(x + radius / 2.0, h / 2.0 - 5.0), radius, &paint);
+
+                // show the subdivisions of the beat as dots underneath it
+                if clicks > 1 {
+                    let spacing = radius * 2.0 / clicks as f32;
+                    for click in 0..clicks {
+                        if beat == beat_of_measure && click == click_of_beat {
+                            paint.set_color(Color::WHITE);
+                        } else {
+                            paint.set_color(Color::from_rgb(128, 128, 128));
+                        }
+                        let dot_x = x - radius / 2.0 + spacing * (click as f32 + 0.5);
+                        canvas.draw_circle(Point::new(dot_x, h / 2.0 + 9.0), 1.5, &paint);
+                    }
+                }
+
                 x += 30.0;
             }
         } else {
@@ -968,10 +1185,22 @@ impl TimeView {
             .time_signature
             .beat_of_measure(current_beat);
 
-        let measure_blob =
-            TextBlob::new(format!("{:03}.{}", measure, beat_of_measure), &font).unwrap();
+        let time = data.engine_state.time;
+        if data.engine_state.engine_state == EngineState::Active && time.0 < 0 {
+            // during the count-in, show the number of beats left until the first measure
+            let spb = (data.engine_state.metric_structure.tempo.samples_per_beat() as i64).max(1);
+            let beats_left = (-time.0 + spb - 1) / spb;
+
+            let mut count_in_paint = text_paint.clone();
+            count_in_paint.set_color(Color::from_rgb(255, 200, 50));
+            let count_in_blob = TextBlob::new(format!("-{}", beats_left), &font).unwrap();
+            canvas.draw_text_blob(&count_in_blob, Point::new(x, h - 12.0), &count_in_paint);
+        } else {
+            let measure_blob =
+                TextBlob::new(format!("{:03}.{}", measure, beat_of_measure), &font).unwrap();
 
-        canvas.draw_text_blob(&measure_blob, Point::new(x, h - 12.0), &text_paint);
+            canvas.draw_text_blob(&measure_blob, Point::new(x, h - 12.0), &text_paint);
+        }
         x += 80.0;
 
         // draw play controls
@@ -1333,6 +1562,7 @@ enum BottomButtonBehavior {
     Load,
     SetSyncMode(QuantizationMode),
     Part(Part),
+    AddPart,
     Undo,
     Redo,
 }
@@ -1372,8 +1602,48 @@ impl LoadWindow {
     }
 }
 
+// Looper and part buttons use the engine's quantization mode when left-clicked, and take effect
+// immediately when right-clicked
+fn click_quantization(button: MouseButton) -> Option<QuantizationMode> {
+    if button == MouseButton::Right {
+        Some(QuantizationMode::Free)
+    } else {
+        None
+    }
+}
+
+// how far along the wait for the first pending global trigger that matches `pred` is, from 0 to 1
+fn global_trigger_progress<F: Fn(&Command) -> bool>(data: &AppData, pred: F) -> f32 {
+    data.global_triggers
+        .iter()
+        .rev()
+        .filter(|(_, _, c)| pred(c))
+        .min_by_key(|(_, t1, _)| t1.0)
+        .map(|(t0, t1, _)| {
+            if *t1 == *t0 {
+                0.0
+            } else {
+                (data.engine_state.time.0 as f32 - t0.0 as f32) / (t1.0 as f32 - t0.0 as f32)
+            }
+        })
+        .unwrap_or(0.0)
+}
+
+// a name that isn't used by any of `parts` yet, using the next free letter
+fn new_part(parts: &[(Part, String)]) -> String {
+    (b'A'..=b'Z')
+        .map(|c| (c as char).to_string())
+        .chain((1..).map(|i| format!("Part{}", i)))
+        .find(|name| !parts.iter().any(|(_, n)| n == name))
+        .unwrap()
+}
+
 struct BottomButtonView {
     buttons: Vec<(BottomButtonBehavior, ControlButton)>,
+    // the parts that have buttons
+    parts: Vec<(Part, String)>,
+    scene_buttons: Vec<(String, ControlButton)>,
+    save_scene_button: ControlButton,
     load_window: LoadWindow,
 }
 
@@ -1394,6 +1664,10 @@ impl BottomButtonView {
                     BottomButtonBehavior::SetSyncMode(QuantizationMode::Free),
                     ControlButton::new("free", c, None, 22.0),
                 ),
+                (
+                    BottomButtonBehavior::SetSyncMode(QuantizationMode::Subdivision),
+                    ControlButton::new("sub", c, None, 22.0),
+                ),
                 (
                     BottomButtonBehavior::SetSyncMode(QuantizationMode::Beat),
                     ControlButton::new("beat", c, None, 22.0),
@@ -1402,21 +1676,10 @@ impl BottomButtonView {
                     BottomButtonBehavior::SetSyncMode(QuantizationMode::Measure),
                     ControlButton::new("measure", c, None, 22.0),
                 ),
+                // the part buttons are added before this one once we know the parts
                 (
-                    BottomButtonBehavior::Part(Part::A),
-                    ControlButton::new("A", c, None, 22.0),
-                ),
-                (
-                    BottomButtonBehavior::Part(Part::B),
-                    ControlButton::new("B", c, None, 22.0),
-                ),
-                (
-                    BottomButtonBehavior::Part(Part::C),
-                    ControlButton::new("C", c, None, 22.0),
-                ),
-                (
-                    BottomButtonBehavior::Part(Part::D),
-                    ControlButton::new("D", c, None, 22.0),
+                    BottomButtonBehavior::AddPart,
+                    ControlButton::new("+", c, None, 22.0),
                 ),
                 (
                     BottomButtonBehavior::Undo,
@@ -1427,6 +1690,9 @@ impl BottomButtonView {
                     ControlButton::new("Redo", c, None, 22.0)
                 ),
             ],
+            parts: vec![],
+            scene_buttons: vec![],
+            save_scene_button: ControlButton::new("+ scene", c, None, 22.0),
             load_window: LoadWindow {
                 active: Arc::new(AtomicBool::new(false)),
             },
@@ -1440,6 +1706,10 @@ impl BottomButtonView {
         controller: &mut Controller,
         last_event: Option<GuiEvent>,
     ) -> Size {
+        if self.parts != data.parts {
+            self.update_part_buttons(&data.parts);
+        }
+
         let mut x = 0.0;
         let load_window = &mut self.load_window;
         for (behavior, button) in &mut self.buttons {
@@ -1449,7 +1719,13 @@ impl BottomButtonView {
             let behavior = behavior.clone();
 
             let on_click = |button: MouseButton| {
-                if button == MouseButton::Left {
+                if let (MouseButton::Right, BottomButtonBehavior::Part(part)) = (button, behavior) {
+                    controller.send_command_with_quantization(
+                        Command::GoToPart(part),
+                        click_quantization(button),
+                        "Failed to change parts",
+                    );
+                } else if button == MouseButton::Left {
                     match behavior {
                         BottomButtonBehavior::Save => {
                             if let Some(mut home_dir) = dirs::home_dir() {
@@ -1469,6 +1745,12 @@ impl BottomButtonView {
                             controller
                                 .send_command(Command::GoToPart(part), "Failed to change parts");
                         }
+                        BottomButtonBehavior::AddPart => {
+                            controller.send_command(
+                                Command::AddPart(Arc::new(new_part(&data.parts))),
+                                "Failed to add part",
+                            );
+                        }
                         BottomButtonBehavior::SetSyncMode(mode) => {
                             controller.send_command(
                                 Command::SetQuantizationMode(mode),
@@ -1504,25 +1786,11 @@ impl BottomButtonView {
             let mut progress_percent = 0.0;
 
             if let BottomButtonBehavior::Part(part) = &behavior {
-                progress_percent = data
-                    .global_triggers
-                    .iter()
-                    .rev()
-                    .filter(|(_, _, c)| match c {
-                        Command::GoToPart(p) => p == part,
-                        // TODO: Think about how to support this for previous part / next part
-                        _ => false,
-                    })
-                    .min_by_key(|(_, t1, _)| t1.0)
-                    .map(|(t0, t1, _)| {
-                        if *t1 == *t0 {
-                            0.0
-                        } else {
-                            (data.engine_state.time.0 as f32 - t0.0 as f32)
-                                / (t1.0 as f32 - t0.0 as f32)
-                        }
-                    })
-                    .unwrap_or(0.0);
+                progress_percent = global_trigger_progress(data, |c| match c {
+                    Command::GoToPart(p) => p == part,
+                    // TODO: Think about how to support this for previous part / next part
+                    _ => false,
+                });
             }
 
             let size = button.draw_with_progress(
@@ -1541,7 +1809,7 @@ impl BottomButtonView {
 
             if behavior == BottomButtonBehavior::Load
                 || behavior == BottomButtonBehavior::SetSyncMode(QuantizationMode::Measure)
-                || behavior == BottomButtonBehavior::Part(Part::D)
+                || behavior == BottomButtonBehavior::AddPart
             {
                 x += 30.0;
             }
@@ -1549,8 +1817,173 @@ impl BottomButtonView {
             canvas.restore();
         }
 
+        x += 20.0;
+        x += self.draw_scenes(canvas, x, data, controller, last_event);
+
         Size::new(x, 40.0)
     }
+
+    fn update_part_buttons(&mut self, parts: &[(Part, String)]) {
+        let c = Color::from_rgb(78, 78, 78);
+        self.buttons
+            .retain(|(b, _)| !matches!(b, BottomButtonBehavior::Part(_)));
+
+        let idx = self
+            .buttons
+            .iter()
+            .position(|(b, _)| *b == BottomButtonBehavior::AddPart)
+            .unwrap_or(self.buttons.len());
+        for (i, (part, name)) in parts.iter().enumerate() {
+            self.buttons.insert(
+                idx + i,
+                (
+                    BottomButtonBehavior::Part(*part),
+                    ControlButton::new(name, c, None, 22.0).with_right_click(),
+                ),
+            );
+        }
+
+        self.parts = parts.to_vec();
+    }
+
+    // draws a button for each scene, and one to save the current mix as a new scene. Like parts,
+    // scenes are recalled with the engine's quantization on left-click and immediately on
+    // right-click.
+    fn draw_scenes(
+        &mut self,
+        canvas: &mut Canvas,
+        start: f32,
+        data: &AppData,
+        controller: &mut Controller,
+        last_event: Option<GuiEvent>,
+    ) -> f32 {
+        if !self.scene_buttons.iter().map(|(n, _)| n).eq(data.scenes.iter()) {
+            let c = Color::from_rgb(78, 78, 78);
+            self.scene_buttons = data
+                .scenes
+                .iter()
+                .map(|n| (n.clone(), ControlButton::new(n, c, None, 22.0).with_right_click()))
+                .collect();
+        }
+
+        let mut x = start;
+        for (name, button) in &mut self.scene_buttons {
+            canvas.save();
+            canvas.translate((x, 0.0));
+
+            let progress_percent = global_trigger_progress(data, |c| match c {
+                Command::RecallScene(n) => **n == *name,
+                _ => false,
+            });
+
+            let size = button.draw_with_progress(
+                canvas,
+                false,
+                false,
+                |button| {
+                    if button == MouseButton::Left || button == MouseButton::Right {
+                        controller.send_command_with_quantization(
+                            Command::RecallScene(Arc::new(name.clone())),
+                            click_quantization(button),
+                            "Failed to recall scene",
+                        );
+                    }
+                },
+                last_event,
+                progress_percent,
+            );
+            x += size.width + 10.0;
+
+            canvas.restore();
+        }
+
+        canvas.save();
+        canvas.translate((x, 0.0));
+        let size = self.save_scene_button.draw(
+            canvas,
+            false,
+            false,
+            |button| {
+                if button == MouseButton::Left {
+                    let name = (1..)
+                        .map(|i| format!("Scene {}", i))
+                        .find(|n| !data.scenes.contains(n))
+                        .unwrap();
+                    controller.send_command(Command::SaveScene(Arc::new(name)), "Failed to save scene");
+                }
+            },
+            last_event,
+        );
+        x += size.width;
+        canvas.restore();
+
+        x - start
+    }
+}
+
+const SONG_VIEW_HEIGHT: f32 = 16.0;
+
+struct SongView {}
+
+impl SongView {
+    // draws the sections of the song as a timeline, with the playing section highlighted
+    fn draw(canvas: &mut Canvas, data: &AppData, w: f32) -> Size {
+        let song = &data.song;
+        let total = song.measures().max(1) as f32;
+
+        let ms = data.engine_state.metric_structure;
+        let measure = ms
+            .tempo
+            .beat(data.engine_state.time)
+            .div_euclid(ms.time_signature.upper as i64);
+
+        let font = Font::new(Typeface::default(), 11.0);
+        let mut paint = Paint::default();
+        paint.set_anti_alias(true);
+        let mut text_paint = Paint::default();
+        text_paint.set_anti_alias(true);
+        text_paint.set_color(Color::WHITE);
+
+        let mut x = 0.0;
+        for (i, section) in song.sections.iter().enumerate() {
+            let width = w * section.measures as f32 / total;
+            let bounds = Rect::new(x + 1.0, 0.0, x + width - 1.0, SONG_VIEW_HEIGHT);
+
+            let playing = data.engine_state.song_section == Some(i);
+            paint.set_color(if playing {
+                Color::from_rgb(80, 80, 80)
+            } else {
+                Color::from_rgb(40, 40, 40)
+            });
+            canvas.draw_rect(bounds, &paint);
+
+            // show how far through the section we are
+            if playing {
+                let start = song.start_measure(i);
+                let progress =
+                    ((measure - start) as f32 / section.measures as f32).clamp(0.0, 1.0);
+                paint.set_color(color_for_mode(LooperMode::Playing));
+                canvas.draw_rect(
+                    Rect::new(
+                        x + 1.0,
+                        SONG_VIEW_HEIGHT - 2.0,
+                        x + 1.0 + (width - 2.0) * progress,
+                        SONG_VIEW_HEIGHT,
+                    ),
+                    &paint,
+                );
+            }
+
+            canvas.save();
+            canvas.clip_rect(bounds, Some(ClipOp::Intersect), Some(true));
+            canvas.draw_str(&section.name, Point::new(x + 4.0, 12.0), &font, &text_paint);
+            canvas.restore();
+
+            x += width;
+        }
+
+        Size::new(w, SONG_VIEW_HEIGHT)
+    }
 }
 
 struct LogMessageView {}
@@ -1589,6 +2022,8 @@ struct LooperView {
             f32,
         )>,
     >,
+    // the parts that have buttons
+    parts: Vec<(Part, String)>,
     state: ButtonState,
     active_button: ActiveButton,
     delete_button: DeleteButton,
@@ -1623,10 +2058,7 @@ impl LooperView {
                         ),
                         15.0,
                     ),
-                    (Self::new_part_button(Part::A, button_height), 0.5),
-                    (Self::new_part_button(Part::B, button_height), 0.5),
-                    (Self::new_part_button(Part::C, button_height), 0.5),
-                    (Self::new_part_button(Part::D, button_height), 0.5),
+                    // followed by a button for each part (see update_part_buttons)
                 ],
                 vec![
                     (
@@ -1657,6 +2089,7 @@ impl LooperView {
                     )
                 ],
             ],
+            parts: vec![],
             state: ButtonState::Default,
             active_button: ActiveButton::new(),
             delete_button: DeleteButton::new(),
@@ -1672,7 +2105,7 @@ impl LooperView {
         h: f32,
         w: f32,
     ) -> Box<dyn FnMut(&mut Canvas, &LooperData, &mut Controller, Option<GuiEvent>) -> Size> {
-        let mut button = ControlButton::new(name, color, Some(w), h);
+        let mut button = ControlButton::new(name, color, Some(w), h).with_right_click();
 
         Box::new(move |canvas, _, controller, last_event| {
             button.draw(
@@ -1680,9 +2113,12 @@ impl LooperView {
                 false,
                 false,
                 |button| {
-                    if button == MouseButton::Left {
-                        controller
-                            .send_command(command.clone(), "Failed to send command to engine");
+                    if button == MouseButton::Left || button == MouseButton::Right {
+                        controller.send_command_with_quantization(
+                            command.clone(),
+                            click_quantization(button),
+                            "Failed to send command to engine",
+                        );
                     }
                 },
                 last_event,
@@ -1719,12 +2155,26 @@ impl LooperView {
         })
     }
 
+    // the buttons in the top row before the part buttons
+    const TOP_ROW_FIXED_BUTTONS: usize = 3;
+
+    fn update_part_buttons(&mut self, parts: &[(Part, String)]) {
+        let button_height = LOOPER_HEIGHT * 0.5 - 15.0;
+        self.buttons[0].truncate(Self::TOP_ROW_FIXED_BUTTONS);
+        for (part, name) in parts {
+            self.buttons[0].push((Self::new_part_button(*part, name, button_height), 0.5));
+        }
+        self.parts = parts.to_vec();
+    }
+
     fn new_part_button(
         part: Part,
+        name: &str,
         h: f32,
     ) -> Box<dyn FnMut(&mut Canvas, &LooperData, &mut Controller, Option<GuiEvent>) -> Size> {
-        let mut button =
-            ControlButton::new(part.name(), Color::from_rgb(78, 78, 78), Some(28.0), h);
+        // single letter names get square buttons, like the default parts
+        let width = if name.chars().count() == 1 { Some(28.0) } else { None };
+        let mut button = ControlButton::new(name, Color::from_rgb(78, 78, 78), width, h);
 
         Box::new(move |canvas, data, controller, last_event| {
             button.draw(
@@ -1755,7 +2205,8 @@ impl LooperView {
         name: &str,
         h: f32,
     ) -> Box<dyn FnMut(&mut Canvas, &LooperData, &mut Controller, Option<GuiEvent>) -> Size> {
-        let mut button = ControlButton::new(name, color_for_mode(mode), Some(100.0), h);
+        let mut button =
+            ControlButton::new(name, color_for_mode(mode), Some(100.0), h).with_right_click();
 
         Box::new(move |canvas, looper, controller, last_event| {
             button.draw(
@@ -1763,7 +2214,7 @@ impl LooperView {
                 looper.mode == mode,
                 false,
                 |button| {
-                    if button == MouseButton::Left {
+                    if button == MouseButton::Left || button == MouseButton::Right {
                         use LooperMode::*;
                         let command = match (looper.mode, mode) {
                             (Recording, Recording) => Some(LooperCommand::Overdub),
@@ -1781,8 +2232,9 @@ impl LooperView {
                         };
 
                         if let Some(command) = command {
-                            controller.send_command(
+                            controller.send_command_with_quantization(
                                 Command::Looper(command, LooperTarget::Id(looper.id)),
+                                click_quantization(button),
                                 "Failed to update looper mode",
                             );
                         }
@@ -1804,6 +2256,10 @@ impl LooperView {
     ) -> Size {
         assert_eq!(self.id, looper.id);
 
+        if self.parts != data.parts {
+            self.update_part_buttons(&data.parts);
+        }
+
         let time = data.engine_state.time - looper.offset;
 
         let ratio = if looper.length == 0 || looper.mode == LooperMode::Recording {
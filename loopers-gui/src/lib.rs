@@ -12,13 +12,15 @@ use skia_safe::{Canvas, Size};
 use crate::app::MainPage;
 use crossbeam_channel::{Sender, TryRecvError, TrySendError};
 use loopers_common::api::{
-    Command, FrameTime, LooperCommand, LooperMode, LooperSpeed, Part, PartSet, QuantizationMode,
+    Command, FrameTime, LooperCommand, LooperMode, LooperSpeed, Names, OutputProtection, Part,
+    PartSet, QuantizationMode, DEFAULT_PARTS,
 };
 use loopers_common::gui_channel::{
-    EngineState, EngineStateSnapshot, GuiCommand, GuiReceiver, GuiSender, LogMessage, Waveform,
-    WAVEFORM_DOWNSAMPLE,
+    EngineState, EngineStateSnapshot, GuiCommand, GuiReceiver, GuiSender, LogMessage,
+    MasterMeters, Waveform, WAVEFORM_DOWNSAMPLE,
 };
-use loopers_common::music::{MetricStructure, Tempo, TimeSignature};
+use loopers_common::music::{Groove, MetricStructure, MetronomePattern, Tempo, TimeSignature};
+use loopers_common::song::Song;
 use sdl2::mouse::MouseButton;
 use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
@@ -116,13 +118,24 @@ impl Log {
 
 #[derive(Clone)]
 pub struct Controller {
-    command_sender: Sender<Command>,
+    command_sender: Sender<(Command, Option<QuantizationMode>)>,
     gui_sender: GuiSender,
 }
 
 impl Controller {
     pub fn send_command(&mut self, command: Command, err: &str) {
-        match self.command_sender.try_send(command) {
+        self.send_command_with_quantization(command, None, err);
+    }
+
+    /// Sends a command that's quantized with the given mode instead of the engine's, if there is
+    /// one
+    pub fn send_command_with_quantization(
+        &mut self,
+        command: Command,
+        quantization: Option<QuantizationMode>,
+        err: &str,
+    ) {
+        match self.command_sender.try_send((command, quantization)) {
             Ok(_) => {}
             Err(TrySendError::Full(_)) => {
                 self.log(err);
@@ -152,6 +165,11 @@ pub struct AppData {
         FrameTime, // trigger time
         Command,
     )>,
+    song: Song,
+    // the engine's parts and their names, in order
+    parts: Vec<(Part, String)>,
+    // the names of the engine's scenes
+    scenes: Vec<String>,
 }
 
 pub struct Gui {
@@ -166,7 +184,7 @@ pub struct Gui {
 impl Gui {
     pub fn new(
         receiver: GuiReceiver,
-        command_sender: Sender<Command>,
+        command_sender: Sender<(Command, Option<QuantizationMode>)>,
         gui_sender: GuiSender,
     ) -> Gui {
         Gui {
@@ -177,6 +195,7 @@ impl Gui {
                     metric_structure: MetricStructure {
                         time_signature: TimeSignature { upper: 4, lower: 4 },
                         tempo: Tempo::from_bpm(120.0),
+                        groove: Groove::default(),
                     },
                     active_looper: 0,
                     looper_count: 0,
@@ -186,11 +205,26 @@ impl Gui {
                     input_levels: [0, 0],
                     looper_levels: [[0; 2]; 64],
                     metronome_volume: 1.0,
+                    metronome_pattern: MetronomePattern::default(),
+                    song_section: None,
+                    master_gain: 0.0,
+                    output_protection: OutputProtection::Off,
+                    master_levels: [0, 0],
+                    master_meters: MasterMeters::new(),
                 },
                 loopers: BTreeMap::new(),
                 show_buttons: SHOW_BUTTONS,
                 messages: Log::new(),
                 global_triggers: Vec::new(),
+                song: Song::default(),
+                parts: {
+                    let names = Names::default();
+                    DEFAULT_PARTS
+                        .iter()
+                        .map(|p| (*p, names.part_name(*p).to_string()))
+                        .collect()
+                },
+                scenes: Vec::new(),
             },
             receiver,
 
@@ -347,6 +381,48 @@ impl Gui {
                         l.trigger = Some((time, command))
                     }
                 }
+                Ok(GuiCommand::GlobalTriggerRemoved(time, command, _)) => {
+                    if let Some(i) = self
+                        .state
+                        .global_triggers
+                        .iter()
+                        .position(|(_, t, c)| *t == time && *c == command)
+                    {
+                        self.state.global_triggers.remove(i);
+                    }
+                }
+                Ok(GuiCommand::LoopTriggerRemoved(id, time, command, _)) => {
+                    if let Some(l) = self.state.loopers.get_mut(&id) {
+                        if l.trigger == Some((time, command)) {
+                            l.trigger = None;
+                        }
+                    }
+                }
+                Ok(GuiCommand::SetSong(song)) => {
+                    self.state.song = song;
+                }
+                Ok(GuiCommand::SetParts(parts)) => {
+                    self.state.parts = parts.iter().map(|(p, n)| (*p, n.to_string())).collect();
+                }
+                Ok(GuiCommand::PartAdded(part, name)) => {
+                    if !self.state.parts.iter().any(|(p, _)| *p == part) {
+                        self.state.parts.push((part, name.to_string()));
+                    }
+                }
+                Ok(GuiCommand::PartRemoved(part)) => {
+                    self.state.parts.retain(|(p, _)| *p != part);
+                }
+                Ok(GuiCommand::SetScenes(scenes)) => {
+                    self.state.scenes = scenes.iter().map(|n| n.to_string()).collect();
+                }
+                Ok(GuiCommand::SceneSaved(name)) => {
+                    if !self.state.scenes.iter().any(|n| *n == *name) {
+                        self.state.scenes.push(name.to_string());
+                    }
+                }
+                Ok(GuiCommand::SceneDeleted(name)) => {
+                    self.state.scenes.retain(|n| *n != *name);
+                }
                 Err(TryRecvError::Empty) => {
                     break;
                 }
@@ -38,6 +38,11 @@ pub trait Button {
     fn set_state(&mut self, state: ButtonState);
     fn get_state(&self) -> ButtonState;
 
+    // buttons are pressed with the left mouse button, and optionally with the right one as well
+    fn accepts_right_click(&self) -> bool {
+        false
+    }
+
     fn handle_event<F: FnOnce(MouseButton)>(
         &mut self,
         canvas: &Canvas,
@@ -58,6 +63,11 @@ pub trait Button {
                             MouseEventType::MouseDown(MouseButton::Left) => {
                                 self.set_state(ButtonState::Pressed);
                             }
+                            MouseEventType::MouseDown(MouseButton::Right)
+                                if self.accepts_right_click() =>
+                            {
+                                self.set_state(ButtonState::Pressed);
+                            }
                             MouseEventType::MouseUp(button) => {
                                 if self.get_state() == ButtonState::Pressed {
                                     on_click(button);
@@ -93,6 +103,7 @@ pub struct ControlButton {
     color: Color,
     width: f32,
     height: f32,
+    right_click: bool,
 }
 
 impl ControlButton {
@@ -110,9 +121,17 @@ impl ControlButton {
             color,
             width: width.unwrap_or(text_size.width + 24.0),
             height,
+            right_click: false,
         }
     }
 
+    /// Makes the button clickable with the right mouse button as well as the left; `on_click` is
+    /// told which one was used
+    pub fn with_right_click(mut self) -> Self {
+        self.right_click = true;
+        self
+    }
+
     pub fn draw<F: FnOnce(MouseButton) -> ()>(
         &mut self,
         canvas: &mut Canvas,
@@ -204,6 +223,10 @@ impl Button for ControlButton {
     fn get_state(&self) -> ButtonState {
         self.state
     }
+
+    fn accepts_right_click(&self) -> bool {
+        self.right_click
+    }
 }
 
 #[allow(dead_code)]
@@ -5,7 +5,7 @@ use coreaudio::audio_unit::render_callback::{self, data};
 use coreaudio::audio_unit::{AudioUnit, Element, SampleFormat, Scope, StreamFormat};
 use coreaudio::sys::*;
 use crossbeam_channel::{bounded, Receiver};
-use loopers_common::api::Command;
+use loopers_common::api::{Command, QuantizationMode};
 use loopers_common::gui_channel::GuiSender;
 use loopers_common::Host;
 use loopers_engine::Engine;
@@ -37,7 +37,7 @@ impl<'a> Host<'a> for CoreAudioHost {
 
 pub fn coreaudio_main(gui: Option<Gui>,
                       gui_sender: GuiSender,
-                      gui_to_engine_receiver: Receiver<Command>,
+                      gui_to_engine_receiver: Receiver<(Command, Option<QuantizationMode>)>,
                       beat_normal: Vec<f32>,
                       beat_emphasis: Vec<f32>,
                       restore: bool) -> Result<(), coreaudio::Error> {
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::{io, thread};
 use jack::{AudioOut, Port, ProcessScope};
 use crossbeam_channel::{bounded, Sender, Receiver};
-use loopers_common::api::Command;
+use loopers_common::api::{Command, QuantizationMode, AUX_BUS_COUNT};
 use loopers_common::gui_channel::GuiSender;
 use loopers_common::Host;
 use loopers_common::midi::MidiEvent;
@@ -21,6 +21,8 @@ enum ClientChangeResponse {
 
 pub struct JackHost<'a> {
     looper_ports: &'a mut HashMap<u32, [Port<AudioOut>; 2]>,
+    aux_ports: &'a mut Vec<[Port<AudioOut>; 2]>,
+    count_in_ports: &'a mut [Port<AudioOut>; 2],
     ps: Option<&'a ProcessScope>,
     port_change_tx: Sender<ClientChange>,
     port_change_resp: Receiver<ClientChangeResponse>,
@@ -62,6 +64,24 @@ impl<'a> Host<'a> for JackHost<'a> {
         let [l, r] = self.looper_ports.get_mut(&id)?;
         Some([l.as_mut_slice(ps), r.as_mut_slice(ps)])
     }
+
+    fn output_for_aux_bus<'b>(&'b mut self, bus: usize) -> Option<[&'b mut [f32]; 2]>
+        where
+            'a: 'b,
+    {
+        let ps = self.ps?;
+        let [l, r] = self.aux_ports.get_mut(bus)?;
+        Some([l.as_mut_slice(ps), r.as_mut_slice(ps)])
+    }
+
+    fn output_for_count_in<'b>(&'b mut self) -> Option<[&'b mut [f32]; 2]>
+        where
+            'a: 'b,
+    {
+        let ps = self.ps?;
+        let [l, r] = &mut *self.count_in_ports;
+        Some([l.as_mut_slice(ps), r.as_mut_slice(ps)])
+    }
 }
 
 struct Notifications;
@@ -151,7 +171,7 @@ impl jack::NotificationHandler for Notifications {
 
 pub fn jack_main(gui: Option<Gui>,
                  gui_sender: GuiSender,
-                 gui_to_engine_receiver: Receiver<Command>,
+                 gui_to_engine_receiver: Receiver<(Command, Option<QuantizationMode>)>,
                  beat_normal: Vec<f32>,
                  beat_emphasis: Vec<f32>,
                  restore: bool) {
@@ -181,10 +201,32 @@ pub fn jack_main(gui: Option<Gui>,
         .register_port("metronome_out_r", jack::AudioOut::default())
         .unwrap();
 
+    let mut count_in_ports = [
+        client
+            .register_port("count_in_out_l", jack::AudioOut::default())
+            .unwrap(),
+        client
+            .register_port("count_in_out_r", jack::AudioOut::default())
+            .unwrap(),
+    ];
+
     let midi_in = client
         .register_port("loopers_midi_in", jack::MidiIn::default())
         .unwrap();
 
+    let mut aux_ports: Vec<[Port<AudioOut>; 2]> = (0..AUX_BUS_COUNT)
+        .map(|i| {
+            [
+                client
+                    .register_port(&format!("aux{}_out_l", i), jack::AudioOut::default())
+                    .unwrap(),
+                client
+                    .register_port(&format!("aux{}_out_r", i), jack::AudioOut::default())
+                    .unwrap(),
+            ]
+        })
+        .collect();
+
     let mut looper_ports: HashMap<u32, [Port<AudioOut>; 2]> = HashMap::new();
 
     let (port_change_tx, port_change_rx) = bounded(10);
@@ -192,6 +234,8 @@ pub fn jack_main(gui: Option<Gui>,
 
     let mut host = JackHost {
         looper_ports: &mut looper_ports,
+        aux_ports: &mut aux_ports,
+        count_in_ports: &mut count_in_ports,
         ps: None,
         port_change_tx: port_change_tx.clone(),
         port_change_resp: port_change_resp_rx.clone(),
@@ -221,7 +265,11 @@ pub fn jack_main(gui: Option<Gui>,
                 *b = 0f32;
             }
 
-            for l in looper_ports.values_mut() {
+            for l in looper_ports
+                .values_mut()
+                .chain(aux_ports.iter_mut())
+                .chain(std::iter::once(&mut count_in_ports))
+            {
                 for c in l {
                     for v in c.as_mut_slice(ps) {
                         *v = 0f32;
@@ -238,6 +286,8 @@ pub fn jack_main(gui: Option<Gui>,
 
             let mut host = JackHost {
                 looper_ports: &mut looper_ports,
+                aux_ports: &mut aux_ports,
+                count_in_ports: &mut count_in_ports,
                 ps: Some(ps),
                 port_change_tx: process_port_change.clone(),
                 port_change_resp: port_change_resp_rx.clone(),